@@ -0,0 +1,523 @@
+/**
+ * Assembler of the kernelino virtual CPU
+ *
+ * The source holds one statement per line, ; starting a comment:
+ *
+ *   label:               label of the next byte of the current segment
+ *   .text, .data         segment the following statements go to
+ *   .string "hi\n"       NUL terminated string
+ *   .byte 1, 'a'         bytes
+ *   .word 1, -2          64 bit little endian words
+ *   .space 16            zeroed bytes
+ *   .equ NAME, value     constant usable as an immediate
+ *   li r0, SYS_WRITE     instruction, see cpu::INSTRUCTIONS
+ *
+ * Registers are r0 to r15, sp being r15. Immediates are decimal or 0x
+ * hexadecimal numbers, 'c' characters, constants and SYS_ followed by the
+ * upper case name of a system call. Memory operands read [reg+imm].
+ * Data directives only go to .data and instructions to .text. Programs
+ * start at _start, or main, or the first instruction. Labels are kept in
+ * the symbol table of the executable.
+ */
+use std::{collections::HashMap, fmt};
+
+use crate::cpu::{self, Format, Instruction, INSTRUCTION_SIZE};
+use crate::exec::{Executable, ProgramKind, Segment, SegmentKind, Symbol};
+use crate::syscall;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/**
+ * Instruction waiting for the labels to be known.
+ */
+struct Statement<'a> {
+    line: usize,
+    opcode: u8,
+    format: Format,
+    operands: Vec<&'a str>,
+}
+
+struct Assembler<'a> {
+    section: SegmentKind,
+    symbols: HashMap<String, (SegmentKind, u32)>,
+    constants: HashMap<String, i64>,
+    statements: Vec<Statement<'a>>,
+    data: Vec<u8>,
+}
+
+/**
+ * Assemble a source into a bytecode executable, or report every error.
+ */
+pub fn assemble(source: &str) -> Result<Executable, Vec<AsmError>> {
+    let mut assembler = Assembler {
+        section: SegmentKind::Text,
+        symbols: HashMap::new(),
+        constants: HashMap::new(),
+        statements: Vec::new(),
+        data: Vec::new(),
+    };
+    let mut errors = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        if let Err(message) = assembler.statement(index + 1, line) {
+            errors.push(AsmError {
+                line: index + 1,
+                message,
+            });
+        }
+    }
+
+    let mut text = Vec::new();
+    for statement in &assembler.statements {
+        match assembler.encode(statement) {
+            Ok(instruction) => text.extend_from_slice(&instruction.encode()),
+            Err(message) => errors.push(AsmError {
+                line: statement.line,
+                message,
+            }),
+        }
+    }
+    if !errors.is_empty() {
+        errors.sort_by_key(|error| error.line);
+        return Err(errors);
+    }
+
+    let entry = ["_start", "main"]
+        .iter()
+        .find_map(|name| match assembler.symbols.get(*name) {
+            Some(&(SegmentKind::Text, offset)) => Some(offset),
+            _ => None,
+        })
+        .unwrap_or(0);
+    let mut segments = vec![Segment {
+        kind: SegmentKind::Text,
+        bytes: text,
+    }];
+    if !assembler.data.is_empty() {
+        segments.push(Segment {
+            kind: SegmentKind::Data,
+            bytes: assembler.data,
+        });
+    }
+    let mut symbols: Vec<Symbol> = assembler
+        .symbols
+        .into_iter()
+        .map(|(name, (kind, offset))| Symbol { name, kind, offset })
+        .collect();
+    symbols.sort_by(|a, b| {
+        (a.kind == SegmentKind::Data, a.offset, &a.name).cmp(&(
+            b.kind == SegmentKind::Data,
+            b.offset,
+            &b.name,
+        ))
+    });
+    Ok(Executable {
+        kind: ProgramKind::Bytecode,
+        entry,
+        interpreter: None,
+        segments,
+        symbols,
+    })
+}
+
+impl<'a> Assembler<'a> {
+    fn offset(&self) -> u32 {
+        match self.section {
+            SegmentKind::Data => self.data.len() as u32,
+            SegmentKind::Text => self.statements.len() as u32 * INSTRUCTION_SIZE as u32,
+        }
+    }
+
+    /**
+     * First pass over a line: labels get their offset and data is laid out.
+     */
+    fn statement(&mut self, number: usize, line: &'a str) -> Result<(), String> {
+        let mut line = strip_comment(line).trim();
+        if let Some((label, rest)) = line.split_once(':') {
+            if is_identifier(label.trim()) {
+                let label = label.trim();
+                if self.symbols.contains_key(label) {
+                    return Err(format!("label '{}' already defined", label));
+                }
+                self.symbols
+                    .insert(label.to_string(), (self.section, self.offset()));
+                line = rest.trim();
+            }
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        let (mnemonic, rest) = line
+            .split_once(char::is_whitespace)
+            .map_or((line, ""), |(mnemonic, rest)| (mnemonic, rest.trim()));
+        let mnemonic_lower = mnemonic.to_lowercase();
+        match mnemonic_lower.as_str() {
+            ".text" => self.section = SegmentKind::Text,
+            ".data" => self.section = SegmentKind::Data,
+            ".equ" => {
+                let (name, value) = rest
+                    .split_once(',')
+                    .ok_or(".equ needs a name and a value")?;
+                let name = name.trim();
+                if !is_identifier(name) {
+                    return Err(format!("bad constant name '{}'", name));
+                }
+                let value = self.immediate(value.trim())?;
+                self.constants.insert(name.to_string(), value);
+            }
+            ".string" | ".byte" | ".word" | ".space" => {
+                if self.section != SegmentKind::Data {
+                    return Err(format!("{} outside .data", mnemonic));
+                }
+                let bytes = self.data_directive(&mnemonic_lower, rest)?;
+                self.data.extend(bytes);
+            }
+            _ if mnemonic.starts_with('.') => {
+                return Err(format!("unknown directive '{}'", mnemonic))
+            }
+            _ => {
+                let &(opcode, _, format) = cpu::INSTRUCTIONS
+                    .iter()
+                    .find(|(_, name, _)| *name == mnemonic_lower)
+                    .ok_or(format!("unknown instruction '{}'", mnemonic))?;
+                if self.section != SegmentKind::Text {
+                    return Err(format!("instruction '{}' outside .text", mnemonic));
+                }
+                self.statements.push(Statement {
+                    line: number,
+                    opcode,
+                    format,
+                    operands: split_operands(rest),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn data_directive(&self, directive: &str, operands: &str) -> Result<Vec<u8>, String> {
+        match directive {
+            ".string" => {
+                let mut bytes = string_literal(operands)?;
+                bytes.push(0);
+                Ok(bytes)
+            }
+            ".space" => {
+                let size = self.immediate(operands)?;
+                let size = usize::try_from(size).map_err(|_| format!("bad size {}", size))?;
+                Ok(vec![0; size])
+            }
+            _ => {
+                let mut bytes = Vec::new();
+                for operand in split_operands(operands) {
+                    let value = self.immediate(operand)?;
+                    if directive == ".byte" {
+                        let byte = u8::try_from(value)
+                            .or_else(|_| i8::try_from(value).map(|byte| byte as u8))
+                            .map_err(|_| format!("byte out of range: {}", value))?;
+                        bytes.push(byte);
+                    } else {
+                        bytes.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+                if bytes.is_empty() {
+                    return Err(format!("{} needs a value", directive));
+                }
+                Ok(bytes)
+            }
+        }
+    }
+
+    fn immediate(&self, operand: &str) -> Result<i64, String> {
+        let (negative, digits) = match operand.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, operand),
+        };
+        let value = if let Some(hex) = digits.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok()
+        } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+            digits.parse().ok()
+        } else if digits.starts_with('\'') {
+            match string_literal(digits)?.as_slice() {
+                [byte] => Some(*byte as i64),
+                _ => None,
+            }
+        } else if let Some(name) = digits.strip_prefix("SYS_") {
+            syscall::number(&name.to_lowercase()).map(|number| number as i64)
+        } else {
+            self.constants.get(digits).copied()
+        };
+        value
+            .map(|value| if negative { -value } else { value })
+            .ok_or(format!("bad immediate '{}'", operand))
+    }
+
+    /**
+     * Second pass: encode an instruction, its labels being known.
+     */
+    fn encode(&self, statement: &Statement) -> Result<Instruction, String> {
+        let operands = &statement.operands;
+        let (_, mnemonic, _) = cpu::INSTRUCTIONS
+            .iter()
+            .find(|(opcode, ..)| *opcode == statement.opcode)
+            .unwrap();
+        let expected = match statement.format {
+            Format::None => 0,
+            Format::Reg | Format::Jump => 1,
+            Format::RegImm | Format::RegReg | Format::Memory | Format::Address => 2,
+            Format::RegRegReg | Format::RegRegImm | Format::Branch => 3,
+        };
+        if operands.len() != expected {
+            return Err(format!(
+                "{} takes {} operands, got {}",
+                mnemonic,
+                expected,
+                operands.len()
+            ));
+        }
+
+        let mut instruction = Instruction {
+            opcode: statement.opcode,
+            ..Default::default()
+        };
+        // The base register of a memory operand is parsed with its offset
+        let registers = match statement.format {
+            Format::Memory => 1,
+            format => format.registers(),
+        };
+        for (index, operand) in operands.iter().take(registers).enumerate() {
+            let register = register(operand)?;
+            match index {
+                0 => instruction.a = register,
+                1 => instruction.b = register,
+                _ => instruction.c = register,
+            }
+        }
+        let imm = match statement.format {
+            Format::RegImm | Format::RegRegImm => self.immediate(operands[expected - 1])?,
+            Format::Memory => {
+                let (base, offset) = self.memory(operands[1])?;
+                instruction.b = base;
+                offset
+            }
+            Format::Jump | Format::Branch => {
+                self.label(operands[expected - 1], SegmentKind::Text)?
+            }
+            Format::Address => self.label(operands[1], SegmentKind::Data)?,
+            _ => 0,
+        };
+        instruction.imm =
+            i32::try_from(imm).map_err(|_| format!("immediate out of range: {}", imm))?;
+        Ok(instruction)
+    }
+
+    /**
+     * [reg], [reg+imm] or [reg-imm].
+     */
+    fn memory(&self, operand: &str) -> Result<(u8, i64), String> {
+        let inner = operand
+            .strip_prefix('[')
+            .and_then(|operand| operand.strip_suffix(']'))
+            .ok_or(format!("bad memory operand '{}'", operand))?;
+        match inner.find(['+', '-']) {
+            Some(index) => {
+                let base = register(inner[..index].trim())?;
+                let offset = self.immediate(inner[index + 1..].trim())?;
+                Ok((
+                    base,
+                    if &inner[index..=index] == "-" {
+                        -offset
+                    } else {
+                        offset
+                    },
+                ))
+            }
+            None => Ok((register(inner.trim())?, 0)),
+        }
+    }
+
+    fn label(&self, operand: &str, kind: SegmentKind) -> Result<i64, String> {
+        match self.symbols.get(operand) {
+            Some(&(label_kind, offset)) if label_kind == kind => Ok(offset as i64),
+            Some(_) => Err(format!(
+                "'{}' is not a {} label",
+                operand,
+                match kind {
+                    SegmentKind::Text => ".text",
+                    SegmentKind::Data => ".data",
+                }
+            )),
+            None => Err(format!("unknown label '{}'", operand)),
+        }
+    }
+}
+
+fn register(operand: &str) -> Result<u8, String> {
+    if operand == "sp" {
+        return Ok(cpu::SP as u8);
+    }
+    operand
+        .strip_prefix('r')
+        .and_then(|number| number.parse::<u8>().ok())
+        .filter(|&number| (number as usize) < cpu::REGISTERS)
+        .ok_or(format!("bad register '{}'", operand))
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/**
+ * Line without its comment, a ; in a string or character not starting one.
+ */
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ';') => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_operands(operands: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (index, c) in operands.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '\'') => quote = Some(c),
+            (None, ',') => {
+                result.push(operands[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    let last = operands[start..].trim();
+    if !last.is_empty() || !result.is_empty() {
+        result.push(last);
+    }
+    result
+}
+
+/**
+ * Bytes of a "string" or 'c' literal, with \n \t \r \0 \\ \" \' and \xHH
+ * escapes.
+ */
+fn string_literal(literal: &str) -> Result<Vec<u8>, String> {
+    let quote = literal.chars().next().filter(|c| *c == '"' || *c == '\'');
+    let inner = quote
+        .and_then(|quote| literal[1..].strip_suffix(quote))
+        .ok_or(format!("bad literal {}", literal))?;
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some(c @ ('\\' | '"' | '\'')) => c as u8,
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16).map_err(|_| format!("bad escape \\x{}", hex))?
+            }
+            c => {
+                return Err(format!(
+                    "bad escape \\{}",
+                    c.map(String::from).unwrap_or_default()
+                ))
+            }
+        });
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, kind: SegmentKind, offset: u32) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            kind,
+            offset,
+        }
+    }
+
+    #[test]
+    fn symbols_are_sorted_by_segment_and_offset() {
+        let source = "\
+        .data
+zeros:  .space 3
+answer: .word 42
+        .text
+helper: ret
+_start: call helper
+        la r1, answer
+.end:   halt
+";
+        let executable = assemble(source).unwrap();
+        let size = INSTRUCTION_SIZE as u32;
+        assert_eq!(
+            executable.symbols,
+            vec![
+                symbol("helper", SegmentKind::Text, 0),
+                symbol("_start", SegmentKind::Text, size),
+                symbol(".end", SegmentKind::Text, 3 * size),
+                symbol("zeros", SegmentKind::Data, 0),
+                symbol("answer", SegmentKind::Data, 3),
+            ]
+        );
+        assert_eq!(executable.entry, size);
+        assert_eq!(executable.text().len(), 4 * INSTRUCTION_SIZE as usize);
+    }
+
+    #[test]
+    fn every_error_is_reported_with_its_line() {
+        let source = "\
+start:  li r0, 1
+start:  nop
+        jmp nowhere
+        li r16, 0
+";
+        let errors: Vec<String> = assemble(source)
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 2: label 'start' already defined",
+                "line 3: unknown label 'nowhere'",
+                "line 4: bad register 'r16'",
+            ]
+        );
+    }
+}
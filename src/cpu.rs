@@ -0,0 +1,630 @@
+/**
+ * Kernelino virtual CPU
+ *
+ * A register machine running the bytecode of executables. It has sixteen
+ * 64 bit registers, r15 being the stack pointer, and fixed size
+ * instructions of 8 bytes:
+ *
+ *   opcode   1 byte
+ *   a, b, c  1 byte each, register operands
+ *   imm      4 bytes  signed immediate, little endian
+ *
+ * Every fetch, load and store goes through the page table of the process:
+ * an unmapped address or a write to the read only text segment raises
+ * SIGSEGV, an unknown opcode or register SIGILL and a division by zero
+ * SIGFPE. Jump, branch and call targets are offsets in the text segment,
+ * la operands offsets in the data segment.
+ *
+ * syscall enters the kernel with the call number in r0 and the arguments
+ * from r1 on: an integer takes a register, a string the address of its
+ * NUL terminated bytes and a buffer its address then its length. The
+ * result comes back in r0, -errno on failure. A buffer returned by the
+ * kernel is copied to the address held by the register following the
+ * arguments, r0 being its length, and a list fills r0, r1 and so on.
+ */
+use crate::errno::Errno;
+use crate::exec::{Executable, SegmentKind};
+use crate::signal::Signal;
+use crate::syscall::{self, ArgKind, Value};
+use crate::vpm::{self, Vpm};
+
+pub const REGISTERS: usize = 16;
+pub const SP: usize = 15;
+pub const INSTRUCTION_SIZE: u64 = 8;
+
+/**
+ * Size of the stack mapped for a program, its arguments included.
+ */
+const STACK_SIZE: usize = 16 * 1024;
+
+/**
+ * Instructions run between two preemption points.
+ */
+const TIME_SLICE: u32 = 64;
+
+/**
+ * Longest string a system call reads from the program memory.
+ */
+const MAX_STRING: usize = 4096;
+
+/**
+ * Return address of the entry point: returning there exits the program
+ * with r0 as status.
+ */
+const EXIT_ADDRESS: u64 = u64::MAX;
+
+pub const OP_HALT: u8 = 0x01;
+pub const OP_NOP: u8 = 0x02;
+pub const OP_SYSCALL: u8 = 0x03;
+pub const OP_LI: u8 = 0x10;
+pub const OP_MOV: u8 = 0x11;
+pub const OP_LA: u8 = 0x12;
+pub const OP_ADD: u8 = 0x20;
+pub const OP_SUB: u8 = 0x21;
+pub const OP_MUL: u8 = 0x22;
+pub const OP_DIV: u8 = 0x23;
+pub const OP_MOD: u8 = 0x24;
+pub const OP_AND: u8 = 0x25;
+pub const OP_OR: u8 = 0x26;
+pub const OP_XOR: u8 = 0x27;
+pub const OP_SHL: u8 = 0x28;
+pub const OP_SHR: u8 = 0x29;
+pub const OP_ADDI: u8 = 0x2a;
+pub const OP_LD: u8 = 0x30;
+pub const OP_LDB: u8 = 0x31;
+pub const OP_ST: u8 = 0x32;
+pub const OP_STB: u8 = 0x33;
+pub const OP_JMP: u8 = 0x40;
+pub const OP_BEQ: u8 = 0x41;
+pub const OP_BNE: u8 = 0x42;
+pub const OP_BLT: u8 = 0x43;
+pub const OP_BGE: u8 = 0x44;
+pub const OP_CALL: u8 = 0x45;
+pub const OP_RET: u8 = 0x46;
+pub const OP_PUSH: u8 = 0x50;
+pub const OP_POP: u8 = 0x51;
+
+/**
+ * Operands of an instruction, as written in assembly.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// halt
+    None,
+    /// push a
+    Reg,
+    /// li a, imm
+    RegImm,
+    /// mov a, b
+    RegReg,
+    /// add a, b, c
+    RegRegReg,
+    /// addi a, b, imm
+    RegRegImm,
+    /// ld a, [b+imm]
+    Memory,
+    /// jmp label
+    Jump,
+    /// beq a, b, label
+    Branch,
+    /// la a, label
+    Address,
+}
+
+impl Format {
+    /**
+     * How many of the a, b and c operands are registers.
+     */
+    pub fn registers(&self) -> usize {
+        match self {
+            Self::None | Self::Jump => 0,
+            Self::Reg | Self::RegImm | Self::Address => 1,
+            Self::RegReg | Self::RegRegImm | Self::Memory | Self::Branch => 2,
+            Self::RegRegReg => 3,
+        }
+    }
+}
+
+/**
+ * Opcode, mnemonic and format of each instruction.
+ */
+pub const INSTRUCTIONS: [(u8, &str, Format); 30] = [
+    (OP_HALT, "halt", Format::None),
+    (OP_NOP, "nop", Format::None),
+    (OP_SYSCALL, "syscall", Format::None),
+    (OP_LI, "li", Format::RegImm),
+    (OP_MOV, "mov", Format::RegReg),
+    (OP_LA, "la", Format::Address),
+    (OP_ADD, "add", Format::RegRegReg),
+    (OP_SUB, "sub", Format::RegRegReg),
+    (OP_MUL, "mul", Format::RegRegReg),
+    (OP_DIV, "div", Format::RegRegReg),
+    (OP_MOD, "mod", Format::RegRegReg),
+    (OP_AND, "and", Format::RegRegReg),
+    (OP_OR, "or", Format::RegRegReg),
+    (OP_XOR, "xor", Format::RegRegReg),
+    (OP_SHL, "shl", Format::RegRegReg),
+    (OP_SHR, "shr", Format::RegRegReg),
+    (OP_ADDI, "addi", Format::RegRegImm),
+    (OP_LD, "ld", Format::Memory),
+    (OP_LDB, "ldb", Format::Memory),
+    (OP_ST, "st", Format::Memory),
+    (OP_STB, "stb", Format::Memory),
+    (OP_JMP, "jmp", Format::Jump),
+    (OP_BEQ, "beq", Format::Branch),
+    (OP_BNE, "bne", Format::Branch),
+    (OP_BLT, "blt", Format::Branch),
+    (OP_BGE, "bge", Format::Branch),
+    (OP_CALL, "call", Format::Jump),
+    (OP_RET, "ret", Format::None),
+    (OP_PUSH, "push", Format::Reg),
+    (OP_POP, "pop", Format::Reg),
+];
+
+/**
+ * Mnemonic and format of an opcode, None if the opcode is illegal.
+ */
+pub fn decode_opcode(opcode: u8) -> Option<(&'static str, Format)> {
+    INSTRUCTIONS
+        .iter()
+        .find(|(op, ..)| *op == opcode)
+        .map(|&(_, mnemonic, format)| (mnemonic, format))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Instruction {
+    pub opcode: u8,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub imm: i32,
+}
+
+impl Instruction {
+    pub fn decode(bytes: [u8; INSTRUCTION_SIZE as usize]) -> Self {
+        Self {
+            opcode: bytes[0],
+            a: bytes[1],
+            b: bytes[2],
+            c: bytes[3],
+            imm: i32::from_le_bytes(bytes[4..].try_into().unwrap()),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; INSTRUCTION_SIZE as usize] {
+        let mut bytes = [self.opcode, self.a, self.b, self.c, 0, 0, 0, 0];
+        bytes[4..].copy_from_slice(&self.imm.to_le_bytes());
+        bytes
+    }
+}
+
+/**
+ * Why the CPU stopped running the program.
+ */
+enum Stop {
+    Exit(i32),
+    Fault(Signal),
+    /// The process was terminated by a signal or the exit system call
+    Terminated,
+}
+
+struct Cpu<'a> {
+    process: &'a Vpm,
+    registers: [i64; REGISTERS],
+    pc: u64,
+    text: u64,
+    data: u64,
+}
+
+impl Cpu<'_> {
+    fn load(&self, address: u64, len: usize) -> Result<Vec<u8>, Errno> {
+        self.process
+            .vmm
+            .lock()
+            .unwrap()
+            .read_bytes(self.process.pid, address, len)
+    }
+
+    fn store(&self, address: u64, bytes: &[u8]) -> Result<(), Errno> {
+        self.process
+            .vmm
+            .lock()
+            .unwrap()
+            .write_bytes(self.process.pid, address, bytes)
+    }
+
+    fn load_word(&self, address: u64) -> Result<i64, Stop> {
+        let bytes = self
+            .load(address, 8)
+            .map_err(|_| Stop::Fault(Signal::SIGSEGV))?;
+        Ok(i64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn store_word(&self, address: u64, word: i64) -> Result<(), Stop> {
+        self.store(address, &word.to_le_bytes())
+            .map_err(|_| Stop::Fault(Signal::SIGSEGV))
+    }
+
+    fn push(&mut self, word: i64) -> Result<(), Stop> {
+        let sp = self.registers[SP].wrapping_sub(8);
+        self.store_word(sp as u64, word)?;
+        self.registers[SP] = sp;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i64, Stop> {
+        let word = self.load_word(self.registers[SP] as u64)?;
+        self.registers[SP] = self.registers[SP].wrapping_add(8);
+        Ok(word)
+    }
+
+    /**
+     * Map the stack and lay the arguments out at its top, argv being a
+     * NULL terminated array of pointers to the strings.
+     */
+    fn start(&mut self, entry: u32, args: &[&str]) -> Result<(), Stop> {
+        let segv = |_| Stop::Fault(Signal::SIGSEGV);
+        let stack = self
+            .process
+            .vmm
+            .lock()
+            .unwrap()
+            .map_stack(self.process.pid, STACK_SIZE)
+            .map_err(segv)?;
+        let mut sp = stack + STACK_SIZE as u64;
+        let mut argv = vec![0];
+        for arg in args.iter().rev() {
+            sp -= arg.len() as u64 + 1;
+            self.store(sp, &[arg.as_bytes(), &[0]].concat())
+                .map_err(segv)?;
+            argv.insert(0, sp as i64);
+        }
+        sp &= !7;
+        for pointer in argv.iter().rev() {
+            sp -= 8;
+            self.store_word(sp, *pointer)?;
+        }
+        self.registers[1] = args.len() as i64;
+        self.registers[2] = sp as i64;
+        self.registers[SP] = sp as i64;
+        self.push(EXIT_ADDRESS as i64)?;
+        self.pc = self.text + entry as u64;
+        Ok(())
+    }
+
+    /**
+     * Preemption point, the TLB holds the translations of whatever ran
+     * meanwhile.
+     */
+    fn preempt(&self) -> Result<(), Stop> {
+        if !self.process.yield_cpu() {
+            return Err(Stop::Terminated);
+        }
+        self.process
+            .vmm
+            .lock()
+            .unwrap()
+            .switch_context(self.process.pid);
+        Ok(())
+    }
+
+    fn run(&mut self) -> Stop {
+        let mut slice = 0;
+        loop {
+            if let Err(stop) = self.step() {
+                return stop;
+            }
+            slice += 1;
+            if slice == TIME_SLICE {
+                slice = 0;
+                if let Err(stop) = self.preempt() {
+                    return stop;
+                }
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<(), Stop> {
+        if self.pc == EXIT_ADDRESS {
+            return Err(Stop::Exit(self.registers[0] as i32));
+        }
+        let bytes = self
+            .load(self.pc, INSTRUCTION_SIZE as usize)
+            .map_err(|_| Stop::Fault(Signal::SIGSEGV))?;
+        let instruction = Instruction::decode(bytes.try_into().unwrap());
+        let (_, format) = decode_opcode(instruction.opcode).ok_or(Stop::Fault(Signal::SIGILL))?;
+        let operands = [instruction.a, instruction.b, instruction.c];
+        if operands[..format.registers()]
+            .iter()
+            .any(|&register| register as usize >= REGISTERS)
+        {
+            return Err(Stop::Fault(Signal::SIGILL));
+        }
+
+        let (a, b, c) = (
+            instruction.a as usize,
+            instruction.b as usize,
+            instruction.c as usize,
+        );
+        let imm = instruction.imm as i64;
+        let r = self.registers;
+        let address = r[b].wrapping_add(imm) as u64;
+        let target = self.text.wrapping_add_signed(imm);
+        let mut next = self.pc.wrapping_add(INSTRUCTION_SIZE);
+        match instruction.opcode {
+            OP_HALT => return Err(Stop::Exit(r[0] as i32)),
+            OP_NOP => {}
+            OP_SYSCALL => {
+                self.syscall();
+                self.pc = next;
+                return self.preempt();
+            }
+            OP_LI => self.registers[a] = imm,
+            OP_MOV => self.registers[a] = r[b],
+            OP_LA => self.registers[a] = self.data.wrapping_add_signed(imm) as i64,
+            OP_ADD => self.registers[a] = r[b].wrapping_add(r[c]),
+            OP_SUB => self.registers[a] = r[b].wrapping_sub(r[c]),
+            OP_MUL => self.registers[a] = r[b].wrapping_mul(r[c]),
+            OP_DIV => {
+                self.registers[a] = r[b].checked_div(r[c]).ok_or(Stop::Fault(Signal::SIGFPE))?
+            }
+            OP_MOD => {
+                self.registers[a] = r[b].checked_rem(r[c]).ok_or(Stop::Fault(Signal::SIGFPE))?
+            }
+            OP_AND => self.registers[a] = r[b] & r[c],
+            OP_OR => self.registers[a] = r[b] | r[c],
+            OP_XOR => self.registers[a] = r[b] ^ r[c],
+            OP_SHL => self.registers[a] = r[b].wrapping_shl(r[c] as u32),
+            OP_SHR => self.registers[a] = (r[b] as u64).wrapping_shr(r[c] as u32) as i64,
+            OP_ADDI => self.registers[a] = r[b].wrapping_add(imm),
+            OP_LD => self.registers[a] = self.load_word(address)?,
+            OP_LDB => {
+                let byte = self
+                    .load(address, 1)
+                    .map_err(|_| Stop::Fault(Signal::SIGSEGV))?;
+                self.registers[a] = byte[0] as i64;
+            }
+            OP_ST => self.store_word(address, r[a])?,
+            OP_STB => self
+                .store(address, &[r[a] as u8])
+                .map_err(|_| Stop::Fault(Signal::SIGSEGV))?,
+            OP_JMP => next = target,
+            OP_BEQ if r[a] == r[b] => next = target,
+            OP_BNE if r[a] != r[b] => next = target,
+            OP_BLT if r[a] < r[b] => next = target,
+            OP_BGE if r[a] >= r[b] => next = target,
+            OP_BEQ | OP_BNE | OP_BLT | OP_BGE => {}
+            OP_CALL => {
+                self.push(next as i64)?;
+                next = target;
+            }
+            OP_RET => next = self.pop()? as u64,
+            OP_PUSH => self.push(r[a])?,
+            OP_POP => self.registers[a] = self.pop()?,
+            _ => return Err(Stop::Fault(Signal::SIGILL)),
+        }
+        self.pc = next;
+        Ok(())
+    }
+
+    /**
+     * Read a NUL terminated string argument.
+     */
+    fn load_string(&self, address: u64) -> Result<String, Errno> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.load(address + bytes.len() as u64, 1)?[0];
+            if byte == 0 {
+                return Ok(String::from_utf8_lossy(&bytes).to_string());
+            }
+            if bytes.len() == MAX_STRING {
+                return Err(Errno::ENAMETOOLONG);
+            }
+            bytes.push(byte);
+        }
+    }
+
+    fn arguments(&self, kinds: &[ArgKind]) -> Result<(Vec<Value>, usize), Errno> {
+        let mut register = 1;
+        let mut args = Vec::new();
+        for kind in kinds {
+            let value = self.registers[register];
+            register += 1;
+            args.push(match kind {
+                ArgKind::Int => Value::Int(value),
+                ArgKind::Str => Value::Str(self.load_string(value as u64)?),
+                ArgKind::Bytes => {
+                    let len =
+                        usize::try_from(self.registers[register]).map_err(|_| Errno::EINVAL)?;
+                    register += 1;
+                    Value::Bytes(self.load(value as u64, len)?)
+                }
+            });
+        }
+        Ok((args, register))
+    }
+
+    /**
+     * Register value of a result, buffers being copied to the program.
+     */
+    fn result(&self, value: &Value, buffer: usize) -> i64 {
+        let bytes = match value {
+            Value::Int(value) => return *value,
+            Value::Str(value) => value.as_bytes(),
+            Value::Bytes(value) => value,
+            Value::List(_) => return -(Errno::EINVAL as i64),
+        };
+        match self.store(self.registers[buffer] as u64, bytes) {
+            Ok(()) => bytes.len() as i64,
+            Err(errno) => -(errno as i64),
+        }
+    }
+
+    fn syscall(&mut self) {
+        let number = u32::try_from(self.registers[0]).unwrap_or(u32::MAX);
+        let kinds = syscall::signature(number).unwrap_or(&[]);
+        let (args, buffer) = match self.arguments(kinds) {
+            Ok(arguments) => arguments,
+            Err(errno) => {
+                self.registers[0] = -(errno as i64);
+                return;
+            }
+        };
+        match syscall::syscall(self.process, number, &args) {
+            Ok(Value::List(values)) => values.iter().enumerate().for_each(|(index, value)| {
+                self.registers[index] = self.result(value, buffer);
+            }),
+            Ok(value) => self.registers[0] = self.result(&value, buffer),
+            Err(errno) => self.registers[0] = -(errno as i64),
+        }
+    }
+}
+
+/**
+ * Run a bytecode executable the process was exec'ed with, its segments
+ * being loaded at the given addresses. Returns its exit status, the
+ * status of the signal if it faulted.
+ */
+pub fn run(process: &Vpm, executable: &Executable, addresses: &[u64], args: &[&str]) -> i32 {
+    let base = |kind: SegmentKind| {
+        executable
+            .segments
+            .iter()
+            .zip(addresses)
+            .find(|(segment, _)| segment.kind == kind)
+            .map_or(0, |(_, &address)| address)
+    };
+    let mut cpu = Cpu {
+        process,
+        registers: [0; REGISTERS],
+        pc: 0,
+        text: base(SegmentKind::Text),
+        data: base(SegmentKind::Data),
+    };
+    let stop = match cpu.start(executable.entry, args) {
+        Ok(()) => cpu.run(),
+        Err(stop) => stop,
+    };
+    match stop {
+        Stop::Exit(code) => code,
+        Stop::Fault(signal) => {
+            process.kill(process.pid, signal).ok();
+            // A handled fault would only fault again
+            process.exit(signal.exit_code());
+            signal.exit_code()
+        }
+        Stop::Terminated => vpm::process(process.pid)
+            .and_then(|process| process.exit_code)
+            .unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::exec::{ProgramKind, Segment};
+    use crate::vmm::{PageSize, Vmm};
+    use std::sync::{Arc, Mutex};
+
+    /**
+     * Exit status of the executable run in a child process.
+     */
+    fn run_program(executable: Executable) -> i32 {
+        let vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        let mut parent = Vpm::new(Arc::new(Mutex::new(vmm)));
+        let child = parent
+            .execute_child("program", move |process| {
+                let addresses = process.exec("program", &executable).unwrap();
+                run(process, &executable, &addresses, &["program"])
+            })
+            .unwrap();
+        match parent.waitpid(Some(child), false) {
+            Ok(Some((_, code))) => code,
+            status => panic!("wait failed: {:?}", status),
+        }
+    }
+
+    fn run_source(source: &str) -> i32 {
+        run_program(asm::assemble(source).unwrap())
+    }
+
+    fn run_instructions(instructions: &[Instruction]) -> i32 {
+        run_program(Executable {
+            kind: ProgramKind::Bytecode,
+            entry: 0,
+            interpreter: None,
+            segments: vec![Segment {
+                kind: SegmentKind::Text,
+                bytes: instructions.iter().flat_map(Instruction::encode).collect(),
+            }],
+            symbols: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn returning_from_the_entry_point_exits_with_r0() {
+        assert_eq!(run_source("_start: li r0, 6\n addi r0, r0, 1\n ret\n"), 7);
+        assert_eq!(run_source("_start: li r0, 3\n halt\n li r0, 4\n ret\n"), 3);
+    }
+
+    #[test]
+    fn division_by_zero_raises_sigfpe() {
+        let fpe = Signal::SIGFPE.exit_code();
+        assert_eq!(
+            run_source("_start: li r1, 1\n li r2, 0\n div r0, r1, r2\n ret\n"),
+            fpe
+        );
+        assert_eq!(
+            run_source("_start: li r1, 1\n li r2, 0\n mod r0, r1, r2\n ret\n"),
+            fpe
+        );
+        // The quotient of the lowest integer by -1 overflows
+        let source =
+            "_start: li r1, 1\n li r2, 63\n shl r1, r1, r2\n li r2, -1\n div r0, r1, r2\n ret\n";
+        assert_eq!(run_source(source), fpe);
+    }
+
+    #[test]
+    fn bad_memory_accesses_raise_sigsegv() {
+        let segv = Signal::SIGSEGV.exit_code();
+        assert_eq!(
+            run_source("_start: li r1, 0x40000000\n ld r0, [r1]\n ret\n"),
+            segv
+        );
+        assert_eq!(
+            run_source("_start: li r1, 0x40000000\n st r0, [r1]\n ret\n"),
+            segv
+        );
+        // The return address pushed by call lies in the read only text
+        let source = "_start: call store\n ret\nstore: ld r1, [r15]\n st r1, [r1]\n ret\n";
+        assert_eq!(run_source(source), segv);
+        let jump = Instruction {
+            opcode: OP_JMP,
+            imm: 1 << 20,
+            ..Default::default()
+        };
+        assert_eq!(run_instructions(&[jump]), segv);
+    }
+
+    #[test]
+    fn bad_instructions_raise_sigill() {
+        let ill = Signal::SIGILL.exit_code();
+        let unknown = Instruction {
+            opcode: 0xff,
+            ..Default::default()
+        };
+        assert_eq!(run_instructions(&[unknown]), ill);
+        let bad_register = Instruction {
+            opcode: OP_MOV,
+            a: REGISTERS as u8,
+            ..Default::default()
+        };
+        assert_eq!(run_instructions(&[bad_register]), ill);
+        let halt = Instruction {
+            opcode: OP_HALT,
+            ..Default::default()
+        };
+        assert_eq!(run_instructions(&[halt]), 0);
+    }
+}
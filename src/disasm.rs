@@ -0,0 +1,316 @@
+/**
+ * Disassembler of the kernelino virtual CPU
+ *
+ * Prints the text segment of a bytecode executable as assembly, the data
+ * segment as a hex dump and the symbol table, objdump style. Symbols name
+ * the targets of jumps and la operands, a syscall is annotated with the
+ * name of the call when r0 was loaded with li in the same block.
+ */
+use crate::cpu::{self, Format, Instruction, INSTRUCTION_SIZE};
+use crate::exec::{Executable, ProgramKind, SegmentKind, Symbol};
+use crate::syscall;
+
+/**
+ * Bytes of data shown per line.
+ */
+const DUMP_WIDTH: usize = 16;
+
+fn register(index: u8) -> String {
+    if index as usize == cpu::SP {
+        "sp".to_string()
+    } else {
+        format!("r{}", index)
+    }
+}
+
+fn label(symbols: &[Symbol], kind: SegmentKind, offset: i32) -> String {
+    symbols
+        .iter()
+        .find(|symbol| symbol.kind == kind && symbol.offset as i64 == offset as i64)
+        .map_or_else(|| format!("{:#x}", offset), |symbol| symbol.name.clone())
+}
+
+/**
+ * Assembly of an instruction, (bad) if it is illegal.
+ */
+pub fn instruction(instruction: &Instruction, symbols: &[Symbol]) -> String {
+    let Some((mnemonic, format)) = cpu::decode_opcode(instruction.opcode) else {
+        return "(bad)".to_string();
+    };
+    let operands = [instruction.a, instruction.b, instruction.c];
+    if operands[..format.registers()]
+        .iter()
+        .any(|&register| register as usize >= cpu::REGISTERS)
+    {
+        return "(bad)".to_string();
+    }
+
+    let (a, b, c) = (
+        register(instruction.a),
+        register(instruction.b),
+        register(instruction.c),
+    );
+    let imm = instruction.imm;
+    let operands = match format {
+        Format::None => return mnemonic.to_string(),
+        Format::Reg => a,
+        Format::RegImm => format!("{}, {}", a, imm),
+        Format::RegReg => format!("{}, {}", a, b),
+        Format::RegRegReg => format!("{}, {}, {}", a, b, c),
+        Format::RegRegImm => format!("{}, {}, {}", a, b, imm),
+        Format::Memory => match imm {
+            0 => format!("{}, [{}]", a, b),
+            _ if imm < 0 => format!("{}, [{}{}]", a, b, imm),
+            _ => format!("{}, [{}+{}]", a, b, imm),
+        },
+        Format::Jump => label(symbols, SegmentKind::Text, imm),
+        Format::Branch => format!("{}, {}, {}", a, b, label(symbols, SegmentKind::Text, imm)),
+        Format::Address => format!("{}, {}", a, label(symbols, SegmentKind::Data, imm)),
+    };
+    format!("{} {}", mnemonic, operands)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn print_labels(symbols: &[Symbol], kind: SegmentKind, offset: usize) {
+    symbols
+        .iter()
+        .filter(|symbol| symbol.kind == kind && symbol.offset as usize == offset)
+        .for_each(|symbol| println!("\n{:04x} <{}>:", offset, symbol.name));
+}
+
+fn print_text(text: &[u8], symbols: &[Symbol]) {
+    println!("\nDisassembly of .text:");
+    let mut r0 = None;
+    for (index, bytes) in text.chunks(INSTRUCTION_SIZE as usize).enumerate() {
+        let offset = index * INSTRUCTION_SIZE as usize;
+        if symbols
+            .iter()
+            .any(|symbol| symbol.kind == SegmentKind::Text && symbol.offset as usize == offset)
+        {
+            r0 = None;
+        }
+        print_labels(symbols, SegmentKind::Text, offset);
+        let Ok(bytes) = <[u8; INSTRUCTION_SIZE as usize]>::try_from(bytes) else {
+            println!("  {:04x}:  {:<23}  (bad)", offset, hex(bytes));
+            continue;
+        };
+        let decoded = Instruction::decode(bytes);
+        let mut assembly = instruction(&decoded, symbols);
+        let writes_r0 = decoded.a == 0
+            && cpu::decode_opcode(decoded.opcode).is_some_and(|(_, format)| {
+                !matches!(format, Format::None | Format::Jump | Format::Branch)
+            })
+            && !matches!(decoded.opcode, cpu::OP_ST | cpu::OP_STB | cpu::OP_PUSH);
+        match decoded.opcode {
+            cpu::OP_LI if decoded.a == 0 => r0 = Some(decoded.imm),
+            cpu::OP_SYSCALL => {
+                if let Some(name) = r0.and_then(|number| syscall::name(number as u32)) {
+                    assembly = format!("{:<23} ; {}", assembly, name);
+                }
+                r0 = None;
+            }
+            cpu::OP_JMP | cpu::OP_CALL | cpu::OP_RET => r0 = None,
+            _ if writes_r0 => r0 = None,
+            _ => {}
+        }
+        println!("  {:04x}:  {}  {}", offset, hex(&bytes), assembly);
+    }
+}
+
+fn print_data(data: &[u8], symbols: &[Symbol]) {
+    println!("\nContents of .data:");
+    let mut starts: Vec<usize> = symbols
+        .iter()
+        .filter(|symbol| symbol.kind == SegmentKind::Data)
+        .map(|symbol| symbol.offset as usize)
+        .filter(|&offset| offset < data.len())
+        .collect();
+    starts.push(0);
+    starts.push(data.len());
+    starts.sort();
+    starts.dedup();
+    for range in starts.windows(2) {
+        print_labels(symbols, SegmentKind::Data, range[0]);
+        for offset in (range[0]..range[1]).step_by(DUMP_WIDTH) {
+            let bytes = &data[offset..range[1].min(offset + DUMP_WIDTH)];
+            let text: String = bytes
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            println!(
+                "  {:04x}:  {:<width$}  {}",
+                offset,
+                hex(bytes),
+                text,
+                width = DUMP_WIDTH * 3 - 1
+            );
+        }
+    }
+}
+
+/**
+ * Print the symbol table, T for text symbols and D for data ones like nm.
+ */
+fn print_symbols(symbols: &[Symbol]) {
+    println!("\nSymbol table:");
+    if symbols.is_empty() {
+        println!("  no symbols");
+    }
+    symbols.iter().for_each(|symbol| {
+        let kind = match symbol.kind {
+            SegmentKind::Text => 'T',
+            SegmentKind::Data => 'D',
+        };
+        println!("  {:04x} {} {}", symbol.offset, kind, symbol.name);
+    });
+}
+
+/**
+ * Print the disassembly of a program, only its symbol table if
+ * symbols_only is set.
+ */
+pub fn print(name: &str, executable: &Executable, symbols_only: bool) {
+    if executable.kind == ProgramKind::Script {
+        println!(
+            "{}: script, interpreter {}",
+            name,
+            executable.interpreter.as_deref().unwrap_or("")
+        );
+        return;
+    }
+    let entry = executable
+        .symbols
+        .iter()
+        .find(|symbol| symbol.kind == SegmentKind::Text && symbol.offset == executable.entry)
+        .map_or(String::new(), |symbol| format!(" <{}>", symbol.name));
+    println!(
+        "{}: bytecode, entry {:04x}{}",
+        name, executable.entry, entry
+    );
+    if !symbols_only {
+        executable
+            .segments
+            .iter()
+            .for_each(|segment| match segment.kind {
+                SegmentKind::Text => print_text(&segment.bytes, &executable.symbols),
+                SegmentKind::Data => print_data(&segment.bytes, &executable.symbols),
+            });
+    }
+    print_symbols(&executable.symbols);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    /**
+     * One instruction of each format, written the way they disassemble.
+     */
+    const SOURCE: &str = "\
+        .data
+message:
+        .string \"hi\"
+        .text
+_start:
+        li r0, 42
+        mov r1, r0
+        la r2, message
+        add r3, r1, r2
+        addi r3, r3, -1
+        ld r4, [sp+8]
+        stb r4, [r3-2]
+        ldb r5, [r3]
+        beq r3, r4, done
+        push r5
+        call done
+        jmp _start
+done:
+        pop r5
+        syscall
+        ret
+";
+
+    /**
+     * Source of the text segment rebuilt from the disassembly.
+     */
+    fn disassemble(executable: &Executable) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (index, bytes) in executable
+            .text()
+            .chunks(INSTRUCTION_SIZE as usize)
+            .enumerate()
+        {
+            let offset = (index * INSTRUCTION_SIZE as usize) as u32;
+            executable
+                .symbols
+                .iter()
+                .filter(|symbol| symbol.kind == SegmentKind::Text && symbol.offset == offset)
+                .for_each(|symbol| lines.push(format!("{}:", symbol.name)));
+            let decoded = Instruction::decode(bytes.try_into().unwrap());
+            lines.push(instruction(&decoded, &executable.symbols));
+        }
+        lines
+    }
+
+    #[test]
+    fn assembled_source_disassembles_to_itself() {
+        let executable = asm::assemble(SOURCE).unwrap();
+        let expected: Vec<&str> = SOURCE
+            .lines()
+            .skip_while(|line| line.trim() != ".text")
+            .skip(1)
+            .map(str::trim)
+            .collect();
+        assert_eq!(disassemble(&executable), expected);
+    }
+
+    #[test]
+    fn disassembly_assembles_to_the_same_executable() {
+        let executable = asm::assemble(SOURCE).unwrap();
+        let data: Vec<&str> = SOURCE
+            .lines()
+            .take_while(|line| line.trim() != ".text")
+            .collect();
+        let source = format!(
+            "{}\n.text\n{}\n",
+            data.join("\n"),
+            disassemble(&executable).join("\n")
+        );
+        assert_eq!(asm::assemble(&source).unwrap(), executable);
+    }
+
+    #[test]
+    fn unknown_opcodes_and_registers_are_bad() {
+        let bad_opcode = Instruction {
+            opcode: 0xff,
+            ..Default::default()
+        };
+        assert_eq!(instruction(&bad_opcode, &[]), "(bad)");
+        let bad_register = Instruction {
+            opcode: cpu::OP_MOV,
+            a: cpu::REGISTERS as u8,
+            ..Default::default()
+        };
+        assert_eq!(instruction(&bad_register, &[]), "(bad)");
+        let jump = Instruction {
+            opcode: cpu::OP_JMP,
+            imm: 0x40,
+            ..Default::default()
+        };
+        assert_eq!(instruction(&jump, &[]), "jmp 0x40");
+    }
+}
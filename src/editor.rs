@@ -1,68 +1,1364 @@
-use std::io;
 /**
- * Minimal file editor
- * "wq" -> save and quit
- * "q" -> quit
+ * Full screen file editor
+ *
+ * The file content is loaded in a buffer of lines shown with their
+ * numbers and edited the vi way. The editor starts in normal mode:
+ *
+ *   h j k l  w b  0 $  gg G   motions, arrows and Page Up/Down work too
+ *   d y c + motion, dd yy cc  delete, yank or change, c enters insert mode
+ *   p P  x  u  Ctrl-R         paste after or before, delete char, undo, redo
+ *   i a I A o O               insert mode, Esc goes back to normal mode
+ *   v V                       visual mode by characters or by lines,
+ *                             d y c apply to the selection
+ *   / n                       search and next match
+ *   :w :q :q! :wq :N          save, quit, go to line N
+ *   :s/pat/rep/g :%s/...      replace pat on the line or everywhere
+ *
+ * Motions and operators take a count prefix, like 3w, 2dd or d2w. In any
+ * mode Ctrl-S saves, Ctrl-Q quits, twice to drop unsaved changes,
+ * Ctrl-F searches, Ctrl-N goes to the next match, Ctrl-Z and Ctrl-Y
+ * undo and redo.
+ *
+ * Saving writes the buffer back to the file through the system calls,
+ * blank lines and the final newline included.
  */
-use std::io::Write;
-use std::sync::{Arc, Mutex};
+use crossterm::{
+    cursor::{Hide, MoveTo, Show},
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    queue,
+    style::{Attribute, Print, SetAttribute, SetForegroundColor},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use std::io::{stdout, Stdout, Write};
 
-use crate::vfs::File;
-use crate::vmm::Vmm;
+use crate::errno::Errno;
+use crate::fd;
+use crate::hexdump;
+use crate::highlight::{self, Kind, Syntax, Theme};
+use crate::syscall::{
+    self, Value, O_RDONLY, O_TRUNC, O_WRONLY, SYS_CLOSE, SYS_OPEN, SYS_POLL, SYS_READ, SYS_WRITE,
+    S_IFIFO, S_IFMT,
+};
+use crate::vpm::Vpm;
 
-#[warn(dead_code)]
-pub struct Editor {}
+/**
+ * Edits that can be undone, the oldest are forgotten first.
+ */
+const UNDO_DEPTH: usize = 256;
+
+const TAB_WIDTH: usize = 4;
+
+const HELP: &str = "i insert  v visual  :w save  :q quit  / find  u undo  ^R redo";
+
+/**
+ * Position of a character in the buffer, row then column.
+ */
+type Position = (usize, usize);
+
+/**
+ * Writes the content of the buffer to the file.
+ */
+type Save<'a> = dyn Fn(Vec<u8>) -> Result<(), Errno> + 'a;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Normal,
+    Insert,
+    /**
+     * Selection from the anchor to the cursor, by lines if set.
+     */
+    Visual(bool),
+}
+
+/**
+ * Text deleted or yanked, pasted back by p and P.
+ */
+#[derive(Debug, Clone)]
+struct Register {
+    text: String,
+    linewise: bool,
+}
+
+/**
+ * Where a motion leads. Operators apply to whole lines when linewise,
+ * else up to the target, included when inclusive.
+ */
+struct Target {
+    position: Position,
+    linewise: bool,
+    inclusive: bool,
+}
+
+/**
+ * Keys typed in normal mode, parsed as they come.
+ */
+enum Parsed {
+    Incomplete,
+    Motion(char),
+    Other,
+}
+
+/**
+ * Class of a character for word motions: blanks, word characters and
+ * punctuation.
+ */
+fn class(c: char) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+/**
+ * Whether the lines are those of an empty file, a single empty line.
+ */
+fn is_empty(lines: &[Vec<char>]) -> bool {
+    lines.len() == 1 && lines[0].is_empty()
+}
+
+/**
+ * Count typed from keys[from], and where the keys after it start. A
+ * leading 0 is the motion, not a count.
+ */
+fn split_count(keys: &[char], from: usize) -> (Option<usize>, usize) {
+    let digits = keys[from..]
+        .iter()
+        .enumerate()
+        .take_while(|&(index, c)| c.is_ascii_digit() && (index > 0 || *c != '0'))
+        .count();
+    let count = keys[from..from + digits]
+        .iter()
+        .collect::<String>()
+        .parse()
+        .ok();
+    (count, from + digits)
+}
+
+fn parse_motion(keys: &[char]) -> Parsed {
+    match keys {
+        [] | ['g'] => Parsed::Incomplete,
+        ['g', 'g', ..] => Parsed::Motion('g'),
+        [c, ..] if "hjklwb0$G".contains(*c) => Parsed::Motion(*c),
+        _ => Parsed::Other,
+    }
+}
+
+/**
+ * Consecutive edits of the same kind are undone together, like a word
+ * typed or erased.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EditKind {
+    Insert,
+    Delete,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+struct Snapshot {
+    lines: Vec<Vec<char>>,
+    row: usize,
+    col: usize,
+}
+
+pub struct Editor {
+    name: String,
+    lines: Vec<Vec<char>>,
+    saved: Vec<Vec<char>>,
+    /**
+     * The file ends with a newline, an empty line of its own in "\n".
+     */
+    final_newline: bool,
+    row: usize,
+    col: usize,
+    top: usize,
+    left: usize,
+    page: usize,
+    undo: Vec<Snapshot>,
+    redo: Vec<Snapshot>,
+    last_edit: Option<EditKind>,
+    search: Vec<char>,
+    message: String,
+    quit_pending: bool,
+    mode: Mode,
+    anchor: Position,
+    pending: String,
+    register: Option<Register>,
+    syntax: Option<&'static Syntax>,
+    theme: Theme,
+}
 
 impl Editor {
-    pub fn write(file_mux: Arc<Mutex<File>>, vmm: Arc<Mutex<Vmm>>) {
-        let mut vmm_mutex = vmm.lock().unwrap();
-        let mut file = file_mux.lock().unwrap();
-        let mut new_content = Vec::<u8>::new();
-        let mut buffer = String::new();
-        print!(
-            "Welcome to the editor! Type 'wq' to save and quit or 'q' to quit without saving.\n"
-        );
+    pub fn new(name: &str, content: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(content);
+        let final_newline = text.ends_with('\n');
+        let mut lines: Vec<Vec<char>> = text
+            .split('\n')
+            .map(|line| line.chars().collect())
+            .collect();
+        if final_newline {
+            lines.pop();
+        }
+        Self {
+            name: name.to_string(),
+            saved: lines.clone(),
+            lines,
+            final_newline,
+            row: 0,
+            col: 0,
+            top: 0,
+            left: 0,
+            page: 1,
+            undo: Vec::new(),
+            redo: Vec::new(),
+            last_edit: None,
+            search: Vec::new(),
+            message: String::new(),
+            quit_pending: false,
+            mode: Mode::Normal,
+            anchor: (0, 0),
+            pending: String::new(),
+            register: None,
+            syntax: highlight::find(name),
+            theme: Theme::default(),
+        }
+    }
+
+    /**
+     * Bytes of the buffer, as saved to the file. Text written in an empty
+     * file gets a final newline.
+     */
+    pub fn content(&self) -> Vec<u8> {
+        let mut text = self
+            .lines
+            .iter()
+            .map(|line| line.iter().collect::<String>())
+            .collect::<Vec<String>>()
+            .join("\n");
+        if self.final_newline || (is_empty(&self.saved) && !is_empty(&self.lines)) {
+            text.push('\n');
+        }
+        text.into_bytes()
+    }
+
+    fn dirty(&self) -> bool {
+        self.lines != self.saved
+    }
+
+    /**
+     * Edit the file full screen until the editor is quit.
+     */
+    pub fn write(process: &Vpm, path: &str) -> Result<(), Errno> {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        if syscall::stat(process, path)?.0 & S_IFMT == S_IFIFO {
+            println!(
+                "{} is a fifo, redirect a command output to it instead",
+                name
+            );
+            return Ok(());
+        }
+        let content = syscall::read_all(process, path)?;
+        // Saving would replace the bytes that are not UTF-8
+        if hexdump::is_binary(&content) {
+            println!("{} is a binary file, view it with hexdump or xxd", name);
+            return Ok(());
+        }
+        let mut editor = Editor::new(name, &content);
+        let (theme, errors) = Theme::load(process);
+        editor.theme = theme;
+        if let Some(error) = errors.first() {
+            editor.message = format!("{}: {}", highlight::THEME_PATH, error);
+        }
+
+        let mut out = stdout();
+        terminal::enable_raw_mode().unwrap();
+        queue!(out, EnterAlternateScreen).unwrap();
         loop {
-            print!("> ");
-            io::stdout().flush().unwrap();
-
-            let mut input = String::new();
-            io::stdin().read_line(&mut input).unwrap();
-
-            match input.trim() {
-                "wq" => {
-                    new_content = buffer.as_bytes().to_vec().clone();
-                    file.size = new_content.len() as u64;
-                    file.vmm_address = vmm_mutex.allocate_bytes(new_content);
-                    println!("File saved successfully!");
-                    return;
+            editor.draw(&mut out);
+            // Waiting for the terminal is sleeping, not using the CPU
+            let args = [Value::Int(fd::STDIN as i64), Value::Int(-1)];
+            syscall::syscall(process, SYS_POLL, &args).ok();
+            let read = event::read();
+            match read {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                    if !editor.handle_key(&mut out, key, &|content| save(process, path, content)) {
+                        break;
+                    }
                 }
-                "q" => {
-                    println!("Exit without save");
-                    return;
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        queue!(out, Show, LeaveAlternateScreen).unwrap();
+        out.flush().unwrap();
+        terminal::disable_raw_mode().unwrap();
+        Ok(())
+    }
+
+    /**
+     * Print the file. Binary files, the ones that are not UTF-8, are
+     * refused like the editor does, hexdump and xxd show them.
+     */
+    pub fn read(process: &Vpm, path: &str) -> Result<(), Errno> {
+        if syscall::stat(process, path)?.0 & S_IFMT == S_IFIFO {
+            return read_fifo(process, path);
+        }
+        let content = syscall::read_all(process, path)?;
+        if hexdump::is_binary(&content) {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            println!("{} is a binary file, view it with hexdump or xxd", name);
+            return Ok(());
+        }
+        let content = String::from_utf8_lossy(&content);
+        content.lines().for_each(|line| {
+            println!("{}", line);
+        });
+        Ok(())
+    }
+
+    fn line_len(&self) -> usize {
+        self.lines[self.row].len()
+    }
+
+    fn checkpoint(&mut self, kind: EditKind) {
+        if kind != EditKind::Other && self.last_edit == Some(kind) {
+            return;
+        }
+        self.undo.push(Snapshot {
+            lines: self.lines.clone(),
+            row: self.row,
+            col: self.col,
+        });
+        if self.undo.len() > UNDO_DEPTH {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+        self.last_edit = Some(kind);
+    }
+
+    /**
+     * Restore the snapshot at the top of from, saving the buffer in to.
+     */
+    fn restore(&mut self, undo: bool) {
+        let (from, to) = if undo {
+            (&mut self.undo, &mut self.redo)
+        } else {
+            (&mut self.redo, &mut self.undo)
+        };
+        let Some(snapshot) = from.pop() else {
+            self.message = format!("Nothing to {}", if undo { "undo" } else { "redo" });
+            return;
+        };
+        to.push(Snapshot {
+            lines: std::mem::replace(&mut self.lines, snapshot.lines),
+            row: self.row,
+            col: self.col,
+        });
+        self.row = snapshot.row.min(self.lines.len() - 1);
+        self.col = snapshot.col.min(self.line_len());
+        self.last_edit = None;
+    }
+
+    fn insert(&mut self, c: char) {
+        self.checkpoint(EditKind::Insert);
+        let col = self.col;
+        self.lines[self.row].insert(col, c);
+        self.col += 1;
+    }
+
+    fn newline(&mut self) {
+        self.checkpoint(EditKind::Other);
+        let rest = self.lines[self.row].split_off(self.col);
+        self.lines.insert(self.row + 1, rest);
+        self.row += 1;
+        self.col = 0;
+    }
+
+    fn backspace(&mut self) {
+        if self.col > 0 {
+            self.checkpoint(EditKind::Delete);
+            self.col -= 1;
+            let col = self.col;
+            self.lines[self.row].remove(col);
+        } else if self.row > 0 {
+            self.checkpoint(EditKind::Other);
+            let line = self.lines.remove(self.row);
+            self.row -= 1;
+            self.col = self.line_len();
+            self.lines[self.row].extend(line);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.col < self.line_len() {
+            self.checkpoint(EditKind::Delete);
+            let col = self.col;
+            self.lines[self.row].remove(col);
+        } else if self.row + 1 < self.lines.len() {
+            self.checkpoint(EditKind::Other);
+            let line = self.lines.remove(self.row + 1);
+            self.lines[self.row].extend(line);
+        }
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.row = row.min(self.lines.len() - 1);
+        self.col = col.min(self.line_len());
+        self.last_edit = None;
+    }
+
+    /**
+     * Move to the next match of the search after the cursor, wrapping
+     * around the end of the buffer.
+     */
+    fn find_next(&mut self) {
+        if self.search.is_empty() {
+            self.message = "No search".to_string();
+            return;
+        }
+        let count = self.lines.len();
+        for step in 0..=count {
+            let row = (self.row + step) % count;
+            let from = if step == 0 { self.col + 1 } else { 0 };
+            let line = &self.lines[row];
+            let found = (from..=line.len().saturating_sub(self.search.len()))
+                .find(|&col| line[col..].starts_with(&self.search));
+            if let Some(col) = found {
+                if step == count && col >= self.col {
+                    break;
                 }
-                _ => {
-                    buffer.push_str(&input);
-                    new_content.extend_from_slice(input.as_bytes());
-                    new_content.push(b'\n');
+                self.message = if row < self.row || (row == self.row && col <= self.col) {
+                    "Search wrapped".to_string()
+                } else {
+                    String::new()
+                };
+                self.move_to(row, col);
+                return;
+            }
+        }
+        self.message = format!("Not found: {}", self.search.iter().collect::<String>());
+    }
+
+    /**
+     * Ask for a value on the message line, None when ESC is pressed.
+     */
+    fn prompt(&mut self, out: &mut Stdout, question: &str) -> Option<String> {
+        let mut answer = String::new();
+        loop {
+            self.message = format!("{}{}", question, answer);
+            self.draw(out);
+            if let Ok(Event::Key(key)) = event::read() {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Enter => {
+                        self.message.clear();
+                        return Some(answer);
+                    }
+                    KeyCode::Esc => {
+                        self.message.clear();
+                        return None;
+                    }
+                    KeyCode::Backspace => {
+                        answer.pop();
+                    }
+                    KeyCode::Char(c) => answer.push(c),
+                    _ => {}
                 }
             }
         }
     }
 
-    pub fn read(file_mux: Arc<Mutex<File>>, vmm: Arc<Mutex<Vmm>>) {
-        let file = file_mux.lock().unwrap();
-        let vmm_mutex = vmm.lock().unwrap();
-        let content = vmm_mutex.get_bytes(file.vmm_address.clone(), file.size);
-        if content.is_empty() {
-            return;
+    fn first_non_blank(&self, row: usize) -> usize {
+        self.lines[row]
+            .iter()
+            .position(|c| !c.is_whitespace())
+            .unwrap_or(0)
+    }
+
+    /**
+     * Character at a position, a newline past the end of the line.
+     */
+    fn char_at(&self, (row, col): Position) -> char {
+        self.lines[row].get(col).copied().unwrap_or('\n')
+    }
+
+    fn next_position(&self, (row, col): Position) -> Option<Position> {
+        if col < self.lines[row].len() {
+            Some((row, col + 1))
+        } else if row + 1 < self.lines.len() {
+            Some((row + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    fn previous_position(&self, (row, col): Position) -> Option<Position> {
+        if col > 0 {
+            Some((row, col - 1))
+        } else if row > 0 {
+            Some((row - 1, self.lines[row - 1].len()))
+        } else {
+            None
+        }
+    }
+
+    fn empty_line(&self, (row, col): Position) -> bool {
+        col == 0 && self.lines[row].is_empty()
+    }
+
+    /**
+     * Start of the next word, an empty line counts as a word.
+     */
+    fn word_forward(&self, from: Position) -> Position {
+        let start = class(self.char_at(from));
+        let mut position = from;
+        if start != 0 {
+            while class(self.char_at(position)) == start {
+                match self.next_position(position) {
+                    Some(next) => position = next,
+                    None => return position,
+                }
+            }
         }
-        let content = String::from_utf8(content).unwrap();
-        content
-            .lines()
-            .filter(|line| !line.is_empty())
-            .for_each(|line| {
-                println!("{}", line);
+        while class(self.char_at(position)) == 0 && !(position != from && self.empty_line(position))
+        {
+            match self.next_position(position) {
+                Some(next) => position = next,
+                None => return position,
+            }
+        }
+        position
+    }
+
+    /**
+     * Start of the word before the cursor, or of the one under it.
+     */
+    fn word_backward(&self, from: Position) -> Position {
+        let mut position = from;
+        loop {
+            match self.previous_position(position) {
+                Some(previous) => position = previous,
+                None => return position,
+            }
+            if class(self.char_at(position)) != 0 || self.empty_line(position) {
+                break;
+            }
+        }
+        let word = class(self.char_at(position));
+        while let Some(previous) = self.previous_position(position) {
+            if word == 0 || class(self.char_at(previous)) != word {
+                break;
+            }
+            position = previous;
+        }
+        position
+    }
+
+    fn motion(&self, motion: char, count: Option<usize>) -> Target {
+        let times = count.unwrap_or(1);
+        let last = self.lines.len() - 1;
+        let (position, linewise, inclusive) = match motion {
+            'h' => ((self.row, self.col.saturating_sub(times)), false, false),
+            'l' => (
+                (self.row, (self.col + times).min(self.line_len())),
+                false,
+                false,
+            ),
+            'j' => (
+                (self.row.saturating_add(times).min(last), self.col),
+                true,
+                false,
+            ),
+            'k' => ((self.row.saturating_sub(times), self.col), true, false),
+            'w' => (
+                (0..times).fold((self.row, self.col), |position, _| {
+                    self.word_forward(position)
+                }),
+                false,
+                false,
+            ),
+            'b' => (
+                (0..times).fold((self.row, self.col), |position, _| {
+                    self.word_backward(position)
+                }),
+                false,
+                false,
+            ),
+            '0' => ((self.row, 0), false, false),
+            '$' => {
+                let row = (self.row + times - 1).min(last);
+                ((row, self.lines[row].len().saturating_sub(1)), false, true)
+            }
+            // gg and G go to the line of the count, the first and last
+            // ones without
+            _ => {
+                let default = if motion == 'g' { 0 } else { last };
+                let row = count.map_or(default, |line| line.saturating_sub(1).min(last));
+                ((row, self.first_non_blank(row)), true, false)
+            }
+        };
+        Target {
+            position,
+            linewise,
+            inclusive,
+        }
+    }
+
+    /**
+     * Text from start to end, end excluded.
+     */
+    fn text(&self, start: Position, end: Position) -> String {
+        let mut text = String::new();
+        for row in start.0..=end.0 {
+            let line = &self.lines[row];
+            let from = if row == start.0 { start.1 } else { 0 };
+            let to = if row == end.0 { end.1 } else { line.len() };
+            text.extend(&line[from.min(line.len())..to.min(line.len())]);
+            if row != end.0 {
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    fn remove(&mut self, start: Position, end: Position) {
+        let end_col = end.1.min(self.lines[end.0].len());
+        let tail = self.lines[end.0].split_off(end_col);
+        self.lines[start.0].truncate(start.1);
+        self.lines[start.0].extend(tail);
+        self.lines.drain(start.0 + 1..=end.0);
+    }
+
+    /**
+     * Insert text at a position, returns the position after it.
+     */
+    fn insert_text(&mut self, (row, col): Position, text: &str) -> Position {
+        let tail = self.lines[row].split_off(col);
+        let mut pieces = text.split('\n');
+        self.lines[row].extend(pieces.next().unwrap_or_default().chars());
+        let mut end = row;
+        for piece in pieces {
+            end += 1;
+            self.lines.insert(end, piece.chars().collect());
+        }
+        let end_col = self.lines[end].len();
+        self.lines[end].extend(tail);
+        (end, end_col)
+    }
+
+    /**
+     * Delete, yank or change from start to end, end excluded, or the
+     * lines from start to end when linewise.
+     */
+    fn operate(&mut self, operator: char, start: Position, end: Position, linewise: bool) {
+        if linewise {
+            let (first, last) = (start.0, end.0.min(self.lines.len() - 1));
+            let text = self.lines[first..=last]
+                .iter()
+                .map(|line| format!("{}\n", line.iter().collect::<String>()))
+                .collect();
+            self.register = Some(Register {
+                text,
+                linewise: true,
             });
+            if operator != 'y' {
+                self.checkpoint(EditKind::Other);
+                self.lines.drain(first..=last);
+                if operator == 'c' || self.lines.is_empty() {
+                    self.lines.insert(first.min(self.lines.len()), Vec::new());
+                }
+            }
+            self.row = first.min(self.lines.len() - 1);
+            self.col = self.first_non_blank(self.row);
+        } else {
+            self.register = Some(Register {
+                text: self.text(start, end),
+                linewise: false,
+            });
+            if operator != 'y' {
+                self.checkpoint(EditKind::Other);
+                self.remove(start, end);
+            }
+            (self.row, self.col) = start;
+        }
+        if operator == 'c' {
+            self.col = if linewise { 0 } else { self.col };
+            self.mode = Mode::Insert;
+        }
+        self.last_edit = None;
+    }
+
+    /**
+     * Apply an operator up to where a motion leads.
+     */
+    fn operate_motion(&mut self, operator: char, motion: char, count: Option<usize>) {
+        let target = self.motion(motion, count);
+        let cursor = (self.row, self.col);
+        let (start, mut end) = if target.position < cursor {
+            (target.position, cursor)
+        } else {
+            (cursor, target.position)
+        };
+        if target.linewise {
+            return self.operate(operator, start, end, true);
+        }
+        if target.inclusive {
+            end.1 += 1;
+        }
+        if motion == 'w' {
+            // The last word of a line stops at its end, and a change
+            // keeps the blanks after the words
+            if end.0 > start.0 {
+                end = (start.0, self.lines[start.0].len());
+            }
+            if operator == 'c' && class(self.char_at(start)) != 0 {
+                while end.1 > start.1 && class(self.char_at((end.0, end.1 - 1))) == 0 {
+                    end.1 -= 1;
+                }
+            }
+        }
+        self.operate(operator, start, end, false);
+    }
+
+    /**
+     * Selection of the visual mode, first and last positions included.
+     */
+    fn selection(&self) -> Option<(Position, Position, bool)> {
+        let Mode::Visual(linewise) = self.mode else {
+            return None;
+        };
+        let cursor = (self.row, self.col);
+        Some((self.anchor.min(cursor), self.anchor.max(cursor), linewise))
+    }
+
+    fn selected(&self, row: usize, col: usize) -> bool {
+        match self.selection() {
+            Some((start, end, true)) => (start.0..=end.0).contains(&row),
+            Some((start, end, false)) => (start..=end).contains(&(row, col)),
+            None => false,
+        }
+    }
+
+    fn paste(&mut self, after: bool, count: usize) {
+        let Some(register) = self.register.clone() else {
+            self.message = "Nothing to paste".to_string();
+            return;
+        };
+        self.checkpoint(EditKind::Other);
+        let text = register.text.repeat(count);
+        if register.linewise {
+            let row = if after { self.row + 1 } else { self.row };
+            let lines = text
+                .split_terminator('\n')
+                .map(|line| line.chars().collect());
+            self.lines.splice(row..row, lines);
+            self.row = row;
+            self.col = self.first_non_blank(row);
+        } else {
+            let col = if after && !self.lines[self.row].is_empty() {
+                self.col + 1
+            } else {
+                self.col
+            };
+            let (row, col) = self.insert_text((self.row, col), &text);
+            self.row = row;
+            self.col = col.saturating_sub(1);
+        }
+        self.last_edit = None;
+    }
+
+    /**
+     * Save the buffer, returns false when the file could not be written.
+     */
+    fn save_buffer(&mut self, save: &Save) -> bool {
+        let content = self.content();
+        let size = content.len();
+        let final_newline = content.ends_with(b"\n");
+        match save(content) {
+            Ok(()) => {
+                self.final_newline = final_newline;
+                self.saved = self.lines.clone();
+                self.message = format!("\"{}\" {} bytes written", self.name, size);
+                true
+            }
+            Err(errno) => {
+                self.message = format!("\"{}\" {}", self.name, errno);
+                false
+            }
+        }
+    }
+
+    fn search_prompt(&mut self, out: &mut Stdout, question: &str) {
+        if let Some(search) = self.prompt(out, question) {
+            if !search.is_empty() {
+                self.search = search.chars().collect();
+            }
+            self.find_next();
+        }
+    }
+
+    /**
+     * Replace pat by rep on the lines, all of them on a line with the g
+     * flag. The pattern is matched literally, empty it is the last search.
+     */
+    fn substitute(&mut self, rows: std::ops::RangeInclusive<usize>, args: &str) {
+        let Some(separator) = args.chars().next().filter(|c| !c.is_alphanumeric()) else {
+            self.message = "Usage: :s/pattern/replacement/g".to_string();
+            return;
+        };
+        let mut parts = args[separator.len_utf8()..].splitn(3, separator);
+        let pattern = match parts.next().unwrap_or_default() {
+            "" => self.search.iter().collect(),
+            pattern => pattern.to_string(),
+        };
+        let replacement = parts.next().unwrap_or_default();
+        let global = parts.next().is_some_and(|flags| flags.contains('g'));
+        if pattern.is_empty() {
+            self.message = "No previous pattern".to_string();
+            return;
+        }
+
+        let mut lines = self.lines.clone();
+        let (mut count, mut last) = (0, None);
+        for row in rows {
+            let line: String = lines[row].iter().collect();
+            let found = line.matches(pattern.as_str()).count();
+            if found == 0 {
+                continue;
+            }
+            let replaced = if global {
+                count += found;
+                line.replace(&pattern, replacement)
+            } else {
+                count += 1;
+                line.replacen(&pattern, replacement, 1)
+            };
+            lines[row] = replaced.chars().collect();
+            last = Some(row);
+        }
+        let Some(row) = last else {
+            self.message = format!("Pattern not found: {}", pattern);
+            return;
+        };
+        self.checkpoint(EditKind::Other);
+        self.lines = lines;
+        self.row = row;
+        self.col = self.first_non_blank(row);
+        self.last_edit = None;
+        self.search = pattern.chars().collect();
+        self.message = format!("{} substitutions", count);
+    }
+
+    /**
+     * Run a command typed after :, returns false when the editor must exit.
+     */
+    fn ex(&mut self, command: &str, save: &Save) -> bool {
+        match command.trim() {
+            "" => {}
+            "w" => {
+                self.save_buffer(save);
+            }
+            "q" if self.dirty() => {
+                self.message = "No write since last change (add ! to override)".to_string();
+            }
+            "q" | "q!" => return false,
+            "wq" | "x" => return !self.save_buffer(save),
+            command => {
+                if let Ok(line) = command.parse::<usize>() {
+                    let row = line.saturating_sub(1).min(self.lines.len() - 1);
+                    self.move_to(row, self.first_non_blank(row));
+                } else if let Some(args) = command.strip_prefix("%s") {
+                    self.substitute(0..=self.lines.len() - 1, args);
+                } else if let Some(args) = command.strip_prefix('s') {
+                    self.substitute(self.row..=self.row, args);
+                } else {
+                    self.message = format!("Not an editor command: {}", command);
+                }
+            }
+        }
+        true
+    }
+
+    /**
+     * Run a key of normal or visual mode that is not a motion, returns
+     * false when the editor must exit.
+     */
+    fn action(&mut self, out: &mut Stdout, key: char, count: Option<usize>, save: &Save) -> bool {
+        let times = count.unwrap_or(1);
+        if let Some((start, end, linewise)) = self.selection() {
+            match key {
+                'd' | 'x' | 'y' | 'c' => {
+                    self.mode = Mode::Normal;
+                    let mut end = (end.0, end.1 + 1);
+                    if !linewise && end.1 > self.lines[end.0].len() && end.0 + 1 < self.lines.len()
+                    {
+                        end = (end.0 + 1, 0);
+                    }
+                    let operator = if key == 'x' { 'd' } else { key };
+                    self.operate(operator, start, end, linewise);
+                }
+                'v' | 'V' if self.mode == Mode::Visual(key == 'V') => self.mode = Mode::Normal,
+                'v' | 'V' => self.mode = Mode::Visual(key == 'V'),
+                _ => {}
+            }
+            return true;
+        }
+
+        match key {
+            'i' => self.mode = Mode::Insert,
+            'a' => {
+                self.col = (self.col + 1).min(self.line_len());
+                self.mode = Mode::Insert;
+            }
+            'I' => {
+                self.col = self.first_non_blank(self.row);
+                self.mode = Mode::Insert;
+            }
+            'A' => {
+                self.col = self.line_len();
+                self.mode = Mode::Insert;
+            }
+            'o' | 'O' => {
+                self.checkpoint(EditKind::Other);
+                let row = if key == 'o' { self.row + 1 } else { self.row };
+                self.lines.insert(row, Vec::new());
+                (self.row, self.col) = (row, 0);
+                self.mode = Mode::Insert;
+            }
+            'x' if self.line_len() > 0 => {
+                let end = (self.col + times).min(self.line_len());
+                self.operate('d', (self.row, self.col), (self.row, end), false);
+            }
+            'p' | 'P' => self.paste(key == 'p', times),
+            'u' => (0..times).for_each(|_| self.restore(true)),
+            'v' | 'V' => {
+                self.anchor = (self.row, self.col);
+                self.mode = Mode::Visual(key == 'V');
+            }
+            '/' => self.search_prompt(out, "/"),
+            'n' => self.find_next(),
+            ':' => {
+                if let Some(command) = self.prompt(out, ":") {
+                    return self.ex(&command, save);
+                }
+            }
+            _ => {}
+        }
+        self.last_edit = None;
+        true
+    }
+
+    /**
+     * Run the keys typed in normal or visual mode once they make a
+     * command, returns false when the editor must exit.
+     */
+    fn command(&mut self, out: &mut Stdout, save: &Save) -> bool {
+        let keys: Vec<char> = self.pending.chars().collect();
+        let (count, at) = split_count(&keys, 0);
+        let Some(&key) = keys.get(at) else {
+            return true;
+        };
+        if "dyc".contains(key) && self.selection().is_none() {
+            let (motion_count, at) = split_count(&keys, at + 1);
+            let count = match (count, motion_count) {
+                (None, None) => None,
+                (count, motion_count) => Some(count.unwrap_or(1) * motion_count.unwrap_or(1)),
+            };
+            match keys.get(at) {
+                None => return true,
+                Some(&motion) if motion == key => {
+                    let last = self.row + count.unwrap_or(1) - 1;
+                    self.operate(key, (self.row, 0), (last, 0), true);
+                }
+                Some(_) => match parse_motion(&keys[at..]) {
+                    Parsed::Incomplete => return true,
+                    Parsed::Motion(motion) => self.operate_motion(key, motion, count),
+                    Parsed::Other => {}
+                },
+            }
+            self.pending.clear();
+            return true;
+        }
+
+        let running = match parse_motion(&keys[at..]) {
+            Parsed::Incomplete => return true,
+            Parsed::Motion(motion) => {
+                let (row, col) = self.motion(motion, count).position;
+                self.move_to(row, col);
+                true
+            }
+            Parsed::Other => self.action(out, key, count, save),
+        };
+        self.pending.clear();
+        running
+    }
+
+    fn insert_key(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Esc => {
+                self.mode = Mode::Normal;
+                self.col = self.col.saturating_sub(1);
+            }
+            KeyCode::Enter => self.newline(),
+            KeyCode::Backspace => self.backspace(),
+            KeyCode::Delete => self.delete(),
+            KeyCode::Tab => (0..TAB_WIDTH - self.col % TAB_WIDTH).for_each(|_| self.insert(' ')),
+            KeyCode::Char(c) => self.insert(c),
+            _ => {}
+        }
+    }
+
+    /**
+     * Returns false when the editor must exit.
+     */
+    fn handle_key(&mut self, out: &mut Stdout, key: KeyEvent, save: &Save) -> bool {
+        let quit_pending = std::mem::take(&mut self.quit_pending);
+        self.message.clear();
+        if key.modifiers.contains(KeyModifiers::CONTROL) {
+            match key.code {
+                KeyCode::Char('q') if self.dirty() && !quit_pending => {
+                    self.message = "Unsaved changes, ^Q again to quit without saving".to_string();
+                    self.quit_pending = true;
+                }
+                KeyCode::Char('q') => return false,
+                KeyCode::Char('s') => {
+                    self.save_buffer(save);
+                }
+                KeyCode::Char('f') => self.search_prompt(out, "Search: "),
+                KeyCode::Char('n') => self.find_next(),
+                KeyCode::Char('z') => self.restore(true),
+                KeyCode::Char('y') | KeyCode::Char('r') => self.restore(false),
+                _ => {}
+            }
+            return true;
+        }
+
+        let mut running = true;
+        match key.code {
+            KeyCode::Up => self.move_to(self.row.saturating_sub(1), self.col),
+            KeyCode::Down => self.move_to(self.row + 1, self.col),
+            KeyCode::Left if self.col > 0 => self.move_to(self.row, self.col - 1),
+            KeyCode::Left if self.row > 0 => self.move_to(self.row - 1, usize::MAX),
+            KeyCode::Right if self.col < self.line_len() => self.move_to(self.row, self.col + 1),
+            KeyCode::Right if self.row + 1 < self.lines.len() => self.move_to(self.row + 1, 0),
+            KeyCode::Home => self.move_to(self.row, 0),
+            KeyCode::End => self.move_to(self.row, usize::MAX),
+            KeyCode::PageUp => self.move_to(self.row.saturating_sub(self.page), self.col),
+            KeyCode::PageDown => self.move_to(self.row + self.page, self.col),
+            code if self.mode == Mode::Insert => self.insert_key(code),
+            KeyCode::Esc => {
+                self.pending.clear();
+                self.mode = Mode::Normal;
+            }
+            KeyCode::Char(c) => {
+                self.pending.push(c);
+                running = self.command(out, save);
+            }
+            _ => {}
+        }
+        // Out of insert mode the cursor stays on a character
+        if self.mode != Mode::Insert && self.col > 0 && self.col >= self.line_len() {
+            self.col = self.line_len().saturating_sub(1);
+        }
+        running
+    }
+
+    fn draw(&mut self, out: &mut Stdout) {
+        let (width, height) = terminal::size().unwrap_or((80, 24));
+        let (width, height) = (width as usize, height as usize);
+        let text_height = height.saturating_sub(2).max(1);
+        let gutter = self.lines.len().to_string().len().max(3) + 1;
+        let text_width = width.saturating_sub(gutter).max(1);
+        self.page = text_height;
+        if self.row < self.top {
+            self.top = self.row;
+        } else if self.row >= self.top + text_height {
+            self.top = self.row + 1 - text_height;
+        }
+        if self.col < self.left {
+            self.left = self.col;
+        } else if self.col >= self.left + text_width {
+            self.left = self.col + 1 - text_width;
+        }
+
+        // Block comments can start above the screen
+        let mut in_comment = false;
+        if let Some(syntax) = self.syntax {
+            self.lines[..self.top.min(self.lines.len())]
+                .iter()
+                .for_each(|line| drop(syntax.highlight(line, &mut in_comment)));
+        }
+
+        queue!(out, Hide).unwrap();
+        for y in 0..text_height {
+            queue!(out, MoveTo(0, y as u16), Clear(ClearType::CurrentLine)).unwrap();
+            let Some(line) = self.lines.get(self.top + y) else {
+                queue!(out, Print("~")).unwrap();
+                continue;
+            };
+            let row = self.top + y;
+            queue!(
+                out,
+                SetAttribute(Attribute::Dim),
+                Print(format!("{:>width$} ", row + 1, width = gutter - 1)),
+                SetAttribute(Attribute::Reset)
+            )
+            .unwrap();
+            let kinds = match self.syntax {
+                Some(syntax) => syntax.highlight(line, &mut in_comment),
+                None => vec![Kind::Plain; line.len()],
+            };
+            // Runs of characters of the same kind, in or out of the visual
+            // selection
+            let mut run = String::new();
+            let mut style = (false, Kind::Plain);
+            for (col, &c) in line.iter().enumerate().skip(self.left).take(text_width) {
+                let next = (self.selected(row, col), kinds[col]);
+                if next != style {
+                    queue!(
+                        out,
+                        Print(std::mem::take(&mut run)),
+                        SetAttribute(Attribute::Reset)
+                    )
+                    .unwrap();
+                    if next.0 {
+                        queue!(out, SetAttribute(Attribute::Reverse)).unwrap();
+                    }
+                    if let Some(color) = self.theme.color(next.1) {
+                        queue!(out, SetForegroundColor(color)).unwrap();
+                    }
+                    style = next;
+                }
+                run.push(if c == '\t' { ' ' } else { c });
+            }
+            queue!(out, Print(run), SetAttribute(Attribute::Reset)).unwrap();
+        }
+
+        let status = format!(
+            " {}{}{}  line {}/{}, col {}",
+            self.name,
+            if self.dirty() { " [+]" } else { "" },
+            self.syntax
+                .map_or(String::new(), |syntax| format!("  {}", syntax.name)),
+            self.row + 1,
+            self.lines.len(),
+            self.col + 1
+        );
+        let fit = |line: &str| line.chars().take(width).collect::<String>();
+        let message = match self.mode {
+            _ if !self.message.is_empty() => &self.message,
+            Mode::Insert => "-- INSERT --",
+            Mode::Visual(false) => "-- VISUAL --",
+            Mode::Visual(true) => "-- VISUAL LINE --",
+            Mode::Normal => HELP,
+        };
+        queue!(
+            out,
+            MoveTo(0, text_height as u16),
+            SetAttribute(Attribute::Reverse),
+            Print(format!("{:<width$}", fit(&status), width = width)),
+            SetAttribute(Attribute::Reset),
+            MoveTo(0, text_height as u16 + 1),
+            Clear(ClearType::CurrentLine),
+            Print(fit(message)),
+            MoveTo(
+                (gutter + self.col - self.left) as u16,
+                (self.row - self.top) as u16
+            ),
+            Show
+        )
+        .unwrap();
+        out.flush().unwrap();
+    }
+}
+
+/**
+ * Replace the content of the file through the system calls.
+ */
+fn save(process: &Vpm, path: &str, content: Vec<u8>) -> Result<(), Errno> {
+    let sys = |number, args: &[Value]| syscall::syscall(process, number, args);
+    let open = [Value::Str(path.to_string()), Value::Int(O_WRONLY | O_TRUNC)];
+    let fd = sys(SYS_OPEN, &open)?;
+    let written = sys(SYS_WRITE, &[fd.clone(), Value::Bytes(content)]);
+    sys(SYS_CLOSE, &[fd])?;
+    written.map(|_| ())
+}
+
+/**
+ * Copy what is written to the FIFO to stdout until its last writer
+ * closes it. Being interrupted by a signal ends the copy.
+ */
+fn read_fifo(process: &Vpm, path: &str) -> Result<(), Errno> {
+    let sys = |number, args: &[Value]| syscall::syscall(process, number, args);
+    let fd = sys(
+        SYS_OPEN,
+        &[Value::Str(path.to_string()), Value::Int(O_RDONLY)],
+    )?;
+    let result = loop {
+        match sys(SYS_READ, &[fd.clone(), Value::Int(4096)]) {
+            Ok(Value::Bytes(bytes)) if bytes.is_empty() => break Ok(()),
+            Ok(bytes) => {
+                if let Err(errno) = sys(SYS_WRITE, &[Value::Int(1), bytes]) {
+                    break Err(errno);
+                }
+            }
+            Err(Errno::EINTR) => break Ok(()),
+            Err(errno) => break Err(errno),
+        }
+    };
+    sys(SYS_CLOSE, &[fd]).ok();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Type keys in the editor, Esc written \x1b and Enter \n.
+     */
+    fn type_keys(editor: &mut Editor, keys: &str) {
+        let mut out = stdout();
+        for c in keys.chars() {
+            let code = match c {
+                '\x1b' => KeyCode::Esc,
+                '\n' => KeyCode::Enter,
+                c => KeyCode::Char(c),
+            };
+            let key = KeyEvent::new(code, KeyModifiers::NONE);
+            assert!(editor.handle_key(&mut out, key, &|_| Ok(())));
+        }
+    }
+
+    fn text(editor: &Editor) -> String {
+        String::from_utf8(editor.content()).unwrap()
+    }
+
+    fn edit(content: &str, keys: &str) -> Editor {
+        let mut editor = Editor::new("file", content.as_bytes());
+        type_keys(&mut editor, keys);
+        editor
+    }
+
+    #[test]
+    fn word_motions_stop_at_words_and_punctuation() {
+        let mut editor = Editor::new("file", b"one two, three\n\nfour\n");
+        let mut positions = Vec::new();
+        for _ in 0..5 {
+            type_keys(&mut editor, "w");
+            positions.push((editor.row, editor.col));
+        }
+        assert_eq!(positions, vec![(0, 4), (0, 7), (0, 9), (1, 0), (2, 0)]);
+        type_keys(&mut editor, "b");
+        assert_eq!((editor.row, editor.col), (1, 0));
+        type_keys(&mut editor, "2b");
+        assert_eq!((editor.row, editor.col), (0, 7));
+    }
+
+    #[test]
+    fn line_motions_take_a_count() {
+        let mut editor = Editor::new("file", b"a\n  b\nc\nd\n");
+        type_keys(&mut editor, "G");
+        assert_eq!((editor.row, editor.col), (3, 0));
+        type_keys(&mut editor, "gg");
+        assert_eq!((editor.row, editor.col), (0, 0));
+        type_keys(&mut editor, "2G");
+        assert_eq!((editor.row, editor.col), (1, 2));
+        type_keys(&mut editor, "0");
+        assert_eq!(editor.col, 0);
+        type_keys(&mut editor, "$");
+        assert_eq!(editor.col, 2);
+        type_keys(&mut editor, "5j");
+        assert_eq!(editor.row, 3);
+        type_keys(&mut editor, "2k");
+        assert_eq!(editor.row, 1);
+    }
+
+    #[test]
+    fn counts_multiply_between_operator_and_motion() {
+        assert_eq!(text(&edit("a b c d e f\n", "2d2w")), "e f\n");
+        assert_eq!(text(&edit("a b c d e f\n", "dw")), "b c d e f\n");
+        assert_eq!(text(&edit("a b c d e f\n", "wd$")), "a \n");
+        assert_eq!(text(&edit("a b c d e f\n", "d$")), "\n");
+        assert_eq!(text(&edit("a b c d e f\n", "wwd0")), "c d e f\n");
+        assert_eq!(text(&edit("abcdef\n", "3x")), "def\n");
+    }
+
+    #[test]
+    fn dw_on_the_last_word_keeps_the_next_line() {
+        assert_eq!(text(&edit("one two\nthree\n", "wdw")), "one \nthree\n");
+    }
+
+    #[test]
+    fn linewise_operators_delete_yank_and_paste_lines() {
+        let mut editor = edit("1\n2\n3\n4\n", "j2dd");
+        assert_eq!(text(&editor), "1\n4\n");
+        type_keys(&mut editor, "P");
+        assert_eq!(text(&editor), "1\n2\n3\n4\n");
+        assert_eq!(text(&edit("1\n2\n", "yyjp")), "1\n2\n1\n");
+        assert_eq!(text(&edit("1\n2\n3\n", "dj")), "3\n");
+        assert_eq!(text(&edit("1\n2\n3\n", "jdG")), "1\n");
+    }
+
+    #[test]
+    fn change_keeps_the_blanks_after_the_word() {
+        let editor = edit("one two\n", "cwsix\x1b");
+        assert_eq!(text(&editor), "six two\n");
+        assert_eq!(editor.mode, Mode::Normal);
+        assert_eq!(text(&edit("one\ntwo\n", "ccnew\x1b")), "new\ntwo\n");
+    }
+
+    #[test]
+    fn visual_selection_is_inclusive() {
+        assert_eq!(text(&edit("abcdef\n", "lvlld")), "aef\n");
+        assert_eq!(text(&edit("1\n2\n3\n", "Vjd")), "3\n");
+        let mut editor = edit("ab\ncd\n", "vjy");
+        assert_eq!(editor.register.as_ref().unwrap().text, "ab\nc");
+        type_keys(&mut editor, "$p");
+        assert_eq!(text(&editor), "abab\nc\ncd\n");
+    }
+
+    #[test]
+    fn undo_restores_the_text_before_an_operator() {
+        let mut editor = edit("one two\n", "dw");
+        assert_eq!(text(&editor), "two\n");
+        type_keys(&mut editor, "u");
+        assert_eq!(text(&editor), "one two\n");
+    }
+
+    #[test]
+    fn final_newline_round_trips() {
+        for content in ["", "\n", "\n\n", "a", "a\n", "a\n\nb"] {
+            let editor = Editor::new("file", content.as_bytes());
+            assert_eq!(text(&editor), content);
+            assert!(!editor.dirty());
+        }
+    }
+
+    #[test]
+    fn text_written_in_an_empty_file_ends_with_a_newline() {
+        let mut editor = edit("", "ia\x1b");
+        assert_eq!(text(&editor), "a\n");
+        type_keys(&mut editor, "x");
+        assert_eq!(text(&editor), "");
+        assert_eq!(text(&edit("a", "Ab\x1b")), "ab");
+    }
+
+    #[test]
+    fn saving_remembers_the_final_newline() {
+        let mut editor = edit("", "ia\x1b");
+        assert!(editor.save_buffer(&|content| {
+            assert_eq!(content, b"a\n");
+            Ok(())
+        }));
+        type_keys(&mut editor, "x");
+        assert_eq!(text(&editor), "\n");
+        assert!(!editor.save_buffer(&|_| Err(Errno::EIO)));
+        assert!(editor.dirty());
     }
 }
@@ -0,0 +1,68 @@
+/**
+ * Kernel error codes, numbered like their POSIX counterparts
+ */
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOTTY = 25,
+    EFBIG = 27,
+    ESPIPE = 29,
+    EPIPE = 32,
+    EDEADLK = 35,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    EMSGSIZE = 90,
+}
+
+impl Errno {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::EPERM => "Operation not permitted",
+            Self::ENOENT => "No such file or directory",
+            Self::ESRCH => "No such process",
+            Self::EINTR => "Interrupted system call",
+            Self::EIO => "Input/output error",
+            Self::ENOEXEC => "Exec format error",
+            Self::EBADF => "Bad file descriptor",
+            Self::ECHILD => "No child processes",
+            Self::ENOMEM => "Out of memory",
+            Self::EACCES => "Permission denied",
+            Self::EFAULT => "Bad address",
+            Self::EBUSY => "Device or resource busy",
+            Self::EEXIST => "File exists",
+            Self::EINVAL => "Invalid argument",
+            Self::EMFILE => "Too many open files",
+            Self::ENOTTY => "Inappropriate ioctl for device",
+            Self::EFBIG => "File too large",
+            Self::ESPIPE => "Illegal seek",
+            Self::EPIPE => "Broken pipe",
+            Self::EDEADLK => "Resource deadlock avoided",
+            Self::ENAMETOOLONG => "File name too long",
+            Self::ENOSYS => "Function not implemented",
+            Self::EMSGSIZE => "Message too long",
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self, self.description())
+    }
+}
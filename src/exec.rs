@@ -0,0 +1,406 @@
+/**
+ * Kernelino executables
+ *
+ * A program is a VFS file with the execute bit set. Its content is a header
+ * followed by segments, all integers little endian:
+ *
+ *   magic        4 bytes  "\x7fKEX"
+ *   version      1 byte   1
+ *   kind         1 byte   0 bytecode, 1 script
+ *   entry        4 bytes  offset of the first instruction in the text segment
+ *   interpreter  2 bytes  length of the interpreter name, then the name
+ *   segments     2 bytes  count, then for each segment:
+ *     kind       1 byte   0 text (read only), 1 data (writable), 2 symbols
+ *     size       4 bytes  then the segment bytes
+ *
+ * The symbols segment is not loaded, it names offsets of the other
+ * segments for the disassembler. Each symbol is the kind of the segment
+ * it belongs to (1 byte), its offset (4 bytes) and its name (2 bytes of
+ * length, then the name).
+ *
+ * A text file starting with "#!interpreter" is a script as well, its whole
+ * content being the text segment.
+ */
+use std::sync::Arc;
+
+use crate::errno::Errno;
+use crate::vfs::{FileKind, VFS};
+
+pub const MAGIC: &[u8; 4] = b"\x7fKEX";
+pub const VERSION: u8 = 1;
+
+/**
+ * Directory searched for programs typed without a path.
+ */
+pub const BIN: &str = "/bin";
+
+/**
+ * Exit codes of a command that could not be found or run, like sh.
+ */
+pub const EXIT_NOT_EXECUTABLE: i32 = 126;
+pub const EXIT_NOT_FOUND: i32 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramKind {
+    Bytecode,
+    Script,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    Text,
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub bytes: Vec<u8>,
+}
+
+/**
+ * Label of an offset in the text or data segment.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SegmentKind,
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Executable {
+    pub kind: ProgramKind,
+    pub entry: u32,
+    pub interpreter: Option<String>,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+}
+
+const SEGMENT_TEXT: u8 = 0;
+const SEGMENT_DATA: u8 = 1;
+const SEGMENT_SYMBOLS: u8 = 2;
+
+impl SegmentKind {
+    fn from_u8(kind: u8) -> Result<Self, Errno> {
+        match kind {
+            SEGMENT_TEXT => Ok(Self::Text),
+            SEGMENT_DATA => Ok(Self::Data),
+            _ => Err(Errno::ENOEXEC),
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Text => SEGMENT_TEXT,
+            Self::Data => SEGMENT_DATA,
+        }
+    }
+}
+
+/**
+ * Reads the fields of a header, any truncation is a format error.
+ */
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Errno> {
+        let end = self.offset.checked_add(len).ok_or(Errno::ENOEXEC)?;
+        let bytes = self.bytes.get(self.offset..end).ok_or(Errno::ENOEXEC)?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Errno> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Errno> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Errno> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+impl Executable {
+    /**
+     * Decode the content of a program file, ENOEXEC if it is neither an
+     * executable nor a script.
+     */
+    pub fn parse(bytes: &[u8]) -> Result<Self, Errno> {
+        if let Some(script) = bytes.strip_prefix(b"#!") {
+            let line = script.split(|&byte| byte == b'\n').next().unwrap_or(&[]);
+            let interpreter = String::from_utf8_lossy(line).trim().to_string();
+            if interpreter.is_empty() {
+                return Err(Errno::ENOEXEC);
+            }
+            return Ok(Self {
+                kind: ProgramKind::Script,
+                entry: 0,
+                interpreter: Some(interpreter),
+                segments: vec![Segment {
+                    kind: SegmentKind::Text,
+                    bytes: bytes.to_vec(),
+                }],
+                symbols: Vec::new(),
+            });
+        }
+
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.take(MAGIC.len())? != MAGIC || reader.u8()? != VERSION {
+            return Err(Errno::ENOEXEC);
+        }
+        let kind = match reader.u8()? {
+            0 => ProgramKind::Bytecode,
+            1 => ProgramKind::Script,
+            _ => return Err(Errno::ENOEXEC),
+        };
+        let entry = reader.u32()?;
+        let interpreter = match reader.u16()? as usize {
+            0 => None,
+            len => Some(String::from_utf8(reader.take(len)?.to_vec()).map_err(|_| Errno::ENOEXEC)?),
+        };
+        let mut segments = Vec::new();
+        let mut symbols = Vec::new();
+        for _ in 0..reader.u16()? {
+            let kind = reader.u8()?;
+            let size = reader.u32()? as usize;
+            let bytes = reader.take(size)?;
+            if kind == SEGMENT_SYMBOLS {
+                symbols.extend(parse_symbols(bytes)?);
+            } else {
+                segments.push(Segment {
+                    kind: SegmentKind::from_u8(kind)?,
+                    bytes: bytes.to_vec(),
+                });
+            }
+        }
+        if kind == ProgramKind::Script && interpreter.is_none() {
+            return Err(Errno::ENOEXEC);
+        }
+        Ok(Self {
+            kind,
+            entry,
+            interpreter,
+            segments,
+            symbols,
+        })
+    }
+
+    /**
+     * Encode the executable in the format parse reads.
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.push(match self.kind {
+            ProgramKind::Bytecode => 0,
+            ProgramKind::Script => 1,
+        });
+        bytes.extend_from_slice(&self.entry.to_le_bytes());
+        let interpreter = self.interpreter.as_deref().unwrap_or("");
+        bytes.extend_from_slice(&(interpreter.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(interpreter.as_bytes());
+        let symbols = (!self.symbols.is_empty()).then(|| {
+            let mut table = Vec::new();
+            self.symbols.iter().for_each(|symbol| {
+                table.push(symbol.kind.to_u8());
+                table.extend_from_slice(&symbol.offset.to_le_bytes());
+                table.extend_from_slice(&(symbol.name.len() as u16).to_le_bytes());
+                table.extend_from_slice(symbol.name.as_bytes());
+            });
+            (SEGMENT_SYMBOLS, table)
+        });
+        let segments: Vec<(u8, &[u8])> = self
+            .segments
+            .iter()
+            .map(|segment| (segment.kind.to_u8(), segment.bytes.as_slice()))
+            .chain(
+                symbols
+                    .as_ref()
+                    .map(|(kind, table)| (*kind, table.as_slice())),
+            )
+            .collect();
+        bytes.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+        segments.iter().for_each(|(kind, segment)| {
+            bytes.push(*kind);
+            bytes.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(segment);
+        });
+        bytes
+    }
+
+    /**
+     * Source of a script, the text segment.
+     */
+    pub fn text(&self) -> &[u8] {
+        self.segments
+            .iter()
+            .find(|segment| segment.kind == SegmentKind::Text)
+            .map(|segment| segment.bytes.as_slice())
+            .unwrap_or(&[])
+    }
+}
+
+fn parse_symbols(bytes: &[u8]) -> Result<Vec<Symbol>, Errno> {
+    let mut reader = Reader { bytes, offset: 0 };
+    let mut symbols = Vec::new();
+    while reader.offset < bytes.len() {
+        let kind = SegmentKind::from_u8(reader.u8()?)?;
+        let offset = reader.u32()?;
+        let len = reader.u16()? as usize;
+        let name = String::from_utf8(reader.take(len)?.to_vec()).map_err(|_| Errno::ENOEXEC)?;
+        symbols.push(Symbol { name, kind, offset });
+    }
+    Ok(symbols)
+}
+
+/**
+ * Path of the program a command name refers to: names without a slash
+ * are looked up in /bin.
+ */
+pub fn path(name: &str) -> String {
+    if name.contains('/') {
+        name.to_string()
+    } else {
+        format!("{}/{}", BIN, name)
+    }
+}
+
+/**
+ * Read and decode the program a command name refers to. EACCES if the
+ * file is not executable.
+ */
+pub fn load(name: &str) -> Result<Executable, Errno> {
+    let mut vfs = VFS.write().unwrap();
+    let file = vfs.lookup(&path(name)).ok_or(Errno::ENOENT)?;
+    let vmm = Arc::clone(&vfs.vpm.vmm);
+    drop(vfs);
+
+    let file = file.lock().unwrap();
+    if file.kind != FileKind::Regular || !file.executable {
+        return Err(Errno::EACCES);
+    }
+    let bytes = file.read_at(&mut vmm.lock().unwrap(), 0, file.size as usize)?;
+    Executable::parse(&bytes)
+}
+
+/**
+ * Whether the command name refers to a program, executable or not.
+ */
+pub fn exists(name: &str) -> bool {
+    VFS.write().unwrap().lookup(&path(name)).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn executable() -> Executable {
+        Executable {
+            kind: ProgramKind::Bytecode,
+            entry: 8,
+            interpreter: None,
+            segments: vec![
+                Segment {
+                    kind: SegmentKind::Text,
+                    bytes: vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+                },
+                Segment {
+                    kind: SegmentKind::Data,
+                    bytes: b"hello".to_vec(),
+                },
+            ],
+            symbols: vec![
+                Symbol {
+                    name: "_start".to_string(),
+                    kind: SegmentKind::Text,
+                    offset: 8,
+                },
+                Symbol {
+                    name: "greeting".to_string(),
+                    kind: SegmentKind::Data,
+                    offset: 0,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn executables_survive_a_round_trip() {
+        let executable = executable();
+        assert_eq!(Executable::parse(&executable.to_bytes()), Ok(executable));
+
+        let script = Executable {
+            kind: ProgramKind::Script,
+            entry: 0,
+            interpreter: Some("/bin/sh".to_string()),
+            segments: vec![Segment {
+                kind: SegmentKind::Text,
+                bytes: b"echo hi\n".to_vec(),
+            }],
+            symbols: Vec::new(),
+        };
+        assert_eq!(Executable::parse(&script.to_bytes()), Ok(script));
+    }
+
+    #[test]
+    fn header_is_laid_out_as_documented() {
+        let bytes = executable().to_bytes();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(bytes[4], VERSION);
+        assert_eq!(bytes[5], 0);
+        assert_eq!(bytes[6..10], 8u32.to_le_bytes());
+        assert_eq!(bytes[10..12], 0u16.to_le_bytes());
+        // Text, data and symbols
+        assert_eq!(bytes[12..14], 3u16.to_le_bytes());
+        assert_eq!(bytes[14], SEGMENT_TEXT);
+        assert_eq!(bytes[15..19], 12u32.to_le_bytes());
+    }
+
+    #[test]
+    fn truncated_executables_are_refused() {
+        let bytes = executable().to_bytes();
+        (0..bytes.len()).for_each(|len| {
+            assert_eq!(
+                Executable::parse(&bytes[..len]),
+                Err(Errno::ENOEXEC),
+                "{} bytes",
+                len
+            );
+        });
+    }
+
+    #[test]
+    fn malformed_headers_are_refused() {
+        let bytes = executable().to_bytes();
+        let corrupt = |index: usize, byte: u8| {
+            let mut bytes = bytes.clone();
+            bytes[index] = byte;
+            Executable::parse(&bytes)
+        };
+        assert_eq!(corrupt(0, 0), Err(Errno::ENOEXEC));
+        assert_eq!(corrupt(4, VERSION + 1), Err(Errno::ENOEXEC));
+        assert_eq!(corrupt(5, 2), Err(Errno::ENOEXEC));
+        assert_eq!(corrupt(14, 3), Err(Errno::ENOEXEC));
+        // A script needs an interpreter
+        assert_eq!(corrupt(5, 1), Err(Errno::ENOEXEC));
+        assert_eq!(Executable::parse(b""), Err(Errno::ENOEXEC));
+        assert_eq!(Executable::parse(b"plain text"), Err(Errno::ENOEXEC));
+    }
+
+    #[test]
+    fn shebang_makes_a_script() {
+        let source = b"#! /bin/ksh \necho hi\n";
+        let script = Executable::parse(source).unwrap();
+        assert_eq!(script.kind, ProgramKind::Script);
+        assert_eq!(script.interpreter.as_deref(), Some("/bin/ksh"));
+        assert_eq!(script.text(), source);
+        assert_eq!(Executable::parse(b"#!\necho hi\n"), Err(Errno::ENOEXEC));
+    }
+}
@@ -0,0 +1,502 @@
+/**
+ * Per process file descriptor tables
+ *
+ * A descriptor refers to an open file description: a VFS file with its
+ * offset, one end of a pipe or the console. Descriptions are shared by
+ * dup, dup2 and fork, and released with the last descriptor referring
+ * to them, closing the pipe end they hold.
+ */
+use crossterm::event;
+use lazy_static::lazy_static;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::errno::Errno;
+use crate::ipc::{self, IPC};
+use crate::vfs::{File, FileKind};
+use crate::vmm::Vmm;
+use crate::vpm::{self, Vpm};
+
+pub const STDIN: u32 = 0;
+pub const STDOUT: u32 = 1;
+pub const STDERR: u32 = 2;
+
+/**
+ * Descriptors a process may have open at once.
+ */
+const MAX_FDS: u32 = 256;
+
+/**
+ * How often a poll without timeout checks the terminal.
+ */
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref FILES: Mutex<HashMap<u32, FdTable>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenFlags {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub create: bool,
+    pub truncate: bool,
+}
+
+impl OpenFlags {
+    /**
+     * Flags of an fopen like mode: r, w, a, r+, w+ or a+.
+     */
+    pub fn from_mode(mode: &str) -> Option<Self> {
+        let (read, write, append, create, truncate) = match mode {
+            "r" => (true, false, false, false, false),
+            "r+" => (true, true, false, false, false),
+            "w" => (false, true, false, true, true),
+            "w+" => (true, true, false, true, true),
+            "a" => (false, true, true, true, false),
+            "a+" => (true, true, true, true, false),
+            _ => return None,
+        };
+        Some(Self {
+            read,
+            write,
+            append,
+            create,
+            truncate,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Set,
+    Current,
+    End,
+}
+
+#[derive(Debug)]
+enum Target {
+    Console,
+    File(Arc<Mutex<File>>),
+    Pipe { id: u32, write: bool },
+}
+
+#[derive(Debug)]
+struct OpenFile {
+    target: Target,
+    flags: OpenFlags,
+    offset: u64,
+    vmm: Arc<Mutex<Vmm>>,
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        if let Target::Pipe { id, write } = self.target {
+            let mut ipc = IPC.lock().unwrap();
+            ipc.pipe_close(&mut self.vmm.lock().unwrap(), id, write);
+        }
+    }
+}
+
+type FdTable = BTreeMap<u32, Arc<Mutex<OpenFile>>>;
+
+/**
+ * Give a new process the descriptors of its parent, or the console as
+ * stdin, stdout and stderr when it has none.
+ */
+pub fn inherit(ppid: u32, pid: u32, vmm: &Arc<Mutex<Vmm>>) {
+    let mut files = FILES.lock().unwrap();
+    let table = match files.get(&ppid) {
+        Some(table) => table.clone(),
+        None => {
+            let console = Arc::new(Mutex::new(OpenFile {
+                target: Target::Console,
+                flags: OpenFlags::from_mode("r+").unwrap(),
+                offset: 0,
+                vmm: Arc::clone(vmm),
+            }));
+            [STDIN, STDOUT, STDERR]
+                .into_iter()
+                .map(|fd| (fd, Arc::clone(&console)))
+                .collect()
+        }
+    };
+    files.insert(pid, table);
+}
+
+/**
+ * Close every descriptor of an exiting process.
+ */
+pub fn close_all(pid: u32) {
+    let table = FILES.lock().unwrap().remove(&pid);
+    // Closing pipe ends locks the IPC, not while holding the tables
+    drop(table);
+}
+
+/**
+ * Install a description at the lowest free descriptor from min.
+ */
+fn install(pid: u32, min: u32, description: Arc<Mutex<OpenFile>>) -> Result<u32, Errno> {
+    let mut files = FILES.lock().unwrap();
+    let table = files.get_mut(&pid).ok_or(Errno::ESRCH)?;
+    let fd = (min..MAX_FDS)
+        .find(|fd| !table.contains_key(fd))
+        .ok_or(Errno::EMFILE)?;
+    table.insert(fd, description);
+    Ok(fd)
+}
+
+fn description(process: &Vpm, fd: u32) -> Result<Arc<Mutex<OpenFile>>, Errno> {
+    FILES
+        .lock()
+        .unwrap()
+        .get(&process.pid)
+        .and_then(|table| table.get(&fd))
+        .cloned()
+        .ok_or(Errno::EBADF)
+}
+
+/**
+ * Open a VFS file, truncating it if asked. Opening a FIFO waits for its
+ * other end like ipc::fifo_open.
+ */
+pub fn open(process: &Vpm, file: Arc<Mutex<File>>, flags: OpenFlags) -> Result<u32, Errno> {
+    if !flags.read && !flags.write {
+        return Err(Errno::EINVAL);
+    }
+    let kind = file.lock().unwrap().kind;
+    let target = match kind {
+        FileKind::Fifo(id) => {
+            if flags.read && flags.write {
+                return Err(Errno::EINVAL);
+            }
+            ipc::fifo_open(process, id, flags.write)?;
+            Target::Pipe {
+                id,
+                write: flags.write,
+            }
+        }
+        FileKind::Regular => {
+            if flags.truncate && flags.write {
+                let mut file = file.lock().unwrap();
+                file.truncate(&mut process.vmm.lock().unwrap())?;
+            }
+            Target::File(file)
+        }
+    };
+    let description = Arc::new(Mutex::new(OpenFile {
+        target,
+        flags,
+        offset: 0,
+        vmm: Arc::clone(&process.vmm),
+    }));
+    install(process.pid, 0, description)
+}
+
+/**
+ * Create an anonymous pipe, returning its read and write descriptors.
+ */
+pub fn pipe(process: &Vpm) -> Result<(u32, u32), Errno> {
+    let id = IPC.lock().unwrap().pipe(&mut process.vmm.lock().unwrap())?;
+    let end = |write| {
+        Arc::new(Mutex::new(OpenFile {
+            target: Target::Pipe { id, write },
+            flags: OpenFlags {
+                read: !write,
+                write,
+                ..Default::default()
+            },
+            offset: 0,
+            vmm: Arc::clone(&process.vmm),
+        }))
+    };
+    let read = install(process.pid, 0, end(false))?;
+    match install(process.pid, 0, end(true)) {
+        Ok(write) => Ok((read, write)),
+        Err(errno) => {
+            close(process, read).ok();
+            Err(errno)
+        }
+    }
+}
+
+/**
+ * Read up to len bytes at the offset of the descriptor, advancing it. An
+ * empty result means end of file. Reading a pipe blocks while it is empty.
+ */
+pub fn read(process: &Vpm, fd: u32, len: usize) -> Result<Vec<u8>, Errno> {
+    let description = description(process, fd)?;
+    let mut open_file = description.lock().unwrap();
+    if !open_file.flags.read {
+        return Err(Errno::EBADF);
+    }
+    match &open_file.target {
+        Target::Console => {
+            // Waiting for the user is sleeping, not using the CPU
+            let mut line = String::new();
+            vpm::block(process.pid).ok();
+            let read = io::stdin().lock().read_line(&mut line);
+            vpm::wake(process.pid).ok();
+            read.map_err(|_| Errno::EIO)?;
+            let mut bytes = line.into_bytes();
+            bytes.truncate(len);
+            Ok(bytes)
+        }
+        Target::Pipe { id, .. } => {
+            let id = *id;
+            // Other descriptors sharing the pipe must not wait for this read
+            drop(open_file);
+            ipc::pipe_read(process, id, len)
+        }
+        Target::File(file) => {
+            let bytes = file.lock().unwrap().read_at(
+                &mut process.vmm.lock().unwrap(),
+                open_file.offset,
+                len,
+            )?;
+            open_file.offset += bytes.len() as u64;
+            Ok(bytes)
+        }
+    }
+}
+
+/**
+ * Wait up to the timeout, for ever without one, for input on the terminal
+ * behind the descriptor. The process is blocked meanwhile.
+ */
+pub fn poll(process: &Vpm, fd: u32, timeout: Option<Duration>) -> Result<bool, Errno> {
+    if !matches!(
+        description(process, fd)?.lock().unwrap().target,
+        Target::Console
+    ) {
+        return Err(Errno::ENOTTY);
+    }
+    vpm::block(process.pid).ok();
+    let ready = match timeout {
+        Some(timeout) => event::poll(timeout),
+        None => loop {
+            match event::poll(POLL_INTERVAL) {
+                Ok(false) => continue,
+                ready => break ready,
+            }
+        },
+    };
+    vpm::wake(process.pid).ok();
+    ready.map_err(|_| Errno::EIO)
+}
+
+/**
+ * Write the bytes at the offset of the descriptor, or at the end of the
+ * file when it was opened to append. Returns how many bytes were written.
+ */
+pub fn write(process: &Vpm, fd: u32, bytes: &[u8]) -> Result<usize, Errno> {
+    let description = description(process, fd)?;
+    let mut open_file = description.lock().unwrap();
+    if !open_file.flags.write {
+        return Err(Errno::EBADF);
+    }
+    match &open_file.target {
+        Target::Console if fd == STDERR => {
+            eprint!("{}", String::from_utf8_lossy(bytes));
+            Ok(bytes.len())
+        }
+        Target::Console => {
+            print!("{}", String::from_utf8_lossy(bytes));
+            io::stdout().flush().map_err(|_| Errno::EIO)?;
+            Ok(bytes.len())
+        }
+        Target::Pipe { id, .. } => {
+            let id = *id;
+            drop(open_file);
+            ipc::pipe_write(process, id, bytes)
+        }
+        Target::File(file) => {
+            let file = Arc::clone(file);
+            let mut file = file.lock().unwrap();
+            if open_file.flags.append {
+                open_file.offset = file.size;
+            }
+            file.write_at(&mut process.vmm.lock().unwrap(), open_file.offset, bytes)?;
+            open_file.offset += bytes.len() as u64;
+            Ok(bytes.len())
+        }
+    }
+}
+
+/**
+ * Move the offset of a file descriptor, returning the new offset. Pipes
+ * and the console cannot seek.
+ */
+pub fn lseek(process: &Vpm, fd: u32, offset: i64, whence: Whence) -> Result<u64, Errno> {
+    let description = description(process, fd)?;
+    let mut open_file = description.lock().unwrap();
+    let base = match (&open_file.target, whence) {
+        (Target::File(_), Whence::Set) => 0,
+        (Target::File(_), Whence::Current) => open_file.offset,
+        (Target::File(file), Whence::End) => file.lock().unwrap().size,
+        _ => return Err(Errno::ESPIPE),
+    };
+    let offset = base.checked_add_signed(offset).ok_or(Errno::EINVAL)?;
+    open_file.offset = offset;
+    Ok(offset)
+}
+
+pub fn close(process: &Vpm, fd: u32) -> Result<(), Errno> {
+    let description = FILES
+        .lock()
+        .unwrap()
+        .get_mut(&process.pid)
+        .and_then(|table| table.remove(&fd))
+        .ok_or(Errno::EBADF)?;
+    drop(description);
+    Ok(())
+}
+
+/**
+ * Duplicate a descriptor on the lowest free one.
+ */
+pub fn dup(process: &Vpm, fd: u32) -> Result<u32, Errno> {
+    let description = description(process, fd)?;
+    install(process.pid, 0, description)
+}
+
+/**
+ * Make new refer to the description of old, closing what new referred to.
+ */
+pub fn dup2(process: &Vpm, old: u32, new: u32) -> Result<u32, Errno> {
+    let description = description(process, old)?;
+    if new >= MAX_FDS {
+        return Err(Errno::EBADF);
+    }
+    let previous = FILES
+        .lock()
+        .unwrap()
+        .get_mut(&process.pid)
+        .ok_or(Errno::ESRCH)?
+        .insert(new, description);
+    drop(previous);
+    Ok(new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::Vfs;
+    use crate::vmm::PageSize;
+
+    /**
+     * A process with a file system of its own, and a file of it opened
+     * with the given mode.
+     */
+    fn open_file(mode: &str, content: &[u8]) -> (Vpm, u32) {
+        let vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        let mut vfs = Vfs::new(Vpm::new(Arc::new(Mutex::new(vmm))));
+        let process = vfs.vpm.clone();
+        let file = vfs.open_file("file", true).unwrap();
+        file.lock()
+            .unwrap()
+            .write_at(&mut process.vmm.lock().unwrap(), 0, content)
+            .unwrap();
+        let fd = open(&process, file, OpenFlags::from_mode(mode).unwrap()).unwrap();
+        (process, fd)
+    }
+
+    #[test]
+    fn descriptors_start_at_the_lowest_free_one() {
+        let (process, fd) = open_file("r", b"");
+        assert_eq!(fd, 3);
+        close(&process, STDIN).unwrap();
+        assert_eq!(dup(&process, fd), Ok(STDIN));
+        assert_eq!(dup(&process, fd), Ok(4));
+        assert_eq!(close(&process, STDIN), Ok(()));
+        assert_eq!(close(&process, STDIN), Err(Errno::EBADF));
+        assert_eq!(dup(&process, 42), Err(Errno::EBADF));
+    }
+
+    #[test]
+    fn duplicates_share_the_offset() {
+        let (process, fd) = open_file("r", b"hello world");
+        let copy = dup(&process, fd).unwrap();
+        assert_eq!(read(&process, fd, 6).unwrap(), b"hello ");
+        assert_eq!(read(&process, copy, 5).unwrap(), b"world");
+        assert_eq!(read(&process, fd, 5).unwrap(), b"");
+        assert_eq!(lseek(&process, copy, 0, Whence::Current), Ok(11));
+
+        // The description outlives the descriptor it was opened on
+        close(&process, fd).unwrap();
+        assert_eq!(lseek(&process, copy, 0, Whence::Set), Ok(0));
+        assert_eq!(read(&process, copy, 5).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn dup2_replaces_the_target_descriptor() {
+        let (process, fd) = open_file("w", b"");
+        assert_eq!(dup2(&process, fd, STDOUT), Ok(STDOUT));
+        write(&process, STDOUT, b"redirected").unwrap();
+        assert_eq!(lseek(&process, fd, 0, Whence::Current), Ok(10));
+
+        assert_eq!(dup2(&process, fd, fd), Ok(fd));
+        assert_eq!(dup2(&process, fd, MAX_FDS), Err(Errno::EBADF));
+        assert_eq!(dup2(&process, 42, STDOUT), Err(Errno::EBADF));
+        assert_eq!(dup2(&process, fd, 100), Ok(100));
+        assert_eq!(lseek(&process, 100, 0, Whence::End), Ok(10));
+    }
+
+    #[test]
+    fn lseek_moves_from_start_current_or_end() {
+        let (process, fd) = open_file("r", b"0123456789");
+        assert_eq!(lseek(&process, fd, 4, Whence::Set), Ok(4));
+        assert_eq!(lseek(&process, fd, 2, Whence::Current), Ok(6));
+        assert_eq!(read(&process, fd, 2).unwrap(), b"67");
+        assert_eq!(lseek(&process, fd, -3, Whence::End), Ok(7));
+        assert_eq!(read(&process, fd, 10).unwrap(), b"789");
+        assert_eq!(lseek(&process, fd, -11, Whence::End), Err(Errno::EINVAL));
+        assert_eq!(lseek(&process, fd, 0, Whence::Current), Ok(10));
+
+        let (read_end, _) = pipe(&process).unwrap();
+        assert_eq!(
+            lseek(&process, read_end, 0, Whence::Set),
+            Err(Errno::ESPIPE)
+        );
+    }
+
+    #[test]
+    fn append_writes_at_the_end_whatever_the_offset() {
+        let (process, fd) = open_file("a+", b"start");
+        lseek(&process, fd, 0, Whence::Set).unwrap();
+        assert_eq!(write(&process, fd, b" end"), Ok(4));
+        assert_eq!(lseek(&process, fd, 0, Whence::Current), Ok(9));
+        lseek(&process, fd, 0, Whence::Set).unwrap();
+        assert_eq!(read(&process, fd, 20).unwrap(), b"start end");
+    }
+
+    #[test]
+    fn failed_fork_inherits_no_descriptor() {
+        let (mut process, fd) = open_file("r", b"data");
+        let mut vmm = process.vmm.lock().unwrap();
+        while vmm.free_memory > 0 {
+            vmm.allocate_page().unwrap();
+        }
+        drop(vmm);
+        let file = description(&process, fd).unwrap();
+        let holders = Arc::strong_count(&file);
+
+        assert_eq!(process.execute_child("child", |_| 0), Err(Errno::ENOMEM));
+        assert_eq!(Arc::strong_count(&file), holders);
+    }
+
+    #[test]
+    fn access_mode_is_enforced() {
+        let (process, fd) = open_file("r", b"data");
+        assert_eq!(write(&process, fd, b"x"), Err(Errno::EBADF));
+        let (process, fd) = open_file("w", b"data");
+        assert_eq!(read(&process, fd, 1), Err(Errno::EBADF));
+        // Opening for writing truncated the file
+        assert_eq!(lseek(&process, fd, 0, Whence::End), Ok(0));
+    }
+}
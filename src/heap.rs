@@ -0,0 +1,308 @@
+/**
+ * Process heap bookkeeping: the program break plus a malloc/free allocator.
+ *
+ * The heap only tracks byte ranges, mapping the pages under the break is
+ * done by the Vmm that owns the address space.
+ */
+use std::collections::{BTreeMap, HashMap};
+
+use crate::errno::Errno;
+
+/**
+ * First virtual page of the heap, far from the pages handed out one by one.
+ */
+pub const HEAP_START_PAGE: u64 = 0x10_0000;
+
+/**
+ * Smallest size class, the classes double up to half a page.
+ */
+const MIN_BLOCK: u64 = 16;
+
+#[derive(Debug, Clone)]
+struct Allocation {
+    size: u64,
+    requested: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Heap {
+    pub start: u64,
+    pub brk: u64,
+    free_blocks: BTreeMap<u64, u64>,
+    allocations: HashMap<u64, Allocation>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HeapStats {
+    pub start: u64,
+    pub brk: u64,
+    pub allocated_blocks: usize,
+    pub allocated_bytes: u64,
+    pub requested_bytes: u64,
+    pub free_blocks: usize,
+    pub free_bytes: u64,
+    pub largest_free_block: u64,
+}
+
+impl HeapStats {
+    /**
+     * Bytes lost to size class rounding, relative to the allocated bytes.
+     */
+    pub fn internal_fragmentation(&self) -> f64 {
+        if self.allocated_bytes == 0 {
+            return 0.0;
+        }
+        (self.allocated_bytes - self.requested_bytes) as f64 / self.allocated_bytes as f64 * 100.0
+    }
+
+    /**
+     * Free bytes that cannot be served by the largest free block.
+     */
+    pub fn external_fragmentation(&self) -> f64 {
+        if self.free_bytes == 0 {
+            return 0.0;
+        }
+        (self.free_bytes - self.largest_free_block) as f64 / self.free_bytes as f64 * 100.0
+    }
+}
+
+impl Heap {
+    pub fn new(page_size: u64) -> Self {
+        let start = HEAP_START_PAGE * page_size;
+        Self {
+            start,
+            brk: start,
+            free_blocks: BTreeMap::new(),
+            allocations: HashMap::new(),
+        }
+    }
+
+    /**
+     * Round the request up to its size class, a power of two up to half a
+     * page, larger requests to whole pages. None if the rounded size does
+     * not fit in 64 bits.
+     */
+    pub fn size_class(size: u64, page_size: u64) -> Option<u64> {
+        match size.max(MIN_BLOCK).checked_next_power_of_two() {
+            Some(class) if class <= page_size / 2 => Some(class),
+            _ => size.div_ceil(page_size).checked_mul(page_size),
+        }
+    }
+
+    /**
+     * Best fit allocation among the free blocks, None when the heap must grow.
+     */
+    pub fn malloc(&mut self, size: u64, page_size: u64) -> Option<u64> {
+        let block_size = Self::size_class(size.max(1), page_size)?;
+        let (&address, &free_size) = self
+            .free_blocks
+            .iter()
+            .filter(|(_, &free_size)| free_size >= block_size)
+            .min_by_key(|(_, &free_size)| free_size)?;
+
+        self.free_blocks.remove(&address);
+        let remainder = free_size - block_size;
+        let block_size = if remainder >= MIN_BLOCK {
+            self.free_blocks.insert(address + block_size, remainder);
+            block_size
+        } else {
+            free_size
+        };
+
+        self.allocations.insert(
+            address,
+            Allocation {
+                size: block_size,
+                requested: size,
+            },
+        );
+        Some(address)
+    }
+
+    /**
+     * Release the block and coalesce it with its free neighbours.
+     */
+    pub fn free(&mut self, address: u64) -> Result<(), Errno> {
+        let allocation = self.allocations.remove(&address).ok_or(Errno::EINVAL)?;
+        self.insert_free_block(address, allocation.size);
+        Ok(())
+    }
+
+    fn insert_free_block(&mut self, address: u64, size: u64) {
+        let mut address = address;
+        let mut size = size;
+
+        let previous = self
+            .free_blocks
+            .range(..address)
+            .next_back()
+            .map(|(&a, &s)| (a, s));
+        if let Some((previous_address, previous_size)) = previous {
+            if previous_address + previous_size == address {
+                self.free_blocks.remove(&previous_address);
+                address = previous_address;
+                size += previous_size;
+            }
+        }
+
+        if let Some(next_size) = self.free_blocks.remove(&(address + size)) {
+            size += next_size;
+        }
+
+        self.free_blocks.insert(address, size);
+    }
+
+    /**
+     * Memory obtained by malloc through sbrk becomes part of the arena.
+     */
+    pub fn extend_arena(&mut self, address: u64, size: u64) {
+        self.insert_free_block(address, size);
+    }
+
+    /**
+     * Move the break down, forgetting free memory above it. EINVAL if a
+     * live allocation would end up above the break.
+     */
+    pub fn shrink(&mut self, new_brk: u64) -> Result<(), Errno> {
+        if self
+            .allocations
+            .iter()
+            .any(|(&address, allocation)| address + allocation.size > new_brk)
+        {
+            return Err(Errno::EINVAL);
+        }
+        self.brk = new_brk;
+        let above: Vec<u64> = self
+            .free_blocks
+            .range(new_brk..)
+            .map(|(&address, _)| address)
+            .collect();
+        above.iter().for_each(|address| {
+            self.free_blocks.remove(address);
+        });
+        let last_block = self
+            .free_blocks
+            .range(..new_brk)
+            .next_back()
+            .map(|(&a, &s)| (a, s));
+        if let Some((address, size)) = last_block {
+            if address + size > new_brk {
+                self.free_blocks.insert(address, new_brk - address);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            start: self.start,
+            brk: self.brk,
+            allocated_blocks: self.allocations.len(),
+            allocated_bytes: self.allocations.values().map(|a| a.size).sum(),
+            requested_bytes: self.allocations.values().map(|a| a.requested).sum(),
+            free_blocks: self.free_blocks.len(),
+            free_bytes: self.free_blocks.values().sum(),
+            largest_free_block: self.free_blocks.values().copied().max().unwrap_or(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE: u64 = 4096;
+
+    fn heap_with_arena(size: u64) -> Heap {
+        let mut heap = Heap::new(PAGE_SIZE);
+        heap.brk = heap.start + size;
+        heap.extend_arena(heap.start, size);
+        heap
+    }
+
+    #[test]
+    fn sizes_are_rounded_to_their_class() {
+        assert_eq!(Heap::size_class(1, PAGE_SIZE), Some(16));
+        assert_eq!(Heap::size_class(16, PAGE_SIZE), Some(16));
+        assert_eq!(Heap::size_class(17, PAGE_SIZE), Some(32));
+        assert_eq!(Heap::size_class(4096, PAGE_SIZE), Some(4096));
+        assert_eq!(Heap::size_class(4097, PAGE_SIZE), Some(8192));
+        assert_eq!(Heap::size_class(u64::MAX, PAGE_SIZE), None);
+
+        let page_size = 64 * 1024;
+        assert_eq!(Heap::size_class(17, page_size), Some(32));
+        assert_eq!(Heap::size_class(4097, page_size), Some(8192));
+        assert_eq!(
+            Heap::size_class(page_size / 2, page_size),
+            Some(page_size / 2)
+        );
+        assert_eq!(
+            Heap::size_class(page_size / 2 + 1, page_size),
+            Some(page_size)
+        );
+        assert_eq!(
+            Heap::size_class(page_size + 1, page_size),
+            Some(2 * page_size)
+        );
+        assert_eq!(Heap::size_class(u64::MAX, page_size), None);
+    }
+
+    #[test]
+    fn blocks_are_split_from_the_best_fit() {
+        let mut heap = heap_with_arena(PAGE_SIZE);
+        let first = heap.malloc(10, PAGE_SIZE).unwrap();
+        let second = heap.malloc(100, PAGE_SIZE).unwrap();
+        assert_eq!(first, heap.start);
+        assert_eq!(second, heap.start + 16);
+
+        let stats = heap.stats();
+        assert_eq!(stats.allocated_bytes, 16 + 128);
+        assert_eq!(stats.requested_bytes, 110);
+        assert_eq!(stats.free_blocks, 1);
+        assert_eq!(stats.free_bytes, PAGE_SIZE - 144);
+        assert_eq!(heap.malloc(PAGE_SIZE, PAGE_SIZE), None);
+    }
+
+    #[test]
+    fn freed_blocks_coalesce_with_their_neighbours() {
+        let mut heap = heap_with_arena(PAGE_SIZE);
+        let blocks: Vec<u64> = (0..3)
+            .map(|_| heap.malloc(64, PAGE_SIZE).unwrap())
+            .collect();
+        heap.free(blocks[0]).unwrap();
+        heap.free(blocks[2]).unwrap();
+        assert_eq!(heap.stats().free_blocks, 2);
+
+        heap.free(blocks[1]).unwrap();
+        let stats = heap.stats();
+        assert_eq!(stats.free_blocks, 1);
+        assert_eq!(stats.largest_free_block, PAGE_SIZE);
+    }
+
+    #[test]
+    fn shrinking_below_a_live_block_is_refused() {
+        let mut heap = heap_with_arena(PAGE_SIZE);
+        let first = heap.malloc(64, PAGE_SIZE).unwrap();
+        let second = heap.malloc(64, PAGE_SIZE).unwrap();
+
+        assert_eq!(heap.shrink(second + 32), Err(Errno::EINVAL));
+        assert_eq!(heap.brk, heap.start + PAGE_SIZE);
+        assert_eq!(heap.stats().free_bytes, PAGE_SIZE - 128);
+
+        assert_eq!(heap.shrink(second + 64), Ok(()));
+        assert_eq!(heap.stats().free_bytes, 0);
+        heap.free(second).unwrap();
+        assert_eq!(heap.shrink(first + 64), Ok(()));
+        assert_eq!(heap.stats().free_bytes, 0);
+        assert_eq!(heap.shrink(heap.start), Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn double_free_is_rejected() {
+        let mut heap = heap_with_arena(PAGE_SIZE);
+        let block = heap.malloc(32, PAGE_SIZE).unwrap();
+        assert_eq!(heap.free(block), Ok(()));
+        assert_eq!(heap.free(block), Err(Errno::EINVAL));
+        assert_eq!(heap.free(heap.start + 8), Err(Errno::EINVAL));
+    }
+}
@@ -0,0 +1,131 @@
+/**
+ * Hex dumps of file contents, in the formats of hexdump -C and xxd. Each
+ * line shows the offset of its first byte, the next 16 bytes in hex and
+ * the printable ones as ASCII, the others as dots.
+ */
+const WIDTH: usize = 16;
+
+fn ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+/**
+ * Canonical hexdump of bytes read from offset. Lines repeating the one
+ * before are collapsed into a *, and the last line is the end offset.
+ */
+pub fn hexdump(bytes: &[u8], offset: usize) -> String {
+    let mut output = String::new();
+    let mut previous: Option<&[u8]> = None;
+    let mut collapsed = false;
+    for (index, line) in bytes.chunks(WIDTH).enumerate() {
+        if line.len() == WIDTH && previous == Some(line) {
+            if !collapsed {
+                output.push_str("*\n");
+                collapsed = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        collapsed = false;
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let (left, right) = hex.split_at(hex.len().min(WIDTH / 2));
+        output.push_str(&format!(
+            "{:08x}  {:<23}  {:<23}  |{}|\n",
+            offset + index * WIDTH,
+            left.join(" "),
+            right.join(" "),
+            ascii(line)
+        ));
+    }
+    if !bytes.is_empty() {
+        output.push_str(&format!("{:08x}\n", offset + bytes.len()));
+    }
+    output
+}
+
+/**
+ * xxd dump of bytes read from offset, hex in groups of two bytes.
+ */
+pub fn xxd(bytes: &[u8], offset: usize) -> String {
+    let mut output = String::new();
+    for (index, line) in bytes.chunks(WIDTH).enumerate() {
+        let hex: Vec<String> = line
+            .chunks(2)
+            .map(|pair| pair.iter().map(|byte| format!("{:02x}", byte)).collect())
+            .collect();
+        output.push_str(&format!(
+            "{:08x}: {:<39}  {}\n",
+            offset + index * WIDTH,
+            hex.join(" "),
+            ascii(line)
+        ));
+    }
+    output
+}
+
+/**
+ * Whether the content is binary rather than text, that is not UTF-8. The
+ * editor refuses to open it and read to print it.
+ */
+pub fn is_binary(bytes: &[u8]) -> bool {
+    std::str::from_utf8(bytes).is_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_anything_utf8() {
+        assert!(!is_binary(b""));
+        assert!(!is_binary(b"plain text\n"));
+        assert!(!is_binary("h\u{e9}llo \u{1f980}".as_bytes()));
+        assert!(is_binary(&[b'a', 0xff, b'b']));
+        // A multi-byte character cut in the middle
+        assert!(is_binary(&"\u{e9}".as_bytes()[..1]));
+    }
+
+    #[test]
+    fn hexdump_splits_lines_in_two_halves() {
+        let bytes: Vec<u8> = (0x41..0x41 + 20).collect();
+        assert_eq!(
+            hexdump(&bytes, 0),
+            "00000000  41 42 43 44 45 46 47 48  49 4a 4b 4c 4d 4e 4f 50  |ABCDEFGHIJKLMNOP|\n\
+             00000010  51 52 53 54                                       |QRST|\n\
+             00000014\n"
+        );
+    }
+
+    #[test]
+    fn hexdump_collapses_repeated_lines() {
+        let mut bytes = vec![0; 48];
+        bytes.push(b'\n');
+        assert_eq!(
+            hexdump(&bytes, 0x100),
+            "00000100  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|\n\
+             *\n\
+             00000130  0a                                                |.|\n\
+             00000131\n"
+        );
+        assert_eq!(hexdump(&[], 0), "");
+    }
+
+    #[test]
+    fn xxd_groups_bytes_by_two() {
+        let bytes = b"Hello, xxd!\n\x00\x7f~ 0123";
+        assert_eq!(
+            xxd(bytes, 16),
+            "00000010: 4865 6c6c 6f2c 2078 7864 210a 007f 7e20  Hello, xxd!...~ \n\
+             00000020: 3031 3233                                0123\n"
+        );
+    }
+}
@@ -0,0 +1,435 @@
+/**
+ * Syntax highlighting of the editor
+ *
+ * The syntax of a file is picked by its extension and splits each line in
+ * keywords, strings, numbers and comments, colored by the theme. Adding a
+ * language is adding its entry to SYNTAXES.
+ *
+ * The theme is read from /.theme when the editor opens, lines of
+ * kind = color where color is a name like dark_green, an ANSI value from
+ * 0 to 255 or #rrggbb. Kinds left out keep their default color.
+ */
+use crossterm::style::Color;
+
+use crate::errno::Errno;
+use crate::syscall::{self, Value, O_CREAT, O_TRUNC, O_WRONLY, SYS_CLOSE, SYS_OPEN, SYS_WRITE};
+use crate::vpm::Vpm;
+
+pub const THEME_PATH: &str = "/.theme";
+
+/**
+ * Configuration written at boot, the colors of Theme::default.
+ */
+const DEFAULT_THEME: &str = "\
+# Colors of the editor: kind = color
+keyword = magenta
+string = green
+number = cyan
+comment = dark_grey
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Plain,
+    Keyword,
+    String,
+    Number,
+    Comment,
+}
+
+pub struct Syntax {
+    pub name: &'static str,
+    extensions: &'static [&'static str],
+    keywords: &'static [&'static str],
+    line_comment: Option<&'static str>,
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static str,
+    /**
+     * The first word of a line is a keyword, like the command of a shell.
+     */
+    first_word: bool,
+    /**
+     * A word followed by = is a keyword, like the name of a variable.
+     */
+    assignment: bool,
+}
+
+pub const SYNTAXES: [Syntax; 4] = [
+    Syntax {
+        name: "lua",
+        extensions: &["lua"],
+        keywords: &[
+            "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto",
+            "if", "in", "local", "nil", "not", "or", "repeat", "return", "then", "true", "until",
+            "while",
+        ],
+        line_comment: Some("--"),
+        block_comment: Some(("--[[", "]]")),
+        quotes: "\"'",
+        first_word: false,
+        assignment: false,
+    },
+    Syntax {
+        name: "ksh",
+        extensions: &["ksh"],
+        keywords: &["exit"],
+        line_comment: Some("#"),
+        block_comment: None,
+        quotes: "\"'",
+        first_word: true,
+        assignment: false,
+    },
+    Syntax {
+        name: "json",
+        extensions: &["json"],
+        keywords: &["true", "false", "null"],
+        line_comment: None,
+        block_comment: None,
+        quotes: "\"",
+        first_word: false,
+        assignment: false,
+    },
+    Syntax {
+        name: "env",
+        extensions: &["env", "theme"],
+        keywords: &["export"],
+        line_comment: Some("#"),
+        block_comment: None,
+        quotes: "\"'",
+        first_word: false,
+        assignment: true,
+    },
+];
+
+/**
+ * Syntax of a file from the extension of its name, .env included.
+ */
+pub fn find(name: &str) -> Option<&'static Syntax> {
+    let (_, extension) = name.rsplit_once('.')?;
+    SYNTAXES
+        .iter()
+        .find(|syntax| syntax.extensions.contains(&extension))
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn starts_with(line: &[char], at: usize, pattern: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    line[at..].starts_with(&pattern)
+}
+
+impl Syntax {
+    /**
+     * Kind of each character of a line. in_comment tells if the line
+     * starts in a block comment and is updated for the next line.
+     */
+    pub fn highlight(&self, line: &[char], in_comment: &mut bool) -> Vec<Kind> {
+        let mut kinds = vec![Kind::Plain; line.len()];
+        let mut first_word = self.first_word;
+        let mut at = 0;
+        while at < line.len() {
+            let c = line[at];
+            let start = at;
+            if let Some((open, close)) = self.block_comment {
+                if *in_comment {
+                    if starts_with(line, at, close) {
+                        at += close.chars().count();
+                        *in_comment = false;
+                    } else {
+                        at += 1;
+                    }
+                    kinds[start..at].fill(Kind::Comment);
+                    continue;
+                }
+                if starts_with(line, at, open) {
+                    at += open.chars().count();
+                    *in_comment = true;
+                    kinds[start..at].fill(Kind::Comment);
+                    continue;
+                }
+            }
+            if self
+                .line_comment
+                .is_some_and(|comment| starts_with(line, at, comment))
+            {
+                kinds[at..].fill(Kind::Comment);
+                break;
+            }
+
+            let previous_word = at > 0 && is_word(line[at - 1]);
+            let number = c.is_ascii_digit()
+                || (c == '-' && line.get(at + 1).is_some_and(|next| next.is_ascii_digit()));
+            if self.quotes.contains(c) {
+                at += 1;
+                while at < line.len() && line[at] != c {
+                    at += if line[at] == '\\' { 2 } else { 1 };
+                }
+                at = (at + 1).min(line.len());
+                kinds[start..at].fill(Kind::String);
+            } else if number && !previous_word {
+                at += 1;
+                while at < line.len() && (is_word(line[at]) || line[at] == '.') {
+                    at += 1;
+                }
+                kinds[start..at].fill(Kind::Number);
+            } else if is_word(c) {
+                while at < line.len() && is_word(line[at]) {
+                    at += 1;
+                }
+                let word: String = line[start..at].iter().collect();
+                if self.keywords.contains(&word.as_str())
+                    || first_word
+                    || (self.assignment
+                        && line[at..].iter().find(|c| !c.is_whitespace()) == Some(&'='))
+                {
+                    kinds[start..at].fill(Kind::Keyword);
+                }
+            } else {
+                at += 1;
+            }
+            if !c.is_whitespace() {
+                first_word = false;
+            }
+        }
+        kinds
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Theme {
+    keyword: Color,
+    string: Color,
+    number: Color,
+    comment: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            keyword: Color::Magenta,
+            string: Color::Green,
+            number: Color::Cyan,
+            comment: Color::DarkGrey,
+        }
+    }
+}
+
+fn parse_color(color: &str) -> Option<Color> {
+    if let Some(hex) = color.strip_prefix('#') {
+        let value = u32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|_| hex.len() == 6)?;
+        return Some(Color::Rgb {
+            r: (value >> 16) as u8,
+            g: (value >> 8) as u8,
+            b: value as u8,
+        });
+    }
+    if let Ok(value) = color.parse::<u8>() {
+        return Some(Color::AnsiValue(value));
+    }
+    Color::try_from(color).ok()
+}
+
+impl Theme {
+    /**
+     * Foreground color of a kind, None to keep the terminal one.
+     */
+    pub fn color(&self, kind: Kind) -> Option<Color> {
+        match kind {
+            Kind::Plain => None,
+            Kind::Keyword => Some(self.keyword),
+            Kind::String => Some(self.string),
+            Kind::Number => Some(self.number),
+            Kind::Comment => Some(self.comment),
+        }
+    }
+
+    /**
+     * Theme of a configuration, with the errors of the lines ignored.
+     */
+    pub fn parse(text: &str) -> (Theme, Vec<String>) {
+        let mut theme = Theme::default();
+        let mut errors = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((kind, color)) = line.split_once('=') else {
+                errors.push(format!("line {}: expected kind = color", number + 1));
+                continue;
+            };
+            let (kind, color) = (kind.trim(), color.trim());
+            let Some(color) = parse_color(color) else {
+                errors.push(format!("line {}: unknown color {}", number + 1, color));
+                continue;
+            };
+            match kind {
+                "keyword" => theme.keyword = color,
+                "string" => theme.string = color,
+                "number" => theme.number = color,
+                "comment" => theme.comment = color,
+                _ => errors.push(format!("line {}: unknown kind {}", number + 1, kind)),
+            }
+        }
+        (theme, errors)
+    }
+
+    /**
+     * Theme of the configuration file, the default one if there is none.
+     */
+    pub fn load(process: &Vpm) -> (Theme, Vec<String>) {
+        match syscall::read_all(process, THEME_PATH) {
+            Ok(content) => Theme::parse(&String::from_utf8_lossy(&content)),
+            Err(Errno::ENOENT) => (Theme::default(), Vec::new()),
+            Err(errno) => (Theme::default(), vec![errno.to_string()]),
+        }
+    }
+}
+
+/**
+ * Write the default theme, at boot in the root directory.
+ */
+pub fn install(process: &Vpm) {
+    let name = THEME_PATH.trim_start_matches('/');
+    let open = [
+        Value::Str(name.to_string()),
+        Value::Int(O_WRONLY | O_CREAT | O_TRUNC),
+    ];
+    let result = syscall::syscall(process, SYS_OPEN, &open).and_then(|fd| {
+        let write = [fd.clone(), Value::Bytes(DEFAULT_THEME.as_bytes().to_vec())];
+        syscall::syscall(process, SYS_WRITE, &write)?;
+        syscall::syscall(process, SYS_CLOSE, &[fd])
+    });
+    if let Err(errno) = result {
+        println!("Cannot install {}: {}", THEME_PATH, errno);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Kinds of a line as letters: k keyword, s string, n number, c comment
+     * and . plain.
+     */
+    fn kinds(extension: &str, lines: &[&str]) -> Vec<String> {
+        let syntax = find(&format!("file.{}", extension)).unwrap();
+        let mut in_comment = false;
+        lines
+            .iter()
+            .map(|line| {
+                let line: Vec<char> = line.chars().collect();
+                syntax
+                    .highlight(&line, &mut in_comment)
+                    .iter()
+                    .map(|kind| match kind {
+                        Kind::Plain => '.',
+                        Kind::Keyword => 'k',
+                        Kind::String => 's',
+                        Kind::Number => 'n',
+                        Kind::Comment => 'c',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn syntax_is_found_by_extension() {
+        assert_eq!(find("init.lua").unwrap().name, "lua");
+        assert_eq!(find("boot.ksh").unwrap().name, "ksh");
+        assert_eq!(find("/etc/config.json").unwrap().name, "json");
+        assert_eq!(find(".env").unwrap().name, "env");
+        assert_eq!(find(".theme").unwrap().name, "env");
+        assert!(find("notes.txt").is_none());
+        assert!(find("README").is_none());
+    }
+
+    #[test]
+    fn lua_has_keywords_strings_numbers_and_block_comments() {
+        assert_eq!(
+            kinds(
+                "lua",
+                &["local x = 'a' -- c", "x2 = -1.5 --[[ a", "b ]] end"]
+            ),
+            vec!["kkkkk.....sss.cccc", ".....nnnn.cccccc", "cccc.kkk"]
+        );
+    }
+
+    #[test]
+    fn ksh_highlights_the_command_word() {
+        assert_eq!(
+            kinds("ksh", &["  echo \"a\\\"b\" 1 # c", "exit"]),
+            vec!["..kkkk.ssssss.n.ccc", "kkkk"]
+        );
+    }
+
+    #[test]
+    fn json_has_no_comments() {
+        assert_eq!(
+            kinds("json", &["{\"a\": [true, null, 3]} # x"]),
+            vec![".sss...kkkk..kkkk..n......"]
+        );
+    }
+
+    #[test]
+    fn env_highlights_assigned_names() {
+        assert_eq!(
+            kinds("env", &["export PATH = \"/bin\" # c", "keyword=magenta"]),
+            vec!["kkkkkk.kkkk...ssssss.ccc", "kkkkkkk........"]
+        );
+    }
+
+    #[test]
+    fn theme_colors_are_names_ansi_values_or_rgb() {
+        let (theme, errors) = Theme::parse(
+            "# comment\n\
+             keyword = dark_green\n\
+             string=208\n\
+             number = #ff8000\n",
+        );
+        assert!(errors.is_empty());
+        assert_eq!(theme.color(Kind::Keyword), Some(Color::DarkGreen));
+        assert_eq!(theme.color(Kind::String), Some(Color::AnsiValue(208)));
+        assert_eq!(
+            theme.color(Kind::Number),
+            Some(Color::Rgb {
+                r: 0xff,
+                g: 0x80,
+                b: 0
+            })
+        );
+        assert_eq!(theme.color(Kind::Comment), Some(Color::DarkGrey));
+        assert_eq!(theme.color(Kind::Plain), None);
+    }
+
+    #[test]
+    fn theme_errors_name_their_line() {
+        let (theme, errors) = Theme::parse("keyword\nstring = #fff\nnumber = red\nlabel = red\n");
+        assert_eq!(
+            errors,
+            vec![
+                "line 1: expected kind = color",
+                "line 2: unknown color #fff",
+                "line 4: unknown kind label",
+            ]
+        );
+        assert_eq!(theme.color(Kind::String), Some(Color::Green));
+        assert_eq!(theme.color(Kind::Number), Some(Color::Red));
+    }
+
+    #[test]
+    fn default_theme_parses_to_the_default_colors() {
+        let (theme, errors) = Theme::parse(DEFAULT_THEME);
+        assert!(errors.is_empty());
+        let default = Theme::default();
+        for kind in [Kind::Keyword, Kind::String, Kind::Number, Kind::Comment] {
+            assert_eq!(theme.color(kind), default.color(kind));
+        }
+    }
+}
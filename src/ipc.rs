@@ -0,0 +1,795 @@
+/**
+ * Inter Process Communication
+ *
+ * Shared memory segments are kernel pages mapped shared into the address
+ * space of every process attaching them. Pipes, FIFOs and message queues
+ * keep their data in kernel pages too; processes waiting on them are
+ * blocked for the scheduler until the IPC state changes.
+ */
+use lazy_static::lazy_static;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Condvar, Mutex, MutexGuard},
+};
+
+use crate::errno::Errno;
+use crate::vmm::{MapKind, PageOwner, Vmm, KERNEL_SPACE};
+use crate::vpm::{self, Vpm};
+
+lazy_static! {
+    pub static ref IPC: Mutex<Ipc> = Mutex::new(Ipc::default());
+    /**
+     * Notified whenever data or ends of a pipe or queue change.
+     */
+    static ref IPC_CHANGED: Condvar = Condvar::new();
+}
+
+pub const DEFAULT_MQ_MAX_MESSAGES: usize = 10;
+pub const DEFAULT_MQ_MAX_SIZE: u64 = 8192;
+
+#[derive(Debug, Clone)]
+struct ShmSegment {
+    name: String,
+    size: u64,
+    pages: Vec<u64>,
+    attachments: Vec<(u32, u64)>,
+    unlinked: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShmInfo {
+    pub id: u32,
+    pub name: String,
+    pub size: u64,
+    pub pages: usize,
+    pub attached: usize,
+    pub unlinked: bool,
+}
+
+/**
+ * Ring buffer living in one kernel page. A FIFO has a name and survives
+ * its ends being closed until it is unlinked.
+ */
+#[derive(Debug, Clone)]
+struct Pipe {
+    name: Option<String>,
+    page: u64,
+    capacity: u64,
+    head: u64,
+    len: u64,
+    readers: u32,
+    writers: u32,
+    /**
+     * Ends opened so far, read then write, so that a FIFO opener notices
+     * a peer that came and went while it was waiting.
+     */
+    opened: [u64; 2],
+    unlinked: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PipeInfo {
+    pub id: u32,
+    pub name: Option<String>,
+    pub buffered: u64,
+    pub capacity: u64,
+    pub readers: u32,
+    pub writers: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Message {
+    pages: Vec<u64>,
+    size: u64,
+    priority: u32,
+}
+
+#[derive(Debug, Clone)]
+struct MessageQueue {
+    name: String,
+    messages: VecDeque<Message>,
+    max_messages: usize,
+    max_size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageQueueInfo {
+    pub id: u32,
+    pub name: String,
+    pub messages: usize,
+    pub bytes: u64,
+    pub max_messages: usize,
+    pub max_size: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Ipc {
+    segments: HashMap<u32, ShmSegment>,
+    next_id: u32,
+    pipes: HashMap<u32, Pipe>,
+    next_pipe_id: u32,
+    queues: HashMap<u32, MessageQueue>,
+    next_queue_id: u32,
+}
+
+impl Pipe {
+    /**
+     * Append as many bytes as fit, returning how many were written.
+     */
+    fn push(&mut self, vmm: &mut Vmm, bytes: &[u8]) -> Result<usize, Errno> {
+        let count = bytes.len().min((self.capacity - self.len) as usize);
+        let mut written = 0;
+        while written < count {
+            let tail = (self.head + self.len) % self.capacity;
+            let chunk = (count - written).min((self.capacity - tail) as usize);
+            vmm.write_page(
+                KERNEL_SPACE,
+                self.page,
+                tail,
+                &bytes[written..written + chunk],
+            )?;
+            self.len += chunk as u64;
+            written += chunk;
+        }
+        Ok(count)
+    }
+
+    /**
+     * Take up to max bytes from the front of the buffer.
+     */
+    fn pop(&mut self, vmm: &mut Vmm, max: usize) -> Result<Vec<u8>, Errno> {
+        let count = max.min(self.len as usize);
+        let mut content = vmm.read_page(KERNEL_SPACE, self.page)?;
+        content.resize(self.capacity as usize, 0);
+        let bytes: Vec<u8> = (0..count as u64)
+            .map(|i| content[((self.head + i) % self.capacity) as usize])
+            .collect();
+        self.head = (self.head + count as u64) % self.capacity;
+        self.len -= count as u64;
+        Ok(bytes)
+    }
+}
+
+/**
+ * Sleep until the IPC state changes, blocking the process meanwhile.
+ */
+fn wait_change<'a>(process: &Vpm, ipc: MutexGuard<'a, Ipc>) -> Result<MutexGuard<'a, Ipc>, Errno> {
+    vpm::wait_on(process.pid, ipc, &IPC_CHANGED)
+}
+
+/**
+ * Open an end of a FIFO, waiting for the other end to be opened too.
+ */
+pub fn fifo_open(process: &Vpm, id: u32, write: bool) -> Result<(), Errno> {
+    let (end, peer) = if write { (1, 0) } else { (0, 1) };
+    let mut ipc = IPC.lock().unwrap();
+    let pipe = ipc.fifo_mut(id)?;
+    if write {
+        pipe.writers += 1;
+    } else {
+        pipe.readers += 1;
+    }
+    pipe.opened[end] += 1;
+    let peers = pipe.opened[peer];
+    IPC_CHANGED.notify_all();
+    loop {
+        let opened = ipc.fifo_mut(id).map(|pipe| {
+            let open = if write { pipe.readers } else { pipe.writers };
+            open > 0 || pipe.opened[peer] != peers
+        });
+        match opened {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(errno) => {
+                ipc.pipe_close(&mut process.vmm.lock().unwrap(), id, write);
+                return Err(errno);
+            }
+        }
+        ipc = match wait_change(process, ipc) {
+            Ok(ipc) => ipc,
+            Err(errno) => {
+                IPC.lock()
+                    .unwrap()
+                    .pipe_close(&mut process.vmm.lock().unwrap(), id, write);
+                return Err(errno);
+            }
+        };
+    }
+}
+
+/**
+ * Read up to max bytes, blocking while the pipe is empty and has writers.
+ * An empty result means end of file.
+ */
+pub fn pipe_read(process: &Vpm, id: u32, max: usize) -> Result<Vec<u8>, Errno> {
+    let mut ipc = IPC.lock().unwrap();
+    loop {
+        let pipe = ipc.pipes.get_mut(&id).ok_or(Errno::EBADF)?;
+        if pipe.len > 0 {
+            let bytes = pipe.pop(&mut process.vmm.lock().unwrap(), max)?;
+            IPC_CHANGED.notify_all();
+            return Ok(bytes);
+        }
+        if pipe.writers == 0 {
+            return Ok(Vec::new());
+        }
+        ipc = wait_change(process, ipc)?;
+    }
+}
+
+/**
+ * Write every byte, blocking while the pipe is full. Fails with EPIPE when
+ * nobody reads the pipe anymore. A write cut short once some bytes went
+ * through returns how many did.
+ */
+pub fn pipe_write(process: &Vpm, id: u32, bytes: &[u8]) -> Result<usize, Errno> {
+    let mut ipc = IPC.lock().unwrap();
+    let mut written = 0;
+    let partial = |written, errno| match written {
+        0 => Err(errno),
+        _ => Ok(written),
+    };
+    loop {
+        let pipe = ipc.pipes.get_mut(&id).ok_or(Errno::EBADF)?;
+        if pipe.readers == 0 {
+            return partial(written, Errno::EPIPE);
+        }
+        written += pipe.push(&mut process.vmm.lock().unwrap(), &bytes[written..])?;
+        IPC_CHANGED.notify_all();
+        if written == bytes.len() {
+            return Ok(written);
+        }
+        ipc = match wait_change(process, ipc) {
+            Ok(ipc) => ipc,
+            Err(errno) => return partial(written, errno),
+        };
+    }
+}
+
+/**
+ * Queue a message, blocking while the queue is full. Higher priority
+ * messages are received first.
+ */
+pub fn mq_send(process: &Vpm, id: u32, bytes: &[u8], priority: u32) -> Result<(), Errno> {
+    let mut ipc = IPC.lock().unwrap();
+    loop {
+        let queue = ipc.queues.get_mut(&id).ok_or(Errno::EBADF)?;
+        if bytes.len() as u64 > queue.max_size {
+            return Err(Errno::EMSGSIZE);
+        }
+        if queue.messages.len() < queue.max_messages {
+            let mut vmm = process.vmm.lock().unwrap();
+            if bytes.len() as u64 > vmm.free_memory {
+                return Err(Errno::ENOMEM);
+            }
+            // An empty message still takes a page so it has an owner
+            let pages = if bytes.is_empty() {
+                vec![vmm.allocate_page()?.0]
+            } else {
+                vmm.allocate_bytes(bytes.to_vec())?
+            };
+            vmm.set_owner(&pages, PageOwner::MessageQueue(id));
+            let position = queue
+                .messages
+                .iter()
+                .position(|message| message.priority < priority)
+                .unwrap_or(queue.messages.len());
+            queue.messages.insert(
+                position,
+                Message {
+                    pages,
+                    size: bytes.len() as u64,
+                    priority,
+                },
+            );
+            IPC_CHANGED.notify_all();
+            return Ok(());
+        }
+        ipc = wait_change(process, ipc)?;
+    }
+}
+
+/**
+ * Take the oldest message of highest priority, blocking while the queue is
+ * empty. Returns the message and its priority.
+ */
+pub fn mq_receive(process: &Vpm, id: u32) -> Result<(Vec<u8>, u32), Errno> {
+    let mut ipc = IPC.lock().unwrap();
+    loop {
+        let queue = ipc.queues.get_mut(&id).ok_or(Errno::EBADF)?;
+        if let Some(message) = queue.messages.pop_front() {
+            let mut vmm = process.vmm.lock().unwrap();
+            let bytes = vmm.get_bytes(message.pages.clone(), message.size);
+            vmm.deallocate_page(message.pages);
+            IPC_CHANGED.notify_all();
+            return Ok((bytes, message.priority));
+        }
+        ipc = wait_change(process, ipc)?;
+    }
+}
+
+impl Ipc {
+    /**
+     * Open the segment with the given name, creating it with size bytes of
+     * zeroed memory if it does not exist yet. Returns the segment id.
+     */
+    pub fn shm_open(&mut self, vmm: &mut Vmm, name: &str, size: u64) -> Result<u32, Errno> {
+        if let Some((&id, segment)) = self
+            .segments
+            .iter()
+            .find(|(_, segment)| segment.name == name && !segment.unlinked)
+        {
+            if size > segment.size {
+                return Err(Errno::EINVAL);
+            }
+            return Ok(id);
+        }
+        if size == 0 {
+            return Err(Errno::EINVAL);
+        }
+        // Checked before the zeroed content is built, which could not fit
+        if size > vmm.free_memory {
+            return Err(Errno::ENOMEM);
+        }
+
+        let pages = vmm.allocate_bytes(vec![0; size as usize])?;
+        let id = self.next_id;
+        self.next_id += 1;
+        vmm.set_owner(&pages, PageOwner::Shm(id));
+        self.segments.insert(
+            id,
+            ShmSegment {
+                name: name.to_string(),
+                size,
+                pages,
+                attachments: Vec::new(),
+                unlinked: false,
+            },
+        );
+        Ok(id)
+    }
+
+    /**
+     * Map the segment in the process address space, returning its address.
+     */
+    pub fn shm_attach(&mut self, vmm: &mut Vmm, pid: u32, id: u32) -> Result<u64, Errno> {
+        let segment = self.segments.get_mut(&id).ok_or(Errno::ENOENT)?;
+        let address = vmm.mmap(pid, &segment.pages, MapKind::Shared)?;
+        segment.attachments.push((pid, address));
+        Ok(address)
+    }
+
+    pub fn shm_detach(&mut self, vmm: &mut Vmm, pid: u32, address: u64) -> Result<(), Errno> {
+        let id = self
+            .segments
+            .iter()
+            .find(|(_, segment)| segment.attachments.contains(&(pid, address)))
+            .map(|(&id, _)| id)
+            .ok_or(Errno::EINVAL)?;
+        let segment = self.segments.get_mut(&id).unwrap();
+        segment
+            .attachments
+            .retain(|&attachment| attachment != (pid, address));
+        vmm.munmap(pid, address)?;
+        self.destroy_if_unused(vmm, id);
+        Ok(())
+    }
+
+    /**
+     * Detach every segment the process attached, done when it terminates.
+     */
+    pub fn detach_all(&mut self, vmm: &mut Vmm, pid: u32) {
+        let addresses: Vec<u64> = self
+            .segments
+            .values()
+            .flat_map(|segment| segment.attachments.iter())
+            .filter(|&&(attached, _)| attached == pid)
+            .map(|&(_, address)| address)
+            .collect();
+        addresses.into_iter().for_each(|address| {
+            self.shm_detach(vmm, pid, address).ok();
+        });
+    }
+
+    /**
+     * Remove the name, the memory is freed once the last process detaches.
+     */
+    pub fn shm_unlink(&mut self, vmm: &mut Vmm, name: &str) -> Result<(), Errno> {
+        let id = self
+            .segments
+            .iter()
+            .find(|(_, segment)| segment.name == name && !segment.unlinked)
+            .map(|(&id, _)| id)
+            .ok_or(Errno::ENOENT)?;
+        self.segments.get_mut(&id).unwrap().unlinked = true;
+        self.destroy_if_unused(vmm, id);
+        Ok(())
+    }
+
+    fn destroy_if_unused(&mut self, vmm: &mut Vmm, id: u32) {
+        let unused = self
+            .segments
+            .get(&id)
+            .is_some_and(|segment| segment.unlinked && segment.attachments.is_empty());
+        if unused {
+            let segment = self.segments.remove(&id).unwrap();
+            vmm.deallocate_page(segment.pages);
+        }
+    }
+
+    /**
+     * Create an anonymous pipe with one read and one write end open.
+     */
+    pub fn pipe(&mut self, vmm: &mut Vmm) -> Result<u32, Errno> {
+        self.create_pipe(vmm, None, 1)
+    }
+
+    /**
+     * Create the pipe behind a FIFO node, it starts with no end open.
+     */
+    pub fn mkfifo(&mut self, vmm: &mut Vmm, name: &str) -> Result<u32, Errno> {
+        self.create_pipe(vmm, Some(name.to_string()), 0)
+    }
+
+    fn create_pipe(
+        &mut self,
+        vmm: &mut Vmm,
+        name: Option<String>,
+        ends: u32,
+    ) -> Result<u32, Errno> {
+        let id = self.next_pipe_id;
+        self.next_pipe_id += 1;
+        let (page, capacity) = vmm.allocate_page()?;
+        vmm.set_owner(&[page], PageOwner::Pipe(id));
+        self.pipes.insert(
+            id,
+            Pipe {
+                name,
+                page,
+                capacity,
+                head: 0,
+                len: 0,
+                readers: ends,
+                writers: ends,
+                opened: [ends as u64; 2],
+                unlinked: false,
+            },
+        );
+        Ok(id)
+    }
+
+    fn fifo_mut(&mut self, id: u32) -> Result<&mut Pipe, Errno> {
+        self.pipes
+            .get_mut(&id)
+            .filter(|pipe| pipe.name.is_some() && !pipe.unlinked)
+            .ok_or(Errno::ENOENT)
+    }
+
+    /**
+     * Close an end. An anonymous pipe is freed with its last end, a FIFO
+     * once it is also unlinked.
+     */
+    pub fn pipe_close(&mut self, vmm: &mut Vmm, id: u32, write: bool) {
+        if let Some(pipe) = self.pipes.get_mut(&id) {
+            let ends = if write {
+                &mut pipe.writers
+            } else {
+                &mut pipe.readers
+            };
+            *ends = ends.saturating_sub(1);
+        }
+        IPC_CHANGED.notify_all();
+        self.destroy_pipe_if_unused(vmm, id);
+    }
+
+    pub fn fifo_unlink(&mut self, vmm: &mut Vmm, id: u32) {
+        if let Some(pipe) = self.pipes.get_mut(&id) {
+            pipe.unlinked = true;
+        }
+        self.destroy_pipe_if_unused(vmm, id);
+    }
+
+    fn destroy_pipe_if_unused(&mut self, vmm: &mut Vmm, id: u32) {
+        let unused = self.pipes.get(&id).is_some_and(|pipe| {
+            pipe.readers == 0 && pipe.writers == 0 && (pipe.name.is_none() || pipe.unlinked)
+        });
+        if unused {
+            let pipe = self.pipes.remove(&id).unwrap();
+            vmm.deallocate_page(vec![pipe.page]);
+        }
+    }
+
+    /**
+     * Open the queue with the given name, creating it if it does not exist.
+     */
+    pub fn mq_open(
+        &mut self,
+        name: &str,
+        max_messages: usize,
+        max_size: u64,
+    ) -> Result<u32, Errno> {
+        if let Some((&id, _)) = self.queues.iter().find(|(_, queue)| queue.name == name) {
+            return Ok(id);
+        }
+        if max_messages == 0 || max_size == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let id = self.next_queue_id;
+        self.next_queue_id += 1;
+        self.queues.insert(
+            id,
+            MessageQueue {
+                name: name.to_string(),
+                messages: VecDeque::new(),
+                max_messages,
+                max_size,
+            },
+        );
+        Ok(id)
+    }
+
+    /**
+     * Destroy the queue and its pending messages, blocked processes get EBADF.
+     */
+    pub fn mq_unlink(&mut self, vmm: &mut Vmm, name: &str) -> Result<(), Errno> {
+        let id = self
+            .queues
+            .iter()
+            .find(|(_, queue)| queue.name == name)
+            .map(|(&id, _)| id)
+            .ok_or(Errno::ENOENT)?;
+        let queue = self.queues.remove(&id).unwrap();
+        queue
+            .messages
+            .into_iter()
+            .for_each(|message| vmm.deallocate_page(message.pages));
+        IPC_CHANGED.notify_all();
+        Ok(())
+    }
+
+    pub fn pipes(&self) -> Vec<PipeInfo> {
+        let mut pipes: Vec<PipeInfo> = self
+            .pipes
+            .iter()
+            .map(|(&id, pipe)| PipeInfo {
+                id,
+                name: pipe.name.clone(),
+                buffered: pipe.len,
+                capacity: pipe.capacity,
+                readers: pipe.readers,
+                writers: pipe.writers,
+            })
+            .collect();
+        pipes.sort_by_key(|pipe| pipe.id);
+        pipes
+    }
+
+    pub fn message_queues(&self) -> Vec<MessageQueueInfo> {
+        let mut queues: Vec<MessageQueueInfo> = self
+            .queues
+            .iter()
+            .map(|(&id, queue)| MessageQueueInfo {
+                id,
+                name: queue.name.clone(),
+                messages: queue.messages.len(),
+                bytes: queue.messages.iter().map(|message| message.size).sum(),
+                max_messages: queue.max_messages,
+                max_size: queue.max_size,
+            })
+            .collect();
+        queues.sort_by_key(|queue| queue.id);
+        queues
+    }
+
+    pub fn shm_segments(&self) -> Vec<ShmInfo> {
+        let mut segments: Vec<ShmInfo> = self
+            .segments
+            .iter()
+            .map(|(&id, segment)| ShmInfo {
+                id,
+                name: segment.name.clone(),
+                size: segment.size,
+                pages: segment.pages.len(),
+                attached: segment.attachments.len(),
+                unlinked: segment.unlinked,
+            })
+            .collect();
+        segments.sort_by_key(|segment| segment.id);
+        segments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmm::PageSize;
+    use crate::vpm::ProcessState;
+    use std::{
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    fn process() -> Vpm {
+        let vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        Vpm::new(Arc::new(Mutex::new(vmm)))
+    }
+
+    /**
+     * A second process on the memory of the first one.
+     */
+    fn peer(process: &Vpm) -> Vpm {
+        Vpm::new(Arc::clone(&process.vmm))
+    }
+
+    fn pipe(process: &Vpm) -> u32 {
+        IPC.lock()
+            .unwrap()
+            .pipe(&mut process.vmm.lock().unwrap())
+            .unwrap()
+    }
+
+    fn pipe_info(id: u32) -> PipeInfo {
+        let ipc = IPC.lock().unwrap();
+        ipc.pipes().into_iter().find(|pipe| pipe.id == id).unwrap()
+    }
+
+    fn wait_blocked(pid: u32) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while vpm::process(pid).unwrap().state != ProcessState::Blocked {
+            assert!(Instant::now() < deadline, "{pid} never blocked");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn shared_memory_is_seen_by_every_process() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        let mut ipc = Ipc::default();
+        vmm.create_address_space(1).unwrap();
+        vmm.create_address_space(2).unwrap();
+        vmm.load_segment(2, b"code", false).unwrap();
+
+        let id = ipc.shm_open(&mut vmm, "segment", 3 * 4096).unwrap();
+        let first = ipc.shm_attach(&mut vmm, 1, id).unwrap();
+        let second = ipc.shm_attach(&mut vmm, 2, id).unwrap();
+        assert_ne!(first, second);
+
+        vmm.write_bytes(1, first + 4096 + 10, b"hello").unwrap();
+        assert_eq!(vmm.read_bytes(2, second + 4096 + 10, 5).unwrap(), b"hello");
+        vmm.write_bytes(2, second + 2 * 4096, b"back").unwrap();
+        assert_eq!(vmm.read_bytes(1, first + 2 * 4096, 4).unwrap(), b"back");
+
+        ipc.shm_detach(&mut vmm, 1, first).unwrap();
+        assert_eq!(vmm.read_bytes(1, first, 1), Err(Errno::EFAULT));
+        assert_eq!(vmm.read_bytes(2, second + 4096 + 10, 5).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn segment_larger_than_memory_is_refused() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        let mut ipc = Ipc::default();
+        let free_memory = vmm.free_memory;
+
+        assert_eq!(ipc.shm_open(&mut vmm, "huge", u64::MAX), Err(Errno::ENOMEM));
+        assert_eq!(ipc.shm_open(&mut vmm, "all", 1 << 24), Err(Errno::ENOMEM));
+        assert_eq!(vmm.free_memory, free_memory);
+        assert!(ipc.segments.is_empty());
+        assert!(ipc.shm_open(&mut vmm, "small", 4096).is_ok());
+    }
+
+    #[test]
+    fn read_returns_end_of_file_once_writers_are_gone() {
+        let process = process();
+        let id = pipe(&process);
+        assert_eq!(pipe_write(&process, id, b"abc"), Ok(3));
+        IPC.lock()
+            .unwrap()
+            .pipe_close(&mut process.vmm.lock().unwrap(), id, true);
+
+        assert_eq!(pipe_read(&process, id, 16).unwrap(), b"abc");
+        assert_eq!(pipe_read(&process, id, 16).unwrap(), b"");
+    }
+
+    #[test]
+    fn write_fails_with_epipe_once_readers_are_gone() {
+        let process = process();
+        let id = pipe(&process);
+        IPC.lock()
+            .unwrap()
+            .pipe_close(&mut process.vmm.lock().unwrap(), id, false);
+
+        assert_eq!(pipe_write(&process, id, b"abc"), Err(Errno::EPIPE));
+    }
+
+    #[test]
+    fn writer_blocks_while_the_pipe_is_full() {
+        let reader = process();
+        let writer = peer(&reader);
+        let id = pipe(&reader);
+        let capacity = pipe_info(id).capacity as usize;
+        let bytes: Vec<u8> = (0..capacity + 10).map(|i| i as u8).collect();
+
+        let blocked = writer.clone();
+        let expected = bytes.clone();
+        let handle = thread::spawn(move || pipe_write(&blocked, id, &expected));
+        wait_blocked(writer.pid);
+        assert_eq!(pipe_info(id).buffered, capacity as u64);
+
+        assert_eq!(pipe_read(&reader, id, capacity).unwrap(), bytes[..capacity]);
+        assert_eq!(handle.join().unwrap(), Ok(capacity + 10));
+        assert_eq!(pipe_read(&reader, id, capacity).unwrap(), bytes[capacity..]);
+    }
+
+    #[test]
+    fn interrupted_write_returns_the_bytes_written() {
+        let reader = process();
+        let writer = peer(&reader);
+        let id = pipe(&reader);
+        let capacity = pipe_info(id).capacity as usize;
+
+        let blocked = writer.clone();
+        let handle = thread::spawn(move || pipe_write(&blocked, id, &vec![1; capacity + 10]));
+        wait_blocked(writer.pid);
+        writer.exit(0);
+        assert_eq!(handle.join().unwrap(), Ok(capacity));
+    }
+
+    #[test]
+    fn fifo_open_waits_for_the_other_end() {
+        let reader = process();
+        let writer = peer(&reader);
+        let id = IPC
+            .lock()
+            .unwrap()
+            .mkfifo(&mut reader.vmm.lock().unwrap(), "rendezvous")
+            .unwrap();
+
+        let opening = reader.clone();
+        let handle = thread::spawn(move || fifo_open(&opening, id, false));
+        wait_blocked(reader.pid);
+        assert_eq!(pipe_info(id).readers, 1);
+
+        assert_eq!(fifo_open(&writer, id, true), Ok(()));
+        assert_eq!(handle.join().unwrap(), Ok(()));
+        assert_eq!(pipe_write(&writer, id, b"hi"), Ok(2));
+        assert_eq!(pipe_read(&reader, id, 16).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn messages_are_received_by_priority() {
+        let process = process();
+        let id = IPC.lock().unwrap().mq_open("priorities", 8, 64).unwrap();
+        for (message, priority) in [("a", 1), ("b", 5), ("c", 5), ("d", 0)] {
+            mq_send(&process, id, message.as_bytes(), priority).unwrap();
+        }
+
+        let received: Vec<(Vec<u8>, u32)> =
+            (0..4).map(|_| mq_receive(&process, id).unwrap()).collect();
+        let expected = [("b", 5), ("c", 5), ("a", 1), ("d", 0)]
+            .map(|(message, priority)| (message.as_bytes().to_vec(), priority));
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn receive_blocks_while_the_queue_is_empty() {
+        let receiver = process();
+        let sender = peer(&receiver);
+        let id = IPC.lock().unwrap().mq_open("empty", 8, 64).unwrap();
+
+        let blocked = receiver.clone();
+        let handle = thread::spawn(move || mq_receive(&blocked, id));
+        wait_blocked(receiver.pid);
+
+        mq_send(&sender, id, b"wake up", 3).unwrap();
+        assert_eq!(handle.join().unwrap(), Ok((b"wake up".to_vec(), 3)));
+    }
+
+    #[test]
+    fn message_larger_than_the_queue_allows_is_refused() {
+        let process = process();
+        let id = IPC.lock().unwrap().mq_open("small", 8, 64).unwrap();
+        assert_eq!(mq_send(&process, id, &[0; 65], 0), Err(Errno::EMSGSIZE));
+        assert_eq!(mq_send(&process, id, &[0; 64], 0), Ok(()));
+    }
+}
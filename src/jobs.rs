@@ -0,0 +1,276 @@
+/**
+ * Shell job control
+ *
+ * A job is a command the shell runs in its own process, launched in the
+ * background with & or suspended with Ctrl-Z.
+ */
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+use crate::signal::Signal;
+use crate::syscall::{self, Value};
+use crate::vpm::{self, ProcessState, Vpm};
+
+lazy_static! {
+    pub static ref JOBS: Mutex<JobTable> = Mutex::new(JobTable::default());
+}
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: usize,
+    pub pid: u32,
+    pub cmdline: String,
+}
+
+#[derive(Debug, Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+}
+
+/**
+ * Status of a finished job, the way shells report it.
+ */
+pub fn exit_status(code: i32) -> String {
+    match Signal::from_exit_code(code) {
+        Some(signal) => signal.description().to_string(),
+        None if code == 0 => "Done".to_string(),
+        None => format!("Exit {}", code),
+    }
+}
+
+impl JobTable {
+    pub fn add(&mut self, pid: u32, cmdline: &str) -> usize {
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        self.jobs.push(Job {
+            id,
+            pid,
+            cmdline: cmdline.to_string(),
+        });
+        id
+    }
+
+    pub fn remove(&mut self, pid: u32) {
+        self.jobs.retain(|job| job.pid != pid);
+    }
+
+    /**
+     * Find a job from a %n or n spec, %+ or an empty spec being the current
+     * job and %- the previous one.
+     */
+    pub fn get(&self, spec: &str) -> Option<Job> {
+        let back = match spec {
+            "" | "%+" | "%%" => 1,
+            "%-" => 2,
+            spec => {
+                let id = spec.trim_start_matches('%').parse::<usize>().ok()?;
+                return self.jobs.iter().find(|job| job.id == id).cloned();
+            }
+        };
+        let position = self.jobs.len().checked_sub(back)?;
+        self.jobs.get(position).cloned()
+    }
+
+    pub fn jobs(&self) -> Vec<Job> {
+        self.jobs.clone()
+    }
+
+    /**
+     * '+' marks the current job, the one fg and bg default to, '-' the previous.
+     */
+    pub fn marker(&self, id: usize) -> char {
+        let position = self.jobs.iter().position(|job| job.id == id);
+        match position.map(|position| self.jobs.len() - position) {
+            Some(1) => '+',
+            Some(2) => '-',
+            _ => ' ',
+        }
+    }
+
+    /**
+     * Reap the finished jobs of the shell, returning them with their status.
+     */
+    pub fn reap(&mut self, shell: &Vpm) -> Vec<(Job, char, String)> {
+        let mut finished = Vec::new();
+        for job in self.jobs.clone() {
+            let args = [Value::Int(job.pid as i64), Value::Int(syscall::WNOHANG)];
+            let status = match syscall::syscall(shell, syscall::SYS_WAIT4, &args) {
+                Ok(Value::List(status)) => match status.as_slice() {
+                    [Value::Int(0), ..] => continue,
+                    [_, Value::Int(code), _] => exit_status(*code as i32),
+                    _ => continue,
+                },
+                // Already reaped by wait
+                _ => exit_status(0),
+            };
+            let marker = self.marker(job.id);
+            finished.push((job, marker, status));
+        }
+        finished.iter().for_each(|(job, _, _)| self.remove(job.pid));
+        finished
+    }
+
+    /**
+     * Status of a job still in the table.
+     */
+    pub fn status(job: &Job) -> &'static str {
+        match vpm::process(job.pid).map(|process| process.state) {
+            Some(ProcessState::Stopped) => "Stopped",
+            Some(ProcessState::Zombie) | None => "Done",
+            Some(_) => "Running",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmm::{PageSize, Vmm};
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    fn shell() -> Vpm {
+        let vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        Vpm::new(Arc::new(Mutex::new(vmm)))
+    }
+
+    /**
+     * A child of the shell running until it is stopped or terminated.
+     */
+    fn spin(shell: &mut Vpm) -> u32 {
+        shell
+            .execute_child("spin", |process| {
+                while process.yield_cpu() {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                0
+            })
+            .unwrap()
+    }
+
+    /**
+     * Wait for the jobs to finish, then reap every finished job and return
+     * their pids, markers and statuses.
+     */
+    fn reap(jobs: &mut JobTable, shell: &Vpm, pids: &[u32]) -> Vec<(u32, char, String)> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let finished = |pid| vpm::process(pid).unwrap().state == ProcessState::Zombie;
+        while !pids.iter().all(|&pid| finished(pid)) {
+            assert!(Instant::now() < deadline, "jobs {pids:?} never finished");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        jobs.reap(shell)
+            .into_iter()
+            .map(|(job, marker, status)| (job.pid, marker, status))
+            .collect()
+    }
+
+    #[test]
+    fn specs_find_jobs() {
+        let mut jobs = JobTable::default();
+        assert!(jobs.get("").is_none());
+        assert!(jobs.get("%-").is_none());
+        (0..3).for_each(|index| {
+            jobs.add(100 + index, &format!("job {}", index));
+        });
+
+        assert_eq!(jobs.get("%2").unwrap().pid, 101);
+        assert_eq!(jobs.get("2").unwrap().pid, 101);
+        assert_eq!(jobs.get("").unwrap().pid, 102);
+        assert_eq!(jobs.get("%+").unwrap().pid, 102);
+        assert_eq!(jobs.get("%%").unwrap().pid, 102);
+        assert_eq!(jobs.get("%-").unwrap().pid, 101);
+        assert!(jobs.get("%4").is_none());
+        assert!(jobs.get("%x").is_none());
+    }
+
+    #[test]
+    fn markers_follow_removals() {
+        let mut jobs = JobTable::default();
+        let ids: Vec<usize> = (0..3).map(|index| jobs.add(100 + index, "job")).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(
+            ids.iter().map(|&id| jobs.marker(id)).collect::<String>(),
+            " -+"
+        );
+
+        jobs.remove(102);
+        assert_eq!(jobs.marker(2), '+');
+        assert_eq!(jobs.marker(1), '-');
+        assert_eq!(jobs.get("%-").unwrap().id, 1);
+
+        // Ids are reused once the highest job is gone
+        assert_eq!(jobs.add(103, "job"), 3);
+        jobs.remove(101);
+        assert_eq!(jobs.marker(3), '+');
+        assert_eq!(jobs.marker(1), '-');
+        assert_eq!(jobs.marker(2), ' ');
+    }
+
+    #[test]
+    fn reap_reports_exit_codes_and_signals() {
+        let mut shell = shell();
+        let mut jobs = JobTable::default();
+        let done = shell.execute_child("true", |_| 0).unwrap();
+        let failed = shell.execute_child("false", |_| 3).unwrap();
+        let killed = spin(&mut shell);
+        let running = spin(&mut shell);
+        [done, failed, killed, running].iter().for_each(|&pid| {
+            jobs.add(pid, "job");
+        });
+
+        assert_eq!(
+            reap(&mut jobs, &shell, &[done, failed]),
+            vec![
+                (done, ' ', "Done".to_string()),
+                (failed, ' ', "Exit 3".to_string())
+            ]
+        );
+        shell.kill(killed, Signal::SIGKILL).unwrap();
+        assert_eq!(
+            reap(&mut jobs, &shell, &[killed]),
+            vec![(killed, '-', "Killed".to_string())]
+        );
+        assert_eq!(jobs.jobs().len(), 1);
+        assert_eq!(JobTable::status(&jobs.get("%+").unwrap()), "Running");
+
+        shell.kill(running, Signal::SIGTERM).unwrap();
+        assert_eq!(
+            reap(&mut jobs, &shell, &[running]),
+            vec![(running, '+', "Terminated".to_string())]
+        );
+    }
+
+    #[test]
+    fn stopped_job_continues_in_the_background() {
+        let mut shell = shell();
+        let mut jobs = JobTable::default();
+        let pid = spin(&mut shell);
+
+        // Ctrl-Z: the shell gets its prompt back and keeps the job
+        shell.kill(pid, Signal::SIGTSTP).unwrap();
+        assert_eq!(shell.waitpid_untraced(pid), Ok(None));
+        let id = jobs.add(pid, "spin");
+        let job = jobs.get("%+").unwrap();
+        assert_eq!((job.id, job.pid), (id, pid));
+        assert_eq!(JobTable::status(&job), "Stopped");
+        assert!(jobs.reap(&shell).is_empty());
+
+        // bg
+        shell.kill(pid, Signal::SIGCONT).unwrap();
+        assert_eq!(JobTable::status(&job), "Running");
+
+        // Stopped again, then fg waits for it to finish
+        shell.kill(pid, Signal::SIGTSTP).unwrap();
+        assert_eq!(shell.waitpid_untraced(pid), Ok(None));
+        shell.kill(pid, Signal::SIGCONT).unwrap();
+        shell.kill(pid, Signal::SIGINT).unwrap();
+        assert_eq!(
+            shell.waitpid_untraced(pid),
+            Ok(Some(Signal::SIGINT.exit_code()))
+        );
+        jobs.remove(pid);
+        assert!(jobs.get("%+").is_none());
+    }
+}
@@ -1,6 +1,24 @@
-use tokio;
+mod asm;
+mod cpu;
+mod disasm;
 mod editor;
+mod errno;
+mod exec;
+mod fd;
+mod heap;
+mod hexdump;
+mod highlight;
+mod ipc;
+mod jobs;
+mod paging;
+mod programs;
+mod ps;
+mod scheduler;
 mod shell;
+mod signal;
+mod sync;
+mod syscall;
+mod top;
 mod utils;
 mod vfs;
 mod vmm;
@@ -0,0 +1,497 @@
+/**
+ * x86-64 like paging structures
+ *
+ * A virtual page number is split in four 9 bits indexes walking the
+ * PML4 -> PDPT -> PD -> PT tables. Every table lives in a frame handed out
+ * by the Vmm, identified by the frame physical address. Huge pages are
+ * entries of the PD with the PS flag, mapping 512 pages at once.
+ */
+use std::collections::{HashMap, VecDeque};
+
+use crate::vmm::PageTableEntry;
+
+const LEVELS: u32 = 4;
+const INDEX_BITS: u32 = 9;
+const INDEX_MASK: u64 = (1 << INDEX_BITS) - 1;
+const DEFAULT_TLB_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone)]
+enum TableSlot {
+    Table(u64),
+    Page(PageTableEntry),
+}
+
+#[derive(Debug, Clone, Default)]
+struct Table {
+    slots: HashMap<u64, TableSlot>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PageTable {
+    root: u64,
+    tables: HashMap<u64, Table>,
+}
+
+/**
+ * Base pages mapped by a PD entry with the PS flag set, a huge page.
+ */
+pub const HUGE_PAGE_SPAN: u64 = 1 << INDEX_BITS;
+
+/**
+ * Index of the virtual page number in the table of the given level, 4 being PML4.
+ */
+fn table_index(virtual_address: u64, level: u32) -> u64 {
+    (virtual_address >> (INDEX_BITS * (level - 1))) & INDEX_MASK
+}
+
+/**
+ * Level of the table holding the entry: the PT, or the PD for a huge page.
+ */
+fn leaf_level(page: &PageTableEntry) -> u32 {
+    if page.is_huge() {
+        2
+    } else {
+        1
+    }
+}
+
+impl PageTable {
+    pub fn new(root: u64) -> Self {
+        let mut tables = HashMap::new();
+        tables.insert(root, Table::default());
+        Self { root, tables }
+    }
+
+    /**
+     * Physical addresses of the frames holding the tables, root included.
+     */
+    pub fn table_frames(&self) -> Vec<u64> {
+        self.tables.keys().copied().collect()
+    }
+
+    /**
+     * Table holding the entry that maps the virtual page number and the
+     * level of that table, 2 for a huge page in the PD. None if the walk fails.
+     */
+    fn walk(&self, virtual_address: u64) -> Option<(u64, u32)> {
+        let mut table = self.root;
+        for level in (1..=LEVELS).rev() {
+            match self.tables[&table]
+                .slots
+                .get(&table_index(virtual_address, level))
+            {
+                Some(TableSlot::Table(next)) if level > 1 => table = *next,
+                Some(TableSlot::Page(_)) => return Some((table, level)),
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    /**
+     * Entry mapping the virtual page number, a huge page covers every page
+     * of its PD slot.
+     */
+    pub fn get(&self, virtual_address: &u64) -> Option<&PageTableEntry> {
+        let (table, level) = self.walk(*virtual_address)?;
+        match self.tables[&table]
+            .slots
+            .get(&table_index(*virtual_address, level))
+        {
+            Some(TableSlot::Page(page)) => Some(page),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, virtual_address: &u64) -> Option<&mut PageTableEntry> {
+        let (table, level) = self.walk(*virtual_address)?;
+        match self
+            .tables
+            .get_mut(&table)?
+            .slots
+            .get_mut(&table_index(*virtual_address, level))
+        {
+            Some(TableSlot::Page(page)) => Some(page),
+            _ => None,
+        }
+    }
+
+    pub fn contains_key(&self, virtual_address: &u64) -> bool {
+        self.get(virtual_address).is_some()
+    }
+
+    /**
+     * Number of tables that must be allocated before inserting the entry.
+     */
+    pub fn missing_tables(&self, page: &PageTableEntry) -> usize {
+        let leaf = leaf_level(page);
+        let mut table = self.root;
+        for level in (leaf + 1..=LEVELS).rev() {
+            match self.tables[&table]
+                .slots
+                .get(&table_index(page.virtual_address(), level))
+            {
+                Some(TableSlot::Table(next)) => table = *next,
+                _ => return (level - leaf) as usize,
+            }
+        }
+        0
+    }
+
+    /**
+     * Insert the entry, building missing tables in the given frames.
+     * new_tables must hold at least missing_tables() frames. Huge pages go
+     * in the PD and their virtual address must be aligned on HUGE_PAGE_SPAN.
+     */
+    pub fn insert(&mut self, page: PageTableEntry, new_tables: Vec<u64>) {
+        let virtual_address = page.virtual_address();
+        let leaf = leaf_level(&page);
+        let mut new_tables = new_tables.into_iter();
+        let mut table = self.root;
+        for level in (leaf + 1..=LEVELS).rev() {
+            let index = table_index(virtual_address, level);
+            let next = match self.tables[&table].slots.get(&index) {
+                Some(TableSlot::Table(next)) => *next,
+                _ => {
+                    let next = new_tables.next().expect("Missing page table frame");
+                    self.tables.insert(next, Table::default());
+                    self.tables
+                        .get_mut(&table)
+                        .unwrap()
+                        .slots
+                        .insert(index, TableSlot::Table(next));
+                    next
+                }
+            };
+            table = next;
+        }
+        self.tables
+            .get_mut(&table)
+            .unwrap()
+            .slots
+            .insert(table_index(virtual_address, leaf), TableSlot::Page(page));
+    }
+
+    /**
+     * Remove the entry mapping the virtual page number and the tables left
+     * empty, returning the entry and the frames of the freed tables.
+     */
+    pub fn remove(&mut self, virtual_address: &u64) -> (Option<PageTableEntry>, Vec<u64>) {
+        let mut path = vec![self.root];
+        let mut leaf = 1;
+        for level in (2..=LEVELS).rev() {
+            let table = *path.last().unwrap();
+            match self.tables[&table]
+                .slots
+                .get(&table_index(*virtual_address, level))
+            {
+                Some(TableSlot::Table(next)) => path.push(*next),
+                Some(TableSlot::Page(_)) => {
+                    leaf = level;
+                    break;
+                }
+                None => return (None, Vec::new()),
+            }
+        }
+
+        let table = *path.last().unwrap();
+        let page = match self
+            .tables
+            .get_mut(&table)
+            .unwrap()
+            .slots
+            .remove(&table_index(*virtual_address, leaf))
+        {
+            Some(TableSlot::Page(page)) => page,
+            _ => return (None, Vec::new()),
+        };
+
+        let mut freed = Vec::new();
+        for (depth, table) in path.iter().enumerate().skip(1).rev() {
+            if !self.tables[table].slots.is_empty() {
+                break;
+            }
+            self.tables.remove(table);
+            freed.push(*table);
+            let parent = path[depth - 1];
+            let level = LEVELS - (depth as u32 - 1);
+            self.tables
+                .get_mut(&parent)
+                .unwrap()
+                .slots
+                .remove(&table_index(*virtual_address, level));
+        }
+
+        (Some(page), freed)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &PageTableEntry> {
+        self.tables
+            .values()
+            .flat_map(|table| table.slots.values())
+            .filter_map(|slot| match slot {
+                TableSlot::Page(page) => Some(page),
+                TableSlot::Table(_) => None,
+            })
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut PageTableEntry> {
+        self.tables
+            .values_mut()
+            .flat_map(|table| table.slots.values_mut())
+            .filter_map(|slot| match slot {
+                TableSlot::Page(page) => Some(page),
+                TableSlot::Table(_) => None,
+            })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = u64> + '_ {
+        self.values().map(|page| page.virtual_address())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TlbEntry {
+    space: u32,
+    virtual_address: u64,
+    physical_address: u64,
+    global: bool,
+}
+
+/**
+ * Translation lookaside buffer caching virtual to physical translations.
+ * Global entries (kernel pages) survive the flush done on context switch.
+ */
+#[derive(Debug, Clone)]
+pub struct Tlb {
+    capacity: usize,
+    entries: VecDeque<TlbEntry>,
+    pub hits: u64,
+    pub misses: u64,
+    pub flushes: u64,
+    pub evictions: u64,
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new(DEFAULT_TLB_CAPACITY)
+    }
+}
+
+impl Tlb {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+            flushes: 0,
+            evictions: 0,
+        }
+    }
+
+    /**
+     * Look the translation up, moving a hit to the most recently used slot.
+     */
+    pub fn lookup(&mut self, space: u32, virtual_address: u64) -> Option<u64> {
+        let position = self
+            .entries
+            .iter()
+            .position(|e| e.space == space && e.virtual_address == virtual_address);
+        match position {
+            Some(position) => {
+                self.hits += 1;
+                let entry = self.entries.remove(position).unwrap();
+                let physical_address = entry.physical_address;
+                self.entries.push_back(entry);
+                Some(physical_address)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        space: u32,
+        virtual_address: u64,
+        physical_address: u64,
+        global: bool,
+    ) {
+        self.invlpg(space, virtual_address);
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+            self.evictions += 1;
+        }
+        self.entries.push_back(TlbEntry {
+            space,
+            virtual_address,
+            physical_address,
+            global,
+        });
+    }
+
+    /**
+     * Invalidate the cached translation of a single page.
+     */
+    pub fn invlpg(&mut self, space: u32, virtual_address: u64) {
+        self.entries
+            .retain(|e| !(e.space == space && e.virtual_address == virtual_address));
+    }
+
+    /**
+     * Drop every non global translation, done on context switch.
+     */
+    pub fn flush(&mut self) {
+        self.flushes += 1;
+        self.entries.retain(|e| e.global);
+    }
+
+    /**
+     * Drop every translation of the address space, global ones included.
+     */
+    pub fn flush_space(&mut self, space: u32) {
+        self.entries.retain(|e| e.space != space);
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64 * 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: u64 = 0x1000;
+
+    /**
+     * Virtual page number with the given index in the PML4, PDPT, PD and PT.
+     */
+    fn page_number(pml4: u64, pdpt: u64, pd: u64, pt: u64) -> u64 {
+        (pml4 << 27) | (pdpt << 18) | (pd << 9) | pt
+    }
+
+    fn sorted(mut frames: Vec<u64>) -> Vec<u64> {
+        frames.sort();
+        frames
+    }
+
+    #[test]
+    fn walk_goes_through_four_levels() {
+        let mut table = PageTable::new(ROOT);
+        let address = page_number(1, 2, 3, 4);
+        let page = PageTableEntry::new(address, 0x9000, false);
+        assert_eq!(table.missing_tables(&page), 3);
+        table.insert(page, vec![0x2000, 0x3000, 0x4000]);
+
+        assert_eq!(table.walk(address), Some((0x4000, 1)));
+        assert_eq!(table.get(&address).unwrap().virtual_address(), address);
+        assert!(!table.contains_key(&page_number(1, 2, 3, 5)));
+        assert!(!table.contains_key(&page_number(2, 2, 3, 4)));
+        assert_eq!(
+            sorted(table.table_frames()),
+            vec![ROOT, 0x2000, 0x3000, 0x4000]
+        );
+
+        // Only the levels the walk has not reached yet need a table
+        let neighbour = PageTableEntry::new(page_number(1, 2, 3, 5), 0xa000, false);
+        assert_eq!(table.missing_tables(&neighbour), 0);
+        let other_pt = PageTableEntry::new(page_number(1, 2, 4, 0), 0xa000, false);
+        assert_eq!(table.missing_tables(&other_pt), 1);
+        let other_pd = PageTableEntry::new(page_number(1, 3, 0, 0), 0xa000, false);
+        assert_eq!(table.missing_tables(&other_pd), 2);
+        let other_pml4 = PageTableEntry::new(page_number(2, 0, 0, 0), 0xa000, false);
+        assert_eq!(table.missing_tables(&other_pml4), 3);
+    }
+
+    #[test]
+    fn huge_page_lives_in_the_pd() {
+        let mut table = PageTable::new(ROOT);
+        let address = page_number(0, 1, 2, 0);
+        let page = PageTableEntry::new(address, 0x200000, true);
+        assert_eq!(table.missing_tables(&page), 2);
+        table.insert(page, vec![0x2000, 0x3000]);
+
+        assert_eq!(table.walk(address + HUGE_PAGE_SPAN - 1), Some((0x3000, 2)));
+        assert_eq!(
+            table.get(&(address + 7)).unwrap().virtual_address(),
+            address
+        );
+        assert!(!table.contains_key(&(address + HUGE_PAGE_SPAN)));
+    }
+
+    #[test]
+    fn empty_tables_are_freed() {
+        let mut table = PageTable::new(ROOT);
+        let first = page_number(0, 0, 0, 1);
+        let second = page_number(0, 0, 1, 1);
+        table.insert(
+            PageTableEntry::new(first, 0x9000, false),
+            vec![0x2000, 0x3000, 0x4000],
+        );
+        table.insert(PageTableEntry::new(second, 0xa000, false), vec![0x5000]);
+
+        // The PD still maps the second page, only the PT of the first goes
+        let (page, freed) = table.remove(&first);
+        assert_eq!(page.unwrap().virtual_address(), first);
+        assert_eq!(freed, vec![0x4000]);
+        assert_eq!(table.remove(&first).1, Vec::<u64>::new());
+
+        let (page, freed) = table.remove(&second);
+        assert_eq!(page.unwrap().virtual_address(), second);
+        assert_eq!(sorted(freed), vec![0x2000, 0x3000, 0x5000]);
+        assert_eq!(table.table_frames(), vec![ROOT]);
+        assert_eq!(table.values().count(), 0);
+    }
+
+    #[test]
+    fn tlb_counts_hits_and_misses() {
+        let mut tlb = Tlb::new(2);
+        assert_eq!(tlb.lookup(1, 10), None);
+        tlb.insert(1, 10, 0x9000, false);
+        assert_eq!(tlb.lookup(1, 10), Some(0x9000));
+        assert_eq!(tlb.lookup(2, 10), None);
+        assert_eq!((tlb.hits, tlb.misses), (1, 2));
+
+        // 10 was used last, 11 is the least recently used entry
+        tlb.insert(1, 11, 0xa000, false);
+        tlb.lookup(1, 10);
+        tlb.insert(1, 12, 0xb000, false);
+        assert_eq!(tlb.evictions, 1);
+        assert_eq!(tlb.lookup(1, 11), None);
+        assert_eq!(tlb.lookup(1, 10), Some(0x9000));
+    }
+
+    #[test]
+    fn flush_keeps_global_entries() {
+        let mut tlb = Tlb::new(4);
+        tlb.insert(0, 10, 0x9000, true);
+        tlb.insert(1, 10, 0xa000, false);
+        tlb.flush();
+        assert_eq!(tlb.flushes, 1);
+        assert_eq!(tlb.lookup(0, 10), Some(0x9000));
+        assert_eq!(tlb.lookup(1, 10), None);
+
+        tlb.flush_space(0);
+        assert_eq!(tlb.lookup(0, 10), None);
+    }
+
+    #[test]
+    fn invlpg_removes_one_entry() {
+        let mut tlb = Tlb::new(4);
+        tlb.insert(1, 10, 0x9000, false);
+        tlb.insert(1, 11, 0xa000, false);
+        tlb.insert(2, 10, 0xb000, false);
+        tlb.invlpg(1, 10);
+        assert_eq!(tlb.lookup(1, 10), None);
+        assert_eq!(tlb.lookup(1, 11), Some(0xa000));
+        assert_eq!(tlb.lookup(2, 10), Some(0xb000));
+    }
+}
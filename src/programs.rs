@@ -0,0 +1,137 @@
+/**
+ * Programs installed in /bin at boot, assembled from their sources.
+ */
+use crate::asm;
+use crate::exec::BIN;
+use crate::syscall::{
+    self, Value, O_CREAT, O_TRUNC, O_WRONLY, SYS_CHDIR, SYS_CHMOD, SYS_CLOSE, SYS_OPEN, SYS_WRITE,
+};
+use crate::vfs::VFS;
+
+/**
+ * Writes the NUL terminated string at r1 to stdout, clobbers r0 to r5.
+ */
+const PUTS: &str = r#"
+puts:
+        mov r2, r1
+        li r3, 0
+        li r5, 0
+puts_length:
+        add r4, r2, r3
+        ldb r4, [r4]
+        beq r4, r5, puts_write
+        addi r3, r3, 1
+        jmp puts_length
+puts_write:
+        li r0, SYS_WRITE
+        li r1, 1
+        syscall
+        ret
+"#;
+
+const HELLO: &str = r#"
+; Greet the world
+        .data
+greeting:
+        .string "Hello from the kernelino CPU!\n"
+
+        .text
+_start:
+        la r1, greeting
+        call puts
+        li r0, 0
+        ret
+"#;
+
+const ECHO: &str = r#"
+; Print the arguments separated by spaces
+        .data
+space:  .string " "
+newline:
+        .string "\n"
+
+        .text
+_start:
+        mov r6, r1              ; argc
+        mov r7, r2              ; argv
+        li r8, 1
+        li r9, 8
+next:
+        bge r8, r6, done
+        mul r10, r8, r9
+        add r10, r7, r10
+        ld r1, [r10]
+        call puts
+        addi r8, r8, 1
+        bge r8, r6, done
+        la r1, space
+        call puts
+        jmp next
+done:
+        la r1, newline
+        call puts
+        li r0, 0
+        ret
+"#;
+
+const SPIN: &str = r#"
+; Burn CPU time until killed
+        .text
+_start:
+        li r1, 0
+loop:
+        addi r1, r1, 1
+        jmp loop
+"#;
+
+const SEGV: &str = r#"
+; Load from an unmapped address
+        .text
+_start:
+        li r1, 0x40000000
+        ld r2, [r1]
+        li r0, 0
+        ret
+"#;
+
+/**
+ * Name and source of each program.
+ */
+const PROGRAMS: [(&str, &str); 4] = [
+    ("hello", HELLO),
+    ("echo", ECHO),
+    ("spin", SPIN),
+    ("segv", SEGV),
+];
+
+/**
+ * Assemble the programs and write them to /bin as executables. Runs at
+ * boot, in the root directory.
+ */
+pub fn install() {
+    let process = VFS.read().unwrap().vpm.clone();
+    let chdir = [Value::Str(BIN.to_string())];
+    if let Err(errno) = syscall::syscall(&process, SYS_CHDIR, &chdir) {
+        println!("Cannot install the programs: {}: {}", BIN, errno);
+        return;
+    }
+    for (name, source) in PROGRAMS {
+        let executable = asm::assemble(&format!("{}{}", source, PUTS))
+            .unwrap_or_else(|errors| panic!("{}: {}", name, errors[0]));
+        let open = [
+            Value::Str(name.to_string()),
+            Value::Int(O_WRONLY | O_CREAT | O_TRUNC),
+        ];
+        let result = syscall::syscall(&process, SYS_OPEN, &open).and_then(|fd| {
+            let write = [fd.clone(), Value::Bytes(executable.to_bytes())];
+            syscall::syscall(&process, SYS_WRITE, &write)?;
+            syscall::syscall(&process, SYS_CLOSE, &[fd])?;
+            let chmod = [Value::Str(format!("{}/{}", BIN, name)), Value::Int(0o755)];
+            syscall::syscall(&process, SYS_CHMOD, &chmod)
+        });
+        if let Err(errno) = result {
+            println!("Cannot install {}: {}", name, errno);
+        }
+    }
+    syscall::syscall(&process, SYS_CHDIR, &[Value::Str("/".to_string())]).ok();
+}
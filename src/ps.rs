@@ -0,0 +1,346 @@
+/**
+ * Non interactive process listings: ps and pstree
+ *
+ * Both render the process table as text so the shell can print it or
+ * redirect it to a file.
+ */
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::syscall::ProcessInfo;
+use crate::vpm;
+
+const DEFAULT_COLUMNS: [&str; 3] = ["pid", "time", "cmd"];
+const FULL_COLUMNS: [&str; 7] = ["pid", "ppid", "s", "ni", "stime", "time", "cmd"];
+
+/**
+ * Columns accepted by ps -o, with their header.
+ */
+const COLUMNS: [(&str, &str); 12] = [
+    ("pid", "PID"),
+    ("ppid", "PPID"),
+    ("s", "S"),
+    ("state", "S"),
+    ("ni", "NI"),
+    ("nice", "NI"),
+    ("pcpu", "%CPU"),
+    ("stime", "STIME"),
+    ("etime", "ELAPSED"),
+    ("time", "TIME"),
+    ("pages", "PAGES"),
+    ("cmd", "CMD"),
+];
+
+/**
+ * Time of day of a timestamp, UTC.
+ */
+fn time_of_day(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    duration(seconds % 86400)
+}
+
+fn duration(seconds: u64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn field(column: &str, process: &ProcessInfo) -> String {
+    let elapsed = process.start_time.elapsed().unwrap_or_default();
+    match column {
+        "pid" => process.pid.to_string(),
+        "ppid" => process.ppid.to_string(),
+        "s" | "state" => process.state.to_string(),
+        "ni" | "nice" => process.nice.to_string(),
+        "pcpu" => {
+            let share = process.cpu_time.as_secs_f64() / elapsed.as_secs_f64().max(0.001);
+            format!("{:.1}", (share * 100.0).min(100.0))
+        }
+        "stime" => time_of_day(process.start_time),
+        "etime" => duration(elapsed.as_secs()),
+        "time" => duration(process.cpu_time.as_secs()),
+        "pages" => process.pages.to_string(),
+        _ => process.cmdline.clone(),
+    }
+}
+
+/**
+ * ps [-e] [-f] [-o col,...]: the shell and its children by default, every
+ * process with -e. Returns the listing or a usage error.
+ */
+pub fn ps(args: &str, shell: u32, processes: &[ProcessInfo]) -> Result<String, String> {
+    let mut all = false;
+    let mut columns: Vec<String> = DEFAULT_COLUMNS.iter().map(|c| c.to_string()).collect();
+    let mut args = args.split_whitespace();
+    while let Some(arg) = args.next() {
+        match arg {
+            "-e" | "-A" => all = true,
+            "-f" => columns = FULL_COLUMNS.iter().map(|c| c.to_string()).collect(),
+            "-ef" | "-fe" => {
+                all = true;
+                columns = FULL_COLUMNS.iter().map(|c| c.to_string()).collect();
+            }
+            "-o" => {
+                let list = args.next().ok_or("ps: option -o requires a column list")?;
+                columns = list.split(',').map(|c| c.to_lowercase()).collect();
+                if let Some(unknown) = columns
+                    .iter()
+                    .find(|c| !COLUMNS.iter().any(|(name, _)| name == c))
+                {
+                    let known: Vec<&str> = COLUMNS.iter().map(|(name, _)| *name).collect();
+                    return Err(format!(
+                        "ps: unknown column {}, known columns: {}",
+                        unknown,
+                        known.join(",")
+                    ));
+                }
+            }
+            _ => return Err("Usage: ps [-e] [-f] [-o column,...]".to_string()),
+        }
+    }
+
+    let processes: Vec<&ProcessInfo> = processes
+        .iter()
+        .filter(|process| all || process.pid == shell || process.ppid == shell)
+        .collect();
+    let mut table: Vec<Vec<String>> = vec![columns
+        .iter()
+        .map(|column| {
+            let (_, header) = COLUMNS.iter().find(|(name, _)| name == column).unwrap();
+            header.to_string()
+        })
+        .collect()];
+    table.extend(processes.iter().map(|process| {
+        columns
+            .iter()
+            .map(|column| field(column, process))
+            .collect::<Vec<String>>()
+    }));
+
+    // Every column is right aligned but the command, always the widest
+    let widths: Vec<usize> = (0..columns.len())
+        .map(|i| table.iter().map(|row| row[i].len()).max().unwrap_or(0))
+        .collect();
+    let lines: Vec<String> = table
+        .iter()
+        .map(|row| {
+            row.iter()
+                .enumerate()
+                .map(|(i, value)| match columns[i].as_str() {
+                    "cmd" if i + 1 == columns.len() => value.clone(),
+                    "cmd" => format!("{:<width$}", value, width = widths[i]),
+                    _ => format!("{:>width$}", value, width = widths[i]),
+                })
+                .collect::<Vec<String>>()
+                .join(" ")
+        })
+        .collect();
+    Ok(lines.join("\n") + "\n")
+}
+
+/**
+ * pstree [-a] [pid]: the process hierarchy rooted at pid, init by default.
+ * Commands are shortened to their name unless -a is given.
+ */
+pub fn pstree(args: &str, processes: &[ProcessInfo]) -> Result<String, String> {
+    let mut arguments = false;
+    let mut root = vpm::INIT_PID;
+    for arg in args.split_whitespace() {
+        match arg {
+            "-a" => arguments = true,
+            pid => root = pid.parse::<u32>().map_err(|_| "Usage: pstree [-a] [pid]")?,
+        }
+    }
+
+    let root = processes
+        .iter()
+        .find(|process| process.pid == root)
+        .ok_or(format!("pstree: no process with pid {}", root))?;
+    let mut lines = Vec::new();
+    render(root, processes, arguments, "", "", &mut lines);
+    Ok(lines.join("\n") + "\n")
+}
+
+fn render(
+    process: &ProcessInfo,
+    processes: &[ProcessInfo],
+    arguments: bool,
+    prefix: &str,
+    children_prefix: &str,
+    lines: &mut Vec<String>,
+) {
+    let name = if arguments {
+        process.cmdline.as_str()
+    } else {
+        process.cmdline.split_whitespace().next().unwrap_or("")
+    };
+    let zombie = if process.state == vpm::ProcessState::Zombie {
+        " <defunct>"
+    } else {
+        ""
+    };
+    lines.push(format!("{}{}({}){}", prefix, name, process.pid, zombie));
+
+    let children: Vec<&ProcessInfo> = processes
+        .iter()
+        .filter(|child| child.ppid == process.pid && child.pid != process.pid)
+        .collect();
+    for (i, child) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        let (branch, continuation) = if last {
+            ("└─", "  ")
+        } else {
+            ("├─", "│ ")
+        };
+        render(
+            child,
+            processes,
+            arguments,
+            &format!("{}{}", children_prefix, branch),
+            &format!("{}{}", children_prefix, continuation),
+            lines,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vpm::ProcessState;
+    use std::time::Duration;
+
+    /**
+     * Started at 01:01:01 the day after the epoch.
+     */
+    fn process(pid: u32, ppid: u32, state: ProcessState, cmdline: &str) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            ppid,
+            state,
+            nice: 0,
+            start_time: UNIX_EPOCH + Duration::from_secs(86400 + 3661),
+            cpu_time: Duration::from_secs(if pid == 2 { 1 } else { 0 }),
+            pages: 0,
+            level: 0,
+            vruntime: 0,
+            cmdline: cmdline.to_string(),
+        }
+    }
+
+    fn lines(lines: &[&str]) -> String {
+        lines.join("\n") + "\n"
+    }
+
+    /**
+     * init with the shell and a daemon, the shell with a child whose own
+     * child exited.
+     */
+    fn processes() -> Vec<ProcessInfo> {
+        vec![
+            process(1, 0, ProcessState::Ready, "init"),
+            process(2, 1, ProcessState::Running, "sh"),
+            process(3, 2, ProcessState::Ready, "sleep 10"),
+            process(4, 1, ProcessState::Blocked, "daemon -x"),
+            process(5, 3, ProcessState::Zombie, "defunct"),
+        ]
+    }
+
+    #[test]
+    fn ps_lists_the_shell_and_its_children_by_default() {
+        let output = ps("", 2, &processes()).unwrap();
+        assert_eq!(
+            output,
+            lines(&[
+                "PID     TIME CMD",
+                "  2 00:00:01 sh",
+                "  3 00:00:00 sleep 10"
+            ])
+        );
+    }
+
+    #[test]
+    fn ps_e_and_f_select_processes_and_columns() {
+        let processes = processes();
+        assert_eq!(
+            ps("-e", 2, &processes).unwrap(),
+            lines(&[
+                "PID     TIME CMD",
+                "  1 00:00:00 init",
+                "  2 00:00:01 sh",
+                "  3 00:00:00 sleep 10",
+                "  4 00:00:00 daemon -x",
+                "  5 00:00:00 defunct",
+            ])
+        );
+        assert_eq!(
+            ps("-f", 2, &processes).unwrap(),
+            lines(&[
+                "PID PPID S NI    STIME     TIME CMD",
+                "  2    1 R  0 01:01:01 00:00:01 sh",
+                "  3    2 S  0 01:01:01 00:00:00 sleep 10",
+            ])
+        );
+        let full = ps("-ef", 2, &processes).unwrap();
+        assert_eq!(full.lines().count(), 6);
+        assert!(full.starts_with("PID PPID S NI    STIME     TIME CMD\n"));
+        assert!(full.contains("  5    3 Z  0 01:01:01 00:00:00 defunct\n"));
+        assert_eq!(ps("-e -f", 2, &processes).unwrap(), full);
+        assert_eq!(ps("-fe", 2, &processes).unwrap(), full);
+    }
+
+    #[test]
+    fn ps_o_keeps_the_column_order() {
+        let processes = processes();
+        assert_eq!(
+            ps("-o pid,cmd", 2, &processes).unwrap(),
+            lines(&["PID CMD", "  2 sh", "  3 sleep 10"])
+        );
+        assert_eq!(
+            ps("-o CMD,Pid", 2, &processes).unwrap(),
+            lines(&["CMD      PID", "sh         2", "sleep 10   3"])
+        );
+    }
+
+    #[test]
+    fn ps_refuses_bad_options() {
+        let processes = processes();
+        let error = ps("-o pid,rss", 2, &processes).unwrap_err();
+        assert!(error.starts_with("ps: unknown column rss, known columns: pid,ppid,"));
+        assert_eq!(
+            ps("-o", 2, &processes).unwrap_err(),
+            "ps: option -o requires a column list"
+        );
+        assert_eq!(
+            ps("-x", 2, &processes).unwrap_err(),
+            "Usage: ps [-e] [-f] [-o column,...]"
+        );
+    }
+
+    #[test]
+    fn pstree_draws_the_hierarchy() {
+        let processes = processes();
+        assert_eq!(
+            pstree("", &processes).unwrap(),
+            lines(&[
+                "init(1)",
+                "├─sh(2)",
+                "│ └─sleep(3)",
+                "│   └─defunct(5) <defunct>",
+                "└─daemon(4)",
+            ])
+        );
+        assert_eq!(
+            pstree("-a 2", &processes).unwrap(),
+            lines(&["sh(2)", "└─sleep 10(3)", "  └─defunct(5) <defunct>"])
+        );
+        assert_eq!(
+            pstree("9", &processes).unwrap_err(),
+            "pstree: no process with pid 9"
+        );
+    }
+}
@@ -0,0 +1,442 @@
+/**
+ * Virtual CPU scheduler
+ *
+ * A ticker thread drives a single virtual CPU. On every tick the policy
+ * picks the runnable process the tick is charged to; processes calling
+ * Vpm::yield_cpu only make progress while they hold the CPU.
+ */
+use lazy_static::lazy_static;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Mutex, Once},
+    time::Duration,
+};
+
+use crate::vpm::{ProcessState, PROCESS_CHANGED, PROCESS_TABLE};
+
+pub const TICK: Duration = Duration::from_millis(10);
+const RR_QUANTUM: u32 = 5;
+const MLFQ_QUANTA: [u32; 3] = [2, 4, 8];
+const MLFQ_BOOST_TICKS: u64 = 100;
+const CFS_MIN_GRANULARITY: u32 = 2;
+const NICE_0_WEIGHT: f64 = 1024.0;
+
+static TICKER: Once = Once::new();
+
+lazy_static! {
+    pub static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new(Policy::RoundRobin));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    RoundRobin,
+    Priority,
+    Mlfq,
+    Cfs,
+}
+
+pub const POLICIES: [Policy; 4] = [
+    Policy::RoundRobin,
+    Policy::Priority,
+    Policy::Mlfq,
+    Policy::Cfs,
+];
+
+impl Policy {
+    /**
+     * Policy of the given number, as passed to sched_setpolicy.
+     */
+    pub fn from_i64(number: i64) -> Option<Self> {
+        POLICIES
+            .iter()
+            .copied()
+            .find(|&policy| policy as i64 == number)
+    }
+
+    pub fn from_str(policy: &str) -> Option<Self> {
+        match policy {
+            "rr" | "round-robin" => Some(Self::RoundRobin),
+            "priority" | "prio" => Some(Self::Priority),
+            "mlfq" => Some(Self::Mlfq),
+            "cfs" | "fair" => Some(Self::Cfs),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let policy = match self {
+            Self::RoundRobin => "rr",
+            Self::Priority => "priority",
+            Self::Mlfq => "mlfq",
+            Self::Cfs => "cfs",
+        };
+        write!(f, "{}", policy)
+    }
+}
+
+/**
+ * Scheduling class of a process, as seen by the sched command.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct SchedInfo {
+    pub level: usize,
+    pub vruntime: u64,
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    pub policy: Policy,
+    queues: [VecDeque<u32>; 3],
+    levels: HashMap<u32, usize>,
+    vruntime: HashMap<u32, u64>,
+    current: Option<u32>,
+    slice: u32,
+    pub ticks: u64,
+    pub idle_ticks: u64,
+    pub context_switches: u64,
+}
+
+/**
+ * CFS weight of a nice value, every nice level is worth about 25% of CPU.
+ */
+fn weight(nice: i32) -> f64 {
+    NICE_0_WEIGHT / 1.25f64.powi(nice)
+}
+
+/**
+ * Start the ticker thread driving the virtual CPU, once.
+ */
+pub fn start() {
+    TICKER.call_once(|| {
+        std::thread::spawn(|| loop {
+            std::thread::sleep(TICK);
+            tick();
+        });
+    });
+}
+
+/**
+ * Give the next tick to the process chosen by the policy and charge it.
+ */
+pub fn tick() {
+    let mut table = PROCESS_TABLE.lock().unwrap();
+    let mut runnable: Vec<(u32, i32)> = table
+        .values()
+        .filter(|process| matches!(process.state, ProcessState::Running | ProcessState::Ready))
+        .map(|process| (process.pid, process.nice))
+        .collect();
+    runnable.sort();
+
+    let current = SCHEDULER.lock().unwrap().pick(&runnable);
+    for (pid, _) in runnable {
+        let process = table.get_mut(&pid).unwrap();
+        if Some(pid) == current {
+            process.state = ProcessState::Running;
+            process.cpu_time += TICK;
+        } else {
+            process.state = ProcessState::Ready;
+        }
+    }
+    drop(table);
+    PROCESS_CHANGED.notify_all();
+}
+
+impl Scheduler {
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            policy,
+            queues: Default::default(),
+            levels: HashMap::new(),
+            vruntime: HashMap::new(),
+            current: None,
+            slice: 0,
+            ticks: 0,
+            idle_ticks: 0,
+            context_switches: 0,
+        }
+    }
+
+    /**
+     * Switch policy, every process starts over in the top queue.
+     */
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+        self.queues.iter_mut().for_each(|queue| queue.clear());
+        self.levels.clear();
+        self.current = None;
+        self.slice = 0;
+    }
+
+    /**
+     * Drop the bookkeeping of an exited process.
+     */
+    pub fn forget(&mut self, pid: u32) {
+        self.queues
+            .iter_mut()
+            .for_each(|queue| queue.retain(|&queued| queued != pid));
+        self.levels.remove(&pid);
+        self.vruntime.remove(&pid);
+        if self.current == Some(pid) {
+            self.current = None;
+        }
+    }
+
+    pub fn info(&self, pid: u32) -> SchedInfo {
+        SchedInfo {
+            level: self.levels.get(&pid).copied().unwrap_or(0),
+            vruntime: self.vruntime.get(&pid).copied().unwrap_or(0),
+        }
+    }
+
+    /**
+     * Make the queues hold exactly the runnable processes: newcomers and
+     * woken processes go to the back of their queue.
+     */
+    fn sync(&mut self, runnable: &[(u32, i32)]) {
+        let is_runnable = |pid: u32| runnable.iter().any(|&(runnable, _)| runnable == pid);
+        self.queues
+            .iter_mut()
+            .for_each(|queue| queue.retain(|&pid| is_runnable(pid)));
+
+        let min_vruntime = runnable
+            .iter()
+            .filter_map(|(pid, _)| self.vruntime.get(pid))
+            .min()
+            .copied()
+            .unwrap_or(0);
+        for &(pid, _) in runnable {
+            if self.queues.iter().any(|queue| queue.contains(&pid)) {
+                continue;
+            }
+            let level = *self.levels.entry(pid).or_insert(0);
+            self.queues[level].push_back(pid);
+            // A sleeper must not monopolize the CPU with the vruntime it saved
+            let vruntime = self.vruntime.entry(pid).or_insert(min_vruntime);
+            *vruntime = (*vruntime).max(min_vruntime);
+        }
+    }
+
+    /**
+     * Keep the current process until its quantum expires, then rotate it to
+     * the back of the queue and take the first eligible process.
+     */
+    fn rotate<F>(&mut self, level: usize, quantum: u32, eligible: F) -> Option<u32>
+    where
+        F: Fn(u32) -> bool,
+    {
+        if let Some(current) = self.current {
+            let queued = self.queues[level].contains(&current);
+            if queued && eligible(current) && self.slice < quantum {
+                return Some(current);
+            }
+            if queued && self.slice >= quantum {
+                self.queues[level].retain(|&pid| pid != current);
+                self.queues[level].push_back(current);
+                self.slice = 0;
+            }
+        }
+        self.queues[level]
+            .iter()
+            .copied()
+            .find(|&pid| eligible(pid))
+    }
+
+    /**
+     * Choose the process running during this tick, None when the CPU idles.
+     */
+    pub fn pick(&mut self, runnable: &[(u32, i32)]) -> Option<u32> {
+        self.ticks += 1;
+        self.sync(runnable);
+        if runnable.is_empty() {
+            self.current = None;
+            self.idle_ticks += 1;
+            return None;
+        }
+
+        let previous = self.current;
+        let nice = |pid: u32| {
+            runnable
+                .iter()
+                .find(|&&(runnable, _)| runnable == pid)
+                .map(|&(_, nice)| nice)
+                .unwrap_or(0)
+        };
+
+        let next = match self.policy {
+            Policy::RoundRobin => self.rotate(0, RR_QUANTUM, |_| true),
+            Policy::Priority => {
+                let highest = runnable.iter().map(|&(_, nice)| nice).min().unwrap();
+                self.rotate(0, RR_QUANTUM, |pid| nice(pid) == highest)
+            }
+            Policy::Mlfq => {
+                if self.ticks.is_multiple_of(MLFQ_BOOST_TICKS) {
+                    self.boost();
+                }
+                if let Some(current) = self.current {
+                    let level = self.levels.get(&current).copied().unwrap_or(0);
+                    if self.slice >= MLFQ_QUANTA[level] && level + 1 < MLFQ_QUANTA.len() {
+                        self.queues[level].retain(|&pid| pid != current);
+                        self.queues[level + 1].push_back(current);
+                        self.levels.insert(current, level + 1);
+                        self.current = None;
+                        self.slice = 0;
+                    }
+                }
+                let level = (0..MLFQ_QUANTA.len())
+                    .find(|&level| !self.queues[level].is_empty())
+                    .unwrap();
+                self.rotate(level, MLFQ_QUANTA[level], |_| true)
+            }
+            Policy::Cfs => {
+                let running = self.current.filter(|&current| {
+                    self.slice < CFS_MIN_GRANULARITY
+                        && runnable.iter().any(|&(pid, _)| pid == current)
+                });
+                running.or_else(|| self.min_vruntime(runnable))
+            }
+        };
+
+        if next != previous {
+            self.context_switches += 1;
+            self.slice = 0;
+        }
+        self.current = next;
+        self.slice += 1;
+        if let Some(pid) = next {
+            let charge = TICK.as_micros() as f64 * NICE_0_WEIGHT / weight(nice(pid));
+            *self.vruntime.entry(pid).or_insert(0) += charge as u64;
+        }
+        next
+    }
+
+    fn min_vruntime(&self, runnable: &[(u32, i32)]) -> Option<u32> {
+        runnable
+            .iter()
+            .map(|&(pid, _)| pid)
+            .min_by_key(|pid| (self.vruntime.get(pid).copied().unwrap_or(0), *pid))
+    }
+
+    /**
+     * Periodic MLFQ priority boost, so demoted processes do not starve.
+     * The running process starts a new quantum in the top queue.
+     */
+    fn boost(&mut self) {
+        let demoted: Vec<u32> = self.queues[1..]
+            .iter_mut()
+            .flat_map(|queue| queue.drain(..))
+            .collect();
+        self.queues[0].extend(demoted);
+        self.levels.values_mut().for_each(|level| *level = 0);
+        self.slice = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Pids picked during the given number of ticks.
+     */
+    fn run(scheduler: &mut Scheduler, runnable: &[(u32, i32)], ticks: usize) -> Vec<Option<u32>> {
+        (0..ticks).map(|_| scheduler.pick(runnable)).collect()
+    }
+
+    fn ticks_of(picks: &[Option<u32>], pid: u32) -> usize {
+        picks.iter().filter(|&&pick| pick == Some(pid)).count()
+    }
+
+    #[test]
+    fn round_robin_rotates_every_quantum() {
+        let mut scheduler = Scheduler::new(Policy::RoundRobin);
+        let picks = run(&mut scheduler, &[(1, 0), (2, 0)], 3 * RR_QUANTUM as usize);
+        let quantum = RR_QUANTUM as usize;
+        assert!(picks[..quantum].iter().all(|&pick| pick == Some(1)));
+        assert!(picks[quantum..2 * quantum]
+            .iter()
+            .all(|&pick| pick == Some(2)));
+        assert!(picks[2 * quantum..].iter().all(|&pick| pick == Some(1)));
+        assert_eq!(scheduler.context_switches, 3);
+    }
+
+    #[test]
+    fn idle_cpu_picks_nobody() {
+        let mut scheduler = Scheduler::new(Policy::RoundRobin);
+        assert_eq!(scheduler.pick(&[]), None);
+        assert_eq!(scheduler.idle_ticks, 1);
+        assert_eq!(scheduler.pick(&[(3, 0)]), Some(3));
+        assert_eq!(scheduler.ticks, 2);
+    }
+
+    #[test]
+    fn priority_only_runs_the_lowest_nice() {
+        let mut scheduler = Scheduler::new(Policy::Priority);
+        let picks = run(&mut scheduler, &[(1, 0), (2, -5), (3, -5)], 40);
+        assert_eq!(ticks_of(&picks, 1), 0);
+        assert_eq!(ticks_of(&picks, 2), 20);
+        assert_eq!(ticks_of(&picks, 3), 20);
+
+        let picks = run(&mut scheduler, &[(1, 0)], 3);
+        assert_eq!(ticks_of(&picks, 1), 3);
+    }
+
+    #[test]
+    fn mlfq_demotes_hogs_and_boosts_them_back() {
+        let mut scheduler = Scheduler::new(Policy::Mlfq);
+        let picks = run(&mut scheduler, &[(1, 0)], MLFQ_QUANTA[0] as usize);
+        assert!(picks.iter().all(|&pick| pick == Some(1)));
+
+        // Its first quantum used up, the hog gives way to a newcomer
+        assert_eq!(scheduler.pick(&[(1, 0), (2, 0)]), Some(2));
+        assert_eq!(scheduler.info(1).level, 1);
+        assert_eq!(scheduler.info(2).level, 0);
+
+        run(&mut scheduler, &[(1, 0), (2, 0)], 20);
+        assert_eq!(scheduler.info(1).level, MLFQ_QUANTA.len() - 1);
+        assert_eq!(scheduler.info(2).level, MLFQ_QUANTA.len() - 1);
+
+        let ticks = MLFQ_BOOST_TICKS as usize - scheduler.ticks as usize;
+        run(&mut scheduler, &[(1, 0), (2, 0)], ticks);
+        assert_eq!(scheduler.info(1).level, 0);
+        assert_eq!(scheduler.info(2).level, 0);
+    }
+
+    #[test]
+    fn cfs_shares_the_cpu_by_weight() {
+        let mut scheduler = Scheduler::new(Policy::Cfs);
+        let picks = run(&mut scheduler, &[(1, 0), (2, 5)], 400);
+        let (nice_0, nice_5) = (ticks_of(&picks, 1), ticks_of(&picks, 2));
+        assert_eq!(nice_0 + nice_5, 400);
+        // A nice 5 process weighs about three times less
+        assert!(
+            nice_0 > 2 * nice_5 && nice_0 < 4 * nice_5,
+            "{} {}",
+            nice_0,
+            nice_5
+        );
+    }
+
+    #[test]
+    fn cfs_newcomer_starts_at_the_minimum_vruntime() {
+        let mut scheduler = Scheduler::new(Policy::Cfs);
+        run(&mut scheduler, &[(1, 0)], 100);
+        let picks = run(&mut scheduler, &[(1, 0), (2, 0)], 20);
+        assert_eq!(ticks_of(&picks, 1), 10);
+        assert_eq!(ticks_of(&picks, 2), 10);
+    }
+
+    #[test]
+    fn switching_policy_forgets_the_queues() {
+        let mut scheduler = Scheduler::new(Policy::Mlfq);
+        run(&mut scheduler, &[(1, 0)], 10);
+        assert_ne!(scheduler.info(1).level, 0);
+        scheduler.set_policy(Policy::RoundRobin);
+        assert_eq!(scheduler.info(1).level, 0);
+        scheduler.forget(1);
+        assert_eq!(scheduler.info(1).vruntime, 0);
+        assert_eq!(scheduler.pick(&[(2, 0)]), Some(2));
+    }
+}
@@ -1,9 +1,39 @@
+/**
+ * Kernelino shell
+ *
+ * Commands run on behalf of the shell or of one of its jobs and reach the
+ * kernel through the system calls, like the programs of /bin do. Listings
+ * of kernel state such as ps, free or ipcs get their snapshot from the
+ * info calls, the way Linux tools read /proc.
+ */
+use crate::asm;
+use crate::cpu;
+use crate::disasm;
+use crate::editor::Editor;
+use crate::errno::Errno;
+use crate::exec::{self, Executable, ProgramKind, SegmentKind};
+use crate::fd;
+use crate::hexdump;
+use crate::highlight;
+use crate::ipc;
+use crate::jobs::{self, JobTable, JOBS};
+use crate::programs;
+use crate::ps;
+use crate::scheduler::Policy;
+use crate::signal::{Signal, SIGNALS};
+use crate::syscall::{self, IpcTables, LockTables, Value};
+use crate::top;
 use crate::utils;
-use lazy_static::lazy_static;
 use std::io::{self, Write};
-use std::sync::RwLock;
 
-use crate::vfs::{init_vfs, Vfs};
+use crate::vfs::VFS;
+use crate::vpm;
+use std::path::PathBuf;
+
+/**
+ * Longest command line read at the prompt.
+ */
+const INPUT_SIZE: i64 = 4096;
 
 enum ShellCommand {
     Exit,
@@ -16,44 +46,90 @@ enum ShellCommand {
     Ls,
     Rm(String),
     Touch(String),
+    MkFifo(String),
     WriteFile(String),
     ReadFile(String),
-    Top,
-}
-
-// Initialize the virtual file system
-lazy_static! {
-    static ref VFS: RwLock<Vfs> = RwLock::new(init_vfs());
+    Top(String),
+    HeapStat(String),
+    Free,
+    VmStat(String),
+    PMap(String),
+    MemMap,
+    MemCheck(String),
+    Ipcs,
+    Mq(String),
+    Locks,
+    Wait(String),
+    Sched(String),
+    Nice(String),
+    Renice(String),
+    Kill(String),
+    Trap(String),
+    Jobs,
+    Fg(String),
+    Bg(String),
+    Ps(String),
+    PsTree(String),
+    Strace(String),
+    Chmod(String),
+    Kasm(String),
+    Kdis(String),
+    Hexdump(String),
+    Xxd(String),
+    Exec(String),
 }
 
 impl ShellCommand {
+    /**
+     * Builtins match their name exactly, any other name is looked up in
+     * the PATH, so /bin/topaz is not taken for top.
+     */
     fn from_str(input: &str) -> Option<Self> {
-        match input {
+        let (name, args) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+        let args = args.trim();
+        let arg = args.to_string();
+        match name {
             "" => Some(Self::NewLine),
-            "exit" => Some(Self::Exit),
-            "help" => Some(Self::Help),
-            "clear" => Some(Self::Clear),
-            "pwd" => Some(Self::Pwd),
-            "ls" => Some(Self::Ls),
-            "top" => Some(Self::Top),
-            _ if input.starts_with("cd") => {
-                Some(Self::Cd(input.trim_start_matches("cd ").to_string()))
-            }
-            _ if input.starts_with("mkdir") => {
-                Some(Self::MkDir(input.trim_start_matches("mkdir ").to_string()))
-            }
-            _ if input.starts_with("rm") => {
-                Some(Self::Rm(input.trim_start_matches("rm ").to_string()))
-            }
-            _ if input.starts_with("touch") => {
-                Some(Self::Touch(input.trim_start_matches("touch ").to_string()))
-            }
-            _ if input.starts_with("write") => Some(Self::WriteFile(
-                input.trim_start_matches("write ").to_string(),
-            )),
-            _ if input.starts_with("read") => Some(Self::ReadFile(
-                input.trim_start_matches("read ").to_string(),
-            )),
+            "exit" if args.is_empty() => Some(Self::Exit),
+            "help" if args.is_empty() => Some(Self::Help),
+            "clear" if args.is_empty() => Some(Self::Clear),
+            "pwd" if args.is_empty() => Some(Self::Pwd),
+            "ls" if args.is_empty() => Some(Self::Ls),
+            "free" if args.is_empty() => Some(Self::Free),
+            "memmap" if args.is_empty() => Some(Self::MemMap),
+            "ipcs" if args.is_empty() => Some(Self::Ipcs),
+            "locks" if args.is_empty() => Some(Self::Locks),
+            "jobs" if args.is_empty() => Some(Self::Jobs),
+            "fg" => Some(Self::Fg(arg)),
+            "bg" => Some(Self::Bg(arg)),
+            "strace" => Some(Self::Strace(arg)),
+            "sched" => Some(Self::Sched(arg)),
+            "nice" => Some(Self::Nice(arg)),
+            "renice" => Some(Self::Renice(arg)),
+            "kill" => Some(Self::Kill(arg)),
+            "trap" => Some(Self::Trap(arg)),
+            "top" => Some(Self::Top(arg)),
+            "pstree" => Some(Self::PsTree(arg)),
+            "ps" => Some(Self::Ps(arg)),
+            "wait" => Some(Self::Wait(arg)),
+            "memcheck" => Some(Self::MemCheck(arg)),
+            "mkfifo" => Some(Self::MkFifo(arg)),
+            "mq" => Some(Self::Mq(arg)),
+            "cd" => Some(Self::Cd(arg)),
+            "mkdir" => Some(Self::MkDir(arg)),
+            "rm" => Some(Self::Rm(arg)),
+            "touch" => Some(Self::Touch(arg)),
+            "write" => Some(Self::WriteFile(arg)),
+            "read" => Some(Self::ReadFile(arg)),
+            "heapstat" => Some(Self::HeapStat(arg)),
+            "vmstat" => Some(Self::VmStat(arg)),
+            "pmap" => Some(Self::PMap(arg)),
+            "chmod" => Some(Self::Chmod(arg)),
+            "kasm" => Some(Self::Kasm(arg)),
+            "kdis" => Some(Self::Kdis(arg)),
+            "hexdump" => Some(Self::Hexdump(arg)),
+            "xxd" => Some(Self::Xxd(arg)),
+            _ if exec::exists(name) => Some(Self::Exec(input.to_string())),
             _ => None,
         }
     }
@@ -70,9 +146,37 @@ impl ShellCommand {
             Self::Ls => cmd_ls(),
             Self::Rm(path) => cmd_rm(path),
             Self::Touch(filename) => cmd_touch(filename),
+            Self::MkFifo(filename) => cmd_mkfifo(filename),
             Self::WriteFile(filename) => cmd_write_file(filename),
             Self::ReadFile(filename) => cmd_read_file(filename),
-            Self::Top => cmd_top()
+            Self::Top(args) => cmd_top(args),
+            Self::HeapStat(pid) => cmd_heapstat(pid),
+            Self::Free => cmd_free(),
+            Self::VmStat(args) => cmd_vmstat(args),
+            Self::PMap(pid) => cmd_pmap(pid),
+            Self::MemMap => cmd_memmap(),
+            Self::MemCheck(args) => cmd_memcheck(args),
+            Self::Ipcs => cmd_ipcs(),
+            Self::Mq(args) => cmd_mq(args),
+            Self::Locks => cmd_locks(),
+            Self::Wait(pid) => cmd_wait(pid),
+            Self::Sched(policy) => cmd_sched(policy),
+            Self::Nice(args) => cmd_nice(args).await,
+            Self::Renice(args) => cmd_renice(args),
+            Self::Kill(args) => cmd_kill(args),
+            Self::Trap(args) => cmd_trap(args),
+            Self::Jobs => cmd_jobs(),
+            Self::Fg(job) => cmd_fg(job),
+            Self::Bg(job) => cmd_bg(job),
+            Self::Ps(args) => cmd_ps(args),
+            Self::PsTree(args) => cmd_pstree(args),
+            Self::Strace(args) => cmd_strace(args),
+            Self::Chmod(args) => cmd_chmod(args),
+            Self::Kasm(args) => cmd_kasm(args),
+            Self::Kdis(args) => cmd_kdis(args),
+            Self::Hexdump(args) => cmd_dump("hexdump", args),
+            Self::Xxd(args) => cmd_dump("xxd", args),
+            Self::Exec(cmdline) => cmd_exec(cmdline).await,
         }
     }
 }
@@ -84,15 +188,58 @@ pub async fn run() {
     // Setting up the terminal
     cmd_clear();
 
+    // Ctrl-C interrupts the foreground process instead of the kernel
+    let shell = shell();
+    #[cfg(unix)]
+    {
+        // Ctrl-Z suspends it
+        let shell = shell.clone();
+        let mut suspend = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::from_raw(
+            Signal::SIGTSTP as i32,
+        ))
+        .unwrap();
+        tokio::spawn(async move {
+            while suspend.recv().await.is_some() {
+                if let Some(pid) = foreground(&shell) {
+                    send_signal(&shell, pid, Signal::SIGTSTP).ok();
+                }
+            }
+        });
+    }
+    let interrupt = shell.clone();
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            match foreground(&interrupt) {
+                Some(pid) => {
+                    send_signal(&interrupt, pid, Signal::SIGINT).ok();
+                }
+                None => {
+                    print!("^C\nkernelino> ");
+                    io::stdout().flush().unwrap();
+                }
+            }
+        }
+    });
+
     loop {
+        report_jobs();
         print!("kernelino> ");
         io::stdout().flush().unwrap();
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+        // The shell sleeps while waiting for the user, leaving the CPU to others
+        let args = [Value::Int(fd::STDIN as i64), Value::Int(INPUT_SIZE)];
+        let input = match syscall::syscall(&shell, syscall::SYS_READ, &args) {
+            Ok(Value::Bytes(bytes)) => String::from_utf8_lossy(&bytes).into_owned(),
+            _ => String::new(),
+        };
 
         let input = input.trim();
 
+        if let Some(command) = input.strip_suffix('&') {
+            cmd_background(command.trim());
+            continue;
+        }
+
         match ShellCommand::from_str(input) {
             Some(cmd) => cmd.execute().await,
             None => println!("Unknown command: {}", input),
@@ -104,9 +251,10 @@ fn init_base_fs() {
     cmd_add_directory("bin");
     cmd_add_directory("tmp");
     cmd_touch(".env");
+    programs::install();
+    highlight::install(&process());
 }
 
-
 fn cmd_exit() {
     println!("Goodbye!");
     std::process::exit(0);
@@ -121,11 +269,45 @@ fn cmd_help() {
     println!("  cd <path> - Change the current working directory");
     println!("  mkdir <name> - Create a new directory");
     println!("  touch <filename> - Create a new file");
-    println!("  write <filename> - Write file content");
+    println!("  mkfifo <name> - Create a named pipe, read it and redirect output to it");
+    println!("  write <filename> - Edit a file with vi keys, :wq to save and quit");
     println!("  read <filename> - Read file content");
     println!("  ls - List directory contents");
     println!("  rm <path> - Remove a file or directory");
-    println!("  top - Show the processes");
+    println!("  top [-d <seconds>] - Show the processes, refreshed every few seconds");
+    println!("  heapstat <pid> - Show heap usage and fragmentation of a process");
+    println!("  free - Show total, used and free memory");
+    println!("  vmstat [delay] [count] - Show page faults, allocations and TLB evictions");
+    println!("  pmap <pid> - Show the memory regions of a process (0 for the kernel)");
+    println!("  memmap - Show used and free physical frames");
+    println!("  memcheck [-v] - Report leaked pages, -v lists the owner of every page");
+    println!("  ipcs - List shared memory segments, pipes and message queues");
+    println!("  mq send <queue> [-p <priority>] <message> - Send a message, blocks while full");
+    println!("  mq recv <queue> - Receive the most urgent message, blocks while empty");
+    println!("  mq rm <queue> - Remove a message queue and its messages");
+    println!("  locks - Show mutexes, semaphores, condvars and rwlocks with holders and waiters");
+    println!("  wait [pid] - Wait for a child process to exit and reap it");
+    println!("  sched [rr|priority|mlfq|cfs] - Show the scheduler or switch its policy");
+    println!("  nice [-n <nice>] <command> - Run a command with a lower or higher priority");
+    println!("  renice <nice> <pid> - Change the nice value of a process");
+    println!("  kill [-<signal>] <pid> - Send a signal to a process, kill -l lists them");
+    println!("  trap [default|ignore|log <signal>...] - Set what the shell and its children do on a signal");
+    println!("  <command> & - Run a command in the background");
+    println!("  jobs - List background and stopped jobs");
+    println!("  fg [%n|%+|%-] - Bring a job to the foreground");
+    println!("  bg [%n|%+|%-] - Resume a stopped job in the background");
+    println!("  ps [-e] [-f] [-o <columns>] [> file] - List processes, -e for all of them");
+    println!("  pstree [-a] [pid] [> file] - Show the process hierarchy");
+    println!("  chmod +x|-x|<mode> <file> - Set or clear the execute bit of a file");
+    println!("  kasm <source> [-o <output>] - Assemble a source file into an executable");
+    println!("  kdis [-t] <program> - Disassemble a program, -t only shows its symbols");
+    println!(
+        "  hexdump [-s <offset>] [-n <length>] <file> [> file] - Show a file in hex and ASCII"
+    );
+    println!("  xxd [-s <offset>] [-l <length>] <file> [> file] - Show a file in hex and ASCII, xxd style");
+    println!("  <program> [args] - Run an executable of /bin, or at the given path");
+    println!("  strace <command> - Run a command, logging its system calls");
+    println!("  strace -p <pid> | -d <pid> - Start or stop logging the system calls of a process");
     println!("  kpm install <package> - Install a package");
     println!("  kpm list - List all available packages");
 }
@@ -139,46 +321,1557 @@ fn cmd_newline() {
 }
 
 fn cmd_pwd() {
-    let vfs = VFS.read().unwrap().clone();
-    vfs.pwd();
+    match syscall::syscall(&process(), syscall::SYS_GETCWD, &[]) {
+        Ok(Value::Str(cwd)) => println!("{}", cwd),
+        Ok(_) => {}
+        Err(errno) => println!("pwd: {}", errno),
+    }
 }
 
 fn cmd_cd(path: &str) {
-    let mut vfs = VFS.write().unwrap();
-    vfs.change_dir(path);
+    let args = [Value::Str(path.to_string())];
+    if let Err(errno) = syscall::syscall(&process(), syscall::SYS_CHDIR, &args) {
+        println!("cd: {}: {}", path, errno);
+    }
 }
 
 fn cmd_add_directory(name: &str) {
-    let mut vfs = VFS.write().unwrap();
-    vfs.add_directory_recursive(name);
+    if name.is_empty() {
+        println!("Usage: mkdir <name>");
+        return;
+    }
+    let args = [Value::Str(name.to_string())];
+    if let Err(errno) = syscall::syscall(&process(), syscall::SYS_MKDIR, &args) {
+        println!("mkdir: {}: {}", name, errno);
+    }
 }
 
 fn cmd_ls() {
-    let mut vfs = VFS.write().unwrap();
-    vfs.list();
+    let process = process();
+    let names = match syscall::syscall(&process, syscall::SYS_GETDENTS, &[]) {
+        Ok(Value::List(names)) => names,
+        Ok(_) => Vec::new(),
+        Err(errno) => {
+            println!("ls: {}", errno);
+            return;
+        }
+    };
+    let mut files = Vec::new();
+    let mut directories = Vec::new();
+    for name in names {
+        let Value::Str(name) = name else {
+            continue;
+        };
+        match syscall::stat(&process, &name) {
+            Ok((mode, _)) if mode & syscall::S_IFMT == syscall::S_IFDIR => {
+                directories.push(format!("{}/", name))
+            }
+            Ok((mode, _)) if mode & syscall::S_IFMT == syscall::S_IFIFO => {
+                files.push(format!("{} fifo", name))
+            }
+            Ok((mode, size)) if mode & syscall::S_IXALL != 0 => {
+                files.push(format!("{}* {}", name, size))
+            }
+            Ok((_, size)) => files.push(format!("{} {}", name, size)),
+            Err(errno) => println!("ls: {}: {}", name, errno),
+        }
+    }
+
+    if files.is_empty() {
+        println!("No files found");
+    } else {
+        files.iter().for_each(|file| println!("{}", file));
+    }
+    if directories.is_empty() {
+        println!("No directories found");
+    } else {
+        directories
+            .iter()
+            .for_each(|directory| println!("{}", directory));
+    }
 }
 
 fn cmd_rm(path: &str) {
-    let mut vfs = VFS.write().unwrap();
-    vfs.remove(path);
+    if path.is_empty() {
+        println!("Usage: rm <path>");
+        return;
+    }
+    let args = [Value::Str(path.to_string())];
+    if let Err(errno) = syscall::syscall(&process(), syscall::SYS_UNLINK, &args) {
+        println!("rm: {}: {}", path, errno);
+    }
 }
 
 fn cmd_touch(filename: &str) {
-    let mut vfs = VFS.write().unwrap();
-    vfs.touch(filename);
+    if filename.is_empty() {
+        println!("Usage: touch <file>");
+        return;
+    }
+    let process = process();
+    let args = [
+        Value::Str(filename.to_string()),
+        Value::Int(syscall::O_WRONLY | syscall::O_CREAT | syscall::O_EXCL),
+    ];
+    let result = syscall::syscall(&process, syscall::SYS_OPEN, &args)
+        .and_then(|fd| syscall::syscall(&process, syscall::SYS_CLOSE, &[fd]));
+    if let Err(errno) = result {
+        println!("touch: {}: {}", filename, errno);
+    }
+}
+
+fn cmd_mkfifo(filename: &str) {
+    if filename.is_empty() {
+        println!("Usage: mkfifo <name>");
+        return;
+    }
+    let args = [
+        Value::Str(filename.to_string()),
+        Value::Int(syscall::S_IFIFO),
+    ];
+    if let Err(errno) = syscall::syscall(&process(), syscall::SYS_MKNOD, &args) {
+        println!("mkfifo: {}: {}", filename, errno);
+    }
 }
 
 fn cmd_write_file(filename: &str) {
-    let mut vfs = VFS.write().unwrap();
-    vfs.write_file(filename, None, None);
+    if filename.is_empty() {
+        println!("Usage: write <file>");
+        return;
+    }
+    let filename = filename.to_string();
+    run_blocking(&format!("write {}", filename), move |process| {
+        if let Err(errno) = Editor::write(process, &filename) {
+            println!("write: {}: {}", filename, errno);
+        }
+    });
 }
 
 fn cmd_read_file(filename: &str) {
-    let mut vfs = VFS.read().unwrap().clone();
-    vfs.read_file(filename);
+    if filename.is_empty() {
+        println!("Usage: read <file>");
+        return;
+    }
+    let filename = filename.to_string();
+    run_blocking(&format!("read {}", filename), move |process| {
+        if let Err(errno) = Editor::read(process, &filename) {
+            println!("read: {}: {}", filename, errno);
+        }
+    });
+}
+
+fn cmd_top(args: &str) {
+    let delay = match args.strip_prefix("-d") {
+        None if args.is_empty() => Some(top::DEFAULT_DELAY),
+        Some(delay) => top::parse_delay(delay),
+        None => None,
+    };
+    match delay {
+        Some(delay) => run_foreground("top", move |process| {
+            top::run(process, delay);
+            0
+        }),
+        None => println!("Usage: top [-d <seconds>]"),
+    }
+}
+
+fn cmd_heapstat(pid: &str) {
+    let pid = match pid.trim().parse::<u32>() {
+        Ok(pid) => pid,
+        Err(_) => {
+            println!("Usage: heapstat <pid>");
+            return;
+        }
+    };
+
+    match syscall::heapinfo(&process(), pid) {
+        Ok(stats) => {
+            println!("Heap of process {}", pid);
+            println!(
+                "  start: {:#x} break: {:#x} size: {} bytes",
+                stats.start,
+                stats.brk,
+                stats.brk - stats.start
+            );
+            println!(
+                "  allocated: {} blocks, {} bytes ({} bytes requested)",
+                stats.allocated_blocks, stats.allocated_bytes, stats.requested_bytes
+            );
+            println!(
+                "  free: {} blocks, {} bytes, largest block {} bytes",
+                stats.free_blocks, stats.free_bytes, stats.largest_free_block
+            );
+            println!(
+                "  internal fragmentation: {:.2}%",
+                stats.internal_fragmentation()
+            );
+            println!(
+                "  external fragmentation: {:.2}%",
+                stats.external_fragmentation()
+            );
+        }
+        Err(e) => println!("heapstat: {}: {}", pid, e),
+    }
+}
+
+fn cmd_free() {
+    let stats = match syscall::meminfo(&process()) {
+        Ok(stats) => stats,
+        Err(errno) => {
+            println!("free: {}", errno);
+            return;
+        }
+    };
+    let free_frames = stats.total_frames - stats.used_frames;
+    println!("{:>8} {:>12} {:>12} {:>12}", "", "total", "used", "free");
+    println!(
+        "{:>8} {:>12} {:>12} {:>12}",
+        "Mem:",
+        stats.total_frames * stats.page_size / 1024,
+        stats.used_frames * stats.page_size / 1024,
+        free_frames * stats.page_size / 1024
+    );
+    println!(
+        "{:>8} {:>12} {:>12} {:>12}",
+        "Frames:", stats.total_frames, stats.used_frames, free_frames
+    );
+    println!(
+        "Page size: {} bytes, huge pages: {} ({} KiB), page tables: {} frames",
+        stats.page_size,
+        stats.huge_pages,
+        stats.huge_page_bytes / 1024,
+        stats.page_table_frames
+    );
+}
+
+/**
+ * vmstat [delay] [count]: first line reports totals since boot, following
+ * lines the activity during each delay. Pages are never evicted, only TLB
+ * entries are.
+ */
+fn cmd_vmstat(args: &str) {
+    let mut args = args.split_whitespace().map(|arg| arg.parse::<u64>());
+    let (delay, count) = match (args.next(), args.next()) {
+        (None, _) => (0, 1),
+        (Some(Ok(delay)), None) => (delay, u64::MAX),
+        (Some(Ok(delay)), Some(Ok(count))) => (delay, count),
+        _ => {
+            println!("Usage: vmstat [delay] [count]");
+            return;
+        }
+    };
+
+    let process = process();
+    println!(
+        "{:>10} {:>10} {:>8} {:>8} {:>8} {:>8} {:>9} {:>8} {:>8}",
+        "free", "used", "pf", "cow", "alloc", "dealloc", "tlb_evict", "tlb_hit", "tlb_miss"
+    );
+    let mut previous = crate::vmm::MemoryStats::default();
+    for iteration in 0..count {
+        if iteration > 0 && !vpm::sleep(std::time::Duration::from_secs(delay)) {
+            break;
+        }
+        let stats = match syscall::meminfo(&process) {
+            Ok(stats) => stats,
+            Err(errno) => {
+                println!("vmstat: {}", errno);
+                break;
+            }
+        };
+        println!(
+            "{:>10} {:>10} {:>8} {:>8} {:>8} {:>8} {:>9} {:>8} {:>8}",
+            (stats.total_frames - stats.used_frames) * stats.page_size / 1024,
+            stats.used_frames * stats.page_size / 1024,
+            stats.page_faults - previous.page_faults,
+            stats.cow_faults - previous.cow_faults,
+            stats.allocations - previous.allocations,
+            stats.deallocations - previous.deallocations,
+            stats.tlb_evictions - previous.tlb_evictions,
+            stats.tlb_hits - previous.tlb_hits,
+            stats.tlb_misses - previous.tlb_misses
+        );
+        previous = stats;
+        if delay == 0 {
+            break;
+        }
+    }
+}
+
+/**
+ * Compact list of frame numbers, e.g. "3-6,9".
+ */
+fn frame_ranges(frames: &[u64]) -> String {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for &frame in frames {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == frame => *end = frame,
+            _ => ranges.push((frame, frame)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn cmd_pmap(pid: &str) {
+    let pid = match pid.trim().parse::<u32>() {
+        Ok(pid) => pid,
+        Err(_) => {
+            println!("Usage: pmap <pid>");
+            return;
+        }
+    };
+
+    match syscall::maps(&process(), pid) {
+        Ok(regions) => {
+            println!("{}:", pid);
+            println!(
+                "{:<18} {:>10} {:<8} {:<8} FRAMES",
+                "ADDRESS", "KBYTES", "FLAGS", "KIND"
+            );
+            regions.iter().for_each(|region| {
+                println!(
+                    "{:<18} {:>10} {:<8} {:<8} {}",
+                    format!("{:#016x}", region.start),
+                    region.size / 1024,
+                    region.flags,
+                    region.kind,
+                    frame_ranges(&region.frames)
+                );
+            });
+            let total: u64 = regions.iter().map(|region| region.size).sum();
+            println!("total {} KiB", total / 1024);
+        }
+        Err(e) => println!("pmap: {}: {}", pid, e),
+    }
+}
+
+fn cmd_memmap() {
+    const COLUMNS: usize = 64;
+    const ROWS: usize = 16;
+
+    let process = process();
+    let (stats, usage) = match syscall::meminfo(&process)
+        .and_then(|stats| Ok((stats, syscall::framemap(&process, COLUMNS * ROWS)?)))
+    {
+        Ok(snapshot) => snapshot,
+        Err(errno) => {
+            println!("memmap: {}", errno);
+            return;
+        }
+    };
+    println!(
+        "{} frames, {} per cell: '#' full, '+' partially used, '.' free",
+        stats.total_frames,
+        stats.total_frames.div_ceil(usage.len() as u64)
+    );
+    usage.chunks(COLUMNS).for_each(|row| {
+        let line: String = row
+            .iter()
+            .map(|&used| {
+                if used >= 1.0 {
+                    '#'
+                } else if used > 0.0 {
+                    '+'
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!("{}", line);
+    });
+    println!(
+        "{} used, {} free",
+        stats.used_frames,
+        stats.total_frames - stats.used_frames
+    );
+}
+
+/**
+ * Pages are leaked when nobody owns them, when their owner file is gone or
+ * when a frame is in use without any page table referencing it.
+ */
+fn cmd_memcheck(args: &str) {
+    let verbose = args == "-v";
+    let report = match syscall::memcheck(&process()) {
+        Ok(report) => report,
+        Err(errno) => {
+            println!("memcheck: {}", errno);
+            return;
+        }
+    };
+
+    if verbose {
+        println!("{:<6} {:<18} OWNER", "SPACE", "PAGE");
+        report.owners.iter().for_each(|(space, address, owner)| {
+            println!("{:<6} {:<18} {}", space, format!("{:#x}", address), owner);
+        });
+    }
+
+    println!("{} pages checked", report.owners.len());
+    println!("Pages with no owner: {}", report.unowned_pages.len());
+    report.unowned_pages.iter().for_each(|(address, size)| {
+        println!("  page {:#x} ({} bytes)", address, size);
+    });
+    println!(
+        "Pages owned by removed files: {}",
+        report.removed_owner.len()
+    );
+    report.removed_owner.iter().for_each(|(address, path)| {
+        println!("  page {:#x} owned by {}", address, path);
+    });
+    println!(
+        "Frames in use but not mapped: {}",
+        report.orphan_frames.len()
+    );
+    if !report.orphan_frames.is_empty() {
+        println!("  frames {}", frame_ranges(&report.orphan_frames));
+    }
+
+    if report.leaked == 0 {
+        println!("No leaks found");
+    } else {
+        println!("Leaked memory: {} bytes", report.leaked);
+    }
+}
+
+fn cmd_ipcs() {
+    let IpcTables {
+        segments,
+        pipes,
+        queues,
+    } = match syscall::ipcinfo(&process()) {
+        Ok(tables) => tables,
+        Err(errno) => {
+            println!("ipcs: {}", errno);
+            return;
+        }
+    };
+
+    println!("------ Shared Memory Segments ------");
+    if segments.is_empty() {
+        println!("No shared memory segments");
+    } else {
+        println!(
+            "{:<6} {:<20} {:>12} {:>6} {:>7} STATUS",
+            "ID", "NAME", "BYTES", "PAGES", "NATTCH"
+        );
+    }
+    segments.iter().for_each(|segment| {
+        println!(
+            "{:<6} {:<20} {:>12} {:>6} {:>7} {}",
+            segment.id,
+            segment.name,
+            segment.size,
+            segment.pages,
+            segment.attached,
+            if segment.unlinked { "dest" } else { "" }
+        );
+    });
+
+    println!("------ Pipes and FIFOs ------");
+    if pipes.is_empty() {
+        println!("No pipes");
+    } else {
+        println!(
+            "{:<6} {:<20} {:>12} {:>12} {:>7} {:>7}",
+            "ID", "NAME", "BUFFERED", "CAPACITY", "READERS", "WRITERS"
+        );
+    }
+    pipes.iter().for_each(|pipe| {
+        println!(
+            "{:<6} {:<20} {:>12} {:>12} {:>7} {:>7}",
+            pipe.id,
+            pipe.name.as_deref().unwrap_or("(anonymous)"),
+            pipe.buffered,
+            pipe.capacity,
+            pipe.readers,
+            pipe.writers
+        );
+    });
+
+    println!("------ Message Queues ------");
+    if queues.is_empty() {
+        println!("No message queues");
+    } else {
+        println!(
+            "{:<6} {:<20} {:>8} {:>12} {:>8} {:>8}",
+            "ID", "NAME", "MESSAGES", "BYTES", "MAXMSG", "MSGSIZE"
+        );
+    }
+    queues.iter().for_each(|queue| {
+        println!(
+            "{:<6} {:<20} {:>8} {:>12} {:>8} {:>8}",
+            queue.id, queue.name, queue.messages, queue.bytes, queue.max_messages, queue.max_size
+        );
+    });
+}
+
+fn cmd_locks() {
+    let LockTables { objects, edges } = match syscall::lockinfo(&process()) {
+        Ok(tables) => tables,
+        Err(errno) => {
+            println!("locks: {}", errno);
+            return;
+        }
+    };
+
+    let pids = |pids: &[u32]| match pids {
+        [] => "-".to_string(),
+        pids => pids
+            .iter()
+            .map(|pid| pid.to_string())
+            .collect::<Vec<String>>()
+            .join(","),
+    };
+    if objects.is_empty() {
+        println!("No synchronization objects");
+        return;
+    }
+    println!(
+        "{:<6} {:<20} {:<10} {:>6} {:<12} WAITERS",
+        "ID", "NAME", "TYPE", "OWNER", "HOLDERS"
+    );
+    objects.iter().for_each(|object| {
+        let holders = match object.count {
+            Some(count) => format!("count {}", count),
+            None => pids(&object.holders),
+        };
+        println!(
+            "{:<6} {:<20} {:<10} {:>6} {:<12} {}",
+            object.id,
+            object.name,
+            object.kind,
+            object.owner,
+            holders,
+            pids(&object.waiters)
+        );
+    });
+
+    println!("------ Wait-for graph ------");
+    if edges.is_empty() {
+        println!("No process waits for another");
+    }
+    edges.iter().for_each(|(waiter, holder, id)| {
+        let object = objects.iter().find(|object| object.id == *id).unwrap();
+        println!("{} -> {} ({} {})", waiter, holder, object.kind, object.name);
+    });
+}
+
+/**
+ * Process the running command makes its system calls as: the job calling
+ * it, or the shell itself.
+ */
+fn process() -> vpm::Vpm {
+    vpm::current().unwrap_or_else(shell)
+}
+
+/**
+ * Process of the shell itself, the one the file system was mounted by.
+ */
+fn shell() -> vpm::Vpm {
+    VFS.read().unwrap().vpm.clone()
+}
+
+/**
+ * Process holding the terminal through tcgetpgrp, None for the shell.
+ */
+fn foreground(process: &vpm::Vpm) -> Option<u32> {
+    match syscall::syscall(process, syscall::SYS_TCGETPGRP, &[]) {
+        Ok(Value::Int(pid)) if pid > 0 => Some(pid as u32),
+        _ => None,
+    }
+}
+
+/**
+ * Wait for a child through wait4. Returns its exit code, or None when it
+ * stopped under WUNTRACED.
+ */
+fn wait_child(process: &vpm::Vpm, pid: u32, options: i64) -> Result<Option<i32>, Errno> {
+    let args = [Value::Int(pid as i64), Value::Int(options)];
+    match syscall::syscall(process, syscall::SYS_WAIT4, &args)? {
+        Value::List(status) => match status.as_slice() {
+            [_, _, Value::Int(1)] => Ok(None),
+            [_, Value::Int(code), _] => Ok(Some(*code as i32)),
+            _ => Err(Errno::EIO),
+        },
+        _ => Err(Errno::EIO),
+    }
+}
+
+/**
+ * Send a signal to a process through the kill system call.
+ */
+fn send_signal(process: &vpm::Vpm, pid: u32, signal: Signal) -> Result<(), Errno> {
+    let args = [Value::Int(pid as i64), Value::Int(signal as i64)];
+    syscall::syscall(process, syscall::SYS_KILL, &args).map(|_| ())
+}
+
+/**
+ * Run func on behalf of the job calling it, or in a foreground child of the
+ * shell, so that a blocking IPC call can be interrupted by a signal or
+ * stopped into a job while the shell keeps its prompt.
+ */
+fn run_blocking<F>(cmdline: &str, func: F)
+where
+    F: FnOnce(&vpm::Vpm) + Send + 'static,
+{
+    match vpm::current() {
+        Some(process) => func(&process),
+        None => run_foreground(cmdline, move |process| {
+            func(process);
+            0
+        }),
+    }
+}
+
+fn cmd_mq(args: &str) {
+    let usage =
+        "Usage: mq send <queue> [-p <priority>] <message> | mq recv <queue> | mq rm <queue>";
+    let mut words = args.splitn(3, ' ');
+    let (command, name) = match (words.next(), words.next()) {
+        (Some(command), Some(name)) if !name.is_empty() => (command, name.to_string()),
+        _ => {
+            println!("{}", usage);
+            return;
+        }
+    };
+    let rest = words.next().unwrap_or("").trim();
+
+    if command == "rm" {
+        let args = [Value::Str(name.clone())];
+        if let Err(errno) = syscall::syscall(&process(), syscall::SYS_MQ_UNLINK, &args) {
+            println!("mq: {}: {}", name, errno);
+        }
+        return;
+    }
+
+    let args = [
+        Value::Str(name.clone()),
+        Value::Int(ipc::DEFAULT_MQ_MAX_MESSAGES as i64),
+        Value::Int(ipc::DEFAULT_MQ_MAX_SIZE as i64),
+    ];
+    let id = match syscall::syscall(&process(), syscall::SYS_MQ_OPEN, &args) {
+        Ok(id) => id,
+        Err(errno) => {
+            println!("mq: {}: {}", name, errno);
+            return;
+        }
+    };
+    match command {
+        "send" => {
+            let (priority, message) = match rest.strip_prefix("-p") {
+                Some(rest) => {
+                    let (priority, message) =
+                        rest.trim().split_once(' ').unwrap_or((rest.trim(), ""));
+                    match priority.parse::<u32>() {
+                        Ok(priority) => (priority, message.trim().to_string()),
+                        Err(_) => {
+                            println!("{}", usage);
+                            return;
+                        }
+                    }
+                }
+                None => (0, rest.to_string()),
+            };
+            run_blocking(&format!("mq send {}", name), move |process| {
+                let args = [
+                    id,
+                    Value::Bytes(message.into_bytes()),
+                    Value::Int(priority as i64),
+                ];
+                // A process interrupted by a signal has nothing left to say
+                match syscall::syscall(process, syscall::SYS_MQ_TIMEDSEND, &args) {
+                    Ok(_) | Err(Errno::EINTR) => {}
+                    Err(errno) => println!("mq: {}: {}", name, errno),
+                }
+            });
+        }
+        "recv" => run_blocking(
+            &format!("mq recv {}", name),
+            move |process| match syscall::syscall(process, syscall::SYS_MQ_TIMEDRECEIVE, &[id]) {
+                Ok(Value::List(message)) => {
+                    if let [Value::Bytes(bytes), Value::Int(priority)] = message.as_slice() {
+                        println!("[{}] {}", priority, String::from_utf8_lossy(bytes));
+                    }
+                }
+                Ok(_) => {}
+                Err(Errno::EINTR) => {}
+                Err(errno) => println!("mq: {}: {}", name, errno),
+            },
+        ),
+        _ => println!("{}", usage),
+    }
+}
+
+fn cmd_wait(pid: &str) {
+    let pid = match pid {
+        "" => -1,
+        pid => match pid.parse::<u32>() {
+            Ok(pid) => pid as i64,
+            Err(_) => {
+                println!("Usage: wait [pid]");
+                return;
+            }
+        },
+    };
+    let args = [Value::Int(pid), Value::Int(0)];
+    match syscall::syscall(&process(), syscall::SYS_WAIT4, &args) {
+        Ok(Value::List(status)) => match status.as_slice() {
+            [Value::Int(0), ..] => {}
+            [Value::Int(pid), Value::Int(code), _] => {
+                println!("{} exited with code {}", pid, code)
+            }
+            _ => {}
+        },
+        Ok(_) => {}
+        Err(errno) => println!("wait: {}", errno),
+    }
+}
+
+fn cmd_sched(policy: &str) {
+    if !policy.is_empty() {
+        let policy = match Policy::from_str(policy) {
+            Some(policy) => policy,
+            None => {
+                println!("Usage: sched [rr|priority|mlfq|cfs]");
+                return;
+            }
+        };
+        let args = [Value::Int(policy as i64)];
+        if let Err(errno) = syscall::syscall(&process(), syscall::SYS_SCHED_SETPOLICY, &args) {
+            println!("sched: {}", errno);
+            return;
+        }
+    }
+
+    let process = process();
+    let (scheduler, processes) = match syscall::sched_info(&process)
+        .and_then(|scheduler| Ok((scheduler, syscall::processes(&process)?)))
+    {
+        Ok(snapshot) => snapshot,
+        Err(errno) => {
+            println!("sched: {}", errno);
+            return;
+        }
+    };
+    println!(
+        "Policy: {}, {} ticks of {} ms, {} idle, {} context switches",
+        scheduler.policy,
+        scheduler.ticks,
+        crate::scheduler::TICK.as_millis(),
+        scheduler.idle_ticks,
+        scheduler.context_switches
+    );
+    println!(
+        "{:>5} {:>2} {:>3} {:>5} {:>10} {:>8} CMD",
+        "PID", "S", "NI", "LEVEL", "VRUNTIME", "TIME"
+    );
+    processes.iter().for_each(|process| {
+        println!(
+            "{:>5} {:>2} {:>3} {:>5} {:>10} {:>8.2} {}",
+            process.pid,
+            process.state,
+            process.nice,
+            process.level,
+            process.vruntime,
+            process.cpu_time.as_secs_f64(),
+            process.cmdline
+        );
+    });
 }
 
-fn cmd_top() {
-    let vfs = VFS.read().unwrap().clone();
-    vfs.vpm.show_processes()
-}
\ No newline at end of file
+/**
+ * Children inherit the nice value of the shell, so the shell runs the
+ * command with its own nice value raised for the duration.
+ */
+async fn cmd_nice(args: &str) {
+    let (increment, command) = match args.strip_prefix("-n") {
+        Some(rest) => {
+            let rest = rest.trim_start();
+            let (increment, command) = rest.split_once(' ').unwrap_or((rest, ""));
+            match increment.parse::<i32>() {
+                Ok(increment) => (increment, command.trim()),
+                Err(_) => {
+                    println!("Usage: nice [-n <nice>] <command>");
+                    return;
+                }
+            }
+        }
+        None => (10, args),
+    };
+    let command = match ShellCommand::from_str(command) {
+        Some(command) => command,
+        None => {
+            println!("nice: unknown command: {}", command);
+            return;
+        }
+    };
+
+    // The command is forked from the shell and inherits its nice value
+    let shell = shell();
+    let pid = shell.pid as i64;
+    let nice = match syscall::syscall(&shell, syscall::SYS_GETPRIORITY, &[Value::Int(pid)]) {
+        Ok(Value::Int(nice)) => nice,
+        _ => 0,
+    };
+    let niced = (nice + increment as i64).clamp(vpm::MIN_NICE as i64, vpm::MAX_NICE as i64);
+    let set_nice = |nice| {
+        let args = [Value::Int(pid), Value::Int(nice)];
+        syscall::syscall(&shell, syscall::SYS_SETPRIORITY, &args).ok();
+    };
+    set_nice(niced);
+    Box::pin(command.execute()).await;
+    set_nice(nice);
+}
+
+fn cmd_renice(args: &str) {
+    let args: Vec<&str> = args
+        .split_whitespace()
+        .filter(|&arg| arg != "-n" && arg != "-p")
+        .collect();
+    let parsed = match args.as_slice() {
+        [nice, pid] => nice.parse::<i32>().ok().zip(pid.parse::<u32>().ok()),
+        _ => None,
+    };
+    let (nice, pid) = match parsed {
+        Some(parsed) => parsed,
+        None => {
+            println!("Usage: renice <nice> <pid>");
+            return;
+        }
+    };
+    let process = process();
+    let args = [Value::Int(pid as i64)];
+    let result = syscall::syscall(&process, syscall::SYS_GETPRIORITY, &args).and_then(|old| {
+        let args = [Value::Int(pid as i64), Value::Int(nice as i64)];
+        syscall::syscall(&process, syscall::SYS_SETPRIORITY, &args)?;
+        Ok(old)
+    });
+    match result {
+        Ok(old) => println!("{}: old priority {}, new priority {}", pid, old, nice),
+        Err(errno) => println!("renice: {}", errno),
+    }
+}
+
+fn cmd_kill(args: &str) {
+    if args == "-l" {
+        let names: Vec<String> = SIGNALS
+            .iter()
+            .map(|signal| format!("{}) {}", *signal as i32, signal))
+            .collect();
+        println!("{}", names.join(" "));
+        return;
+    }
+
+    let (signal, pids) = match args.strip_prefix('-') {
+        Some(rest) => {
+            let (signal, pids) = rest.split_once(' ').unwrap_or((rest, ""));
+            (Signal::from_str(signal), pids)
+        }
+        None => (Some(Signal::SIGTERM), args),
+    };
+    let pids: Option<Vec<u32>> = pids
+        .split_whitespace()
+        .map(|pid| pid.parse::<u32>().ok())
+        .collect();
+    let (signal, pids) = match (signal, pids) {
+        (Some(signal), Some(pids)) if !pids.is_empty() => (signal, pids),
+        _ => {
+            println!("Usage: kill [-<signal>] <pid>...");
+            return;
+        }
+    };
+
+    let process = process();
+    pids.iter().for_each(|&pid| {
+        if let Err(errno) = send_signal(&process, pid, signal) {
+            println!("kill: ({}) - {}", pid, errno);
+        }
+    });
+}
+
+/**
+ * Set the action of signals for the shell, inherited by the processes it
+ * starts afterwards. Without arguments, list the signals not left to
+ * their default action.
+ */
+fn cmd_trap(args: &str) {
+    let process = process();
+    if args.is_empty() {
+        SIGNALS
+            .iter()
+            .filter(|signal| signal.catchable())
+            .for_each(|&signal| {
+                let args = [Value::Int(signal as i64), Value::Int(syscall::SIG_QUERY)];
+                match syscall::syscall(&process, syscall::SYS_RT_SIGACTION, &args) {
+                    Ok(Value::Int(syscall::SIG_IGN)) => println!("trap ignore {}", signal),
+                    Ok(Value::Int(syscall::SIG_LOG)) => println!("trap log {}", signal),
+                    _ => {}
+                }
+            });
+        return;
+    }
+
+    let (action, signals) = args.split_once(' ').unwrap_or((args, ""));
+    let action = match action {
+        "default" => Some(syscall::SIG_DFL),
+        "ignore" => Some(syscall::SIG_IGN),
+        "log" => Some(syscall::SIG_LOG),
+        _ => None,
+    };
+    let signals: Option<Vec<Signal>> = signals.split_whitespace().map(Signal::from_str).collect();
+    let (action, signals) = match (action, signals) {
+        (Some(action), Some(signals)) if !signals.is_empty() => (action, signals),
+        _ => {
+            println!("Usage: trap [default|ignore|log <signal>...]");
+            return;
+        }
+    };
+
+    signals.iter().for_each(|&signal| {
+        let args = [Value::Int(signal as i64), Value::Int(action)];
+        if let Err(errno) = syscall::syscall(&process, syscall::SYS_RT_SIGACTION, &args) {
+            println!("trap: {}: {}", signal, errno);
+        }
+    });
+}
+
+/**
+ * Run the command in a child process of the shell without waiting for it.
+ */
+fn cmd_background(input: &str) {
+    let command = match ShellCommand::from_str(input) {
+        Some(command) => command,
+        None => {
+            println!("Unknown command: {}", input);
+            return;
+        }
+    };
+    let runtime = tokio::runtime::Handle::current();
+    let mut shell = shell();
+    let result = shell.execute_child(input, move |_| {
+        runtime.block_on(command.execute());
+        0
+    });
+    let pid = match result {
+        Ok(pid) => pid,
+        Err(errno) => {
+            println!("{}: fork: {}", input, errno);
+            return;
+        }
+    };
+    let id = JOBS.lock().unwrap().add(pid, input);
+    println!("[{}] {}", id, pid);
+}
+
+/**
+ * Notify the jobs that finished since the last prompt.
+ */
+fn report_jobs() {
+    let shell = shell();
+    let finished = JOBS.lock().unwrap().reap(&shell);
+    finished.iter().for_each(|(job, marker, status)| {
+        println!("[{}]{}  {:<24}{}", job.id, marker, status, job.cmdline);
+    });
+}
+
+fn cmd_jobs() {
+    let jobs = JOBS.lock().unwrap();
+    jobs.jobs().iter().for_each(|job| {
+        let status = JobTable::status(job);
+        let background = if status == "Running" { " &" } else { "" };
+        println!(
+            "[{}]{}  {:<24}{}{}",
+            job.id,
+            jobs.marker(job.id),
+            status,
+            job.cmdline,
+            background
+        );
+    });
+}
+
+fn cmd_fg(spec: &str) {
+    let job = match JOBS.lock().unwrap().get(spec) {
+        Some(job) => job,
+        None => {
+            println!(
+                "fg: {}: no such job",
+                if spec.is_empty() { "current" } else { spec }
+            );
+            return;
+        }
+    };
+    println!("{}", job.cmdline);
+
+    let shell = shell();
+    send_signal(&shell, job.pid, Signal::SIGCONT).ok();
+    match wait_foreground(job.pid) {
+        Ok(Some(code)) => {
+            JOBS.lock().unwrap().remove(job.pid);
+            if Signal::from_exit_code(code).is_some() {
+                println!("{}", jobs::exit_status(code));
+            }
+        }
+        Ok(None) => {
+            let marker = JOBS.lock().unwrap().marker(job.id);
+            println!();
+            println!("[{}]{}  {:<24}{}", job.id, marker, "Stopped", job.cmdline);
+        }
+        Err(errno) => {
+            JOBS.lock().unwrap().remove(job.pid);
+            println!("fg: {}", errno);
+        }
+    }
+}
+
+/**
+ * Give the terminal to a child of the shell until it exits or stops.
+ * Returns its exit code, or None when it stopped.
+ */
+fn wait_foreground(pid: u32) -> Result<Option<i32>, Errno> {
+    let shell = shell();
+    let tcsetpgrp = |pid: i64| syscall::syscall(&shell, syscall::SYS_TCSETPGRP, &[Value::Int(pid)]);
+    let background = tcsetpgrp(pid as i64)?;
+    let status = wait_child(&shell, pid, syscall::WUNTRACED);
+    tcsetpgrp(background.int()?).ok();
+    status
+}
+
+fn cmd_bg(spec: &str) {
+    let jobs = JOBS.lock().unwrap();
+    let job = match jobs.get(spec) {
+        Some(job) => job,
+        None => {
+            println!(
+                "bg: {}: no such job",
+                if spec.is_empty() { "current" } else { spec }
+            );
+            return;
+        }
+    };
+    let shell = shell();
+    match send_signal(&shell, job.pid, Signal::SIGCONT) {
+        Ok(()) => println!("[{}]{} {} &", job.id, jobs.marker(job.id), job.cmdline),
+        Err(errno) => println!("bg: {}", errno),
+    }
+}
+
+/**
+ * Split "args > file" or "args >> file" into the arguments and the target
+ * file, with whether to append to it.
+ */
+fn split_redirect(args: &str) -> (&str, Option<(&str, bool)>) {
+    if let Some((args, file)) = args.split_once(">>") {
+        return (args.trim(), Some((file.trim(), true)));
+    }
+    if let Some((args, file)) = args.split_once('>') {
+        return (args.trim(), Some((file.trim(), false)));
+    }
+    (args, None)
+}
+
+/**
+ * Print the output of a command or store it in the redirection file.
+ */
+fn emit(output: &str, redirect: Option<(&str, bool)>) {
+    let (file, append) = match redirect {
+        Some(("", _)) => {
+            println!("Missing file name after redirection");
+            return;
+        }
+        Some(redirect) => redirect,
+        None => {
+            let args = [Value::Int(1), Value::Bytes(output.as_bytes().to_vec())];
+            syscall::syscall(&process(), syscall::SYS_WRITE, &args).ok();
+            return;
+        }
+    };
+    let output = output.as_bytes().to_vec();
+    let file = file.to_string();
+    // Opening a fifo waits for a reader, in a process that can be interrupted
+    run_blocking(&format!("write {}", file), move |process| {
+        let sys = |number, args: &[Value]| syscall::syscall(process, number, args);
+        let mode = if append {
+            syscall::O_APPEND
+        } else {
+            syscall::O_TRUNC
+        };
+        let flags = Value::Int(syscall::O_WRONLY | syscall::O_CREAT | mode);
+        // Written to the file's own fd, the stdout of a script or a job
+        // running the command stays where it was
+        let result = sys(syscall::SYS_OPEN, &[Value::Str(file.clone()), flags]).and_then(|fd| {
+            let written = sys(syscall::SYS_WRITE, &[fd.clone(), Value::Bytes(output)]);
+            sys(syscall::SYS_CLOSE, &[fd])?;
+            written
+        });
+        match result {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(errno) => println!("Cannot write {}: {}", file, errno),
+        }
+    });
+}
+
+fn cmd_ps(args: &str) {
+    let (args, redirect) = split_redirect(args);
+    let shell = shell().pid;
+    let output = syscall::processes(&process())
+        .map_err(|errno| format!("ps: {}", errno))
+        .and_then(|processes| ps::ps(args, shell, &processes));
+    match output {
+        Ok(output) => emit(&output, redirect),
+        Err(error) => println!("{}", error),
+    }
+}
+
+fn cmd_pstree(args: &str) {
+    let (args, redirect) = split_redirect(args);
+    let output = syscall::processes(&process())
+        .map_err(|errno| format!("pstree: {}", errno))
+        .and_then(|processes| ps::pstree(args, &processes));
+    match output {
+        Ok(output) => emit(&output, redirect),
+        Err(error) => println!("{}", error),
+    }
+}
+
+/**
+ * strace <command> runs the command in a traced child of the shell, which
+ * becomes a job if it is stopped. -p and -d trace a running process.
+ */
+fn cmd_strace(args: &str) {
+    let usage = "Usage: strace <command> | strace -p <pid> | strace -d <pid>";
+    let attach = match args.split_once(' ') {
+        Some(("-p", pid)) => Some((pid.trim(), true)),
+        Some(("-d", pid)) => Some((pid.trim(), false)),
+        _ => None,
+    };
+    if let Some((pid, enabled)) = attach {
+        match pid.parse::<u32>().ok().and_then(vpm::process) {
+            Some(process) => {
+                syscall::trace(process.pid, enabled);
+                let action = if enabled { "attached" } else { "detached" };
+                println!("strace: Process {} {}", process.pid, action);
+            }
+            None => println!("{}", usage),
+        }
+        return;
+    }
+
+    if args.is_empty() {
+        println!("{}", usage);
+        return;
+    }
+    let command = match ShellCommand::from_str(args) {
+        Some(command) => command,
+        None => {
+            println!("strace: unknown command: {}", args);
+            return;
+        }
+    };
+    let runtime = tokio::runtime::Handle::current();
+    run_foreground(args, move |process| {
+        syscall::trace(process.pid, true);
+        runtime.block_on(command.execute());
+        syscall::trace(process.pid, false);
+        0
+    });
+}
+
+/**
+ * Run func in a child of the shell holding the terminal, like a command
+ * typed without &. The child becomes a job if it is stopped.
+ */
+fn run_foreground<F>(cmdline: &str, func: F)
+where
+    F: FnOnce(&vpm::Vpm) -> i32 + Send + 'static,
+{
+    let mut shell = shell();
+    let pid = match shell.execute_child(cmdline, func) {
+        Ok(pid) => pid,
+        Err(errno) => {
+            println!("{}: fork: {}", cmdline, errno);
+            return;
+        }
+    };
+    match wait_foreground(pid) {
+        Ok(Some(code)) => {
+            if Signal::from_exit_code(code).is_some() {
+                println!("{}", jobs::exit_status(code));
+            }
+        }
+        Ok(None) => {
+            let mut jobs = JOBS.lock().unwrap();
+            let id = jobs.add(pid, cmdline);
+            println!();
+            println!("[{}]{}  {:<24}{}", id, jobs.marker(id), "Stopped", cmdline);
+        }
+        Err(errno) => println!("{}: {}", cmdline, errno),
+    }
+}
+
+fn cmd_chmod(args: &str) {
+    let usage = "Usage: chmod +x|-x|<mode> <file>";
+    let (mode, file) = match args.split_once(' ') {
+        Some((mode, file)) if !file.trim().is_empty() => (mode, file.trim()),
+        _ => {
+            println!("{}", usage);
+            return;
+        }
+    };
+    let mode = match mode {
+        "+x" => 0o755,
+        "-x" => 0o644,
+        mode => match i64::from_str_radix(mode, 8) {
+            Ok(mode) => mode,
+            Err(_) => {
+                println!("{}", usage);
+                return;
+            }
+        },
+    };
+    let args = [Value::Str(file.to_string()), Value::Int(mode)];
+    if let Err(errno) = syscall::syscall(&process(), syscall::SYS_CHMOD, &args) {
+        println!("chmod: {}: {}", file, errno);
+    }
+}
+
+/**
+ * Write an executable to a file of the current directory and make it
+ * executable.
+ */
+fn write_executable(process: &vpm::Vpm, name: &str, executable: &Executable) -> Result<(), Errno> {
+    let sys = |number, args: &[Value]| syscall::syscall(process, number, args);
+    let flags = syscall::O_WRONLY | syscall::O_CREAT | syscall::O_TRUNC;
+    let fd = sys(
+        syscall::SYS_OPEN,
+        &[Value::Str(name.to_string()), Value::Int(flags)],
+    )?;
+    let written = sys(
+        syscall::SYS_WRITE,
+        &[fd.clone(), Value::Bytes(executable.to_bytes())],
+    );
+    sys(syscall::SYS_CLOSE, &[fd])?;
+    written?;
+    sys(
+        syscall::SYS_CHMOD,
+        &[Value::Str(name.to_string()), Value::Int(0o755)],
+    )?;
+    Ok(())
+}
+
+/**
+ * kasm <source> [-o <output>]: assemble a source file into an executable
+ * of the current directory, named after the source without its extension
+ * by default. Every error is reported with its line number.
+ */
+fn cmd_kasm(args: &str) {
+    let usage = "Usage: kasm <source> [-o <output>]";
+    let words: Vec<&str> = args.split_whitespace().collect();
+    let (source, output) = match words.as_slice() {
+        [source] => match PathBuf::from(source)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+        {
+            Some(stem) if stem != *source => (*source, stem.to_string()),
+            _ => {
+                println!("kasm: {}: no extension, give the output with -o", source);
+                return;
+            }
+        },
+        [source, "-o", output] | ["-o", output, source] => (*source, output.to_string()),
+        _ => {
+            println!("{}", usage);
+            return;
+        }
+    };
+    if output.contains('/') {
+        println!("kasm: {}: the output goes to the current directory", output);
+        return;
+    }
+
+    let process = process();
+    let text = match syscall::read_all(&process, source) {
+        Ok(text) => text,
+        Err(errno) => {
+            println!("kasm: {}: {}", source, errno);
+            return;
+        }
+    };
+    let executable = match asm::assemble(&String::from_utf8_lossy(&text)) {
+        Ok(executable) => executable,
+        Err(errors) => {
+            errors
+                .iter()
+                .for_each(|error| println!("{}:{}: {}", source, error.line, error.message));
+            println!(
+                "kasm: {} error{}",
+                errors.len(),
+                if errors.len() == 1 { "" } else { "s" }
+            );
+            return;
+        }
+    };
+    if let Err(errno) = write_executable(&process, &output, &executable) {
+        println!("kasm: {}: {}", output, errno);
+        return;
+    }
+    let size = |kind| {
+        executable
+            .segments
+            .iter()
+            .find(|segment| segment.kind == kind)
+            .map_or(0, |segment| segment.bytes.len())
+    };
+    println!(
+        "kasm: {}: {} instructions, {} bytes of data, {} symbols",
+        output,
+        size(SegmentKind::Text) / cpu::INSTRUCTION_SIZE as usize,
+        size(SegmentKind::Data),
+        executable.symbols.len()
+    );
+}
+
+/**
+ * kdis [-t] <program>: disassemble a program of the current directory or
+ * of /bin, only showing its symbol table with -t.
+ */
+fn cmd_kdis(args: &str) {
+    let (symbols_only, name) = match args.split_whitespace().collect::<Vec<&str>>().as_slice() {
+        [name] => (false, name.to_string()),
+        ["-t", name] => (true, name.to_string()),
+        _ => {
+            println!("Usage: kdis [-t] <program>");
+            return;
+        }
+    };
+    let path = match syscall::stat(&process(), &name) {
+        Ok(_) => name.clone(),
+        Err(_) => exec::path(&name),
+    };
+    let executable =
+        syscall::read_all(&process(), &path).and_then(|bytes| Executable::parse(&bytes));
+    match executable {
+        Ok(executable) => disasm::print(&name, &executable, symbols_only),
+        Err(errno) => println!("kdis: {}: {}", name, errno),
+    }
+}
+
+/**
+ * hexdump [-s <offset>] [-n <length>] <file> and
+ * xxd [-s <offset>] [-l <length>] <file>: dump length bytes of any file
+ * from offset, all of them by default. Numbers can be 0x hexadecimal.
+ */
+fn cmd_dump(command: &str, args: &str) {
+    let length_option = if command == "xxd" { "-l" } else { "-n" };
+    let usage = format!(
+        "Usage: {} [-s <offset>] [{} <length>] <file>",
+        command, length_option
+    );
+    let (args, redirect) = split_redirect(args);
+    let number = |value: &str| match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    };
+    let (mut offset, mut length, mut file) = (0, None, None);
+    let mut words = args.split_whitespace();
+    while let Some(word) = words.next() {
+        match word {
+            "-s" => match words.next().and_then(number) {
+                Some(value) => offset = value,
+                None => return println!("{}", usage),
+            },
+            _ if word == length_option => match words.next().and_then(number) {
+                Some(value) => length = Some(value),
+                None => return println!("{}", usage),
+            },
+            _ if file.is_none() && !word.starts_with('-') => file = Some(word),
+            _ => return println!("{}", usage),
+        }
+    }
+    let Some(file) = file else {
+        return println!("{}", usage);
+    };
+
+    let content = match syscall::read_all(&process(), file) {
+        Ok(content) => content,
+        Err(errno) => return println!("{}: {}: {}", command, file, errno),
+    };
+    let start = offset.min(content.len());
+    let end = length.map_or(content.len(), |length| {
+        start.saturating_add(length).min(content.len())
+    });
+    let output = match command {
+        "xxd" => hexdump::xxd(&content[start..end], start),
+        _ => hexdump::hexdump(&content[start..end], start),
+    };
+    emit(&output, redirect);
+}
+
+/**
+ * Run a program. A job was forked for its command, so the program replaces
+ * it; typed at the prompt, it runs in a foreground child of the shell.
+ */
+async fn cmd_exec(cmdline: &str) {
+    match vpm::current() {
+        Some(process) => {
+            let code = exec_program(&process, cmdline).await;
+            process.exit(code);
+        }
+        None => {
+            let runtime = tokio::runtime::Handle::current();
+            let command = cmdline.to_string();
+            run_foreground(cmdline, move |process| {
+                runtime.block_on(exec_program(process, &command))
+            });
+        }
+    }
+}
+
+/**
+ * Load the program of the command line in the process and run it,
+ * returning its exit code.
+ */
+async fn exec_program(process: &vpm::Vpm, cmdline: &str) -> i32 {
+    let args: Vec<&str> = cmdline.split_whitespace().collect();
+    let name = args[0];
+    let executable = match exec::load(name) {
+        Ok(executable) => executable,
+        Err(errno) => {
+            println!("{}: {}", name, errno);
+            return match errno {
+                Errno::ENOENT => exec::EXIT_NOT_FOUND,
+                _ => exec::EXIT_NOT_EXECUTABLE,
+            };
+        }
+    };
+    let addresses = match process.exec(cmdline, &executable) {
+        Ok(addresses) => addresses,
+        Err(errno) => {
+            println!("{}: {}", name, errno);
+            return exec::EXIT_NOT_EXECUTABLE;
+        }
+    };
+
+    let interpreter = executable
+        .interpreter
+        .as_deref()
+        .and_then(|interpreter| interpreter.split_whitespace().next())
+        .map(|interpreter| interpreter.rsplit('/').next().unwrap());
+    match (executable.kind, interpreter) {
+        (ProgramKind::Script, Some("ksh")) => {
+            Box::pin(run_script(process, executable.text(), &args)).await
+        }
+        (ProgramKind::Script, Some(interpreter)) => {
+            println!(
+                "{}: bad interpreter {}: {}",
+                name,
+                interpreter,
+                Errno::ENOEXEC
+            );
+            exec::EXIT_NOT_EXECUTABLE
+        }
+        (ProgramKind::Bytecode, _) => cpu::run(process, &executable, &addresses, &args),
+        (ProgramKind::Script, None) => {
+            println!("{}: {}", name, Errno::ENOEXEC);
+            exec::EXIT_NOT_EXECUTABLE
+        }
+    }
+}
+
+/**
+ * ksh, the interpreter of shell scripts. Every line is a command, $0 to $9
+ * expand to the arguments and # starts a comment. Built-in commands run in
+ * the script process, programs in a child it waits for. exit [code] ends
+ * the script, which otherwise exits with the status of its last command.
+ */
+async fn run_script(process: &vpm::Vpm, source: &[u8], args: &[&str]) -> i32 {
+    let source = String::from_utf8_lossy(source);
+    let mut status = 0;
+    for (number, line) in source.lines().enumerate() {
+        if !process.yield_cpu() {
+            break;
+        }
+        let mut expanded = String::new();
+        let mut chars = line.trim().chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek().and_then(|next| next.to_digit(10))) {
+                ('$', Some(index)) => {
+                    chars.next();
+                    expanded.push_str(args.get(index as usize).copied().unwrap_or(""));
+                }
+                _ => expanded.push(c),
+            }
+        }
+        let line = expanded.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "exit" || line.starts_with("exit ") {
+            return line["exit".len()..].trim().parse::<i32>().unwrap_or(status);
+        }
+
+        status = match ShellCommand::from_str(line) {
+            Some(ShellCommand::Exec(cmdline)) => {
+                let runtime = tokio::runtime::Handle::current();
+                let mut parent = process.clone();
+                let name = cmdline.clone();
+                let pid = match parent.execute_child(&name, move |child| {
+                    runtime.block_on(exec_program(child, &cmdline))
+                }) {
+                    Ok(pid) => pid,
+                    Err(errno) => {
+                        println!(
+                            "{}: line {}: {}: fork: {}",
+                            args[0],
+                            number + 1,
+                            name,
+                            errno
+                        );
+                        status = 1;
+                        continue;
+                    }
+                };
+                match wait_child(process, pid, 0) {
+                    Ok(Some(code)) => code,
+                    _ => 0,
+                }
+            }
+            Some(command) => {
+                Box::pin(command.execute()).await;
+                0
+            }
+            None => {
+                println!(
+                    "{}: line {}: unknown command: {}",
+                    args[0],
+                    number + 1,
+                    line
+                );
+                exec::EXIT_NOT_FOUND
+            }
+        };
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_ranges_join_consecutive_frames() {
+        assert_eq!(frame_ranges(&[3, 4, 5, 6, 9]), "3-6,9");
+        assert_eq!(frame_ranges(&[]), "");
+        assert_eq!(frame_ranges(&[7]), "7");
+        assert_eq!(frame_ranges(&[1, 3, 4]), "1,3-4");
+    }
+}
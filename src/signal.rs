@@ -0,0 +1,213 @@
+/**
+ * Signals, numbered like their POSIX counterparts
+ *
+ * Default actions are carried out by the kernel as soon as the signal is
+ * sent, handlers run in the process itself at its next preemption point.
+ */
+use std::{fmt, sync::Arc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Signal {
+    SIGINT = 2,
+    SIGILL = 4,
+    SIGFPE = 8,
+    SIGKILL = 9,
+    SIGSEGV = 11,
+    SIGTERM = 15,
+    SIGCHLD = 17,
+    SIGCONT = 18,
+    SIGSTOP = 19,
+    SIGTSTP = 20,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+pub const SIGNALS: [Signal; 10] = [
+    Signal::SIGINT,
+    Signal::SIGILL,
+    Signal::SIGFPE,
+    Signal::SIGKILL,
+    Signal::SIGSEGV,
+    Signal::SIGTERM,
+    Signal::SIGCHLD,
+    Signal::SIGCONT,
+    Signal::SIGSTOP,
+    Signal::SIGTSTP,
+];
+
+impl Signal {
+    /**
+     * Parse a signal number or name, with or without the SIG prefix.
+     */
+    pub fn from_str(signal: &str) -> Option<Self> {
+        if let Ok(number) = signal.parse::<i64>() {
+            return Self::from_i64(number);
+        }
+        let name = signal.to_uppercase();
+        let name = name.strip_prefix("SIG").unwrap_or(&name);
+        SIGNALS.iter().copied().find(|s| s.name() == name)
+    }
+
+    /**
+     * Signal of the given number, as passed to the system calls.
+     */
+    pub fn from_i64(number: i64) -> Option<Self> {
+        SIGNALS.iter().copied().find(|&s| s as i64 == number)
+    }
+
+    /**
+     * Name without the SIG prefix, as listed by kill -l.
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::SIGINT => "INT",
+            Self::SIGILL => "ILL",
+            Self::SIGFPE => "FPE",
+            Self::SIGKILL => "KILL",
+            Self::SIGSEGV => "SEGV",
+            Self::SIGTERM => "TERM",
+            Self::SIGCHLD => "CHLD",
+            Self::SIGCONT => "CONT",
+            Self::SIGSTOP => "STOP",
+            Self::SIGTSTP => "TSTP",
+        }
+    }
+
+    /**
+     * Status shown by the shell for a job the signal terminated or stopped.
+     */
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::SIGINT => "Interrupt",
+            Self::SIGILL => "Illegal instruction",
+            Self::SIGFPE => "Floating point exception",
+            Self::SIGKILL => "Killed",
+            Self::SIGSEGV => "Segmentation fault",
+            Self::SIGTERM => "Terminated",
+            Self::SIGCHLD => "Child exited",
+            Self::SIGCONT => "Continued",
+            Self::SIGSTOP => "Stopped (signal)",
+            Self::SIGTSTP => "Stopped",
+        }
+    }
+
+    pub fn default_action(&self) -> DefaultAction {
+        match self {
+            Self::SIGINT
+            | Self::SIGILL
+            | Self::SIGFPE
+            | Self::SIGKILL
+            | Self::SIGSEGV
+            | Self::SIGTERM => DefaultAction::Terminate,
+            Self::SIGCHLD => DefaultAction::Ignore,
+            Self::SIGCONT => DefaultAction::Continue,
+            Self::SIGSTOP | Self::SIGTSTP => DefaultAction::Stop,
+        }
+    }
+
+    /**
+     * SIGKILL and SIGSTOP can be neither caught nor ignored.
+     */
+    pub fn catchable(&self) -> bool {
+        !matches!(self, Self::SIGKILL | Self::SIGSTOP)
+    }
+
+    /**
+     * Exit code of a process terminated by the signal, as shells report it.
+     */
+    pub fn exit_code(&self) -> i32 {
+        128 + *self as i32
+    }
+
+    /**
+     * Signal that terminated a process with the given exit code, if any.
+     */
+    pub fn from_exit_code(code: i32) -> Option<Self> {
+        SIGNALS
+            .iter()
+            .copied()
+            .find(|signal| signal.exit_code() == code)
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SIG{}", self.name())
+    }
+}
+
+pub type Handler = Arc<dyn Fn(Signal) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub enum SignalAction {
+    #[default]
+    Default,
+    Ignore,
+    Handle(Handler),
+}
+
+impl fmt::Debug for SignalAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "Default"),
+            Self::Ignore => write!(f, "Ignore"),
+            Self::Handle(_) => write!(f, "Handle"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_parse_from_numbers_and_names() {
+        assert_eq!(Signal::from_str("9"), Some(Signal::SIGKILL));
+        assert_eq!(Signal::from_str("KILL"), Some(Signal::SIGKILL));
+        assert_eq!(Signal::from_str("sigterm"), Some(Signal::SIGTERM));
+        assert_eq!(Signal::from_str("Tstp"), Some(Signal::SIGTSTP));
+        assert_eq!(Signal::from_str("3"), None);
+        assert_eq!(Signal::from_str("SIGFOO"), None);
+        assert_eq!(Signal::from_i64(-2), None);
+        SIGNALS.iter().for_each(|&signal| {
+            assert_eq!(Signal::from_i64(signal as i64), Some(signal));
+            assert_eq!(Signal::from_str(&signal.to_string()), Some(signal));
+        });
+    }
+
+    #[test]
+    fn default_actions_follow_posix() {
+        let action = |signal: Signal| signal.default_action();
+        assert_eq!(action(Signal::SIGINT), DefaultAction::Terminate);
+        assert_eq!(action(Signal::SIGSEGV), DefaultAction::Terminate);
+        assert_eq!(action(Signal::SIGKILL), DefaultAction::Terminate);
+        assert_eq!(action(Signal::SIGCHLD), DefaultAction::Ignore);
+        assert_eq!(action(Signal::SIGCONT), DefaultAction::Continue);
+        assert_eq!(action(Signal::SIGSTOP), DefaultAction::Stop);
+        assert_eq!(action(Signal::SIGTSTP), DefaultAction::Stop);
+
+        let uncatchable: Vec<Signal> = SIGNALS
+            .iter()
+            .copied()
+            .filter(|signal| !signal.catchable())
+            .collect();
+        assert_eq!(uncatchable, [Signal::SIGKILL, Signal::SIGSTOP]);
+    }
+
+    #[test]
+    fn exit_codes_map_back_to_their_signal() {
+        assert_eq!(Signal::SIGKILL.exit_code(), 137);
+        SIGNALS.iter().for_each(|&signal| {
+            assert_eq!(Signal::from_exit_code(signal.exit_code()), Some(signal));
+        });
+        assert_eq!(Signal::from_exit_code(0), None);
+        assert_eq!(Signal::from_exit_code(1), None);
+    }
+}
@@ -0,0 +1,657 @@
+/**
+ * Kernel synchronization objects for virtual processes
+ *
+ * Mutexes, counting semaphores, condition variables and reader-writer
+ * locks belong to the process creating them and are destroyed when it
+ * exits, or as soon as no other process holds or waits on them anymore.
+ * Locks held by an exiting process are released. Waiting on an
+ * object blocks the process for the scheduler, and a wait that would close
+ * a cycle in the wait-for graph fails with EDEADLK instead of hanging.
+ */
+use lazy_static::lazy_static;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Condvar, Mutex},
+};
+
+use crate::errno::Errno;
+use crate::vpm::{self, Vpm};
+
+lazy_static! {
+    pub static ref SYNC: Mutex<SyncTable> = Mutex::new(SyncTable::default());
+    /**
+     * Notified whenever an object is released, signaled or destroyed.
+     */
+    static ref SYNC_CHANGED: Condvar = Condvar::new();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncKind {
+    Mutex,
+    Semaphore,
+    Condvar,
+    RwLock,
+}
+
+impl fmt::Display for SyncKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Self::Mutex => "mutex",
+            Self::Semaphore => "semaphore",
+            Self::Condvar => "condvar",
+            Self::RwLock => "rwlock",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum State {
+    Mutex {
+        holder: Option<u32>,
+    },
+    Semaphore {
+        count: u32,
+    },
+    Condvar {
+        signaled: HashSet<u32>,
+    },
+    RwLock {
+        readers: Vec<u32>,
+        writer: Option<u32>,
+    },
+}
+
+/**
+ * What a waiting process asked for: exclusive access, shared access of a
+ * reader, or a condition.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Exclusive,
+    Shared,
+    Condition,
+}
+
+#[derive(Debug, Clone)]
+struct SyncObject {
+    name: String,
+    owner: u32,
+    /**
+     * The owner exited while other processes still used the object.
+     */
+    orphaned: bool,
+    state: State,
+    waiters: Vec<(u32, Access)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncInfo {
+    pub id: u32,
+    pub name: String,
+    pub kind: SyncKind,
+    pub owner: u32,
+    pub holders: Vec<u32>,
+    pub count: Option<u32>,
+    pub waiters: Vec<u32>,
+}
+
+#[derive(Debug, Default)]
+pub struct SyncTable {
+    objects: HashMap<u32, SyncObject>,
+    next_id: u32,
+}
+
+impl SyncObject {
+    fn kind(&self) -> SyncKind {
+        match self.state {
+            State::Mutex { .. } => SyncKind::Mutex,
+            State::Semaphore { .. } => SyncKind::Semaphore,
+            State::Condvar { .. } => SyncKind::Condvar,
+            State::RwLock { .. } => SyncKind::RwLock,
+        }
+    }
+
+    /**
+     * Processes a waiter of the object waits for. Semaphores and condition
+     * variables have no holder, so their waiters add no edge to the graph.
+     */
+    fn holders(&self) -> Vec<u32> {
+        match &self.state {
+            State::Mutex { holder } => holder.iter().copied().collect(),
+            State::RwLock { readers, writer } => {
+                readers.iter().chain(writer.iter()).copied().collect()
+            }
+            State::Semaphore { .. } | State::Condvar { .. } => Vec::new(),
+        }
+    }
+
+    /**
+     * Nobody holds the object, waits on it or is about to be woken by it.
+     */
+    fn unused(&self) -> bool {
+        let signaled = match &self.state {
+            State::Condvar { signaled } => !signaled.is_empty(),
+            _ => false,
+        };
+        self.holders().is_empty() && self.waiters.is_empty() && !signaled
+    }
+
+    /**
+     * Waiters are served in arrival order, a reader only lets readers
+     * queued before any writer go first.
+     */
+    fn first_in_line(&self, pid: u32, access: Access) -> bool {
+        self.waiters
+            .iter()
+            .take_while(|&&(waiter, _)| waiter != pid)
+            .all(|&(_, queued)| access == Access::Shared && queued == Access::Shared)
+    }
+
+    /**
+     * Take the object for pid if it is free, returning whether it did.
+     */
+    fn try_acquire(&mut self, pid: u32, access: Access) -> bool {
+        if !self.first_in_line(pid, access) {
+            return false;
+        }
+        match (&mut self.state, access) {
+            (State::Mutex { holder }, _) if holder.is_none() => *holder = Some(pid),
+            (State::Semaphore { count }, _) if *count > 0 => *count -= 1,
+            (State::RwLock { readers, writer }, Access::Shared) if writer.is_none() => {
+                readers.push(pid)
+            }
+            (State::RwLock { readers, writer }, Access::Exclusive)
+                if writer.is_none() && readers.is_empty() =>
+            {
+                *writer = Some(pid)
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl SyncTable {
+    pub fn create(&mut self, owner: u32, name: &str, kind: SyncKind, count: u32) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let state = match kind {
+            SyncKind::Mutex => State::Mutex { holder: None },
+            SyncKind::Semaphore => State::Semaphore { count },
+            SyncKind::Condvar => State::Condvar {
+                signaled: HashSet::new(),
+            },
+            SyncKind::RwLock => State::RwLock {
+                readers: Vec::new(),
+                writer: None,
+            },
+        };
+        self.objects.insert(
+            id,
+            SyncObject {
+                name: name.to_string(),
+                owner,
+                orphaned: false,
+                state,
+                waiters: Vec::new(),
+            },
+        );
+        id
+    }
+
+    /**
+     * Destroy an object nobody holds nor waits for.
+     */
+    pub fn destroy(&mut self, id: u32) -> Result<(), Errno> {
+        let object = self.objects.get(&id).ok_or(Errno::EINVAL)?;
+        if !object.holders().is_empty() || !object.waiters.is_empty() {
+            return Err(Errno::EBUSY);
+        }
+        self.objects.remove(&id);
+        Ok(())
+    }
+
+    fn object(&mut self, id: u32, kind: SyncKind) -> Result<&mut SyncObject, Errno> {
+        self.objects
+            .get_mut(&id)
+            .filter(|object| object.kind() == kind)
+            .ok_or(Errno::EINVAL)
+    }
+
+    /**
+     * Edges of the wait-for graph: each waiting process with the processes
+     * holding the object it waits for.
+     */
+    pub fn wait_for(&self) -> Vec<(u32, u32, u32)> {
+        let mut edges: Vec<(u32, u32, u32)> = self
+            .objects
+            .iter()
+            .flat_map(|(&id, object)| {
+                let holders = object.holders();
+                object
+                    .waiters
+                    .iter()
+                    .flat_map(move |&(waiter, _)| {
+                        holders
+                            .clone()
+                            .into_iter()
+                            .filter(move |&holder| holder != waiter)
+                            .map(move |holder| (waiter, holder, id))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        edges.sort();
+        edges
+    }
+
+    /**
+     * Take the object for pid, or queue pid as one of its waiters when it
+     * is not available. A queued pid leaves the queue once it gets it.
+     * Returns whether pid got it, EDEADLK when waiting would never end.
+     */
+    fn request(
+        &mut self,
+        pid: u32,
+        id: u32,
+        kind: SyncKind,
+        access: Access,
+    ) -> Result<bool, Errno> {
+        let object = self.object(id, kind)?;
+        if object.holders().contains(&pid) && access == Access::Exclusive {
+            return Err(Errno::EDEADLK);
+        }
+        if object.try_acquire(pid, access) {
+            object.waiters.retain(|&(waiter, _)| waiter != pid);
+            return Ok(true);
+        }
+        if self.would_deadlock(pid, id) {
+            return Err(Errno::EDEADLK);
+        }
+        self.object(id, kind)?.waiters.push((pid, access));
+        Ok(false)
+    }
+
+    /**
+     * Whether pid waiting for the object would close a cycle, that is one
+     * of its holders already waits, directly or not, for pid.
+     */
+    fn would_deadlock(&self, pid: u32, id: u32) -> bool {
+        let edges = self.wait_for();
+        let mut visited = HashSet::new();
+        let mut stack: Vec<u32> = self.objects[&id].holders();
+        while let Some(process) = stack.pop() {
+            if process == pid {
+                return true;
+            }
+            if visited.insert(process) {
+                stack.extend(
+                    edges
+                        .iter()
+                        .filter(|&&(waiter, _, _)| waiter == process)
+                        .map(|&(_, holder, _)| holder),
+                );
+            }
+        }
+        false
+    }
+
+    /**
+     * Forget a process: it stops waiting, what it holds is released and
+     * the objects it created are destroyed once nobody else uses them.
+     */
+    pub fn release_all(&mut self, pid: u32) {
+        self.objects.values_mut().for_each(|object| {
+            object.orphaned |= object.owner == pid;
+            object.waiters.retain(|&(waiter, _)| waiter != pid);
+            match &mut object.state {
+                State::Mutex { holder } if *holder == Some(pid) => *holder = None,
+                State::RwLock { readers, writer } => {
+                    readers.retain(|&reader| reader != pid);
+                    if *writer == Some(pid) {
+                        *writer = None;
+                    }
+                }
+                State::Condvar { signaled } => {
+                    signaled.remove(&pid);
+                }
+                _ => {}
+            }
+        });
+        self.changed();
+    }
+
+    /**
+     * Destroy the orphaned objects left unused and wake the waiters to
+     * check the objects they wait for.
+     */
+    fn changed(&mut self) {
+        self.objects
+            .retain(|_, object| !(object.orphaned && object.unused()));
+        SYNC_CHANGED.notify_all();
+    }
+
+    pub fn objects(&self) -> Vec<SyncInfo> {
+        let mut objects: Vec<SyncInfo> = self
+            .objects
+            .iter()
+            .map(|(&id, object)| SyncInfo {
+                id,
+                name: object.name.clone(),
+                kind: object.kind(),
+                owner: object.owner,
+                holders: object.holders(),
+                count: match object.state {
+                    State::Semaphore { count } => Some(count),
+                    _ => None,
+                },
+                waiters: object.waiters.iter().map(|&(waiter, _)| waiter).collect(),
+            })
+            .collect();
+        objects.sort_by_key(|object| object.id);
+        objects
+    }
+}
+
+/**
+ * Create an object owned by the process. Semaphores start at count.
+ */
+pub fn create(process: &Vpm, name: &str, kind: SyncKind, count: u32) -> u32 {
+    SYNC.lock().unwrap().create(process.pid, name, kind, count)
+}
+
+/**
+ * Acquire the object, blocking until it is available. The process leaves
+ * the queue when the wait fails.
+ */
+fn acquire(process: &Vpm, id: u32, kind: SyncKind, access: Access) -> Result<(), Errno> {
+    let pid = process.pid;
+    let mut table = SYNC.lock().unwrap();
+    if table.request(pid, id, kind, access)? {
+        return Ok(());
+    }
+
+    loop {
+        table = match vpm::wait_on(pid, table, &SYNC_CHANGED) {
+            Ok(table) => table,
+            Err(errno) => {
+                let mut table = SYNC.lock().unwrap();
+                if let Ok(object) = table.object(id, kind) {
+                    object.waiters.retain(|&(waiter, _)| waiter != pid);
+                }
+                table.changed();
+                return Err(errno);
+            }
+        };
+        let object = table.object(id, kind)?;
+        if object.try_acquire(pid, access) {
+            object.waiters.retain(|&(waiter, _)| waiter != pid);
+            table.changed();
+            return Ok(());
+        }
+    }
+}
+
+/**
+ * Lock the mutex, blocking while another process holds it.
+ */
+pub fn mutex_lock(process: &Vpm, id: u32) -> Result<(), Errno> {
+    acquire(process, id, SyncKind::Mutex, Access::Exclusive)
+}
+
+/**
+ * Release a mutex, only its holder may.
+ */
+pub fn mutex_unlock(process: &Vpm, id: u32) -> Result<(), Errno> {
+    let mut table = SYNC.lock().unwrap();
+    unlock(&mut table, process.pid, id)
+}
+
+fn unlock(table: &mut SyncTable, pid: u32, id: u32) -> Result<(), Errno> {
+    match &mut table.object(id, SyncKind::Mutex)?.state {
+        State::Mutex { holder } if *holder == Some(pid) => *holder = None,
+        _ => return Err(Errno::EPERM),
+    }
+    table.changed();
+    Ok(())
+}
+
+/**
+ * Decrement the semaphore, blocking while it is zero.
+ */
+pub fn sem_wait(process: &Vpm, id: u32) -> Result<(), Errno> {
+    acquire(process, id, SyncKind::Semaphore, Access::Exclusive)
+}
+
+/**
+ * Increment the semaphore, waking a process waiting on it.
+ */
+pub fn sem_post(id: u32) -> Result<(), Errno> {
+    let mut table = SYNC.lock().unwrap();
+    if let State::Semaphore { count } = &mut table.object(id, SyncKind::Semaphore)?.state {
+        *count = count.checked_add(1).ok_or(Errno::EINVAL)?;
+    }
+    SYNC_CHANGED.notify_all();
+    Ok(())
+}
+
+/**
+ * Lock for reading, shared with the other readers.
+ */
+pub fn read_lock(process: &Vpm, id: u32) -> Result<(), Errno> {
+    acquire(process, id, SyncKind::RwLock, Access::Shared)
+}
+
+/**
+ * Lock for writing, excluding readers and other writers.
+ */
+pub fn write_lock(process: &Vpm, id: u32) -> Result<(), Errno> {
+    acquire(process, id, SyncKind::RwLock, Access::Exclusive)
+}
+
+/**
+ * Release the read or write lock the process holds.
+ */
+pub fn rw_unlock(process: &Vpm, id: u32) -> Result<(), Errno> {
+    let pid = process.pid;
+    let mut table = SYNC.lock().unwrap();
+    if let State::RwLock { readers, writer } = &mut table.object(id, SyncKind::RwLock)?.state {
+        if *writer == Some(pid) {
+            *writer = None;
+        } else if let Some(position) = readers.iter().position(|&reader| reader == pid) {
+            readers.remove(position);
+        } else {
+            return Err(Errno::EPERM);
+        }
+    }
+    table.changed();
+    Ok(())
+}
+
+/**
+ * Atomically release the mutex and wait for the condition to be signaled,
+ * then lock the mutex again.
+ */
+pub fn cond_wait(process: &Vpm, id: u32, mutex: u32) -> Result<(), Errno> {
+    let pid = process.pid;
+    let mut table = SYNC.lock().unwrap();
+    table.object(id, SyncKind::Condvar)?;
+    unlock(&mut table, pid, mutex)?;
+    table
+        .object(id, SyncKind::Condvar)?
+        .waiters
+        .push((pid, Access::Condition));
+
+    loop {
+        table = match vpm::wait_on(pid, table, &SYNC_CHANGED) {
+            Ok(table) => table,
+            Err(errno) => {
+                let mut table = SYNC.lock().unwrap();
+                if let Ok(object) = table.object(id, SyncKind::Condvar) {
+                    object.waiters.retain(|&(waiter, _)| waiter != pid);
+                }
+                table.changed();
+                return Err(errno);
+            }
+        };
+        let condvar = table.object(id, SyncKind::Condvar)?;
+        if let State::Condvar { signaled } = &mut condvar.state {
+            if signaled.remove(&pid) {
+                break;
+            }
+        }
+    }
+    table.changed();
+    drop(table);
+    mutex_lock(process, mutex)
+}
+
+/**
+ * Wake the longest waiting process, or all of them.
+ */
+fn notify(id: u32, all: bool) -> Result<(), Errno> {
+    let mut table = SYNC.lock().unwrap();
+    let condvar = table.object(id, SyncKind::Condvar)?;
+    let woken = if all { condvar.waiters.len() } else { 1 };
+    let woken: Vec<u32> = condvar
+        .waiters
+        .drain(..woken.min(condvar.waiters.len()))
+        .map(|(waiter, _)| waiter)
+        .collect();
+    if let State::Condvar { signaled } = &mut condvar.state {
+        signaled.extend(woken);
+    }
+    SYNC_CHANGED.notify_all();
+    Ok(())
+}
+
+pub fn cond_signal(id: u32) -> Result<(), Errno> {
+    notify(id, false)
+}
+
+pub fn cond_broadcast(id: u32) -> Result<(), Errno> {
+    notify(id, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Mutexes created by process 1, each held by the process of the same
+     * index in holders.
+     */
+    fn held_mutexes(table: &mut SyncTable, holders: &[u32]) -> Vec<u32> {
+        holders
+            .iter()
+            .map(|&holder| {
+                let id = table.create(1, "mutex", SyncKind::Mutex, 0);
+                assert_eq!(
+                    table.request(holder, id, SyncKind::Mutex, Access::Exclusive),
+                    Ok(true)
+                );
+                id
+            })
+            .collect()
+    }
+
+    fn lock(table: &mut SyncTable, pid: u32, id: u32) -> Result<bool, Errno> {
+        table.request(pid, id, SyncKind::Mutex, Access::Exclusive)
+    }
+
+    #[test]
+    fn two_processes_locking_each_others_mutex_deadlock() {
+        let mut table = SyncTable::default();
+        let mutexes = held_mutexes(&mut table, &[2, 3]);
+
+        assert_eq!(lock(&mut table, 2, mutexes[1]), Ok(false));
+        assert_eq!(table.wait_for(), [(2, 3, mutexes[1])]);
+        assert_eq!(lock(&mut table, 3, mutexes[0]), Err(Errno::EDEADLK));
+        // The refused process was not queued
+        assert_eq!(table.wait_for(), [(2, 3, mutexes[1])]);
+    }
+
+    #[test]
+    fn longer_cycle_is_detected() {
+        let mut table = SyncTable::default();
+        let mutexes = held_mutexes(&mut table, &[2, 3, 4, 5]);
+
+        assert_eq!(lock(&mut table, 2, mutexes[1]), Ok(false));
+        assert_eq!(lock(&mut table, 3, mutexes[2]), Ok(false));
+        assert_eq!(lock(&mut table, 4, mutexes[3]), Ok(false));
+        assert_eq!(lock(&mut table, 5, mutexes[0]), Err(Errno::EDEADLK));
+    }
+
+    #[test]
+    fn waiting_without_a_cycle_is_not_a_deadlock() {
+        let mut table = SyncTable::default();
+        let mutexes = held_mutexes(&mut table, &[2, 3]);
+
+        // 4 waits for 2 which waits for 3, none of them waits for 4
+        assert_eq!(lock(&mut table, 2, mutexes[1]), Ok(false));
+        assert_eq!(lock(&mut table, 4, mutexes[0]), Ok(false));
+        assert_eq!(lock(&mut table, 5, mutexes[0]), Ok(false));
+
+        // Readers sharing a lock do not wait for each other
+        let rwlock = table.create(1, "rwlock", SyncKind::RwLock, 0);
+        for reader in [2, 3] {
+            assert_eq!(
+                table.request(reader, rwlock, SyncKind::RwLock, Access::Shared),
+                Ok(true)
+            );
+        }
+        assert_eq!(
+            table.request(6, rwlock, SyncKind::RwLock, Access::Exclusive),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn relocking_a_held_mutex_deadlocks() {
+        let mut table = SyncTable::default();
+        let mutexes = held_mutexes(&mut table, &[2]);
+        assert_eq!(lock(&mut table, 2, mutexes[0]), Err(Errno::EDEADLK));
+    }
+
+    #[test]
+    fn exit_releases_only_the_holds_and_waits_of_the_process() {
+        let mut table = SyncTable::default();
+        let mutexes = held_mutexes(&mut table, &[2, 3]);
+        assert_eq!(lock(&mut table, 3, mutexes[0]), Ok(false));
+        assert_eq!(lock(&mut table, 4, mutexes[1]), Ok(false));
+
+        table.release_all(2);
+        let objects = table.objects();
+        assert_eq!(objects[0].holders, [] as [u32; 0]);
+        assert_eq!(objects[0].waiters, [3]);
+        assert_eq!(objects[1].holders, [3]);
+        assert_eq!(objects[1].waiters, [4]);
+    }
+
+    #[test]
+    fn objects_of_an_exited_owner_live_while_others_use_them() {
+        let mut table = SyncTable::default();
+        let mutexes = held_mutexes(&mut table, &[2, 3]);
+        let unused = table.create(1, "unused", SyncKind::Semaphore, 1);
+        let other = table.create(5, "other", SyncKind::Mutex, 0);
+        assert_eq!(lock(&mut table, 4, mutexes[1]), Ok(false));
+
+        table.release_all(1);
+        let ids: Vec<u32> = table.objects().iter().map(|object| object.id).collect();
+        assert_eq!(ids, [mutexes[0], mutexes[1], other]);
+        assert!(!ids.contains(&unused));
+
+        unlock(&mut table, 2, mutexes[0]).unwrap();
+        let ids: Vec<u32> = table.objects().iter().map(|object| object.id).collect();
+        assert_eq!(ids, [mutexes[1], other]);
+
+        // The waiter takes the mutex once its holder exits, then drops it
+        table.release_all(3);
+        assert_eq!(lock(&mut table, 4, mutexes[1]), Ok(true));
+        unlock(&mut table, 4, mutexes[1]).unwrap();
+        let ids: Vec<u32> = table.objects().iter().map(|object| object.id).collect();
+        assert_eq!(ids, [other]);
+    }
+}
@@ -0,0 +1,2320 @@
+/**
+ * System call interface
+ *
+ * Programs reach the kernel subsystems through syscall(), which looks the
+ * number up in the table, checks the arguments against the signature of
+ * the call and returns a value or an errno. Numbers follow Linux x86-64
+ * where a counterpart exists, kernelino specific calls start at 500.
+ * Calls made by a traced process are logged to stderr, strace style.
+ */
+use lazy_static::lazy_static;
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::errno::Errno;
+use crate::fd::{self, OpenFlags, Whence};
+use crate::heap::HeapStats;
+use crate::ipc::{self, MessageQueueInfo, PipeInfo, ShmInfo, IPC};
+use crate::scheduler::{self, Policy, SCHEDULER};
+use crate::signal::{Signal, SignalAction};
+use crate::sync::{self, SyncInfo, SyncKind, SYNC};
+use crate::vfs::{FileKind, VFS};
+use crate::vmm::{MapKind, MemoryRegion, MemoryStats, PageOwner};
+use crate::vpm::{self, ProcessState, Vpm};
+
+pub const SYS_READ: u32 = 0;
+pub const SYS_WRITE: u32 = 1;
+pub const SYS_OPEN: u32 = 2;
+pub const SYS_CLOSE: u32 = 3;
+pub const SYS_STAT: u32 = 4;
+pub const SYS_POLL: u32 = 7;
+pub const SYS_LSEEK: u32 = 8;
+pub const SYS_MMAP: u32 = 9;
+pub const SYS_MUNMAP: u32 = 11;
+pub const SYS_BRK: u32 = 12;
+pub const SYS_RT_SIGACTION: u32 = 13;
+pub const SYS_PIPE: u32 = 22;
+pub const SYS_SCHED_YIELD: u32 = 24;
+pub const SYS_MSYNC: u32 = 26;
+pub const SYS_SHMGET: u32 = 29;
+pub const SYS_SHMAT: u32 = 30;
+pub const SYS_DUP: u32 = 32;
+pub const SYS_DUP2: u32 = 33;
+pub const SYS_NANOSLEEP: u32 = 35;
+pub const SYS_GETPID: u32 = 39;
+pub const SYS_EXIT: u32 = 60;
+pub const SYS_WAIT4: u32 = 61;
+pub const SYS_KILL: u32 = 62;
+pub const SYS_SHMDT: u32 = 67;
+pub const SYS_GETDENTS: u32 = 78;
+pub const SYS_GETCWD: u32 = 79;
+pub const SYS_CHDIR: u32 = 80;
+pub const SYS_MKDIR: u32 = 83;
+pub const SYS_UNLINK: u32 = 87;
+pub const SYS_CHMOD: u32 = 90;
+pub const SYS_GETPPID: u32 = 110;
+pub const SYS_MKNOD: u32 = 133;
+pub const SYS_GETPRIORITY: u32 = 140;
+pub const SYS_SETPRIORITY: u32 = 141;
+pub const SYS_MQ_OPEN: u32 = 240;
+pub const SYS_MQ_UNLINK: u32 = 241;
+pub const SYS_MQ_TIMEDSEND: u32 = 242;
+pub const SYS_MQ_TIMEDRECEIVE: u32 = 243;
+pub const SYS_SYNC_CREATE: u32 = 500;
+pub const SYS_SYNC_DESTROY: u32 = 501;
+pub const SYS_MUTEX_LOCK: u32 = 502;
+pub const SYS_MUTEX_UNLOCK: u32 = 503;
+pub const SYS_SEM_WAIT: u32 = 504;
+pub const SYS_SEM_POST: u32 = 505;
+pub const SYS_COND_WAIT: u32 = 506;
+pub const SYS_COND_SIGNAL: u32 = 507;
+pub const SYS_COND_BROADCAST: u32 = 508;
+pub const SYS_RWLOCK_RDLOCK: u32 = 509;
+pub const SYS_RWLOCK_WRLOCK: u32 = 510;
+pub const SYS_RWLOCK_UNLOCK: u32 = 511;
+pub const SYS_SHM_UNLINK: u32 = 512;
+pub const SYS_MALLOC: u32 = 513;
+pub const SYS_FREE: u32 = 514;
+pub const SYS_SCHED_SETPOLICY: u32 = 515;
+pub const SYS_GETPROCS: u32 = 516;
+pub const SYS_SCHED_GETINFO: u32 = 517;
+pub const SYS_MEMINFO: u32 = 518;
+pub const SYS_HEAPINFO: u32 = 519;
+pub const SYS_GETMAPS: u32 = 520;
+pub const SYS_FRAMEMAP: u32 = 521;
+pub const SYS_MEMCHECK: u32 = 522;
+pub const SYS_IPCINFO: u32 = 523;
+pub const SYS_LOCKINFO: u32 = 524;
+pub const SYS_TCSETPGRP: u32 = 525;
+pub const SYS_TCGETPGRP: u32 = 526;
+
+pub const O_RDONLY: i64 = 0;
+pub const O_WRONLY: i64 = 0o1;
+pub const O_RDWR: i64 = 0o2;
+pub const O_CREAT: i64 = 0o100;
+pub const O_EXCL: i64 = 0o200;
+pub const O_TRUNC: i64 = 0o1000;
+pub const O_APPEND: i64 = 0o2000;
+const O_ACCMODE: i64 = 0o3;
+
+pub const MAP_SHARED: i64 = 0x01;
+pub const MAP_PRIVATE: i64 = 0x02;
+
+pub const S_IFMT: i64 = 0o170000;
+pub const S_IFIFO: i64 = 0o10000;
+pub const S_IFDIR: i64 = 0o40000;
+pub const S_IFREG: i64 = 0o100000;
+pub const S_IXALL: i64 = 0o111;
+pub const WNOHANG: i64 = 1;
+pub const WUNTRACED: i64 = 2;
+
+pub const SIG_QUERY: i64 = -1;
+pub const SIG_DFL: i64 = 0;
+pub const SIG_IGN: i64 = 1;
+pub const SIG_LOG: i64 = 2;
+
+const SYNC_KINDS: [SyncKind; 4] = [
+    SyncKind::Mutex,
+    SyncKind::Semaphore,
+    SyncKind::Condvar,
+    SyncKind::RwLock,
+];
+
+const PROCESS_STATES: [ProcessState; 5] = [
+    ProcessState::Running,
+    ProcessState::Ready,
+    ProcessState::Blocked,
+    ProcessState::Stopped,
+    ProcessState::Zombie,
+];
+
+/**
+ * Usage of a frame bucket reported by framemap, in thousandths.
+ */
+const FRAMEMAP_SCALE: f64 = 1000.0;
+
+/**
+ * Longest string or buffer shown whole in a trace.
+ */
+const TRACE_STRING_SIZE: usize = 32;
+
+lazy_static! {
+    static ref TRACED: Mutex<HashSet<u32>> = Mutex::new(HashSet::new());
+}
+
+/**
+ * Argument or result of a system call.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Str(String),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+}
+
+/**
+ * Kind of a system call argument.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    Int,
+    Str,
+    Bytes,
+}
+
+type Handler = fn(&Vpm, &[Value]) -> Result<Value, Errno>;
+
+/**
+ * Process as reported by getprocs, with its resident pages and its state
+ * in the scheduler.
+ */
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    pub state: ProcessState,
+    pub nice: i32,
+    pub start_time: SystemTime,
+    pub cpu_time: Duration,
+    pub pages: u64,
+    pub level: usize,
+    pub vruntime: u64,
+    pub cmdline: String,
+}
+
+/**
+ * Scheduler counters reported by sched_getinfo.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SchedStats {
+    pub policy: Policy,
+    pub ticks: u64,
+    pub idle_ticks: u64,
+    pub context_switches: u64,
+}
+
+/**
+ * Result of the memcheck audit: the owner of every page, the kernel pages
+ * nobody claims, the frames no page table references, the pages of removed
+ * files and the bytes lost to all of them.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct MemcheckReport {
+    pub owners: Vec<(u32, u64, String)>,
+    pub unowned_pages: Vec<(u64, u64)>,
+    pub orphan_frames: Vec<u64>,
+    pub removed_owner: Vec<(u64, String)>,
+    pub leaked: u64,
+}
+
+/**
+ * Tables reported by ipcinfo.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct IpcTables {
+    pub segments: Vec<ShmInfo>,
+    pub pipes: Vec<PipeInfo>,
+    pub queues: Vec<MessageQueueInfo>,
+}
+
+/**
+ * Synchronization objects reported by lockinfo and the edges of the
+ * wait-for graph: waiter, holder and object.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct LockTables {
+    pub objects: Vec<SyncInfo>,
+    pub edges: Vec<(u32, u32, u32)>,
+}
+
+use ArgKind::{Bytes, Int, Str};
+
+/**
+ * Number, name, argument kinds and handler of each system call.
+ */
+const SYSCALLS: [(u32, &str, &[ArgKind], Handler); 65] = [
+    (SYS_READ, "read", &[Int, Int], sys_read),
+    (SYS_WRITE, "write", &[Int, Bytes], sys_write),
+    (SYS_OPEN, "open", &[Str, Int], sys_open),
+    (SYS_CLOSE, "close", &[Int], sys_close),
+    (SYS_STAT, "stat", &[Str], sys_stat),
+    (SYS_POLL, "poll", &[Int, Int], sys_poll),
+    (SYS_LSEEK, "lseek", &[Int, Int, Int], sys_lseek),
+    (SYS_MMAP, "mmap", &[Str, Int, Int], sys_mmap),
+    (SYS_MUNMAP, "munmap", &[Int], sys_munmap),
+    (SYS_BRK, "brk", &[Int], sys_brk),
+    (
+        SYS_RT_SIGACTION,
+        "rt_sigaction",
+        &[Int, Int],
+        sys_rt_sigaction,
+    ),
+    (SYS_PIPE, "pipe", &[], sys_pipe),
+    (SYS_SCHED_YIELD, "sched_yield", &[], sys_sched_yield),
+    (SYS_MSYNC, "msync", &[Int], sys_msync),
+    (SYS_SHMGET, "shmget", &[Str, Int], sys_shmget),
+    (SYS_SHMAT, "shmat", &[Int], sys_shmat),
+    (SYS_DUP, "dup", &[Int], sys_dup),
+    (SYS_DUP2, "dup2", &[Int, Int], sys_dup2),
+    (SYS_NANOSLEEP, "nanosleep", &[Int], sys_nanosleep),
+    (SYS_GETPID, "getpid", &[], sys_getpid),
+    (SYS_EXIT, "exit", &[Int], sys_exit),
+    (SYS_WAIT4, "wait4", &[Int, Int], sys_wait4),
+    (SYS_KILL, "kill", &[Int, Int], sys_kill),
+    (SYS_SHMDT, "shmdt", &[Int], sys_shmdt),
+    (SYS_GETDENTS, "getdents", &[], sys_getdents),
+    (SYS_GETCWD, "getcwd", &[], sys_getcwd),
+    (SYS_CHDIR, "chdir", &[Str], sys_chdir),
+    (SYS_MKDIR, "mkdir", &[Str], sys_mkdir),
+    (SYS_UNLINK, "unlink", &[Str], sys_unlink),
+    (SYS_CHMOD, "chmod", &[Str, Int], sys_chmod),
+    (SYS_GETPPID, "getppid", &[], sys_getppid),
+    (SYS_MKNOD, "mknod", &[Str, Int], sys_mknod),
+    (SYS_GETPRIORITY, "getpriority", &[Int], sys_getpriority),
+    (SYS_SETPRIORITY, "setpriority", &[Int, Int], sys_setpriority),
+    (SYS_MQ_OPEN, "mq_open", &[Str, Int, Int], sys_mq_open),
+    (SYS_MQ_UNLINK, "mq_unlink", &[Str], sys_mq_unlink),
+    (
+        SYS_MQ_TIMEDSEND,
+        "mq_timedsend",
+        &[Int, Bytes, Int],
+        sys_mq_timedsend,
+    ),
+    (
+        SYS_MQ_TIMEDRECEIVE,
+        "mq_timedreceive",
+        &[Int],
+        sys_mq_timedreceive,
+    ),
+    (
+        SYS_SYNC_CREATE,
+        "sync_create",
+        &[Int, Str, Int],
+        sys_sync_create,
+    ),
+    (SYS_SYNC_DESTROY, "sync_destroy", &[Int], sys_sync_destroy),
+    (SYS_MUTEX_LOCK, "mutex_lock", &[Int], sys_mutex_lock),
+    (SYS_MUTEX_UNLOCK, "mutex_unlock", &[Int], sys_mutex_unlock),
+    (SYS_SEM_WAIT, "sem_wait", &[Int], sys_sem_wait),
+    (SYS_SEM_POST, "sem_post", &[Int], sys_sem_post),
+    (SYS_COND_WAIT, "cond_wait", &[Int, Int], sys_cond_wait),
+    (SYS_COND_SIGNAL, "cond_signal", &[Int], sys_cond_signal),
+    (
+        SYS_COND_BROADCAST,
+        "cond_broadcast",
+        &[Int],
+        sys_cond_broadcast,
+    ),
+    (
+        SYS_RWLOCK_RDLOCK,
+        "rwlock_rdlock",
+        &[Int],
+        sys_rwlock_rdlock,
+    ),
+    (
+        SYS_RWLOCK_WRLOCK,
+        "rwlock_wrlock",
+        &[Int],
+        sys_rwlock_wrlock,
+    ),
+    (
+        SYS_RWLOCK_UNLOCK,
+        "rwlock_unlock",
+        &[Int],
+        sys_rwlock_unlock,
+    ),
+    (SYS_SHM_UNLINK, "shm_unlink", &[Str], sys_shm_unlink),
+    (SYS_MALLOC, "malloc", &[Int], sys_malloc),
+    (SYS_FREE, "free", &[Int], sys_free),
+    (
+        SYS_SCHED_SETPOLICY,
+        "sched_setpolicy",
+        &[Int],
+        sys_sched_setpolicy,
+    ),
+    (SYS_GETPROCS, "getprocs", &[], sys_getprocs),
+    (SYS_SCHED_GETINFO, "sched_getinfo", &[], sys_sched_getinfo),
+    (SYS_MEMINFO, "meminfo", &[], sys_meminfo),
+    (SYS_HEAPINFO, "heapinfo", &[Int], sys_heapinfo),
+    (SYS_GETMAPS, "getmaps", &[Int], sys_getmaps),
+    (SYS_FRAMEMAP, "framemap", &[Int], sys_framemap),
+    (SYS_MEMCHECK, "memcheck", &[], sys_memcheck),
+    (SYS_IPCINFO, "ipcinfo", &[], sys_ipcinfo),
+    (SYS_LOCKINFO, "lockinfo", &[], sys_lockinfo),
+    (SYS_TCSETPGRP, "tcsetpgrp", &[Int], sys_tcsetpgrp),
+    (SYS_TCGETPGRP, "tcgetpgrp", &[], sys_tcgetpgrp),
+];
+
+impl Value {
+    pub fn int(&self) -> Result<i64, Errno> {
+        match self {
+            Self::Int(value) => Ok(*value),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    pub fn str(&self) -> Result<&str, Errno> {
+        match self {
+            Self::Str(value) => Ok(value),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    pub fn bytes(&self) -> Result<&[u8], Errno> {
+        match self {
+            Self::Bytes(value) => Ok(value),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    fn kind(&self) -> Option<ArgKind> {
+        match self {
+            Self::Int(_) => Some(ArgKind::Int),
+            Self::Str(_) => Some(ArgKind::Str),
+            Self::Bytes(_) => Some(ArgKind::Bytes),
+            Self::List(_) => None,
+        }
+    }
+}
+
+/**
+ * Strings and buffers are quoted and cut like strace does.
+ */
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quote = |f: &mut fmt::Formatter<'_>, bytes: &[u8]| {
+            let shown = &bytes[..bytes.len().min(TRACE_STRING_SIZE)];
+            write!(f, "\"{}\"", shown.escape_ascii())?;
+            if bytes.len() > TRACE_STRING_SIZE {
+                write!(f, "...")?;
+            }
+            Ok(())
+        };
+        match self {
+            Self::Int(value) => write!(f, "{}", value),
+            Self::Str(value) => quote(f, value.as_bytes()),
+            Self::Bytes(value) => quote(f, value),
+            Self::List(values) => {
+                let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                write!(f, "[{}]", values.join(", "))
+            }
+        }
+    }
+}
+
+/**
+ * Enter the kernel on behalf of the process.
+ */
+pub fn syscall(process: &Vpm, number: u32, args: &[Value]) -> Result<Value, Errno> {
+    let &(_, name, kinds, handler) = match SYSCALLS.iter().find(|(n, ..)| *n == number) {
+        Some(syscall) => syscall,
+        None => {
+            trace_call(
+                process.pid,
+                &format!("syscall_{}", number),
+                args,
+                &Err(Errno::ENOSYS),
+            );
+            return Err(Errno::ENOSYS);
+        }
+    };
+    let valid = args.len() == kinds.len()
+        && args
+            .iter()
+            .zip(kinds)
+            .all(|(arg, &kind)| arg.kind() == Some(kind));
+    let result = if valid {
+        handler(process, args)
+    } else {
+        Err(Errno::EINVAL)
+    };
+    trace_call(process.pid, name, args, &result);
+    result
+}
+
+/**
+ * Argument kinds of a system call, None if the number is unknown.
+ */
+pub fn signature(number: u32) -> Option<&'static [ArgKind]> {
+    SYSCALLS
+        .iter()
+        .find(|(n, ..)| *n == number)
+        .map(|&(_, _, kinds, _)| kinds)
+}
+
+/**
+ * Name of a system call, None if the number is unknown.
+ */
+pub fn name(number: u32) -> Option<&'static str> {
+    SYSCALLS
+        .iter()
+        .find(|(n, ..)| *n == number)
+        .map(|&(_, name, ..)| name)
+}
+
+/**
+ * Number of the system call with the given name.
+ */
+pub fn number(name: &str) -> Option<u32> {
+    SYSCALLS
+        .iter()
+        .find(|(_, n, ..)| *n == name)
+        .map(|&(number, ..)| number)
+}
+
+/**
+ * Whole content of a file, read through the system calls.
+ */
+pub fn read_all(process: &Vpm, path: &str) -> Result<Vec<u8>, Errno> {
+    let sys = |number, args: &[Value]| syscall(process, number, args);
+    let path = Value::Str(path.to_string());
+    let fd = sys(SYS_OPEN, &[path, Value::Int(O_RDONLY)])?;
+    let mut content = Vec::new();
+    let result = loop {
+        match sys(SYS_READ, &[fd.clone(), Value::Int(4096)]) {
+            Ok(Value::Bytes(bytes)) if bytes.is_empty() => break Ok(content),
+            Ok(Value::Bytes(bytes)) => content.extend(bytes),
+            Ok(_) => break Err(Errno::EIO),
+            Err(errno) => break Err(errno),
+        }
+    };
+    sys(SYS_CLOSE, &[fd]).ok();
+    result
+}
+
+/**
+ * Mode and size of a file or directory, through the system calls.
+ */
+pub fn stat(process: &Vpm, path: &str) -> Result<(i64, u64), Errno> {
+    match syscall(process, SYS_STAT, &[Value::Str(path.to_string())])? {
+        Value::List(stat) => match stat.as_slice() {
+            [Value::Int(mode), Value::Int(size)] => Ok((*mode, *size as u64)),
+            _ => Err(Errno::EIO),
+        },
+        _ => Err(Errno::EIO),
+    }
+}
+
+/**
+ * Snapshot handed out by an info call, flattened into a Value. The
+ * encoding and the decoding of each type sit side by side, a record whose
+ * fields do not match exactly fails to decode.
+ */
+trait Record: Sized {
+    fn encode(&self) -> Value;
+    fn decode(value: &Value) -> Option<Self>;
+}
+
+/**
+ * Fields of a struct record, read in the order they were encoded.
+ */
+struct Fields<'a>(std::slice::Iter<'a, Value>);
+
+impl Fields<'_> {
+    fn next<T: Record>(&mut self) -> Option<T> {
+        T::decode(self.0.next()?)
+    }
+}
+
+/**
+ * Decode a struct record, None unless decode reads every field.
+ */
+fn fields<T>(value: &Value, decode: impl FnOnce(&mut Fields) -> Option<T>) -> Option<T> {
+    let Value::List(values) = value else {
+        return None;
+    };
+    let mut fields = Fields(values.iter());
+    let record = decode(&mut fields)?;
+    fields.0.as_slice().is_empty().then_some(record)
+}
+
+fn int<T: TryFrom<i64>>(value: &Value) -> Option<T> {
+    T::try_from(value.int().ok()?).ok()
+}
+
+impl Record for i32 {
+    fn encode(&self) -> Value {
+        Value::Int(*self as i64)
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        int(value)
+    }
+}
+
+impl Record for u32 {
+    fn encode(&self) -> Value {
+        Value::Int(*self as i64)
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        int(value)
+    }
+}
+
+impl Record for u64 {
+    fn encode(&self) -> Value {
+        Value::Int(*self as i64)
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        int(value)
+    }
+}
+
+impl Record for usize {
+    fn encode(&self) -> Value {
+        Value::Int(*self as i64)
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        int(value)
+    }
+}
+
+impl Record for bool {
+    fn encode(&self) -> Value {
+        Value::Int(*self as i64)
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        match value.int().ok()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Record for String {
+    fn encode(&self) -> Value {
+        Value::Str(self.clone())
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        value.str().ok().map(str::to_string)
+    }
+}
+
+/**
+ * Milliseconds since the epoch.
+ */
+impl Record for SystemTime {
+    fn encode(&self) -> Value {
+        let since_epoch = self.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Value::Int(since_epoch.as_millis() as i64)
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        Some(UNIX_EPOCH + Duration::from_millis(int(value)?))
+    }
+}
+
+/**
+ * Microseconds.
+ */
+impl Record for Duration {
+    fn encode(&self) -> Value {
+        Value::Int(self.as_micros() as i64)
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        Some(Duration::from_micros(int(value)?))
+    }
+}
+
+impl Record for ProcessState {
+    fn encode(&self) -> Value {
+        Value::Str(self.to_string())
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        let state = value.str().ok()?;
+        PROCESS_STATES
+            .iter()
+            .copied()
+            .find(|known| known.to_string() == state)
+    }
+}
+
+/**
+ * Numbered like sched_setpolicy.
+ */
+impl Record for Policy {
+    fn encode(&self) -> Value {
+        Value::Int(*self as i64)
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        Policy::from_i64(value.int().ok()?)
+    }
+}
+
+/**
+ * Numbered like sync_create.
+ */
+impl Record for SyncKind {
+    fn encode(&self) -> Value {
+        Value::Int(*self as i64)
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        SYNC_KINDS.get(int::<usize>(value)?).copied()
+    }
+}
+
+/**
+ * An empty list or a list holding the value.
+ */
+impl<T: Record> Record for Option<T> {
+    fn encode(&self) -> Value {
+        Value::List(self.iter().map(Record::encode).collect())
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        match value {
+            Value::List(values) if values.is_empty() => Some(None),
+            Value::List(values) if values.len() == 1 => T::decode(&values[0]).map(Some),
+            _ => None,
+        }
+    }
+}
+
+impl<T: Record> Record for Vec<T> {
+    fn encode(&self) -> Value {
+        Value::List(self.iter().map(Record::encode).collect())
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        match value {
+            Value::List(values) => values.iter().map(T::decode).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl<A: Record, B: Record> Record for (A, B) {
+    fn encode(&self) -> Value {
+        Value::List(vec![self.0.encode(), self.1.encode()])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| Some((fields.next()?, fields.next()?)))
+    }
+}
+
+impl<A: Record, B: Record, C: Record> Record for (A, B, C) {
+    fn encode(&self) -> Value {
+        Value::List(vec![self.0.encode(), self.1.encode(), self.2.encode()])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some((fields.next()?, fields.next()?, fields.next()?))
+        })
+    }
+}
+
+impl Record for ProcessInfo {
+    fn encode(&self) -> Value {
+        Value::List(vec![
+            self.pid.encode(),
+            self.ppid.encode(),
+            self.state.encode(),
+            self.nice.encode(),
+            self.start_time.encode(),
+            self.cpu_time.encode(),
+            self.pages.encode(),
+            self.level.encode(),
+            self.vruntime.encode(),
+            self.cmdline.encode(),
+        ])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some(Self {
+                pid: fields.next()?,
+                ppid: fields.next()?,
+                state: fields.next()?,
+                nice: fields.next()?,
+                start_time: fields.next()?,
+                cpu_time: fields.next()?,
+                pages: fields.next()?,
+                level: fields.next()?,
+                vruntime: fields.next()?,
+                cmdline: fields.next()?,
+            })
+        })
+    }
+}
+
+impl Record for SchedStats {
+    fn encode(&self) -> Value {
+        Value::List(vec![
+            self.policy.encode(),
+            self.ticks.encode(),
+            self.idle_ticks.encode(),
+            self.context_switches.encode(),
+        ])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some(Self {
+                policy: fields.next()?,
+                ticks: fields.next()?,
+                idle_ticks: fields.next()?,
+                context_switches: fields.next()?,
+            })
+        })
+    }
+}
+
+/**
+ * The TLB hit rate is left out, the caller works it out from the counters.
+ */
+impl Record for MemoryStats {
+    fn encode(&self) -> Value {
+        Value::List(vec![
+            self.page_size.encode(),
+            self.total_frames.encode(),
+            self.used_frames.encode(),
+            self.huge_pages.encode(),
+            self.huge_page_bytes.encode(),
+            self.cow_faults.encode(),
+            self.tlb_hits.encode(),
+            self.tlb_misses.encode(),
+            self.tlb_flushes.encode(),
+            self.tlb_evictions.encode(),
+            self.page_table_frames.encode(),
+            self.page_faults.encode(),
+            self.allocations.encode(),
+            self.deallocations.encode(),
+        ])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        let mut stats = fields(value, |fields| {
+            Some(Self {
+                page_size: fields.next()?,
+                total_frames: fields.next()?,
+                used_frames: fields.next()?,
+                huge_pages: fields.next()?,
+                huge_page_bytes: fields.next()?,
+                cow_faults: fields.next()?,
+                tlb_hits: fields.next()?,
+                tlb_misses: fields.next()?,
+                tlb_hit_rate: 0.0,
+                tlb_flushes: fields.next()?,
+                tlb_evictions: fields.next()?,
+                page_table_frames: fields.next()?,
+                page_faults: fields.next()?,
+                allocations: fields.next()?,
+                deallocations: fields.next()?,
+            })
+        })?;
+        let lookups = stats.tlb_hits + stats.tlb_misses;
+        if lookups > 0 {
+            stats.tlb_hit_rate = stats.tlb_hits as f64 / lookups as f64 * 100.0;
+        }
+        Some(stats)
+    }
+}
+
+impl Record for HeapStats {
+    fn encode(&self) -> Value {
+        Value::List(vec![
+            self.start.encode(),
+            self.brk.encode(),
+            self.allocated_blocks.encode(),
+            self.allocated_bytes.encode(),
+            self.requested_bytes.encode(),
+            self.free_blocks.encode(),
+            self.free_bytes.encode(),
+            self.largest_free_block.encode(),
+        ])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some(Self {
+                start: fields.next()?,
+                brk: fields.next()?,
+                allocated_blocks: fields.next()?,
+                allocated_bytes: fields.next()?,
+                requested_bytes: fields.next()?,
+                free_blocks: fields.next()?,
+                free_bytes: fields.next()?,
+                largest_free_block: fields.next()?,
+            })
+        })
+    }
+}
+
+impl Record for MemoryRegion {
+    fn encode(&self) -> Value {
+        Value::List(vec![
+            self.start.encode(),
+            self.size.encode(),
+            self.kind.encode(),
+            self.flags.encode(),
+            self.frames.encode(),
+        ])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some(Self {
+                start: fields.next()?,
+                size: fields.next()?,
+                kind: fields.next()?,
+                flags: fields.next()?,
+                frames: fields.next()?,
+            })
+        })
+    }
+}
+
+impl Record for MemcheckReport {
+    fn encode(&self) -> Value {
+        Value::List(vec![
+            self.owners.encode(),
+            self.unowned_pages.encode(),
+            self.orphan_frames.encode(),
+            self.removed_owner.encode(),
+            self.leaked.encode(),
+        ])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some(Self {
+                owners: fields.next()?,
+                unowned_pages: fields.next()?,
+                orphan_frames: fields.next()?,
+                removed_owner: fields.next()?,
+                leaked: fields.next()?,
+            })
+        })
+    }
+}
+
+impl Record for ShmInfo {
+    fn encode(&self) -> Value {
+        Value::List(vec![
+            self.id.encode(),
+            self.name.encode(),
+            self.size.encode(),
+            self.pages.encode(),
+            self.attached.encode(),
+            self.unlinked.encode(),
+        ])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some(Self {
+                id: fields.next()?,
+                name: fields.next()?,
+                size: fields.next()?,
+                pages: fields.next()?,
+                attached: fields.next()?,
+                unlinked: fields.next()?,
+            })
+        })
+    }
+}
+
+impl Record for PipeInfo {
+    fn encode(&self) -> Value {
+        Value::List(vec![
+            self.id.encode(),
+            self.name.encode(),
+            self.buffered.encode(),
+            self.capacity.encode(),
+            self.readers.encode(),
+            self.writers.encode(),
+        ])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some(Self {
+                id: fields.next()?,
+                name: fields.next()?,
+                buffered: fields.next()?,
+                capacity: fields.next()?,
+                readers: fields.next()?,
+                writers: fields.next()?,
+            })
+        })
+    }
+}
+
+impl Record for MessageQueueInfo {
+    fn encode(&self) -> Value {
+        Value::List(vec![
+            self.id.encode(),
+            self.name.encode(),
+            self.messages.encode(),
+            self.bytes.encode(),
+            self.max_messages.encode(),
+            self.max_size.encode(),
+        ])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some(Self {
+                id: fields.next()?,
+                name: fields.next()?,
+                messages: fields.next()?,
+                bytes: fields.next()?,
+                max_messages: fields.next()?,
+                max_size: fields.next()?,
+            })
+        })
+    }
+}
+
+impl Record for IpcTables {
+    fn encode(&self) -> Value {
+        Value::List(vec![
+            self.segments.encode(),
+            self.pipes.encode(),
+            self.queues.encode(),
+        ])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some(Self {
+                segments: fields.next()?,
+                pipes: fields.next()?,
+                queues: fields.next()?,
+            })
+        })
+    }
+}
+
+impl Record for SyncInfo {
+    fn encode(&self) -> Value {
+        Value::List(vec![
+            self.id.encode(),
+            self.name.encode(),
+            self.kind.encode(),
+            self.owner.encode(),
+            self.holders.encode(),
+            self.count.encode(),
+            self.waiters.encode(),
+        ])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some(Self {
+                id: fields.next()?,
+                name: fields.next()?,
+                kind: fields.next()?,
+                owner: fields.next()?,
+                holders: fields.next()?,
+                count: fields.next()?,
+                waiters: fields.next()?,
+            })
+        })
+    }
+}
+
+impl Record for LockTables {
+    fn encode(&self) -> Value {
+        Value::List(vec![self.objects.encode(), self.edges.encode()])
+    }
+
+    fn decode(value: &Value) -> Option<Self> {
+        fields(value, |fields| {
+            Some(Self {
+                objects: fields.next()?,
+                edges: fields.next()?,
+            })
+        })
+    }
+}
+
+/**
+ * Make an info call and decode its snapshot, EIO if it is malformed.
+ */
+fn info<T: Record>(process: &Vpm, number: u32, args: &[Value]) -> Result<T, Errno> {
+    T::decode(&syscall(process, number, args)?).ok_or(Errno::EIO)
+}
+
+/**
+ * Every process, through getprocs.
+ */
+pub fn processes(process: &Vpm) -> Result<Vec<ProcessInfo>, Errno> {
+    info(process, SYS_GETPROCS, &[])
+}
+
+/**
+ * Policy and counters of the scheduler, through sched_getinfo.
+ */
+pub fn sched_info(process: &Vpm) -> Result<SchedStats, Errno> {
+    info(process, SYS_SCHED_GETINFO, &[])
+}
+
+/**
+ * Frame and paging counters of the memory manager, through meminfo.
+ */
+pub fn meminfo(process: &Vpm) -> Result<MemoryStats, Errno> {
+    info(process, SYS_MEMINFO, &[])
+}
+
+/**
+ * Heap of a process, through heapinfo.
+ */
+pub fn heapinfo(process: &Vpm, pid: u32) -> Result<HeapStats, Errno> {
+    info(process, SYS_HEAPINFO, &[Value::Int(pid as i64)])
+}
+
+/**
+ * Mapped regions of a process, through getmaps.
+ */
+pub fn maps(process: &Vpm, pid: u32) -> Result<Vec<MemoryRegion>, Errno> {
+    info(process, SYS_GETMAPS, &[Value::Int(pid as i64)])
+}
+
+/**
+ * Fraction of used frames in each of the given number of buckets, through
+ * framemap.
+ */
+pub fn framemap(process: &Vpm, buckets: usize) -> Result<Vec<f64>, Errno> {
+    let usage: Vec<u64> = info(process, SYS_FRAMEMAP, &[Value::Int(buckets as i64)])?;
+    Ok(usage
+        .into_iter()
+        .map(|used| used as f64 / FRAMEMAP_SCALE)
+        .collect())
+}
+
+/**
+ * Leak audit of the memory manager, through memcheck.
+ */
+pub fn memcheck(process: &Vpm) -> Result<MemcheckReport, Errno> {
+    info(process, SYS_MEMCHECK, &[])
+}
+
+/**
+ * Shared memory segments, pipes and message queues, through ipcinfo.
+ */
+pub fn ipcinfo(process: &Vpm) -> Result<IpcTables, Errno> {
+    info(process, SYS_IPCINFO, &[])
+}
+
+/**
+ * Synchronization objects and the wait-for graph, through lockinfo.
+ */
+pub fn lockinfo(process: &Vpm) -> Result<LockTables, Errno> {
+    info(process, SYS_LOCKINFO, &[])
+}
+
+/**
+ * Start or stop tracing the system calls of a process. Its descendants
+ * are traced with it, like strace -f does.
+ */
+pub fn trace(pid: u32, enabled: bool) {
+    let mut traced = TRACED.lock().unwrap();
+    if enabled {
+        traced.insert(pid);
+    } else {
+        traced.remove(&pid);
+    }
+}
+
+fn traced(pid: u32) -> bool {
+    let traced = TRACED.lock().unwrap();
+    if traced.is_empty() {
+        return false;
+    }
+    let mut pid = pid;
+    loop {
+        if traced.contains(&pid) {
+            return true;
+        }
+        match vpm::process(pid) {
+            Some(process) if process.ppid != pid => pid = process.ppid,
+            _ => return false,
+        }
+    }
+}
+
+fn trace_call(pid: u32, name: &str, args: &[Value], result: &Result<Value, Errno>) {
+    if !traced(pid) {
+        return;
+    }
+    let args: Vec<String> = match name {
+        "open" => vec![
+            args[0].to_string(),
+            open_flags_name(args[1].int().unwrap_or(0)),
+        ],
+        _ => args.iter().map(|arg| arg.to_string()).collect(),
+    };
+    let result = match result {
+        Ok(value) => value.to_string(),
+        Err(errno) => format!("-1 {:?} ({})", errno, errno.description()),
+    };
+    eprintln!("[pid {}] {}({}) = {}", pid, name, args.join(", "), result);
+}
+
+fn open_flags_name(flags: i64) -> String {
+    let mut names = vec![match flags & O_ACCMODE {
+        O_WRONLY => "O_WRONLY",
+        O_RDWR => "O_RDWR",
+        _ => "O_RDONLY",
+    }];
+    [
+        (O_CREAT, "O_CREAT"),
+        (O_EXCL, "O_EXCL"),
+        (O_TRUNC, "O_TRUNC"),
+        (O_APPEND, "O_APPEND"),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags & flag != 0)
+    .for_each(|(_, name)| names.push(name));
+    names.join("|")
+}
+
+fn open_flags(flags: i64) -> Result<OpenFlags, Errno> {
+    if flags & !(O_ACCMODE | O_CREAT | O_EXCL | O_TRUNC | O_APPEND) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let (read, write) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(Errno::EINVAL),
+    };
+    Ok(OpenFlags {
+        read,
+        write,
+        append: flags & O_APPEND != 0,
+        create: flags & O_CREAT != 0,
+        truncate: flags & O_TRUNC != 0,
+    })
+}
+
+fn fd(arg: &Value) -> Result<u32, Errno> {
+    u32::try_from(arg.int()?).map_err(|_| Errno::EBADF)
+}
+
+fn id(arg: &Value) -> Result<u32, Errno> {
+    u32::try_from(arg.int()?).map_err(|_| Errno::EINVAL)
+}
+
+fn pid(arg: &Value) -> Result<u32, Errno> {
+    u32::try_from(arg.int()?).map_err(|_| Errno::ESRCH)
+}
+
+fn ok() -> Result<Value, Errno> {
+    Ok(Value::Int(0))
+}
+
+fn sys_read(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let len = usize::try_from(args[1].int()?).map_err(|_| Errno::EINVAL)?;
+    Ok(Value::Bytes(fd::read(process, fd(&args[0])?, len)?))
+}
+
+/**
+ * Wait up to the given milliseconds, for ever when -1, for input on the
+ * terminal behind the descriptor. Returns 1 once there is, 0 on timeout.
+ */
+fn sys_poll(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let timeout = match args[1].int()? {
+        -1 => None,
+        millis => Some(Duration::from_millis(
+            u64::try_from(millis).map_err(|_| Errno::EINVAL)?,
+        )),
+    };
+    Ok(Value::Int(fd::poll(process, fd(&args[0])?, timeout)? as i64))
+}
+
+fn sys_write(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let written = fd::write(process, fd(&args[0])?, args[1].bytes()?)?;
+    Ok(Value::Int(written as i64))
+}
+
+/**
+ * With O_CREAT and O_EXCL, the file must not exist yet.
+ */
+fn sys_open(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let path = args[0].str()?;
+    let flags = open_flags(args[1].int()?)?;
+    let exclusive = flags.create && args[1].int()? & O_EXCL != 0;
+    // The lock is released before opening, a FIFO waits for its other end
+    let file = {
+        let mut vfs = VFS.write().unwrap();
+        if exclusive && (vfs.lookup(path).is_some() || vfs.is_dir(path)) {
+            return Err(Errno::EEXIST);
+        }
+        vfs.open_file(path, flags.create)?
+    };
+    Ok(Value::Int(fd::open(process, file, flags)? as i64))
+}
+
+fn sys_close(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    fd::close(process, fd(&args[0])?)?;
+    ok()
+}
+
+/**
+ * Mode and size of a file or directory. The mode holds its type and the
+ * permissions, only the execute bits of which are kept by chmod.
+ */
+fn sys_stat(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let path = args[0].str()?;
+    let mut vfs = VFS.write().unwrap();
+    let (mode, size) = match vfs.lookup(path) {
+        Some(file) => {
+            let file = file.lock().unwrap();
+            match file.kind {
+                FileKind::Fifo(_) => (S_IFIFO | 0o644, 0),
+                FileKind::Regular if file.executable => (S_IFREG | 0o755, file.size),
+                FileKind::Regular => (S_IFREG | 0o644, file.size),
+            }
+        }
+        None if vfs.is_dir(path) => (S_IFDIR | 0o755, 0),
+        None => return Err(Errno::ENOENT),
+    };
+    Ok(Value::List(vec![Value::Int(mode), Value::Int(size as i64)]))
+}
+
+/**
+ * Names of the entries of the current directory.
+ */
+fn sys_getdents(_: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    let entries = VFS.write().unwrap().entries()?;
+    Ok(Value::List(entries.into_iter().map(Value::Str).collect()))
+}
+
+fn sys_getcwd(_: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    Ok(Value::Str(VFS.read().unwrap().cwd()))
+}
+
+fn sys_chdir(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    VFS.write().unwrap().change_dir(args[0].str()?)?;
+    ok()
+}
+
+/**
+ * Create a directory in the current one, a/b creating the missing ones.
+ */
+fn sys_mkdir(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    VFS.write()
+        .unwrap()
+        .add_directory_recursive(args[0].str()?)?;
+    ok()
+}
+
+/**
+ * Remove a file, or a directory with everything under it.
+ */
+fn sys_unlink(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    VFS.write().unwrap().remove(args[0].str()?)?;
+    ok()
+}
+
+fn sys_lseek(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let whence = match args[2].int()? {
+        0 => Whence::Set,
+        1 => Whence::Current,
+        2 => Whence::End,
+        _ => return Err(Errno::EINVAL),
+    };
+    let offset = fd::lseek(process, fd(&args[0])?, args[1].int()?, whence)?;
+    Ok(Value::Int(offset as i64))
+}
+
+/**
+ * Map a file, MAP_SHARED or MAP_PRIVATE, covering at least length bytes.
+ * Returns the address of the mapping.
+ */
+fn sys_mmap(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let length = u64::try_from(args[1].int()?).map_err(|_| Errno::EINVAL)?;
+    let kind = match args[2].int()? {
+        MAP_SHARED => MapKind::Shared,
+        MAP_PRIVATE => MapKind::Private,
+        _ => return Err(Errno::EINVAL),
+    };
+    let address = VFS
+        .write()
+        .unwrap()
+        .mmap(args[0].str()?, process.pid, length, kind)?;
+    Ok(Value::Int(address as i64))
+}
+
+fn sys_munmap(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let address = u64::try_from(args[0].int()?).map_err(|_| Errno::EINVAL)?;
+    VFS.write().unwrap().munmap(process.pid, address)?;
+    ok()
+}
+
+/**
+ * Flush a file mapping, the file grows to the last byte written through it.
+ */
+fn sys_msync(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let address = u64::try_from(args[0].int()?).map_err(|_| Errno::EINVAL)?;
+    VFS.write().unwrap().msync(process.pid, address)?;
+    ok()
+}
+
+/**
+ * Set the program break, 0 only returns the current one.
+ */
+fn sys_brk(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let mut vmm = process.vmm.lock().unwrap();
+    let brk = match u64::try_from(args[0].int()?).map_err(|_| Errno::EINVAL)? {
+        0 => vmm.sbrk(process.pid, 0)?,
+        brk => vmm.brk(process.pid, brk)?,
+    };
+    Ok(Value::Int(brk as i64))
+}
+
+/**
+ * Allocate size bytes on the heap of the process, growing the break as
+ * needed. Returns the address of the block.
+ */
+fn sys_malloc(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let size = u64::try_from(args[0].int()?).map_err(|_| Errno::EINVAL)?;
+    let address = process.vmm.lock().unwrap().malloc(process.pid, size)?;
+    Ok(Value::Int(address as i64))
+}
+
+fn sys_free(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let address = u64::try_from(args[0].int()?).map_err(|_| Errno::EINVAL)?;
+    process.vmm.lock().unwrap().free(process.pid, address)?;
+    ok()
+}
+
+fn sys_pipe(process: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    let (read, write) = fd::pipe(process)?;
+    Ok(Value::List(vec![
+        Value::Int(read as i64),
+        Value::Int(write as i64),
+    ]))
+}
+
+fn sys_sched_yield(process: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    if !process.yield_cpu() {
+        return Err(Errno::EINTR);
+    }
+    ok()
+}
+
+fn sys_shmget(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let size = u64::try_from(args[1].int()?).map_err(|_| Errno::EINVAL)?;
+    let mut ipc = IPC.lock().unwrap();
+    let id = ipc.shm_open(&mut process.vmm.lock().unwrap(), args[0].str()?, size)?;
+    Ok(Value::Int(id as i64))
+}
+
+fn sys_shmat(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let mut ipc = IPC.lock().unwrap();
+    let address = ipc.shm_attach(&mut process.vmm.lock().unwrap(), process.pid, id(&args[0])?)?;
+    Ok(Value::Int(address as i64))
+}
+
+fn sys_shmdt(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let address = u64::try_from(args[0].int()?).map_err(|_| Errno::EINVAL)?;
+    let mut ipc = IPC.lock().unwrap();
+    ipc.shm_detach(&mut process.vmm.lock().unwrap(), process.pid, address)?;
+    ok()
+}
+
+fn sys_shm_unlink(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let mut ipc = IPC.lock().unwrap();
+    ipc.shm_unlink(&mut process.vmm.lock().unwrap(), args[0].str()?)?;
+    ok()
+}
+
+fn sys_dup(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    Ok(Value::Int(fd::dup(process, fd(&args[0])?)? as i64))
+}
+
+fn sys_dup2(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    Ok(Value::Int(
+        fd::dup2(process, fd(&args[0])?, fd(&args[1])?)? as i64
+    ))
+}
+
+/**
+ * Sleep for the given milliseconds, blocked for the scheduler. The sleep
+ * goes tick by tick so that a signal cuts it short with EINTR, once the
+ * handlers ran or the process was terminated.
+ */
+fn sys_nanosleep(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let millis = u64::try_from(args[0].int()?).map_err(|_| Errno::EINVAL)?;
+    let deadline = Instant::now() + Duration::from_millis(millis);
+    vpm::block(process.pid)?;
+    let mut interrupted = false;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        if left.is_zero() {
+            break;
+        }
+        std::thread::sleep(left.min(scheduler::TICK));
+        interrupted = vpm::process(process.pid).is_none_or(|process| {
+            process.state == ProcessState::Zombie || !process.pending.is_empty()
+        });
+        if interrupted {
+            break;
+        }
+    }
+    vpm::wake(process.pid).ok();
+    if !process.yield_cpu() || interrupted {
+        return Err(Errno::EINTR);
+    }
+    ok()
+}
+
+fn sys_getpid(process: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    Ok(Value::Int(process.pid as i64))
+}
+
+fn sys_getppid(process: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    let parent = vpm::process(process.pid).ok_or(Errno::ESRCH)?.ppid;
+    Ok(Value::Int(parent as i64))
+}
+
+/**
+ * Terminate the process, the caller must stop running on its behalf.
+ */
+fn sys_exit(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let code = i32::try_from(args[0].int()?).map_err(|_| Errno::EINVAL)?;
+    process.exit(code);
+    ok()
+}
+
+/**
+ * Wait for a child, any child when pid is -1. Returns the pid, exit code
+ * and whether it stopped, pid 0 when WNOHANG is set and none exited. With
+ * WUNTRACED the given child is also reported once it stops.
+ */
+fn sys_wait4(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let child = match args[0].int()? {
+        -1 => None,
+        _ => Some(pid(&args[0])?),
+    };
+    let options = args[1].int()?;
+    if options & !(WNOHANG | WUNTRACED) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    vpm::block(process.pid).ok();
+    let untraced = options & WUNTRACED != 0;
+    let status = match child {
+        Some(child) if untraced && options & WNOHANG == 0 => process
+            .waitpid_untraced(child)
+            .map(|code| Some((child, code.unwrap_or(0), code.is_none()))),
+        _ if untraced => Err(Errno::EINVAL),
+        _ => process
+            .waitpid(child, options & WNOHANG != 0)
+            .map(|status| status.map(|(pid, code)| (pid, code, false))),
+    };
+    vpm::wake(process.pid).ok();
+    let (pid, code, stopped) = status?.unwrap_or((0, 0, false));
+    Ok(Value::List(vec![
+        Value::Int(pid as i64),
+        Value::Int(code as i64),
+        Value::Int(stopped as i64),
+    ]))
+}
+
+fn sys_kill(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let signal = Signal::from_i64(args[1].int()?).ok_or(Errno::EINVAL)?;
+    process.kill(pid(&args[0])?, signal)?;
+    ok()
+}
+
+/**
+ * Set the action of a signal to SIG_DFL, SIG_IGN or SIG_LOG, a handler
+ * writing the caught signal to stderr. Returns the previous action,
+ * SIG_QUERY only reads it.
+ */
+fn sys_rt_sigaction(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let signal = Signal::from_i64(args[0].int()?).ok_or(Errno::EINVAL)?;
+    let action = match args[1].int()? {
+        SIG_QUERY => None,
+        SIG_DFL => Some(SignalAction::Default),
+        SIG_IGN => Some(SignalAction::Ignore),
+        SIG_LOG => Some(SignalAction::Handle(Arc::new(log_signal))),
+        _ => return Err(Errno::EINVAL),
+    };
+    let old = match action {
+        Some(action) => process.signal(signal, action)?,
+        None => process.action(signal),
+    };
+    Ok(Value::Int(match old {
+        SignalAction::Default => SIG_DFL,
+        SignalAction::Ignore => SIG_IGN,
+        SignalAction::Handle(_) => SIG_LOG,
+    }))
+}
+
+/**
+ * Handler of SIG_LOG, run by the process that caught the signal.
+ */
+fn log_signal(signal: Signal) {
+    let message = format!("Caught {}\n", signal);
+    match vpm::current() {
+        Some(process) => {
+            fd::write(&process, fd::STDERR, message.as_bytes()).ok();
+        }
+        None => eprint!("{}", message),
+    }
+}
+
+/**
+ * Only FIFOs can be created this way.
+ */
+fn sys_mknod(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    if args[1].int()? & S_IFIFO == 0 {
+        return Err(Errno::EINVAL);
+    }
+    VFS.write().unwrap().mkfifo(args[0].str()?)?;
+    ok()
+}
+
+/**
+ * Only the execute bits of the mode are kept, any of them makes the file
+ * executable.
+ */
+fn sys_chmod(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let mode = args[1].int()?;
+    if !(0..=0o7777).contains(&mode) {
+        return Err(Errno::EINVAL);
+    }
+    VFS.write()
+        .unwrap()
+        .chmod(args[0].str()?, mode & S_IXALL != 0)?;
+    ok()
+}
+
+fn sys_getpriority(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let process = vpm::process(pid(&args[0])?)
+        .filter(|process| process.state != ProcessState::Zombie)
+        .ok_or(Errno::ESRCH)?;
+    Ok(Value::Int(process.nice as i64))
+}
+
+fn sys_setpriority(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let nice = i32::try_from(args[1].int()?).map_err(|_| Errno::EINVAL)?;
+    vpm::set_nice(pid(&args[0])?, nice)?;
+    ok()
+}
+
+/**
+ * Switch the scheduler to round robin (0), priority (1), mlfq (2) or
+ * cfs (3), returning the previous policy.
+ */
+fn sys_sched_setpolicy(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let policy = Policy::from_i64(args[0].int()?).ok_or(Errno::EINVAL)?;
+    let mut scheduler = SCHEDULER.lock().unwrap();
+    let old = scheduler.policy;
+    scheduler.set_policy(policy);
+    Ok(Value::Int(old as i64))
+}
+
+/**
+ * Every process with its resident pages and its state in the scheduler.
+ */
+fn sys_getprocs(process: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    let processes = vpm::processes();
+    let vmm = process.vmm.lock().unwrap();
+    let pages: Vec<u64> = processes
+        .iter()
+        .map(|process| vmm.resident_pages(process.pid))
+        .collect();
+    drop(vmm);
+    let scheduler = SCHEDULER.lock().unwrap();
+    let processes: Vec<ProcessInfo> = processes
+        .into_iter()
+        .zip(pages)
+        .map(|(process, pages)| {
+            let info = scheduler.info(process.pid);
+            ProcessInfo {
+                pid: process.pid,
+                ppid: process.ppid,
+                state: process.state,
+                nice: process.nice,
+                start_time: process.start_time,
+                cpu_time: process.cpu_time,
+                pages,
+                level: info.level,
+                vruntime: info.vruntime,
+                cmdline: process.cmdline,
+            }
+        })
+        .collect();
+    Ok(processes.encode())
+}
+
+fn sys_sched_getinfo(_: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    let scheduler = SCHEDULER.lock().unwrap();
+    let stats = SchedStats {
+        policy: scheduler.policy,
+        ticks: scheduler.ticks,
+        idle_ticks: scheduler.idle_ticks,
+        context_switches: scheduler.context_switches,
+    };
+    Ok(stats.encode())
+}
+
+fn sys_meminfo(process: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    Ok(process.vmm.lock().unwrap().stats().encode())
+}
+
+fn sys_heapinfo(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let stats = process.vmm.lock().unwrap().heap_stats(pid(&args[0])?)?;
+    Ok(stats.encode())
+}
+
+/**
+ * Regions of the address space, like /proc/<pid>/maps.
+ */
+fn sys_getmaps(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let regions = process.vmm.lock().unwrap().regions(pid(&args[0])?)?;
+    Ok(regions.encode())
+}
+
+/**
+ * Usage of each of the given number of frame buckets, in thousandths
+ * rounded up so that a bucket with a used frame never reads as free.
+ */
+fn sys_framemap(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let buckets = usize::try_from(args[0].int()?)
+        .ok()
+        .filter(|&buckets| buckets > 0)
+        .ok_or(Errno::EINVAL)?;
+    let usage: Vec<u64> = process
+        .vmm
+        .lock()
+        .unwrap()
+        .frame_usage(buckets)
+        .into_iter()
+        .map(|used| (used * FRAMEMAP_SCALE).ceil() as u64)
+        .collect();
+    Ok(usage.encode())
+}
+
+/**
+ * Leak audit: the owner of every page, the kernel pages nobody claims, the
+ * frames no page table references and the pages of removed files.
+ */
+fn sys_memcheck(process: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    let file_paths = VFS.read().unwrap().file_paths();
+    let vmm = process.vmm.lock().unwrap();
+    let page_size = vmm.stats().page_size;
+    let owners = vmm.page_owners();
+    let report = vmm.memcheck();
+    drop(vmm);
+
+    let removed_owner: Vec<(u64, String)> = owners
+        .iter()
+        .filter_map(|(_, address, owner)| match owner {
+            Some(PageOwner::File(path)) if !file_paths.contains(path) => {
+                Some((*address, path.display().to_string()))
+            }
+            _ => None,
+        })
+        .collect();
+    let leaked = report
+        .unowned_pages
+        .iter()
+        .map(|(_, size)| size)
+        .sum::<u64>()
+        + (removed_owner.len() + report.orphan_frames.len()) as u64 * page_size;
+
+    let owners = owners
+        .into_iter()
+        .map(|(space, address, owner)| {
+            let owner = match owner {
+                Some(PageOwner::File(path)) => format!("file {}", path.display()),
+                Some(PageOwner::Process(pid)) => format!("process {}", pid),
+                Some(PageOwner::Shm(id)) => format!("shm {}", id),
+                Some(PageOwner::Pipe(id)) => format!("pipe {}", id),
+                Some(PageOwner::MessageQueue(id)) => format!("mqueue {}", id),
+                None => String::from("-"),
+            };
+            (space, address, owner)
+        })
+        .collect();
+    let report = MemcheckReport {
+        owners,
+        unowned_pages: report.unowned_pages,
+        orphan_frames: report.orphan_frames,
+        removed_owner,
+        leaked,
+    };
+    Ok(report.encode())
+}
+
+fn sys_ipcinfo(_: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    let ipc = IPC.lock().unwrap();
+    let tables = IpcTables {
+        segments: ipc.shm_segments(),
+        pipes: ipc.pipes(),
+        queues: ipc.message_queues(),
+    };
+    Ok(tables.encode())
+}
+
+fn sys_lockinfo(_: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    let sync = SYNC.lock().unwrap();
+    let tables = LockTables {
+        objects: sync.objects(),
+        edges: sync.wait_for(),
+    };
+    Ok(tables.encode())
+}
+
+/**
+ * Give the terminal to the process, 0 takes it back from the foreground.
+ * Returns the previous foreground process, 0 when there was none.
+ */
+fn sys_tcsetpgrp(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let foreground = match pid(&args[0])? {
+        0 => None,
+        pid => Some(vpm::process(pid).ok_or(Errno::ESRCH)?.pid),
+    };
+    Ok(Value::Int(
+        vpm::set_foreground(foreground).unwrap_or(0) as i64
+    ))
+}
+
+/**
+ * Process holding the terminal, 0 when the shell does.
+ */
+fn sys_tcgetpgrp(_: &Vpm, _: &[Value]) -> Result<Value, Errno> {
+    Ok(Value::Int(vpm::foreground().unwrap_or(0) as i64))
+}
+
+fn sys_mq_open(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let max_messages = usize::try_from(args[1].int()?).map_err(|_| Errno::EINVAL)?;
+    let max_size = u64::try_from(args[2].int()?).map_err(|_| Errno::EINVAL)?;
+    let id = IPC
+        .lock()
+        .unwrap()
+        .mq_open(args[0].str()?, max_messages, max_size)?;
+    Ok(Value::Int(id as i64))
+}
+
+fn sys_mq_unlink(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let mut ipc = IPC.lock().unwrap();
+    ipc.mq_unlink(&mut process.vmm.lock().unwrap(), args[0].str()?)?;
+    ok()
+}
+
+fn sys_mq_timedsend(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let priority = u32::try_from(args[2].int()?).map_err(|_| Errno::EINVAL)?;
+    ipc::mq_send(process, id(&args[0])?, args[1].bytes()?, priority)?;
+    ok()
+}
+
+fn sys_mq_timedreceive(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let (bytes, priority) = ipc::mq_receive(process, id(&args[0])?)?;
+    Ok(Value::List(vec![
+        Value::Bytes(bytes),
+        Value::Int(priority as i64),
+    ]))
+}
+
+/**
+ * Create a mutex (0), semaphore (1), condvar (2) or rwlock (3). The count
+ * is the initial value of a semaphore.
+ */
+fn sys_sync_create(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    let kind = usize::try_from(args[0].int()?)
+        .ok()
+        .and_then(|kind| SYNC_KINDS.get(kind).copied())
+        .ok_or(Errno::EINVAL)?;
+    let count = u32::try_from(args[2].int()?).map_err(|_| Errno::EINVAL)?;
+    Ok(Value::Int(
+        sync::create(process, args[1].str()?, kind, count) as i64,
+    ))
+}
+
+fn sys_sync_destroy(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    SYNC.lock().unwrap().destroy(id(&args[0])?)?;
+    ok()
+}
+
+fn sys_mutex_lock(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    sync::mutex_lock(process, id(&args[0])?)?;
+    ok()
+}
+
+fn sys_mutex_unlock(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    sync::mutex_unlock(process, id(&args[0])?)?;
+    ok()
+}
+
+fn sys_sem_wait(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    sync::sem_wait(process, id(&args[0])?)?;
+    ok()
+}
+
+fn sys_sem_post(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    sync::sem_post(id(&args[0])?)?;
+    ok()
+}
+
+fn sys_cond_wait(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    sync::cond_wait(process, id(&args[0])?, id(&args[1])?)?;
+    ok()
+}
+
+fn sys_cond_signal(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    sync::cond_signal(id(&args[0])?)?;
+    ok()
+}
+
+fn sys_cond_broadcast(_: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    sync::cond_broadcast(id(&args[0])?)?;
+    ok()
+}
+
+fn sys_rwlock_rdlock(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    sync::read_lock(process, id(&args[0])?)?;
+    ok()
+}
+
+fn sys_rwlock_wrlock(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    sync::write_lock(process, id(&args[0])?)?;
+    ok()
+}
+
+fn sys_rwlock_unlock(process: &Vpm, args: &[Value]) -> Result<Value, Errno> {
+    sync::rw_unlock(process, id(&args[0])?)?;
+    ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmm::{PageSize, Vmm};
+    use std::sync::mpsc;
+
+    fn spawn() -> Vpm {
+        let vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        Vpm::new(Arc::new(Mutex::new(vmm)))
+    }
+
+    #[test]
+    fn table_lookups_agree() {
+        assert_eq!(number("kill"), Some(SYS_KILL));
+        assert_eq!(name(SYS_KILL), Some("kill"));
+        assert_eq!(signature(SYS_KILL), Some(&[Int, Int][..]));
+        assert_eq!(number("fork"), None);
+        assert_eq!(name(1000), None);
+        SYSCALLS.iter().for_each(|&(number, name, ..)| {
+            assert_eq!(self::number(name), Some(number), "{}", name);
+        });
+    }
+
+    #[test]
+    fn unknown_number_and_bad_arguments_are_refused() {
+        let process = spawn();
+        assert_eq!(syscall(&process, 1000, &[]), Err(Errno::ENOSYS));
+        assert_eq!(
+            syscall(&process, SYS_GETPID, &[Value::Int(0)]),
+            Err(Errno::EINVAL)
+        );
+        assert_eq!(syscall(&process, SYS_CLOSE, &[]), Err(Errno::EINVAL));
+        assert_eq!(
+            syscall(&process, SYS_CLOSE, &[Value::Str("0".to_string())]),
+            Err(Errno::EINVAL)
+        );
+        assert_eq!(
+            syscall(&process, SYS_GETPID, &[]),
+            Ok(Value::Int(process.pid as i64))
+        );
+    }
+
+    #[test]
+    fn errors_of_the_subsystems_reach_the_caller() {
+        let process = spawn();
+        assert_eq!(
+            syscall(&process, SYS_CLOSE, &[Value::Int(42)]),
+            Err(Errno::EBADF)
+        );
+        assert_eq!(
+            syscall(&process, SYS_KILL, &[Value::Int(0), Value::Int(15)]),
+            Err(Errno::ESRCH)
+        );
+        assert_eq!(
+            syscall(
+                &process,
+                SYS_KILL,
+                &[Value::Int(process.pid as i64), Value::Int(3)]
+            ),
+            Err(Errno::EINVAL)
+        );
+        assert_eq!(
+            syscall(&process, SYS_NANOSLEEP, &[Value::Int(-1)]),
+            Err(Errno::EINVAL)
+        );
+        assert_eq!(
+            syscall(&process, SYS_SCHED_SETPOLICY, &[Value::Int(4)]),
+            Err(Errno::EINVAL)
+        );
+
+        let Ok(Value::List(fds)) = syscall(&process, SYS_PIPE, &[]) else {
+            panic!("pipe failed");
+        };
+        assert_eq!(
+            syscall(
+                &process,
+                SYS_WRITE,
+                &[fds[0].clone(), Value::Bytes(b"x".to_vec())]
+            ),
+            Err(Errno::EBADF)
+        );
+        assert_eq!(
+            syscall(
+                &process,
+                SYS_WRITE,
+                &[fds[1].clone(), Value::Bytes(b"x".to_vec())]
+            ),
+            Ok(Value::Int(1))
+        );
+        assert_eq!(
+            syscall(&process, SYS_READ, &[fds[0].clone(), Value::Int(10)]),
+            Ok(Value::Bytes(b"x".to_vec()))
+        );
+    }
+
+    #[test]
+    fn info_calls_report_the_kernel_state() {
+        let process = spawn();
+        let sys = |number, args: &[Value]| syscall(&process, number, args);
+        let address = sys(SYS_MALLOC, &[Value::Int(100)]).unwrap();
+
+        let own = processes(&process)
+            .unwrap()
+            .into_iter()
+            .find(|info| info.pid == process.pid)
+            .unwrap();
+        assert_eq!(own.cmdline, "init");
+        assert_eq!(
+            own.pages,
+            process.vmm.lock().unwrap().resident_pages(process.pid)
+        );
+
+        let stats = meminfo(&process).unwrap();
+        assert_eq!(
+            stats.used_frames,
+            process.vmm.lock().unwrap().stats().used_frames
+        );
+        let usage = framemap(&process, 4).unwrap();
+        assert_eq!(usage.len(), 4);
+        assert!(usage[0] > 0.0);
+        assert_eq!(sys(SYS_FRAMEMAP, &[Value::Int(0)]), Err(Errno::EINVAL));
+
+        let heap = heapinfo(&process, process.pid).unwrap();
+        assert_eq!(heap.allocated_blocks, 1);
+        let regions = maps(&process, process.pid).unwrap();
+        let address = address.int().unwrap() as u64;
+        assert!(regions.iter().any(|region| region.kind == "heap"
+            && (region.start..region.start + region.size).contains(&address)));
+        assert_eq!(heapinfo(&process, u32::MAX).unwrap_err(), Errno::ESRCH);
+
+        let Ok(Value::List(fds)) = sys(SYS_PIPE, &[]) else {
+            panic!("pipe failed");
+        };
+        let tables = ipcinfo(&process).unwrap();
+        assert!(tables
+            .pipes
+            .iter()
+            .any(|pipe| pipe.name.is_none() && pipe.writers == 1));
+        fds.iter().for_each(|fd| {
+            sys(SYS_CLOSE, std::slice::from_ref(fd)).unwrap();
+        });
+
+        let args = [Value::Int(1), Value::Str("info".to_string()), Value::Int(2)];
+        let id = sys(SYS_SYNC_CREATE, &args).unwrap().int().unwrap() as u32;
+        let tables = lockinfo(&process).unwrap();
+        let object = tables
+            .objects
+            .iter()
+            .find(|object| object.id == id)
+            .unwrap();
+        assert_eq!(object.kind, SyncKind::Semaphore);
+        assert_eq!(object.count, Some(2));
+        assert_eq!(object.owner, process.pid);
+        sys(SYS_SYNC_DESTROY, &[Value::Int(id as i64)]).unwrap();
+
+        let policy = sched_info(&process).unwrap().policy;
+        assert_eq!(
+            sys(SYS_SCHED_SETPOLICY, &[Value::Int(policy as i64)]),
+            Ok(Value::Int(policy as i64))
+        );
+    }
+
+    /**
+     * Decode the encoding of a record and encode it again, then check that
+     * a missing or an extra field is refused.
+     */
+    fn round_trip<T: Record>(record: &T) {
+        let value = record.encode();
+        let decoded = T::decode(&value).expect("record does not decode");
+        assert_eq!(decoded.encode(), value);
+        let Value::List(fields) = value else {
+            panic!("record is not a list");
+        };
+        let missing = Value::List(fields[..fields.len() - 1].to_vec());
+        assert!(T::decode(&missing).is_none());
+        let extra = Value::List([fields, vec![Value::Int(0)]].concat());
+        assert!(T::decode(&extra).is_none());
+    }
+
+    #[test]
+    fn info_records_survive_a_round_trip() {
+        round_trip(&ProcessInfo {
+            pid: 2,
+            ppid: 1,
+            state: ProcessState::Stopped,
+            nice: -5,
+            start_time: UNIX_EPOCH + Duration::from_millis(1_234),
+            cpu_time: Duration::from_micros(5_678),
+            pages: 3,
+            level: 4,
+            vruntime: 9,
+            cmdline: "sh -c ls".to_string(),
+        });
+        round_trip(&SchedStats {
+            policy: Policy::Cfs,
+            ticks: 10,
+            idle_ticks: 2,
+            context_switches: 7,
+        });
+        round_trip(&MemoryStats {
+            page_size: 4096,
+            total_frames: 1,
+            used_frames: 2,
+            huge_pages: 3,
+            huge_page_bytes: 4,
+            cow_faults: 5,
+            tlb_hits: 6,
+            tlb_misses: 7,
+            tlb_hit_rate: 0.0,
+            tlb_flushes: 8,
+            tlb_evictions: 9,
+            page_table_frames: 10,
+            page_faults: 11,
+            allocations: 12,
+            deallocations: 13,
+        });
+        round_trip(&HeapStats {
+            start: 1,
+            brk: 2,
+            allocated_blocks: 3,
+            allocated_bytes: 4,
+            requested_bytes: 5,
+            free_blocks: 6,
+            free_bytes: 7,
+            largest_free_block: 8,
+        });
+        round_trip(&MemoryRegion {
+            start: 0x1000,
+            size: 0x2000,
+            kind: "heap".to_string(),
+            flags: "rw-p".to_string(),
+            frames: vec![3, 4],
+        });
+        round_trip(&MemcheckReport {
+            owners: vec![(1, 0x1000, "process 1".to_string())],
+            unowned_pages: vec![(0x2000, 4096)],
+            orphan_frames: vec![5],
+            removed_owner: vec![(0x3000, "/tmp/gone".to_string())],
+            leaked: 12288,
+        });
+        round_trip(&IpcTables {
+            segments: vec![ShmInfo {
+                id: 1,
+                name: "/shm".to_string(),
+                size: 2,
+                pages: 3,
+                attached: 4,
+                unlinked: true,
+            }],
+            pipes: vec![
+                PipeInfo {
+                    id: 5,
+                    name: None,
+                    buffered: 6,
+                    capacity: 7,
+                    readers: 8,
+                    writers: 9,
+                },
+                PipeInfo {
+                    id: 10,
+                    name: Some("/fifo".to_string()),
+                    buffered: 11,
+                    capacity: 12,
+                    readers: 13,
+                    writers: 14,
+                },
+            ],
+            queues: vec![MessageQueueInfo {
+                id: 15,
+                name: "/queue".to_string(),
+                messages: 16,
+                bytes: 17,
+                max_messages: 18,
+                max_size: 19,
+            }],
+        });
+        round_trip(&LockTables {
+            objects: vec![
+                SyncInfo {
+                    id: 1,
+                    name: "lock".to_string(),
+                    kind: SyncKind::RwLock,
+                    owner: 2,
+                    holders: vec![3, 4],
+                    count: None,
+                    waiters: vec![5],
+                },
+                SyncInfo {
+                    id: 6,
+                    name: "sem".to_string(),
+                    kind: SyncKind::Semaphore,
+                    owner: 7,
+                    holders: vec![],
+                    count: Some(8),
+                    waiters: vec![],
+                },
+            ],
+            edges: vec![(5, 3, 1)],
+        });
+    }
+
+    #[test]
+    fn decoding_refuses_values_out_of_range() {
+        assert_eq!(u32::decode(&Value::Int(-1)), None);
+        assert_eq!(bool::decode(&Value::Int(2)), None);
+        assert_eq!(ProcessState::decode(&Value::Str("Lost".to_string())), None);
+        assert_eq!(SyncKind::decode(&Value::Int(SYNC_KINDS.len() as i64)), None);
+        let twice = Value::List(vec![Value::Int(1), Value::Int(2)]);
+        assert_eq!(Option::<u32>::decode(&twice), None);
+        let stats = MemoryStats {
+            tlb_hits: 3,
+            tlb_misses: 1,
+            ..Default::default()
+        };
+        let decoded = MemoryStats::decode(&stats.encode()).unwrap();
+        assert_eq!(decoded.tlb_hit_rate, 75.0);
+    }
+
+    #[test]
+    fn job_control_calls_follow_a_stopped_child() {
+        let mut process = spawn();
+        let child = process
+            .execute_child("spin", |child| {
+                while child.yield_cpu() {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                0
+            })
+            .unwrap();
+        let pid = Value::Int(child as i64);
+        let status = |pid, code, stopped| {
+            Ok(Value::List(vec![
+                Value::Int(pid),
+                Value::Int(code),
+                Value::Int(stopped),
+            ]))
+        };
+        let wait4 = |pid: &Value, options| {
+            syscall(&process, SYS_WAIT4, &[pid.clone(), Value::Int(options)])
+        };
+        let tcsetpgrp = |pid: &Value| syscall(&process, SYS_TCSETPGRP, std::slice::from_ref(pid));
+
+        assert_eq!(tcsetpgrp(&pid), Ok(Value::Int(0)));
+        assert_eq!(syscall(&process, SYS_TCGETPGRP, &[]), Ok(pid.clone()));
+        assert_eq!(tcsetpgrp(&Value::Int(u32::MAX as i64)), Err(Errno::ESRCH));
+
+        process.kill(child, Signal::SIGTSTP).unwrap();
+        assert_eq!(wait4(&pid, WUNTRACED), status(child as i64, 0, 1));
+        assert_eq!(wait4(&pid, WNOHANG), status(0, 0, 0));
+        assert_eq!(wait4(&Value::Int(-1), WUNTRACED), Err(Errno::EINVAL));
+        assert_eq!(wait4(&pid, WNOHANG | WUNTRACED), Err(Errno::EINVAL));
+        assert_eq!(wait4(&pid, 4), Err(Errno::EINVAL));
+
+        process.kill(child, Signal::SIGCONT).unwrap();
+        process.kill(child, Signal::SIGKILL).unwrap();
+        let killed = Signal::SIGKILL.exit_code() as i64;
+        assert_eq!(wait4(&pid, WUNTRACED), status(child as i64, killed, 0));
+        assert_eq!(tcsetpgrp(&Value::Int(0)), Ok(pid.clone()));
+
+        let Ok(Value::List(fds)) = syscall(&process, SYS_PIPE, &[]) else {
+            panic!("pipe failed");
+        };
+        assert_eq!(
+            syscall(&process, SYS_POLL, &[fds[0].clone(), Value::Int(0)]),
+            Err(Errno::ENOTTY)
+        );
+        fds.iter().for_each(|fd| {
+            syscall(&process, SYS_CLOSE, std::slice::from_ref(fd)).unwrap();
+        });
+    }
+
+    #[test]
+    fn sigaction_returns_the_previous_action() {
+        let process = spawn();
+        let sigaction = |signal: Signal, action| {
+            let args = [Value::Int(signal as i64), Value::Int(action)];
+            syscall(&process, SYS_RT_SIGACTION, &args)
+        };
+        assert_eq!(
+            sigaction(Signal::SIGTERM, SIG_QUERY),
+            Ok(Value::Int(SIG_DFL))
+        );
+        assert_eq!(sigaction(Signal::SIGTERM, SIG_IGN), Ok(Value::Int(SIG_DFL)));
+        assert_eq!(sigaction(Signal::SIGTERM, SIG_LOG), Ok(Value::Int(SIG_IGN)));
+        assert_eq!(
+            sigaction(Signal::SIGTERM, SIG_QUERY),
+            Ok(Value::Int(SIG_LOG))
+        );
+        assert_eq!(sigaction(Signal::SIGTERM, 3), Err(Errno::EINVAL));
+        assert_eq!(sigaction(Signal::SIGKILL, SIG_IGN), Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn signal_cuts_a_sleep_short() {
+        let mut parent = spawn();
+        let (sender, receiver) = mpsc::channel();
+        let child = parent
+            .execute_child("sleep", move |process| {
+                let started = Instant::now();
+                let result = syscall(process, SYS_NANOSLEEP, &[Value::Int(10_000)]);
+                sender.send((result, started.elapsed())).unwrap();
+                0
+            })
+            .unwrap();
+        while vpm::process(child).is_some_and(|child| child.state != ProcessState::Blocked) {
+            std::thread::sleep(scheduler::TICK);
+        }
+
+        let args = [Value::Int(child as i64), Value::Int(Signal::SIGKILL as i64)];
+        syscall(&parent, SYS_KILL, &args).unwrap();
+        let (result, elapsed) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(result, Err(Errno::EINTR));
+        assert!(elapsed < Duration::from_secs(1));
+        assert_eq!(
+            parent.waitpid(Some(child), false),
+            Ok(Some((child, Signal::SIGKILL.exit_code())))
+        );
+    }
+}
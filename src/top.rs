@@ -0,0 +1,560 @@
+/**
+ * Interactive process viewer
+ *
+ * Full screen view of the process table refreshed every interval, CPU
+ * usage is the share of scheduler ticks a process got since the last
+ * refresh.
+ */
+use crossterm::{
+    cursor::{Hide, MoveTo, Show},
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+    queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use std::{
+    collections::HashMap,
+    io::{stdout, Stdout, Write},
+    time::{Duration, Instant},
+};
+
+use crate::fd;
+use crate::scheduler::Policy;
+use crate::signal::Signal;
+use crate::syscall::{self, ProcessInfo, SchedStats, Value};
+use crate::vmm::MemoryStats;
+use crate::vpm::{ProcessState, Vpm};
+
+pub const DEFAULT_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Pid,
+    Cpu,
+    Time,
+    Pages,
+}
+
+impl SortKey {
+    const ALL: [SortKey; 4] = [Self::Pid, Self::Cpu, Self::Time, Self::Pages];
+
+    fn column(&self) -> &'static str {
+        match self {
+            Self::Pid => "PID",
+            Self::Cpu => "%CPU",
+            Self::Time => "TIME",
+            Self::Pages => "PAGES",
+        }
+    }
+
+    fn next(&self, step: isize) -> Self {
+        let position = Self::ALL.iter().position(|key| key == self).unwrap() as isize;
+        let count = Self::ALL.len() as isize;
+        Self::ALL[((position + step).rem_euclid(count)) as usize]
+    }
+}
+
+struct Row {
+    process: ProcessInfo,
+    cpu: f64,
+}
+
+struct Top<'a> {
+    process: &'a Vpm,
+    delay: Duration,
+    sort: SortKey,
+    reverse: bool,
+    message: String,
+    previous_cpu: HashMap<u32, Duration>,
+    previous_ticks: (u64, u64),
+    rows: Vec<Row>,
+    idle: f64,
+    policy: Option<Policy>,
+    memory: MemoryStats,
+}
+
+/**
+ * Refresh interval given in seconds, None unless it is positive and fits
+ * a Duration: inf or 1e30 seconds parse but are no Duration.
+ */
+pub fn parse_delay(seconds: &str) -> Option<Duration> {
+    seconds
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|&seconds| seconds > 0.0)
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+}
+
+/**
+ * Run top until q or ESC is pressed, refreshing every delay.
+ */
+pub fn run(process: &Vpm, delay: Duration) {
+    let mut top = Top::new(process, delay);
+
+    let mut out = stdout();
+    terminal::enable_raw_mode().unwrap();
+    queue!(out, EnterAlternateScreen, Hide).unwrap();
+
+    top.sample();
+    let mut next_refresh = Instant::now() + top.delay;
+    loop {
+        top.draw(&mut out);
+        let timeout = next_refresh.saturating_duration_since(Instant::now());
+        // Waiting for the terminal is sleeping, not using the CPU
+        let args = [
+            Value::Int(fd::STDIN as i64),
+            Value::Int(timeout.as_millis() as i64),
+        ];
+        let pressed = syscall::syscall(top.process, syscall::SYS_POLL, &args);
+        if pressed != Ok(Value::Int(1)) {
+            top.sample();
+            next_refresh = Instant::now() + top.delay;
+            continue;
+        }
+        if let Event::Key(key) = event::read().unwrap() {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            if !top.handle_key(&mut out, key) {
+                break;
+            }
+        }
+    }
+
+    queue!(out, Show, LeaveAlternateScreen).unwrap();
+    out.flush().unwrap();
+    terminal::disable_raw_mode().unwrap();
+}
+
+impl<'a> Top<'a> {
+    fn new(process: &'a Vpm, delay: Duration) -> Self {
+        Top {
+            process,
+            delay,
+            sort: SortKey::Cpu,
+            reverse: false,
+            message: String::new(),
+            previous_cpu: HashMap::new(),
+            previous_ticks: (0, 0),
+            rows: Vec::new(),
+            idle: 0.0,
+            policy: None,
+            memory: MemoryStats::default(),
+        }
+    }
+
+    /**
+     * Take a snapshot of the process table, the scheduler and the memory
+     * through the system calls and compute the CPU shares since the
+     * previous one.
+     */
+    fn sample(&mut self) {
+        let snapshot = syscall::sched_info(self.process).and_then(|scheduler| {
+            let processes = syscall::processes(self.process)?;
+            Ok((scheduler, processes, syscall::meminfo(self.process)?))
+        });
+        match snapshot {
+            Ok((scheduler, processes, memory)) => self.update(scheduler, processes, memory),
+            Err(errno) => self.message = format!("Cannot read the process table: {}", errno),
+        }
+    }
+
+    /**
+     * Compute the CPU share of each process from its own CPU time since
+     * the previous snapshot.
+     */
+    fn update(&mut self, scheduler: SchedStats, processes: Vec<ProcessInfo>, memory: MemoryStats) {
+        self.policy = Some(scheduler.policy);
+        self.memory = memory;
+        let (ticks, idle_ticks) = (scheduler.ticks, scheduler.idle_ticks);
+        let elapsed_ticks = ticks.saturating_sub(self.previous_ticks.0).max(1);
+        let elapsed = crate::scheduler::TICK * elapsed_ticks as u32;
+        self.idle =
+            idle_ticks.saturating_sub(self.previous_ticks.1) as f64 / elapsed_ticks as f64 * 100.0;
+        self.previous_ticks = (ticks, idle_ticks);
+
+        self.rows = processes
+            .into_iter()
+            .map(|process| {
+                let previous = self
+                    .previous_cpu
+                    .get(&process.pid)
+                    .copied()
+                    .unwrap_or_default();
+                let cpu = process.cpu_time.saturating_sub(previous).as_secs_f64()
+                    / elapsed.as_secs_f64()
+                    * 100.0;
+                Row {
+                    process,
+                    cpu: cpu.min(100.0),
+                }
+            })
+            .collect();
+        self.previous_cpu = self
+            .rows
+            .iter()
+            .map(|row| (row.process.pid, row.process.cpu_time))
+            .collect();
+        self.sort_rows();
+    }
+
+    fn sort_rows(&mut self) {
+        match self.sort {
+            SortKey::Pid => self.rows.sort_by_key(|row| row.process.pid),
+            SortKey::Cpu => self.rows.sort_by(|a, b| b.cpu.total_cmp(&a.cpu)),
+            SortKey::Time => self
+                .rows
+                .sort_by_key(|row| std::cmp::Reverse(row.process.cpu_time)),
+            SortKey::Pages => self
+                .rows
+                .sort_by_key(|row| std::cmp::Reverse(row.process.pages)),
+        }
+        if self.reverse {
+            self.rows.reverse();
+        }
+    }
+
+    fn draw(&self, out: &mut Stdout) {
+        let (width, height) = terminal::size().unwrap_or((80, 24));
+        let memory = &self.memory;
+        let count = |state: ProcessState| {
+            self.rows
+                .iter()
+                .filter(|row| row.process.state == state)
+                .count()
+        };
+        let policy = self
+            .policy
+            .map_or(String::from("-"), |policy| policy.to_string());
+
+        let mut lines = vec![
+            format!(
+                "Tasks: {} total, {} running, {} ready, {} blocked, {} stopped, {} zombie",
+                self.rows.len(),
+                count(ProcessState::Running),
+                count(ProcessState::Ready),
+                count(ProcessState::Blocked),
+                count(ProcessState::Stopped),
+                count(ProcessState::Zombie)
+            ),
+            format!(
+                "CPU: {:.1}% busy, {:.1}% idle, policy {}, refresh {:.1}s",
+                100.0 - self.idle,
+                self.idle,
+                policy,
+                self.delay.as_secs_f64()
+            ),
+            format!(
+                "MEM: {}/{} frames used, page size {} bytes, {} huge pages, {} COW faults",
+                memory.used_frames,
+                memory.total_frames,
+                memory.page_size,
+                memory.huge_pages,
+                memory.cow_faults
+            ),
+            format!(
+                "TLB: {} hits, {} misses ({:.2}% hit rate), {} flushes",
+                memory.tlb_hits, memory.tlb_misses, memory.tlb_hit_rate, memory.tlb_flushes
+            ),
+            self.message.clone(),
+        ];
+        let rows: Vec<String> = self
+            .rows
+            .iter()
+            .map(|row| {
+                format!(
+                    "{:>5} {:>5} {:>2} {:>3} {:>6.1} {:>9.2} {:>6} {}",
+                    row.process.pid,
+                    row.process.ppid,
+                    row.process.state,
+                    row.process.nice,
+                    row.cpu,
+                    row.process.cpu_time.as_secs_f64(),
+                    row.process.pages,
+                    row.process.cmdline
+                )
+            })
+            .collect();
+
+        let header = [
+            ("PID", 5),
+            ("PPID", 5),
+            ("S", 2),
+            ("NI", 3),
+            ("%CPU", 6),
+            ("TIME", 9),
+            ("PAGES", 6),
+        ]
+        .iter()
+        .map(|&(column, size)| {
+            let marker = if column == self.sort.column() {
+                "*"
+            } else {
+                ""
+            };
+            format!("{:>size$}", format!("{}{}", marker, column), size = size)
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+            + " CMD";
+
+        queue!(out, MoveTo(0, 0), Clear(ClearType::All)).unwrap();
+        let fit = |line: &str| line.chars().take(width as usize).collect::<String>();
+        for (y, line) in lines.drain(..).enumerate() {
+            queue!(out, MoveTo(0, y as u16), Print(fit(&line))).unwrap();
+        }
+        let top = 5;
+        queue!(
+            out,
+            MoveTo(0, top),
+            SetAttribute(Attribute::Reverse),
+            Print(format!("{:<width$}", fit(&header), width = width as usize)),
+            SetAttribute(Attribute::Reset)
+        )
+        .unwrap();
+        let visible = height.saturating_sub(top + 2) as usize;
+        for (y, row) in rows.iter().take(visible).enumerate() {
+            queue!(out, MoveTo(0, top + 1 + y as u16), Print(fit(row))).unwrap();
+        }
+        queue!(
+            out,
+            MoveTo(0, height.saturating_sub(1)),
+            Print(fit(
+                "q quit  P cpu  T time  M pages  N pid  < > column  R reverse  k kill  r renice  d delay"
+            ))
+        )
+        .unwrap();
+        out.flush().unwrap();
+    }
+
+    /**
+     * Ask for a value on the message line, None when ESC is pressed.
+     */
+    fn prompt(&mut self, out: &mut Stdout, question: &str) -> Option<String> {
+        let mut answer = String::new();
+        loop {
+            self.message = format!("{}{}", question, answer);
+            self.draw(out);
+            if let Event::Key(key) = event::read().unwrap() {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Enter => return Some(answer),
+                    KeyCode::Esc => return None,
+                    KeyCode::Backspace => {
+                        answer.pop();
+                    }
+                    KeyCode::Char(c) => answer.push(c),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /**
+     * Returns false when top must exit.
+     */
+    fn handle_key(&mut self, out: &mut Stdout, key: KeyEvent) -> bool {
+        self.message.clear();
+        match key.code {
+            KeyCode::Esc | KeyCode::Char('q') => return false,
+            KeyCode::Char('P') => self.sort = SortKey::Cpu,
+            KeyCode::Char('T') => self.sort = SortKey::Time,
+            KeyCode::Char('M') => self.sort = SortKey::Pages,
+            KeyCode::Char('N') => self.sort = SortKey::Pid,
+            KeyCode::Char('<') => self.sort = self.sort.next(-1),
+            KeyCode::Char('>') => self.sort = self.sort.next(1),
+            KeyCode::Char('R') => self.reverse = !self.reverse,
+            KeyCode::Char('k') => self.kill(out),
+            KeyCode::Char('r') => self.renice(out),
+            KeyCode::Char('d') | KeyCode::Char('s') => self.change_delay(out),
+            _ => {}
+        }
+        self.sort_rows();
+        true
+    }
+
+    fn kill(&mut self, out: &mut Stdout) {
+        let pid = self.prompt(out, "PID to signal/kill: ");
+        let pid = match pid.map(|pid| pid.trim().parse::<u32>()) {
+            Some(Ok(pid)) => pid,
+            Some(Err(_)) => {
+                self.message = "Invalid PID".to_string();
+                return;
+            }
+            None => return self.message.clear(),
+        };
+        let signal = match self.prompt(out, &format!("Send pid {} signal [TERM]: ", pid)) {
+            Some(signal) if signal.trim().is_empty() => Some(Signal::SIGTERM),
+            Some(signal) => Signal::from_str(signal.trim()),
+            None => return self.message.clear(),
+        };
+        self.message = match signal {
+            Some(signal) => match syscall::syscall(
+                self.process,
+                syscall::SYS_KILL,
+                &[Value::Int(pid as i64), Value::Int(signal as i64)],
+            ) {
+                Ok(_) => String::new(),
+                Err(errno) => format!("Failed signal pid {}: {}", pid, errno),
+            },
+            None => "Invalid signal".to_string(),
+        };
+    }
+
+    fn renice(&mut self, out: &mut Stdout) {
+        let pid = self.prompt(out, "PID to renice: ");
+        let pid = match pid.map(|pid| pid.trim().parse::<u32>()) {
+            Some(Ok(pid)) => pid,
+            Some(Err(_)) => {
+                self.message = "Invalid PID".to_string();
+                return;
+            }
+            None => return self.message.clear(),
+        };
+        let nice = match self.prompt(out, &format!("Renice PID {} to value: ", pid)) {
+            Some(nice) => nice.trim().parse::<i32>(),
+            None => return self.message.clear(),
+        };
+        self.message = match nice {
+            Ok(nice) => match syscall::syscall(
+                self.process,
+                syscall::SYS_SETPRIORITY,
+                &[Value::Int(pid as i64), Value::Int(nice as i64)],
+            ) {
+                Ok(_) => String::new(),
+                Err(errno) => format!("Failed renice of PID {} to {}: {}", pid, nice, errno),
+            },
+            Err(_) => "Invalid nice value".to_string(),
+        };
+    }
+
+    fn change_delay(&mut self, out: &mut Stdout) {
+        let delay = self.prompt(
+            out,
+            &format!("Change delay from {:.1} to: ", self.delay.as_secs_f64()),
+        );
+        self.message = match delay.as_deref().map(parse_delay) {
+            Some(Some(delay)) => {
+                self.delay = delay;
+                String::new()
+            }
+            Some(None) => "Invalid delay".to_string(),
+            None => String::new(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmm::{PageSize, Vmm};
+    use std::{
+        sync::{Arc, Mutex},
+        time::UNIX_EPOCH,
+    };
+
+    fn spawn() -> Vpm {
+        let vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        Vpm::new(Arc::new(Mutex::new(vmm)))
+    }
+
+    fn info(pid: u32, ppid: u32, cpu_millis: u64, pages: u64) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            ppid,
+            state: ProcessState::Ready,
+            nice: 0,
+            start_time: UNIX_EPOCH,
+            cpu_time: Duration::from_millis(cpu_millis),
+            pages,
+            level: 0,
+            vruntime: 0,
+            cmdline: format!("process {}", pid),
+        }
+    }
+
+    fn scheduler(ticks: u64, idle_ticks: u64) -> SchedStats {
+        SchedStats {
+            policy: Policy::RoundRobin,
+            ticks,
+            idle_ticks,
+            context_switches: 0,
+        }
+    }
+
+    fn pids(top: &Top) -> Vec<u32> {
+        top.rows.iter().map(|row| row.process.pid).collect()
+    }
+
+    #[test]
+    fn rows_sort_by_every_key() {
+        let shell = spawn();
+        let mut top = Top::new(&shell, DEFAULT_DELAY);
+        top.rows = [(1, 30, 20, 5.0), (2, 10, 40, 50.0), (3, 20, 30, 25.0)]
+            .into_iter()
+            .map(|(pid, cpu_millis, pages, cpu)| Row {
+                process: info(pid, 0, cpu_millis, pages),
+                cpu,
+            })
+            .collect();
+
+        let expected = [
+            (SortKey::Pid, [1, 2, 3]),
+            (SortKey::Cpu, [2, 3, 1]),
+            (SortKey::Time, [1, 3, 2]),
+            (SortKey::Pages, [2, 3, 1]),
+        ];
+        for (sort, order) in expected {
+            top.sort = sort;
+            top.reverse = false;
+            top.sort_rows();
+            assert_eq!(pids(&top), order, "{:?}", sort);
+            top.reverse = true;
+            top.sort_rows();
+            let reversed: Vec<u32> = order.iter().rev().copied().collect();
+            assert_eq!(pids(&top), reversed, "{:?} reversed", sort);
+        }
+    }
+
+    #[test]
+    fn children_get_their_own_cpu_share() {
+        let shell = spawn();
+        let mut top = Top::new(&shell, DEFAULT_DELAY);
+        let memory = MemoryStats::default();
+        top.update(
+            scheduler(100, 0),
+            vec![info(2, 1, 500, 0), info(3, 2, 0, 0)],
+            memory.clone(),
+        );
+
+        // 10 ticks of 10 ms: the parent ran 20 ms of them, its child 60 ms
+        top.update(
+            scheduler(110, 2),
+            vec![info(2, 1, 520, 0), info(3, 2, 60, 0)],
+            memory,
+        );
+        let share = |pid| {
+            top.rows
+                .iter()
+                .find(|row| row.process.pid == pid)
+                .unwrap()
+                .cpu
+        };
+        assert!((share(2) - 20.0).abs() < 1e-9);
+        assert!((share(3) - 60.0).abs() < 1e-9);
+        assert!((top.idle - 20.0).abs() < 1e-9);
+        assert_eq!(pids(&top), [3, 2]);
+    }
+
+    #[test]
+    fn delays_must_be_positive_durations() {
+        assert_eq!(parse_delay("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_delay(" 0.5 "), Some(Duration::from_millis(500)));
+        ["", "0", "-1", "abc", "NaN", "inf", "1e30"]
+            .iter()
+            .for_each(|delay| assert_eq!(parse_delay(delay), None, "{}", delay));
+    }
+}
@@ -5,17 +5,13 @@ use crossterm::{
 };
 
 use reqwest::{Client, Method};
-use std::{
-    collections::HashMap,
-    io::{stdout},
-};
+use std::{collections::HashMap, io::stdout};
 
 pub fn is_unix_symbol(s: &str) -> bool {
     const PROTECTED_SYMBOL: [&str; 3] = ["/", ".", ".."];
     PROTECTED_SYMBOL.contains(&s)
 }
 
-
 pub fn clear_terminal() {
     stdout().execute(Clear(ClearType::All)).unwrap();
     stdout().execute(MoveTo(0, 0)).unwrap();
@@ -28,8 +24,8 @@ pub async fn http_async_get(
     print_download_percentage: bool,
 ) -> Option<Vec<u8>> {
     let mut request = Client::new().request(Method::GET, url);
-    if params.is_some() {
-        request = request.query(&params.unwrap());
+    if let Some(params) = params {
+        request = request.query(&params);
     }
 
     let mut response = match request.send().await {
@@ -42,7 +38,7 @@ pub async fn http_async_get(
 
     if response.status().is_client_error() || response.status().is_server_error() {
         println!("Url: {}, Error: {}", url, response.text().await.unwrap());
-        return None;
+        None
     } else {
         let content_size = response.content_length().unwrap();
         let mut bytes_stream: Vec<u8> = Vec::new();
@@ -56,6 +52,6 @@ pub async fn http_async_get(
             }
         }
 
-        return Some(bytes_stream);
+        Some(bytes_stream)
     }
 }
@@ -1,22 +1,127 @@
-use crate::editor::Editor;
+use crate::errno::Errno;
+use crate::ipc::IPC;
 use crate::utils;
-use crate::vmm::Vmm;
+use crate::vmm::{MapKind, PageOwner, PageSize, Vmm, KERNEL_SPACE};
 use crate::vpm::Vpm;
-use std::sync::{Arc, Mutex};
-use std::{collections::HashMap, path::PathBuf};
+use lazy_static::lazy_static;
+use std::sync::{Arc, Mutex, RwLock};
+use std::{
+    collections::HashMap,
+    path::{Component, PathBuf},
+};
 
 const SEPARATOR: &str = "/";
 
+// Initialize the virtual file system
+lazy_static! {
+    pub static ref VFS: RwLock<Vfs> = RwLock::new(init_vfs());
+}
+
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct File {
-    name: String,
-    path: PathBuf,
+    pub name: String,
+    pub path: PathBuf,
     pub vmm_address: Vec<u64>,
     pub size: u64,
+    pub kind: FileKind,
+    pub executable: bool,
+}
+
+impl File {
+    /**
+     * Read up to len bytes from offset, page by page.
+     */
+    pub fn read_at(&self, vmm: &mut Vmm, offset: u64, len: usize) -> Result<Vec<u8>, Errno> {
+        let end = self.size.min(offset.saturating_add(len as u64));
+        let mut bytes = Vec::new();
+        let mut page_start = 0;
+        for &address in &self.vmm_address {
+            if page_start >= end {
+                break;
+            }
+            let page_end = page_start + vmm.pages_size(&[address]);
+            if page_end > offset {
+                let mut content = vmm.read_page(KERNEL_SPACE, address)?;
+                content.resize((page_end - page_start) as usize, 0);
+                let from = offset.max(page_start) - page_start;
+                let to = end.min(page_end) - page_start;
+                bytes.extend_from_slice(&content[from as usize..to as usize]);
+            }
+            page_start = page_end;
+        }
+        Ok(bytes)
+    }
+
+    /**
+     * Write the bytes at offset, growing the file with new pages as needed.
+     * Writing past the end fills the gap with zeros. A file cannot outgrow
+     * the memory (EFBIG) and grows only into free frames (ENOMEM).
+     */
+    pub fn write_at(&mut self, vmm: &mut Vmm, offset: u64, bytes: &[u8]) -> Result<(), Errno> {
+        let end = offset
+            .checked_add(bytes.len() as u64)
+            .filter(|&end| end <= vmm.total_memory)
+            .ok_or(Errno::EFBIG)?;
+        if end.saturating_sub(vmm.pages_size(&self.vmm_address)) > vmm.free_memory {
+            return Err(Errno::ENOMEM);
+        }
+        let (offset, bytes) = if offset > self.size {
+            let mut padded = vec![0; (offset - self.size) as usize];
+            padded.extend_from_slice(bytes);
+            (self.size, padded)
+        } else {
+            (offset, bytes.to_vec())
+        };
+        while vmm.pages_size(&self.vmm_address) < end {
+            let (vmm_address, _) = vmm.allocate_page()?;
+            vmm.set_owner(&[vmm_address], PageOwner::File(self.path.clone()));
+            self.vmm_address.push(vmm_address);
+        }
+
+        let mut page_start = 0;
+        for &address in &self.vmm_address {
+            if page_start >= end {
+                break;
+            }
+            let page_end = page_start + vmm.pages_size(&[address]);
+            if page_end > offset {
+                let from = offset.max(page_start);
+                let to = end.min(page_end);
+                let chunk = &bytes[(from - offset) as usize..(to - offset) as usize];
+                vmm.write_page(KERNEL_SPACE, address, from - page_start, chunk)?;
+            }
+            page_start = page_end;
+        }
+        self.size = self.size.max(end);
+        Ok(())
+    }
+
+    /**
+     * Drop the content, keeping a single empty page.
+     */
+    pub fn truncate(&mut self, vmm: &mut Vmm) -> Result<(), Errno> {
+        vmm.deallocate_page(std::mem::take(&mut self.vmm_address));
+        self.size = 0;
+        let (vmm_address, _) = vmm.allocate_page()?;
+        vmm.set_owner(&[vmm_address], PageOwner::File(self.path.clone()));
+        self.vmm_address = vec![vmm_address];
+        Ok(())
+    }
+}
+
+/**
+ * A FIFO node holds no pages itself, its data lives in the IPC pipe.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileKind {
+    #[default]
+    Regular,
+    Fifo(u32),
 }
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 struct Directory {
     name: String,
     parent: Option<Box<Directory>>,
@@ -35,6 +140,18 @@ impl Directory {
             path,
         }
     }
+
+    fn all_files(&self) -> Vec<Arc<Mutex<File>>> {
+        self.files
+            .values()
+            .cloned()
+            .chain(
+                self.subdirectories
+                    .values()
+                    .flat_map(|subdir| subdir.all_files()),
+            )
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -55,71 +172,46 @@ impl Vfs {
         }
     }
 
-    pub fn list(&mut self) {
-        let current_dir = self.get_dir_in_vfs(
-            self.cwd
-                .clone()
-                .into_os_string()
-                .into_string()
-                .unwrap()
-                .as_str(),
-        );
-        if let Some(dir) = current_dir {
-            if dir.files.is_empty() {
-                println!("No files found");
-            } else {
-                for file in dir.files.values() {
-                    let file = file.as_ref().lock().unwrap();
-                    println!("{} {}", file.name, file.size);
-                }
-            }
-
-            if dir.subdirectories.is_empty() {
-                println!("No directories found");
-            } else {
-                for subdir in dir.subdirectories.values() {
-                    println!("{}/", subdir.name);
-                }
-            }
-        }
+    /**
+     * Names of the files and subdirectories of the current directory.
+     */
+    pub fn entries(&mut self) -> Result<Vec<String>, Errno> {
+        let cwd = self.cwd.clone();
+        let dir = self
+            .get_dir_in_vfs(cwd.to_str().unwrap())
+            .ok_or(Errno::ENOENT)?;
+        Ok(dir
+            .files
+            .keys()
+            .chain(dir.subdirectories.keys())
+            .cloned()
+            .collect())
     }
 
-    pub fn pwd(self) {
-        println!(
-            "{}",
-            self.cwd.clone().into_os_string().into_string().unwrap()
-        );
+    pub fn cwd(&self) -> String {
+        self.cwd.to_string_lossy().to_string()
     }
 
-    pub fn add_directory_recursive(&mut self, dirnames: &str) {
+    pub fn add_directory_recursive(&mut self, dirnames: &str) -> Result<(), Errno> {
         let mut current_path = self.cwd.clone();
-        let mut current_dir = match self.get_dir_in_vfs(current_path.to_str().unwrap()) {
-            Some(dir) => dir,
-            None => return,
-        };
+        let mut current_dir = self
+            .get_dir_in_vfs(current_path.to_str().unwrap())
+            .ok_or(Errno::ENOENT)?;
 
         if utils::is_unix_symbol(dirnames) {
-            println!("Invalid directory name");
-            return;
+            return Err(Errno::EINVAL);
         }
 
         if dirnames.split(SEPARATOR).count() > 1 {
             let dirs = dirnames.split(SEPARATOR);
             for dir in dirs {
-                if !current_dir.subdirectories.contains_key(dir) {
-                    current_dir.subdirectories.insert(
-                        String::from(dir),
-                        Directory::new(
-                            dir,
-                            current_path.join(dir),
-                            Some(Box::new(current_dir.clone())),
-                        ),
-                    );
-                } else {
-                    let curr = current_dir.subdirectories.get_mut(dir).unwrap();
-                    current_path = curr.path.clone();
-                    current_dir = curr;
-                }
+                let parent = Box::new(current_dir.clone());
+                let path = current_path.join(dir);
+                current_dir = current_dir
+                    .subdirectories
+                    .entry(String::from(dir))
+                    .or_insert_with(|| Directory::new(dir, path, Some(parent)));
+                current_path = current_dir.path.clone();
             }
         } else {
             if !current_dir.subdirectories.contains_key(dirnames) {
@@ -132,176 +224,262 @@ impl Vfs {
                     ),
                 );
             } else {
-                println!("Directory already exists");
+                return Err(Errno::EEXIST);
             }
         }
+        Ok(())
     }
 
-    pub fn change_dir(&mut self, dir: &str) {
-        if dir == "." {
-            return;
-        }
-
-        if dir == "/" {
-            self.cwd = PathBuf::from(SEPARATOR);
-            return;
-        }
-
-        if dir == ".." {
-            match self.cwd.parent() {
-                Some(parent) => {
-                    self.cwd = parent.to_path_buf();
-                    return;
-                },
-                None => {
-                    return
-                }
-            }
-        }
-
-        let dir_to = self.get_dir_in_vfs(self.cwd.join(dir).to_str().unwrap());
-        if let Some(dir) = dir_to {
-            self.cwd = dir.path.clone();
-        } else {
-            println!("Directory not found");
-        }
+    pub fn change_dir(&mut self, dir: &str) -> Result<(), Errno> {
+        let path = self.resolve(dir);
+        self.get_dir_in_vfs(path.to_str().unwrap())
+            .ok_or(Errno::ENOENT)?;
+        self.cwd = path;
+        Ok(())
     }
 
-    pub fn remove(&mut self, files_path: &str) {
+    pub fn remove(&mut self, files_path: &str) -> Result<(), Errno> {
         let file = files_path.split(SEPARATOR).last();
-        if file.unwrap().contains(".") {
+        let cwd = self.cwd.clone();
+        // FIFOs and programs usually have no extension
+        let is_file = self
+            .get_dir_in_vfs(cwd.join(files_path).parent().unwrap().to_str().unwrap())
+            .is_some_and(|dir| dir.files.contains_key(file.unwrap()));
+        if file.unwrap().contains(".") || is_file {
             let dir_path = &files_path
                 .split(SEPARATOR)
                 .take(files_path.split(SEPARATOR).count() - 1)
                 .collect::<Vec<&str>>()
                 .join(SEPARATOR);
             let current_dir = self.get_dir_in_vfs(self.cwd.join(dir_path).to_str().unwrap());
-            if let Some(dir) = current_dir {
-                if dir.files.contains_key(file.unwrap()) {
-                    let file_address_to_deallocate = dir
-                        .files
-                        .get(file.unwrap())
-                        .unwrap()
-                        .lock()
-                        .unwrap()
-                        .vmm_address
-                        .clone();
-                    dir.files.remove(file.unwrap());
-                    let mut vmm = self.vpm.vmm.lock().unwrap();
-                    vmm.deallocate_page(file_address_to_deallocate);
-                    drop(vmm);
-                } else {
-                    println!("File {} not found.", file.unwrap());
-                }
-            } else {
-                println!("Directory {} not found.", dir_path);
-            }
+            let removed = current_dir
+                .and_then(|dir| dir.files.remove(file.unwrap()))
+                .ok_or(Errno::ENOENT)?;
+            release(&self.vpm.vmm, &removed.lock().unwrap());
         } else {
-            let dir_to_remove = self.get_dir_in_vfs(self.cwd.join(files_path).to_str().unwrap());
-            if let Some(dir) = dir_to_remove {
-                dir.parent
-                    .as_mut()
-                    .unwrap()
-                    .subdirectories
-                    .remove(&dir.name);
-            } else {
-                println!("Directory {} not found.", files_path)
-            }
+            let dir_path = self.cwd.join(files_path);
+            let parent_path = dir_path.parent().map(|parent| parent.to_path_buf());
+            let dir_name = dir_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string());
+            let removed = match (parent_path, dir_name) {
+                (Some(parent_path), Some(dir_name)) => self
+                    .get_dir_in_vfs(parent_path.to_str().unwrap())
+                    .and_then(|parent| parent.subdirectories.remove(&dir_name)),
+                _ => None,
+            };
+            // Give back the pages of every file living under the directory
+            removed
+                .ok_or(Errno::ENOENT)?
+                .all_files()
+                .iter()
+                .for_each(|file| {
+                    release(&self.vpm.vmm, &file.lock().unwrap());
+                });
         }
+        Ok(())
     }
 
-    pub fn touch(&mut self, filename: &str) {
+    /**
+     * Create an empty file in the current directory.
+     */
+    pub fn touch(&mut self, filename: &str) -> Result<(), Errno> {
         if filename.contains(SEPARATOR) {
-            println!("Please provide only filename with extension {}", filename);
-            return;
+            return Err(Errno::EINVAL);
         }
 
-        let mut vmm = self.vpm.vmm.lock().unwrap();
-        let (vmm_address, _) = vmm.allocate_page();
+        let cwd = self.cwd.clone();
+        let vmm = Arc::clone(&self.vpm.vmm);
+        let current_dir = self
+            .get_dir_in_vfs(cwd.to_str().unwrap())
+            .ok_or(Errno::ENOENT)?;
+        if current_dir.files.contains_key(filename) {
+            return Err(Errno::EEXIST);
+        }
+
+        let mut vmm = vmm.lock().unwrap();
+        let (vmm_address, _) = vmm.allocate_page()?;
+        vmm.set_owner(&[vmm_address], PageOwner::File(cwd.join(filename)));
         drop(vmm);
 
+        let new_file = File {
+            vmm_address: vec![vmm_address],
+            name: filename.to_string(),
+            path: cwd.join(filename),
+            size: 0,
+            kind: FileKind::Regular,
+            executable: false,
+        };
+        current_dir
+            .files
+            .insert(filename.to_string(), Arc::new(Mutex::new(new_file)));
+        Ok(())
+    }
+
+    /**
+     * Create a named pipe in the current directory.
+     */
+    pub fn mkfifo(&mut self, filename: &str) -> Result<(), Errno> {
+        if filename.contains(SEPARATOR) || utils::is_unix_symbol(filename) {
+            return Err(Errno::EINVAL);
+        }
+
         let cwd = self.cwd.clone();
-        let current_dir = self.get_dir_in_vfs(cwd.to_str().unwrap()).unwrap();
-        if !current_dir.files.contains_key(filename) {
-            let new_file = File {
-                vmm_address: vec![vmm_address],
-                name: filename.to_string(),
-                path: PathBuf::from(cwd.join(filename)),
-                size: 0,
-            };
-            current_dir
-                .files
-                .insert(filename.to_string(), Arc::new(Mutex::new(new_file)));
-        } else {
-            println!("File {} already exists.", filename)
+        let vmm = Arc::clone(&self.vpm.vmm);
+        let current_dir = self
+            .get_dir_in_vfs(cwd.to_str().unwrap())
+            .ok_or(Errno::ENOENT)?;
+        if current_dir.files.contains_key(filename) {
+            return Err(Errno::EEXIST);
         }
+        let path = cwd.join(filename);
+        let id = IPC
+            .lock()
+            .unwrap()
+            .mkfifo(&mut vmm.lock().unwrap(), path.to_str().unwrap())?;
+        let fifo = File {
+            vmm_address: Vec::new(),
+            name: filename.to_string(),
+            path,
+            size: 0,
+            kind: FileKind::Fifo(id),
+            executable: false,
+        };
+        current_dir
+            .files
+            .insert(filename.to_string(), Arc::new(Mutex::new(fifo)));
+        Ok(())
     }
 
     /**
-     * Allow to write file content, if no bytes are provided, it will open the editor
+     * File of the current directory to open a descriptor on, created when
+     * missing if create is set.
      */
-    pub fn write_file(
-        &mut self,
-        filename: &str,
-        bytes_to_write: Option<Vec<u8>>,
-        filepath: Option<&str>,
-    ) {
-        if let Some(file) = self.get_file_in_cwd(filename) {
-            let vmm_clone = Arc::clone(&self.vpm.vmm);
-            if bytes_to_write.is_none() {
-                self.vpm.execute(move |_| {
-                    Editor::write(file, vmm_clone);
-                });
-            } else {
-                self.write_file_bytes(vmm_clone, file, bytes_to_write.unwrap(), filepath);
+    pub fn open_file(&mut self, filename: &str, create: bool) -> Result<Arc<Mutex<File>>, Errno> {
+        // Files are created in the current directory, existing ones can be
+        // opened by path
+        if filename.contains(SEPARATOR) {
+            return self.lookup(filename).ok_or(Errno::ENOENT);
+        }
+        let cwd = self.cwd.clone();
+        let dir = self
+            .get_dir_in_vfs(cwd.to_str().unwrap())
+            .ok_or(Errno::ENOENT)?;
+        if !dir.files.contains_key(filename) {
+            if !create || filename.contains(SEPARATOR) {
+                return Err(Errno::ENOENT);
             }
+            self.touch(filename)?;
         }
+        self.get_dir_in_vfs(cwd.to_str().unwrap())
+            .and_then(|dir| dir.files.get(filename).cloned())
+            .ok_or(Errno::ENOENT)
     }
 
-    fn write_file_bytes(
-        &mut self,
-        vmm: Arc<Mutex<Vmm>>,
-        file: Arc<Mutex<File>>,
-        bytes: Vec<u8>,
-        filepath: Option<&str>,
-    ) {
-        if filepath.is_none() {
-            println!("Please provide a file path");
-            return;
+    /**
+     * Map the file into the address space of the process, growing the file
+     * pages to cover at least length bytes. Returns the mapping address.
+     */
+    pub fn mmap(&mut self, path: &str, pid: u32, length: u64, kind: MapKind) -> Result<u64, Errno> {
+        let file = self.lookup(path).ok_or(Errno::ENOENT)?;
+        let mut file = file.lock().unwrap();
+        if file.kind != FileKind::Regular {
+            return Err(Errno::EACCES);
+        }
+        let mut vmm = self.vpm.vmm.lock().unwrap();
+
+        let bytes_needed = length.max(file.size).max(1);
+        while vmm.pages_size(&file.vmm_address) < bytes_needed {
+            let (vmm_address, _) = vmm.allocate_page()?;
+            vmm.set_owner(&[vmm_address], PageOwner::File(file.path.clone()));
+            file.vmm_address.push(vmm_address);
         }
 
-        self.vpm.execute(move |_| {
-            let mut vmm = vmm.lock().unwrap();
-            let virtual_addresses = vmm.allocate_bytes(bytes.clone());
-            let mut file = file.lock().unwrap();
-            file.size = bytes.len() as u64;
-            file.vmm_address = virtual_addresses;
-            file.path = PathBuf::from(filepath.unwrap());
-        });
+        vmm.mmap(pid, &file.vmm_address, kind)
     }
 
-    pub fn read_file(&mut self, filename: &str) {
-        if let Some(file) = self.get_file_in_cwd(filename) {
-            let vmm_clone = Arc::clone(&self.vpm.vmm);
-            self.vpm.execute(move |_| {
-                Editor::read(file, vmm_clone);
-            });
-        }
+    pub fn munmap(&mut self, pid: u32, address: u64) -> Result<(), Errno> {
+        self.vpm.vmm.lock().unwrap().munmap(pid, address)
     }
 
-    pub fn get_file_in_cwd(&mut self, filename: &str) -> Option<Arc<Mutex<File>>> {
-        let cwd = self.cwd.clone();
-        if let Some(current_dir) = self.get_dir_in_vfs(cwd.to_str().unwrap()) {
-            if current_dir.files.contains_key(filename) {
-                return Some(current_dir.files.get(filename).unwrap().clone());
-            } else {
-                println!("File {} not found.", filename);
-                return None;
+    /**
+     * Write back what the process wrote through the mapping, updating the
+     * size of the mapped file.
+     */
+    pub fn msync(&mut self, pid: u32, address: u64) -> Result<(), Errno> {
+        let owner = self.vpm.vmm.lock().unwrap().mapping_owner(pid, address)?;
+        let Some(PageOwner::File(path)) = owner else {
+            return Err(Errno::EINVAL);
+        };
+        let file = self.lookup(path.to_str().unwrap()).ok_or(Errno::ENOENT)?;
+        let mut file = file.lock().unwrap();
+        let mut vmm = self.vpm.vmm.lock().unwrap();
+        file.size = vmm.msync(pid, address, file.size)?;
+        Ok(())
+    }
+
+    /**
+     * Path of every file in the file system.
+     */
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        self.root
+            .all_files()
+            .iter()
+            .map(|file| file.lock().unwrap().path.clone())
+            .collect()
+    }
+
+    /**
+     * File at the path, absolute or relative to the current directory, with
+     * . and .. resolved.
+     */
+    pub fn lookup(&mut self, path: &str) -> Option<Arc<Mutex<File>>> {
+        let path = self.resolve(path);
+        let name = path.file_name()?.to_str()?;
+        self.get_dir_in_vfs(path.parent()?.to_str()?)?
+            .files
+            .get(name)
+            .cloned()
+    }
+
+    /**
+     * Whether the path, absolute or relative to the current directory, is
+     * a directory.
+     */
+    pub fn is_dir(&mut self, path: &str) -> bool {
+        let path = self.resolve(path);
+        self.get_dir_in_vfs(path.to_str().unwrap()).is_some()
+    }
+
+    /**
+     * Absolute path of a path relative to the current directory, with
+     * . and .. resolved.
+     */
+    fn resolve(&self, path: &str) -> PathBuf {
+        let mut resolved = PathBuf::new();
+        for component in self.cwd.join(path).components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                component => resolved.push(component),
             }
-        } else {
-            println!("Directory {} not found.", cwd.to_str().unwrap());
-            None
         }
+        resolved
+    }
+
+    /**
+     * Set or clear the execute bit of a regular file.
+     */
+    pub fn chmod(&mut self, path: &str, executable: bool) -> Result<(), Errno> {
+        let file = self.lookup(path).ok_or(Errno::ENOENT)?;
+        let mut file = file.lock().unwrap();
+        if file.kind != FileKind::Regular {
+            return Err(Errno::EPERM);
+        }
+        file.executable = executable;
+        Ok(())
     }
 
     fn get_dir_in_vfs(&mut self, path: &str) -> Option<&mut Directory> {
@@ -320,8 +498,122 @@ impl Vfs {
     }
 }
 
+/**
+ * Give back what a removed file holds: its pages, or its FIFO.
+ */
+fn release(vmm: &Mutex<Vmm>, file: &File) {
+    match file.kind {
+        FileKind::Regular => vmm
+            .lock()
+            .unwrap()
+            .deallocate_page(file.vmm_address.clone()),
+        FileKind::Fifo(id) => {
+            let mut ipc = IPC.lock().unwrap();
+            ipc.fifo_unlink(&mut vmm.lock().unwrap(), id);
+        }
+    }
+}
+
+/**
+ * The page size can be chosen with KERNELINO_PAGE_SIZE (4K, 16K or 64K).
+ */
 pub fn init_vfs() -> Vfs {
-    Vfs::new(Vpm::new(Arc::new(Mutex::new(Vmm::new(
+    let page_size = std::env::var("KERNELINO_PAGE_SIZE")
+        .ok()
+        .and_then(|size| PageSize::from_str(&size))
+        .unwrap_or_default();
+    Vfs::new(Vpm::new(Arc::new(Mutex::new(Vmm::with_page_size(
         1024 * 1024 * 1024 * 4,
+        page_size,
     )))))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_through_mapping_reaches_file_after_msync() {
+        let vmm = Arc::new(Mutex::new(Vmm::with_page_size(
+            1 << 24,
+            PageSize::default(),
+        )));
+        let mut vfs = Vfs::new(Vpm::new(Arc::clone(&vmm)));
+        let pid = vfs.vpm.pid;
+        let file = vfs.open_file("notes", true).unwrap();
+        file.lock()
+            .unwrap()
+            .write_at(&mut vmm.lock().unwrap(), 0, b"hello")
+            .unwrap();
+
+        let address = vfs.mmap("notes", pid, 4096 + 8, MapKind::Shared).unwrap();
+        let mut content = b"HELLO".to_vec();
+        content.resize(4096 + 8, 0);
+        content[4096..].copy_from_slice(b"mapped!\n");
+        vmm.lock()
+            .unwrap()
+            .write_bytes(pid, address, &content[..5])
+            .unwrap();
+        vmm.lock()
+            .unwrap()
+            .write_bytes(pid, address + 4096, &content[4096..])
+            .unwrap();
+        assert_eq!(file.lock().unwrap().size, 5);
+
+        vfs.msync(pid, address).unwrap();
+        let file = file.lock().unwrap();
+        assert_eq!(file.size, content.len() as u64);
+        let read = file.read_at(&mut vmm.lock().unwrap(), 0, 8192).unwrap();
+        assert_eq!(read, content);
+        drop(file);
+        vfs.munmap(pid, address).unwrap();
+        assert_eq!(vfs.msync(pid, address), Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn write_past_memory_fails_without_growing_the_file() {
+        let memory = 1 << 24;
+        let vmm = Arc::new(Mutex::new(Vmm::with_page_size(memory, PageSize::default())));
+        let mut vfs = Vfs::new(Vpm::new(Arc::clone(&vmm)));
+        let file = vfs.open_file("sparse", true).unwrap();
+        let mut file = file.lock().unwrap();
+        let mut vmm = vmm.lock().unwrap();
+        let free_memory = vmm.free_memory;
+
+        assert_eq!(file.write_at(&mut vmm, u64::MAX, b"x"), Err(Errno::EFBIG));
+        assert_eq!(file.write_at(&mut vmm, memory, b"x"), Err(Errno::EFBIG));
+        assert_eq!(
+            file.write_at(&mut vmm, memory - 1, b"x"),
+            Err(Errno::ENOMEM)
+        );
+        assert_eq!(file.size, 0);
+        assert_eq!(vmm.free_memory, free_memory);
+
+        file.write_at(&mut vmm, 8192, b"x").unwrap();
+        assert_eq!(file.size, 8193);
+        assert_eq!(file.read_at(&mut vmm, 8190, 8).unwrap(), b"\0\0x");
+    }
+
+    #[test]
+    fn rewriting_a_file_frees_its_old_pages() {
+        let vmm = Arc::new(Mutex::new(Vmm::with_page_size(
+            1 << 24,
+            PageSize::default(),
+        )));
+        let mut vfs = Vfs::new(Vpm::new(Arc::clone(&vmm)));
+        let file = vfs.open_file("rewritten", true).unwrap();
+        let mut file = file.lock().unwrap();
+        let mut vmm = vmm.lock().unwrap();
+        let content = vec![b'x'; 3 * 4096 + 10];
+
+        let mut used_frames = None;
+        for _ in 0..10 {
+            file.truncate(&mut vmm).unwrap();
+            file.write_at(&mut vmm, 0, &content).unwrap();
+            let used = vmm.stats().used_frames;
+            assert_eq!(*used_frames.get_or_insert(used), used);
+        }
+        assert_eq!(file.read_at(&mut vmm, 0, 8).unwrap(), b"xxxxxxxx");
+        assert!(vmm.memcheck().unowned_pages.is_empty());
+    }
+}
@@ -1,16 +1,131 @@
 /**
  * Virtual Memory Manager
  */
-use std::{cmp::min, collections::HashMap};
+use std::{
+    cmp::min,
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use crate::errno::Errno;
+use crate::heap::{Heap, HeapStats, HEAP_START_PAGE};
+use crate::paging::{PageTable, Tlb, HUGE_PAGE_SPAN};
 
 const DEFAULT_PAGE_SIZE: u64 = 4096;
 
+/**
+ * Huge pages are backed by a run of contiguous frames and have the PS flag
+ * set. They are only used with 4 KiB pages, when a PD entry spans 2 MiB.
+ */
+pub const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+/**
+ * Address space used by the kernel itself (file contents, etc.).
+ * Processes get their own address space keyed by their pid.
+ */
+pub const KERNEL_SPACE: u32 = 0;
+
+/**
+ * Address space exec builds the new image in, no pid reaches it. The lock
+ * on the memory manager is held until the image replaces the old one.
+ */
+pub const EXEC_SPACE: u32 = u32::MAX;
+
+const FLAG_PRESENT: u8 = 0b0000_0001;
+const FLAG_READ_WRITE: u8 = 0b0000_0010;
+const FLAG_USER: u8 = 0b0000_0100;
+const FLAG_ACCESSED: u8 = 0b0001_0000;
+const FLAG_DIRTY: u8 = 0b0010_0000;
+const FLAG_PAGE_SIZE: u8 = 0b0100_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PageSize {
+    #[default]
+    Size4K,
+    Size16K,
+    Size64K,
+}
+
+impl PageSize {
+    pub fn bytes(&self) -> u64 {
+        match self {
+            Self::Size4K => DEFAULT_PAGE_SIZE,
+            Self::Size16K => 16 * 1024,
+            Self::Size64K => 64 * 1024,
+        }
+    }
+
+    pub fn from_str(size: &str) -> Option<Self> {
+        match size.trim().to_uppercase().as_str() {
+            "4K" | "4096" => Some(Self::Size4K),
+            "16K" | "16384" => Some(Self::Size16K),
+            "64K" | "65536" => Some(Self::Size64K),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStats {
+    pub page_size: u64,
+    pub total_frames: u64,
+    pub used_frames: u64,
+    pub huge_pages: u64,
+    pub huge_page_bytes: u64,
+    pub cow_faults: u64,
+    pub tlb_hits: u64,
+    pub tlb_misses: u64,
+    pub tlb_hit_rate: f64,
+    pub tlb_flushes: u64,
+    pub tlb_evictions: u64,
+    pub page_table_frames: u64,
+    pub page_faults: u64,
+    pub allocations: u64,
+    pub deallocations: u64,
+}
+
+/**
+ * Who holds a page: kernel pages belong to the file or the shared memory
+ * segment storing its content in them, pages of a process address space
+ * belong to the process.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageOwner {
+    File(PathBuf),
+    Process(u32),
+    Shm(u32),
+    Pipe(u32),
+    MessageQueue(u32),
+}
+
+/**
+ * Result of a memcheck: kernel pages nobody claims and frames in use that
+ * no page table references anymore.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct LeakReport {
+    pub unowned_pages: Vec<(u64, u64)>,
+    pub orphan_frames: Vec<u64>,
+}
+
+/**
+ * Run of contiguous virtual pages with the same flags and origin, as shown by pmap.
+ */
+#[derive(Debug, Clone)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub size: u64,
+    pub kind: String,
+    pub flags: String,
+    pub frames: Vec<u64>,
+}
+
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 struct Frame {
     id: u64,
     address: u64,
     in_use: bool,
+    ref_count: u32,
     content: Option<Vec<u8>>,
 }
 
@@ -26,132 +141,1589 @@ struct Frame {
  * PS (Page Size): 0b0100_0000
  * PG (Page Global): 0b1000_0000
  *
+ * copy_on_write marks a page that is read-only only because its frame is
+ * shared with a forked process: the first write copies the frame.
  */
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub struct PageTableEntry {
     virtual_address: u64,
     physical_address: u64,
     flags: u8,
+    copy_on_write: bool,
+}
+
+impl PageTableEntry {
+    pub fn virtual_address(&self) -> u64 {
+        self.virtual_address
+    }
+
+    pub fn is_huge(&self) -> bool {
+        self.flags & FLAG_PAGE_SIZE != 0
+    }
+
+    /**
+     * Present user page, for the paging tests.
+     */
+    #[cfg(test)]
+    pub fn new(virtual_address: u64, physical_address: u64, huge: bool) -> Self {
+        let size = if huge { FLAG_PAGE_SIZE } else { 0 };
+        Self {
+            virtual_address,
+            physical_address,
+            flags: FLAG_PRESENT | FLAG_READ_WRITE | FLAG_USER | size,
+            copy_on_write: false,
+        }
+    }
+}
+
+/**
+ * Shared mappings write straight into the backing pages, private ones get
+ * copy-on-write pages so changes never reach the backing pages.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapKind {
+    Shared,
+    Private,
+}
+
+/**
+ * Region of a process address space backed by kernel pages (e.g. a file).
+ * start and span count pages, mmap hands out the address of the first byte.
+ * written_end is the highest byte offset written through the mapping.
+ */
+#[derive(Debug, Clone)]
+struct Mapping {
+    start: u64,
+    span: u64,
+    backing: Vec<u64>,
+    kind: MapKind,
+    written_end: u64,
+}
+
+impl Mapping {
+    fn contains(&self, virtual_address: u64) -> bool {
+        virtual_address >= self.start && virtual_address < self.start + self.span
+    }
+}
+
+/**
+ * Pages a program segment was loaded in, text segments are read only.
+ */
+#[derive(Debug, Clone)]
+struct LoadedSegment {
+    start: u64,
+    span: u64,
+    writable: bool,
+    stack: bool,
+}
+
+#[derive(Debug, Clone)]
+struct AddressSpace {
+    page_table: PageTable,
+    next_virtual_address: u64,
+    mappings: Vec<Mapping>,
+    segments: Vec<LoadedSegment>,
+    heap: Heap,
+}
+
+impl AddressSpace {
+    fn new(page_size: u64, root_table: u64) -> Self {
+        Self {
+            page_table: PageTable::new(root_table),
+            next_virtual_address: 0,
+            mappings: Vec::new(),
+            segments: Vec::new(),
+            heap: Heap::new(page_size),
+        }
+    }
+
+    fn mapping_at(&mut self, virtual_address: u64) -> Option<&mut Mapping> {
+        self.mappings
+            .iter_mut()
+            .find(|mapping| mapping.contains(virtual_address))
+    }
+
+    fn is_shared(&self, virtual_address: u64) -> bool {
+        self.mappings
+            .iter()
+            .any(|mapping| mapping.kind == MapKind::Shared && mapping.contains(virtual_address))
+    }
 }
 
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub struct Vmm {
     pub total_memory: u64,
     pub free_memory: u64,
+    page_size: u64,
     frames: Vec<Frame>,
-    page_table: HashMap<u64, PageTableEntry>,
-    next_virtual_address: u64,
+    spaces: HashMap<u32, AddressSpace>,
+    owners: HashMap<u64, PageOwner>,
+    cow_faults: u64,
+    page_faults: u64,
+    allocations: u64,
+    deallocations: u64,
+    tlb: Tlb,
+    current_space: u32,
 }
 
 impl Vmm {
-    pub fn new(total_memory: u64) -> Self {
-        let num_frames = total_memory / DEFAULT_PAGE_SIZE;
+    pub fn with_page_size(total_memory: u64, page_size: PageSize) -> Self {
+        let page_size = page_size.bytes();
+        let num_frames = total_memory / page_size;
         let mut frames = Vec::new();
         for frame_id in 0..num_frames {
             frames.push(Frame {
                 id: frame_id,
                 in_use: false,
-                address: frame_id * DEFAULT_PAGE_SIZE,
+                ref_count: 0,
+                address: frame_id * page_size,
                 content: None,
             });
         }
 
-        Self {
-            free_memory: total_memory,
-            page_table: HashMap::new(),
-            total_memory,
+        let mut vmm = Self {
+            free_memory: num_frames * page_size,
+            total_memory: num_frames * page_size,
+            page_size,
             frames,
-            next_virtual_address: 0,
+            spaces: HashMap::new(),
+            owners: HashMap::new(),
+            cow_faults: 0,
+            page_faults: 0,
+            allocations: 0,
+            deallocations: 0,
+            tlb: Tlb::default(),
+            current_space: KERNEL_SPACE,
+        };
+        let root_table = vmm
+            .take_free_frame()
+            .expect("Out of memory allocating kernel page table.");
+        let root_table = vmm.frames[root_table].address;
+        vmm.spaces
+            .insert(KERNEL_SPACE, AddressSpace::new(page_size, root_table));
+        vmm
+    }
+
+    fn frames_per_huge_page(&self) -> usize {
+        (HUGE_PAGE_SIZE / self.page_size) as usize
+    }
+
+    fn frame_index(&self, physical_address: u64) -> usize {
+        (physical_address / self.page_size) as usize
+    }
+
+    fn entry_frames(&self, page: &PageTableEntry) -> usize {
+        if page.flags & FLAG_PAGE_SIZE != 0 {
+            self.frames_per_huge_page()
+        } else {
+            1
         }
     }
 
-    pub fn allocate_page(&mut self) -> (u64, u64) {
-        let frame = self
-            .frames
+    fn entry_size(&self, page: &PageTableEntry) -> u64 {
+        self.entry_frames(page) as u64 * self.page_size
+    }
+
+    fn take_free_frame(&mut self) -> Result<usize, Errno> {
+        self.take_frames(1)
+    }
+
+    /**
+     * Reserve count contiguous free frames aligned on count, returning the
+     * index of the first one.
+     */
+    fn take_frames(&mut self, count: usize) -> Result<usize, Errno> {
+        let index = if count == 1 {
+            self.frames.iter().position(|f| !f.in_use)
+        } else {
+            (0..self.frames.len())
+                .step_by(count)
+                .filter(|&index| index + count <= self.frames.len())
+                .find(|&index| self.frames[index..index + count].iter().all(|f| !f.in_use))
+        }
+        .ok_or(Errno::ENOMEM)?;
+
+        self.frames[index..index + count]
+            .iter_mut()
+            .for_each(|frame| {
+                frame.in_use = true;
+                frame.ref_count = 1;
+                frame.content = None;
+            });
+        self.free_memory -= count as u64 * self.page_size;
+        self.allocations += count as u64;
+        Ok(index)
+    }
+
+    fn retain_page(&mut self, page: &PageTableEntry) {
+        let first = self.frame_index(page.physical_address);
+        let count = self.entry_frames(page);
+        self.frames[first..first + count]
+            .iter_mut()
+            .for_each(|frame| frame.ref_count += 1);
+    }
+
+    fn release_page(&mut self, page: &PageTableEntry) {
+        (0..self.entry_frames(page) as u64).for_each(|index| {
+            self.release_frame(page.physical_address + index * self.page_size);
+        });
+    }
+
+    /**
+     * Drop one reference to the frame, freeing it when nobody maps it anymore.
+     */
+    fn release_frame(&mut self, physical_address: u64) {
+        let index = self.frame_index(physical_address);
+        let frame = &mut self.frames[index];
+        frame.ref_count = frame.ref_count.saturating_sub(1);
+        if frame.ref_count == 0 && frame.in_use {
+            frame.in_use = false;
+            frame.content = None;
+            self.free_memory += self.page_size;
+            self.deallocations += 1;
+        }
+    }
+
+    fn space_mut(&mut self, space: u32) -> Result<&mut AddressSpace, Errno> {
+        self.spaces.get_mut(&space).ok_or(Errno::EFAULT)
+    }
+
+    /**
+     * Add the entry to the page table, taking frames for the missing
+     * intermediate tables.
+     */
+    fn insert_page(&mut self, space: u32, page: PageTableEntry) -> Result<(), Errno> {
+        let missing = self.space_mut(space)?.page_table.missing_tables(&page);
+        let mut tables = Vec::new();
+        for _ in 0..missing {
+            match self.take_free_frame() {
+                Ok(frame_index) => tables.push(self.frames[frame_index].address),
+                Err(e) => {
+                    tables.iter().for_each(|&table| self.release_frame(table));
+                    return Err(e);
+                }
+            }
+        }
+        self.space_mut(space)?.page_table.insert(page, tables);
+        Ok(())
+    }
+
+    /**
+     * Remove the entry from the page table and the TLB, freeing the tables
+     * left empty. The frames of the page itself are not released.
+     */
+    fn remove_page(&mut self, space: u32, virtual_address: u64) -> Option<PageTableEntry> {
+        let (page, freed_tables) = self
+            .spaces
+            .get_mut(&space)?
+            .page_table
+            .remove(&virtual_address);
+        freed_tables
             .iter()
-            .find(|f| !f.in_use)
-            .expect("Out of memory allocating page.");
-        let virtual_address = self.next_virtual_address;
-        self.next_virtual_address += 1;
+            .for_each(|&table| self.release_frame(table));
+        if let Some(page) = &page {
+            self.invalidate(space, page);
+        }
+        page
+    }
+
+    /**
+     * Drop the TLB translations of every page the entry maps.
+     */
+    fn invalidate(&mut self, space: u32, page: &PageTableEntry) {
+        let span = self.entry_frames(page) as u64;
+        (page.virtual_address..page.virtual_address + span)
+            .for_each(|virtual_address| self.tlb.invlpg(space, virtual_address));
+    }
+
+    /**
+     * Virtual to physical translation, going through the TLB first and
+     * walking the page table on a miss. A page inside a huge page
+     * translates to its own frame of the run.
+     */
+    fn translate(&mut self, space: u32, virtual_address: u64) -> Result<u64, Errno> {
+        if let Some(physical_address) = self.tlb.lookup(space, virtual_address) {
+            return Ok(physical_address);
+        }
+        let physical_address = match self
+            .spaces
+            .get(&space)
+            .and_then(|address_space| address_space.page_table.get(&virtual_address))
+        {
+            Some(page) => {
+                page.physical_address + (virtual_address - page.virtual_address) * self.page_size
+            }
+            None => {
+                self.page_faults += 1;
+                return Err(Errno::EFAULT);
+            }
+        };
+        self.tlb.insert(
+            space,
+            virtual_address,
+            physical_address,
+            space == KERNEL_SPACE,
+        );
+        Ok(physical_address)
+    }
+
+    /**
+     * Make the process the one running on the CPU, flushing its
+     * predecessor's translations from the TLB.
+     */
+    pub fn switch_context(&mut self, pid: u32) {
+        if self.current_space != pid {
+            self.tlb.flush();
+            self.current_space = pid;
+        }
+    }
+
+    fn map_new_page(&mut self, space: u32) -> Result<u64, Errno> {
+        self.space_mut(space)?;
+        let frame_index = self.take_free_frame()?;
+        let physical_address = self.frames[frame_index].address;
+        let address_space = self.space_mut(space)?;
+        let virtual_address = address_space.next_virtual_address;
+        address_space.next_virtual_address += 1;
 
         let page = PageTableEntry {
-            physical_address: frame.address,
+            physical_address,
             virtual_address,
             flags: 0b0000_0111,
+            copy_on_write: false,
+        };
+        if let Err(e) = self.insert_page(space, page) {
+            self.release_frame(physical_address);
+            return Err(e);
+        }
+
+        Ok(virtual_address)
+    }
+
+    fn map_page_at(&mut self, space: u32, virtual_address: u64) -> Result<(), Errno> {
+        if self
+            .space_mut(space)?
+            .page_table
+            .contains_key(&virtual_address)
+        {
+            return Err(Errno::EINVAL);
+        }
+        let frame_index = self.take_free_frame()?;
+        let physical_address = self.frames[frame_index].address;
+        let page = PageTableEntry {
+            physical_address,
+            virtual_address,
+            flags: 0b0000_0111,
+            copy_on_write: false,
+        };
+        if let Err(e) = self.insert_page(space, page) {
+            self.release_frame(physical_address);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn unmap_page(&mut self, space: u32, virtual_address: u64) -> Result<(), Errno> {
+        self.space_mut(space)?;
+        let page = self
+            .remove_page(space, virtual_address)
+            .ok_or(Errno::EFAULT)?;
+        self.release_page(&page);
+        Ok(())
+    }
+
+    pub fn allocate_page(&mut self) -> Result<(u64, u64), Errno> {
+        let virtual_address = self.map_new_page(KERNEL_SPACE)?;
+        Ok((virtual_address, self.page_size))
+    }
+
+    /**
+     * Map a 2 MiB page in the kernel space, an entry of the PD. Its virtual
+     * address is aligned on the number of small pages it spans.
+     */
+    fn allocate_huge_page(&mut self) -> Result<u64, Errno> {
+        let count = self.frames_per_huge_page();
+        let frame_index = self.take_frames(count)?;
+        let physical_address = self.frames[frame_index].address;
+        let space = self.space_mut(KERNEL_SPACE)?;
+        let virtual_address = space.next_virtual_address.div_ceil(count as u64) * count as u64;
+        space.next_virtual_address = virtual_address + count as u64;
+
+        let page = PageTableEntry {
+            physical_address,
+            virtual_address,
+            flags: 0b0100_0111,
+            copy_on_write: false,
         };
-        self.page_table.insert(virtual_address, page);
-        self.free_memory -= DEFAULT_PAGE_SIZE;
+        if let Err(e) = self.insert_page(KERNEL_SPACE, page.clone()) {
+            self.release_page(&page);
+            return Err(e);
+        }
+        Ok(virtual_address)
+    }
 
-        (virtual_address, DEFAULT_PAGE_SIZE)
+    /**
+     * Total bytes the given kernel pages can hold.
+     */
+    pub fn pages_size(&self, virtual_addresses: &[u64]) -> u64 {
+        let page_table = &self.spaces[&KERNEL_SPACE].page_table;
+        virtual_addresses
+            .iter()
+            .filter_map(|address| page_table.get(address))
+            .map(|page| self.entry_size(page))
+            .sum()
     }
 
     pub fn deallocate_page(&mut self, virtual_addresses: Vec<u64>) {
         virtual_addresses.iter().for_each(|address| {
-            if let Some(page) = self.page_table.remove(&address) {
-                self.free_memory += DEFAULT_PAGE_SIZE;
-                if let Some(frame) = self
-                    .frames
-                    .iter_mut()
-                    .find(|f| f.address == page.physical_address)
-                {
-                    frame.in_use = false;
-                    frame.content = None;
-                }
+            self.owners.remove(address);
+            if let Some(page) = self.remove_page(KERNEL_SPACE, *address) {
+                self.release_page(&page);
             } else {
                 panic!("Cannot deallocate page {}", address);
             }
         });
     }
 
-    pub fn allocate_bytes(&mut self, bytes: Vec<u8>) -> Vec<u64> {
+    /**
+     * Copy bytes into new kernel pages. Without enough free frames nothing
+     * is allocated and ENOMEM is returned.
+     */
+    pub fn allocate_bytes(&mut self, bytes: Vec<u8>) -> Result<Vec<u64>, Errno> {
+        if bytes.len() as u64 > self.free_memory {
+            return Err(Errno::ENOMEM);
+        }
         let mut remaining_bytes = bytes.as_slice();
         let mut virtual_addresses: Vec<u64> = Vec::<u64>::new();
 
         while !remaining_bytes.is_empty() {
-            let (virtual_address, _) = self.allocate_page();
-            let page = self.page_table.get_mut(&virtual_address).unwrap();
-            if let Some(frame) = self
-                .frames
-                .iter_mut()
-                .find(|f| f.address == page.physical_address)
+            // Big contents go in huge pages while enough contiguous frames exist
+            let huge_page = if remaining_bytes.len() as u64 >= HUGE_PAGE_SIZE
+                && self.frames_per_huge_page() as u64 == HUGE_PAGE_SPAN
             {
-                frame.in_use = true;
+                self.allocate_huge_page().ok()
+            } else {
+                None
+            };
+            let (virtual_address, page_size) = match huge_page {
+                Some(virtual_address) => (virtual_address, HUGE_PAGE_SIZE),
+                // The page tables may take the frames left
+                None => match self.allocate_page() {
+                    Ok(page) => page,
+                    Err(errno) => {
+                        self.deallocate_page(virtual_addresses);
+                        return Err(errno);
+                    }
+                },
+            };
+            let physical_address = self
+                .translate(KERNEL_SPACE, virtual_address)
+                .expect("Page not found");
+            let frame_index = self.frame_index(physical_address);
 
-                let bytes_to_copy = min(remaining_bytes.len(), DEFAULT_PAGE_SIZE as usize);
-                frame.content = Some(remaining_bytes[..bytes_to_copy].to_vec());
-                remaining_bytes = &remaining_bytes[bytes_to_copy..];
-            }
+            let bytes_to_copy = min(remaining_bytes.len(), page_size as usize);
+            remaining_bytes[..bytes_to_copy]
+                .chunks(self.page_size as usize)
+                .enumerate()
+                .for_each(|(index, chunk)| {
+                    self.frames[frame_index + index].content = Some(chunk.to_vec());
+                });
+            remaining_bytes = &remaining_bytes[bytes_to_copy..];
 
             virtual_addresses.push(virtual_address);
         }
 
-        virtual_addresses
+        Ok(virtual_addresses)
+    }
+
+    /**
+     * Record who holds the kernel pages, pages without owner are reported by memcheck.
+     */
+    pub fn set_owner(&mut self, virtual_addresses: &[u64], owner: PageOwner) {
+        virtual_addresses.iter().for_each(|&address| {
+            self.owners.insert(address, owner.clone());
+        });
     }
 
-    pub fn get_bytes(&self, virtual_addresses: Vec<u64>, size: u64) -> Vec<u8> {
+    pub fn get_bytes(&mut self, virtual_addresses: Vec<u64>, size: u64) -> Vec<u8> {
         let mut bytes = Vec::new();
         let mut remaining_size = size;
-        let mut current_virtual_address = virtual_addresses[0];
         virtual_addresses.iter().for_each(|&address| {
-            let page = self.page_table.get(&address).expect("Page not found");
-            let frame = self
-                .frames
+            let physical_address = self
+                .translate(KERNEL_SPACE, address)
+                .expect("Page not found");
+            let first = self.frame_index(physical_address);
+            let frames = self.pages_size(&[address]) / self.page_size;
+            for frame in &self.frames[first..first + frames as usize] {
+                let Some(content) = frame.content.as_ref() else {
+                    continue;
+                };
+                let bytes_to_copy = min(remaining_size as usize, content.len());
+                bytes.extend_from_slice(&content[..bytes_to_copy]);
+                remaining_size -= bytes_to_copy as u64;
+            }
+        });
+
+        bytes
+    }
+
+    pub fn create_address_space(&mut self, pid: u32) -> Result<(), Errno> {
+        self.release_address_space(pid);
+        let root_table = self.take_free_frame()?;
+        let root_table = self.frames[root_table].address;
+        self.spaces
+            .insert(pid, AddressSpace::new(self.page_size, root_table));
+        Ok(())
+    }
+
+    /**
+     * Give the process the address space built under another id, releasing
+     * the one it had.
+     */
+    pub fn replace_address_space(&mut self, pid: u32, staged: u32) -> Result<(), Errno> {
+        let space = self.spaces.remove(&staged).ok_or(Errno::EFAULT)?;
+        self.tlb.flush_space(staged);
+        self.release_address_space(pid);
+        self.spaces.insert(pid, space);
+        Ok(())
+    }
+
+    /**
+     * Drop every mapping of the process, freeing frames nobody else shares.
+     */
+    pub fn release_address_space(&mut self, pid: u32) {
+        if pid == KERNEL_SPACE {
+            return;
+        }
+        if let Some(space) = self.spaces.remove(&pid) {
+            space
+                .page_table
+                .values()
+                .for_each(|page| self.release_page(page));
+            space
+                .page_table
+                .table_frames()
+                .iter()
+                .for_each(|&table| self.release_frame(table));
+            self.tlb.flush_space(pid);
+        }
+    }
+
+    /**
+     * Give the child a copy of the parent's page table. Frames are shared and
+     * every writable page becomes read-only copy-on-write on both sides,
+     * except pages of shared mappings which stay shared.
+     */
+    pub fn fork_address_space(&mut self, parent: u32, child: u32) -> Result<(), Errno> {
+        let parent_space = self.spaces.get(&parent).ok_or(Errno::EFAULT)?;
+        let private_pages: HashSet<u64> = parent_space
+            .page_table
+            .values()
+            .filter(|page| page.flags & FLAG_READ_WRITE != 0)
+            .map(|page| page.virtual_address)
+            .filter(|&address| !parent_space.is_shared(address))
+            .collect();
+        let pages: Vec<PageTableEntry> = parent_space
+            .page_table
+            .values()
+            .cloned()
+            .map(|mut page| {
+                if private_pages.contains(&page.virtual_address) {
+                    page.flags &= !FLAG_READ_WRITE;
+                    page.copy_on_write = true;
+                }
+                page
+            })
+            .collect();
+        let mut child_space = parent_space.clone();
+
+        // The child walks its own tables, built in fresh frames before the
+        // parent gives up writing to its private pages
+        self.release_address_space(child);
+        let root_table = self.take_free_frame()?;
+        child_space.page_table = PageTable::new(self.frames[root_table].address);
+        self.spaces.insert(child, child_space);
+        for page in pages {
+            if let Err(e) = self.insert_page(child, page.clone()) {
+                // No half built child may keep frames
+                self.release_address_space(child);
+                return Err(e);
+            }
+            self.retain_page(&page);
+        }
+        self.space_mut(parent)?
+            .page_table
+            .values_mut()
+            .filter(|page| private_pages.contains(&page.virtual_address))
+            .for_each(|page| {
+                page.flags &= !FLAG_READ_WRITE;
+                page.copy_on_write = true;
+            });
+        Ok(())
+    }
+
+    pub fn read_page(&mut self, pid: u32, virtual_address: u64) -> Result<Vec<u8>, Errno> {
+        let page = self
+            .space_mut(pid)?
+            .page_table
+            .get_mut(&virtual_address)
+            .ok_or(Errno::EFAULT)?;
+        page.flags |= FLAG_ACCESSED;
+        let page = page.clone();
+        let first = self.frame_index(page.physical_address);
+        Ok(self.frames[first..first + self.entry_frames(&page)]
+            .iter()
+            .flat_map(|frame| frame.content.iter().flatten())
+            .copied()
+            .collect())
+    }
+
+    /**
+     * Grow the contents of the frames of a run starting at first so that
+     * its first end bytes are contiguous, padding with zeros.
+     */
+    fn fill_frames(&mut self, first: usize, end: usize) {
+        let page_size = self.page_size as usize;
+        for index in 0..end.div_ceil(page_size) {
+            let content = self.frames[first + index]
+                .content
+                .get_or_insert_with(Vec::new);
+            let frame_end = min(end - index * page_size, page_size);
+            if content.len() < frame_end {
+                content.resize(frame_end, 0);
+            }
+        }
+    }
+
+    pub fn write_page(
+        &mut self,
+        pid: u32,
+        virtual_address: u64,
+        offset: u64,
+        bytes: &[u8],
+    ) -> Result<(), Errno> {
+        let page = self
+            .spaces
+            .get(&pid)
+            .and_then(|space| space.page_table.get(&virtual_address))
+            .ok_or(Errno::EFAULT)?;
+        // Offset inside the entry, a huge page spans several pages
+        let offset = (virtual_address - page.virtual_address) * self.page_size + offset;
+        if offset + bytes.len() as u64 > self.entry_size(page) {
+            return Err(Errno::EINVAL);
+        }
+        let entry_address = page.virtual_address;
+        if page.flags & FLAG_READ_WRITE == 0 {
+            if !page.copy_on_write {
+                return Err(Errno::EACCES);
+            }
+            self.handle_cow_fault(pid, virtual_address)?;
+        }
+
+        let page = self
+            .space_mut(pid)?
+            .page_table
+            .get_mut(&virtual_address)
+            .ok_or(Errno::EFAULT)?;
+        page.flags |= FLAG_ACCESSED | FLAG_DIRTY;
+        let physical_address = page.physical_address;
+        let first = self.frame_index(physical_address);
+        let end = (offset as usize) + bytes.len();
+        self.fill_frames(first, end);
+        let page_size = self.page_size as usize;
+        let mut written = 0;
+        while written < bytes.len() {
+            let at = offset as usize + written;
+            let chunk = min(bytes.len() - written, page_size - at % page_size);
+            let content = self.frames[first + at / page_size]
+                .content
+                .as_mut()
+                .unwrap();
+            content[at % page_size..at % page_size + chunk]
+                .copy_from_slice(&bytes[written..written + chunk]);
+            written += chunk;
+        }
+
+        let page_size = self.page_size;
+        if let Some(mapping) = self.space_mut(pid)?.mapping_at(entry_address) {
+            let mapped_end = (entry_address - mapping.start) * page_size + end as u64;
+            mapping.written_end = mapping.written_end.max(mapped_end);
+        }
+        Ok(())
+    }
+
+    /**
+     * Copy a program segment into fresh pages of the process, read only
+     * unless writable. Returns the virtual address of its first byte.
+     */
+    pub fn load_segment(&mut self, pid: u32, bytes: &[u8], writable: bool) -> Result<u64, Errno> {
+        let page_size = self.page_size;
+        let span = (bytes.len() as u64).div_ceil(page_size).max(1);
+        let mut start = None;
+        for index in 0..span {
+            let virtual_address = self.map_new_page(pid)?;
+            start.get_or_insert(virtual_address);
+            let from = (index * page_size) as usize;
+            let to = min(from + page_size as usize, bytes.len());
+            if from < to {
+                self.write_page(pid, virtual_address, 0, &bytes[from..to])?;
+            }
+            if !writable {
+                let page = self
+                    .space_mut(pid)?
+                    .page_table
+                    .get_mut(&virtual_address)
+                    .ok_or(Errno::EFAULT)?;
+                page.flags &= !FLAG_READ_WRITE;
+            }
+        }
+        let start = start.unwrap();
+        self.space_mut(pid)?.segments.push(LoadedSegment {
+            start,
+            span,
+            writable,
+            stack: false,
+        });
+        Ok(start * page_size)
+    }
+
+    /**
+     * Map size bytes of zeroed, writable pages as the stack of the process.
+     * Returns the virtual address of its lowest byte.
+     */
+    pub fn map_stack(&mut self, pid: u32, size: usize) -> Result<u64, Errno> {
+        let start = self.load_segment(pid, &vec![0; size], true)?;
+        if let Some(segment) = self.space_mut(pid)?.segments.last_mut() {
+            segment.stack = true;
+        }
+        Ok(start)
+    }
+
+    /**
+     * Read len bytes of the process memory at a virtual address, the way a
+     * load instruction does: every page goes through the TLB and an
+     * unmapped one is a page fault (EFAULT).
+     */
+    pub fn read_bytes(&mut self, pid: u32, address: u64, len: usize) -> Result<Vec<u8>, Errno> {
+        let page_size = self.page_size;
+        let mut bytes = Vec::new();
+        let mut address = address;
+        while bytes.len() < len {
+            let offset = (address % page_size) as usize;
+            let chunk = min(len - bytes.len(), page_size as usize - offset);
+            let physical_address = self.translate(pid, address / page_size)?;
+            let content = self.frames[self.frame_index(physical_address)]
+                .content
+                .as_deref()
+                .unwrap_or(&[]);
+            bytes.extend(
+                (offset..offset + chunk).map(|index| content.get(index).copied().unwrap_or(0)),
+            );
+            address += chunk as u64;
+        }
+        Ok(bytes)
+    }
+
+    /**
+     * Write bytes to the process memory at a virtual address, the way a
+     * store instruction does. Copy on write pages are copied, read only
+     * ones refuse the write (EACCES).
+     */
+    pub fn write_bytes(&mut self, pid: u32, address: u64, bytes: &[u8]) -> Result<(), Errno> {
+        let page_size = self.page_size;
+        let mut address = address;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let offset = address % page_size;
+            let chunk = min(bytes.len(), (page_size - offset) as usize);
+            self.translate(pid, address / page_size)?;
+            self.write_page(pid, address / page_size, offset, &bytes[..chunk])?;
+            address += chunk as u64;
+            bytes = &bytes[chunk..];
+        }
+        Ok(())
+    }
+
+    /**
+     * Map kernel pages (e.g. File.vmm_address) into the process address space.
+     * Returns the virtual address of the first byte of the mapping.
+     */
+    pub fn mmap(&mut self, pid: u32, backing: &[u64], kind: MapKind) -> Result<u64, Errno> {
+        if backing.is_empty() {
+            return Err(Errno::EINVAL);
+        }
+
+        let kernel_table = &self.spaces[&KERNEL_SPACE].page_table;
+        let backing_pages = backing
+            .iter()
+            .map(|address| kernel_table.get(address).cloned().ok_or(Errno::EFAULT))
+            .collect::<Result<Vec<PageTableEntry>, Errno>>()?;
+
+        let mut virtual_address = self.space_mut(pid)?.next_virtual_address;
+        let mut pages = Vec::new();
+        for backing_page in backing_pages.iter() {
+            let size_flag = backing_page.flags & FLAG_PAGE_SIZE;
+            if size_flag != 0 {
+                // A PD entry maps an aligned run of pages
+                virtual_address = virtual_address.div_ceil(HUGE_PAGE_SPAN) * HUGE_PAGE_SPAN;
+            }
+            let page = match kind {
+                MapKind::Shared => PageTableEntry {
+                    virtual_address,
+                    physical_address: backing_page.physical_address,
+                    flags: 0b0000_0111 | size_flag,
+                    copy_on_write: false,
+                },
+                MapKind::Private => PageTableEntry {
+                    virtual_address,
+                    physical_address: backing_page.physical_address,
+                    flags: 0b0000_0101 | size_flag,
+                    copy_on_write: true,
+                },
+            };
+            virtual_address += self.entry_frames(backing_page) as u64;
+            pages.push(page);
+        }
+        let start = pages[0].virtual_address;
+        let span = virtual_address - start;
+
+        for (index, page) in pages.iter().enumerate() {
+            if let Err(e) = self.insert_page(pid, page.clone()) {
+                // Nothing may stay mapped without a mapping to unmap it
+                pages[..index].iter().for_each(|page| {
+                    self.remove_page(pid, page.virtual_address);
+                    self.release_page(page);
+                });
+                return Err(e);
+            }
+            self.retain_page(page);
+        }
+        let space = self.space_mut(pid)?;
+        space.next_virtual_address = virtual_address;
+        space.mappings.push(Mapping {
+            start,
+            span,
+            backing: backing.to_vec(),
+            kind,
+            written_end: 0,
+        });
+
+        Ok(start * self.page_size)
+    }
+
+    /**
+     * Page number of the first byte of a mapping, which starts a page.
+     */
+    fn mapping_page(&self, address: u64) -> Result<u64, Errno> {
+        if !address.is_multiple_of(self.page_size) {
+            return Err(Errno::EINVAL);
+        }
+        Ok(address / self.page_size)
+    }
+
+    /**
+     * Owner of the kernel pages backing the mapping at the address, the
+     * file of a file mapping.
+     */
+    pub fn mapping_owner(&self, pid: u32, address: u64) -> Result<Option<PageOwner>, Errno> {
+        let start = self.mapping_page(address)?;
+        let mapping = self
+            .spaces
+            .get(&pid)
+            .ok_or(Errno::EFAULT)?
+            .mappings
+            .iter()
+            .find(|mapping| mapping.start == start)
+            .ok_or(Errno::EINVAL)?;
+        Ok(mapping
+            .backing
+            .first()
+            .and_then(|page| self.owners.get(page))
+            .cloned())
+    }
+
+    pub fn munmap(&mut self, pid: u32, address: u64) -> Result<(), Errno> {
+        let start = self.mapping_page(address)?;
+        let space = self.space_mut(pid)?;
+        let position = space
+            .mappings
+            .iter()
+            .position(|mapping| mapping.start == start)
+            .ok_or(Errno::EINVAL)?;
+        let mapping = space.mappings.remove(position);
+        let pages: Vec<PageTableEntry> = (0..mapping.span)
+            .filter_map(|index| self.remove_page(pid, start + index))
+            .collect();
+        pages.iter().for_each(|page| self.release_page(page));
+        Ok(())
+    }
+
+    /**
+     * Flush a mapping back to its backing pages and return the new size of
+     * the backing object given its current size. Private mappings never
+     * change the backing object.
+     */
+    pub fn msync(&mut self, pid: u32, address: u64, size: u64) -> Result<u64, Errno> {
+        let start = self.mapping_page(address)?;
+        let mapping = self
+            .space_mut(pid)?
+            .mappings
+            .iter()
+            .find(|mapping| mapping.start == start)
+            .cloned()
+            .ok_or(Errno::EINVAL)?;
+        if mapping.kind == MapKind::Private || mapping.written_end <= size {
+            return Ok(size);
+        }
+
+        // Pages before the last written byte must be full so get_bytes reads
+        // the backing object contiguously.
+        let new_size = mapping.written_end;
+        let kernel_table = &self.spaces[&KERNEL_SPACE].page_table;
+        let backing_pages: Vec<(u64, u64)> = mapping
+            .backing
+            .iter()
+            .map(|address| {
+                let page = kernel_table.get(address).expect("Page not found");
+                (page.physical_address, self.entry_size(page))
+            })
+            .collect();
+        let mut page_start = 0;
+        for (physical_address, size) in backing_pages {
+            if page_start >= new_size {
+                break;
+            }
+            let page_end = min(new_size - page_start, size) as usize;
+            self.fill_frames(self.frame_index(physical_address), page_end);
+            page_start += size;
+        }
+
+        Ok(new_size)
+    }
+
+    /**
+     * First write to a copy-on-write page: the last sharer takes the frame
+     * back as writable, everybody else gets a private copy.
+     */
+    fn handle_cow_fault(&mut self, pid: u32, virtual_address: u64) -> Result<(), Errno> {
+        self.cow_faults += 1;
+        self.page_faults += 1;
+        let old_page = self
+            .space_mut(pid)?
+            .page_table
+            .get(&virtual_address)
+            .cloned()
+            .ok_or(Errno::EFAULT)?;
+        let old_index = self.frame_index(old_page.physical_address);
+
+        let new_physical_address = if self.frames[old_index].ref_count == 1 {
+            old_page.physical_address
+        } else {
+            let count = self.entry_frames(&old_page);
+            let new_index = self.take_frames(count)?;
+            for index in 0..count {
+                self.frames[new_index + index].content =
+                    self.frames[old_index + index].content.clone();
+            }
+            self.release_page(&old_page);
+            self.frames[new_index].address
+        };
+
+        let page = self
+            .space_mut(pid)?
+            .page_table
+            .get_mut(&virtual_address)
+            .ok_or(Errno::EFAULT)?;
+        page.physical_address = new_physical_address;
+        page.flags |= FLAG_PRESENT | FLAG_READ_WRITE;
+        page.copy_on_write = false;
+        self.invalidate(pid, &old_page);
+        Ok(())
+    }
+
+    /**
+     * Set the program break of the process, mapping or unmapping heap pages
+     * so that every byte in [heap start, break) is backed by a frame. The
+     * break cannot go below a live allocation.
+     */
+    pub fn brk(&mut self, pid: u32, new_brk: u64) -> Result<u64, Errno> {
+        let heap = &mut self.space_mut(pid)?.heap;
+        let (start, old_brk) = (heap.start, heap.brk);
+        if new_brk < start {
+            return Err(Errno::EINVAL);
+        }
+        // Checked before any page is unmapped
+        if new_brk < old_brk {
+            heap.shrink(new_brk)?;
+        }
+
+        let old_pages = (old_brk - start).div_ceil(self.page_size);
+        let new_pages = (new_brk - start).div_ceil(self.page_size);
+        for page in old_pages..new_pages {
+            if let Err(e) = self.map_page_at(pid, HEAP_START_PAGE + page) {
+                (old_pages..page).for_each(|mapped| {
+                    let _ = self.unmap_page(pid, HEAP_START_PAGE + mapped);
+                });
+                return Err(e);
+            }
+        }
+        for page in new_pages..old_pages {
+            self.unmap_page(pid, HEAP_START_PAGE + page)?;
+        }
+
+        self.space_mut(pid)?.heap.brk = new_brk;
+        Ok(new_brk)
+    }
+
+    /**
+     * Move the program break by increment bytes, returning the previous break.
+     */
+    pub fn sbrk(&mut self, pid: u32, increment: i64) -> Result<u64, Errno> {
+        let old_brk = self.space_mut(pid)?.heap.brk;
+        let new_brk = old_brk.checked_add_signed(increment).ok_or(Errno::EINVAL)?;
+        self.brk(pid, new_brk)?;
+        Ok(old_brk)
+    }
+
+    pub fn malloc(&mut self, pid: u32, size: u64) -> Result<u64, Errno> {
+        loop {
+            let page_size = self.page_size;
+            if let Some(address) = self.space_mut(pid)?.heap.malloc(size, page_size) {
+                return Ok(address);
+            }
+            let increment = Heap::size_class(size.max(1), page_size)
+                .and_then(|class| class.div_ceil(page_size).checked_mul(page_size))
+                .ok_or(Errno::ENOMEM)?;
+            let old_brk = self.sbrk(pid, i64::try_from(increment).map_err(|_| Errno::ENOMEM)?)?;
+            self.space_mut(pid)?.heap.extend_arena(old_brk, increment);
+        }
+    }
+
+    pub fn free(&mut self, pid: u32, address: u64) -> Result<(), Errno> {
+        self.space_mut(pid)?.heap.free(address)
+    }
+
+    pub fn heap_stats(&self, pid: u32) -> Result<HeapStats, Errno> {
+        self.spaces
+            .get(&pid)
+            .map(|space| space.heap.stats())
+            .ok_or(Errno::ESRCH)
+    }
+
+    /**
+     * Number of base pages mapped in the address space, shared ones included.
+     */
+    pub fn resident_pages(&self, pid: u32) -> u64 {
+        self.spaces
+            .get(&pid)
+            .map(|space| {
+                space
+                    .page_table
+                    .values()
+                    .map(|page| self.entry_frames(page) as u64)
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        let huge_pages = self
+            .spaces
+            .values()
+            .flat_map(|space| space.page_table.values())
+            .filter(|page| page.flags & FLAG_PAGE_SIZE != 0)
+            .map(|page| page.physical_address)
+            .collect::<HashSet<u64>>()
+            .len() as u64;
+
+        MemoryStats {
+            page_size: self.page_size,
+            total_frames: self.frames.len() as u64,
+            used_frames: self.frames.iter().filter(|f| f.in_use).count() as u64,
+            huge_pages,
+            huge_page_bytes: huge_pages * HUGE_PAGE_SIZE,
+            cow_faults: self.cow_faults,
+            tlb_hits: self.tlb.hits,
+            tlb_misses: self.tlb.misses,
+            tlb_hit_rate: self.tlb.hit_rate(),
+            tlb_flushes: self.tlb.flushes,
+            tlb_evictions: self.tlb.evictions,
+            page_faults: self.page_faults,
+            allocations: self.allocations,
+            deallocations: self.deallocations,
+            page_table_frames: self
+                .spaces
+                .values()
+                .map(|space| space.page_table.table_frames().len() as u64)
+                .sum(),
+        }
+    }
+
+    /**
+     * Mapped regions of the address space, consecutive pages sharing flags
+     * and origin are merged together.
+     */
+    pub fn regions(&self, pid: u32) -> Result<Vec<MemoryRegion>, Errno> {
+        let space = self.spaces.get(&pid).ok_or(Errno::ESRCH)?;
+        let mut pages: Vec<&PageTableEntry> = space.page_table.values().collect();
+        pages.sort_by_key(|page| page.virtual_address);
+
+        let heap_end = space.heap.brk.div_ceil(self.page_size);
+        let mut regions: Vec<MemoryRegion> = Vec::new();
+        let mut next_address = None;
+        for page in pages {
+            let segment = space.segments.iter().find(|segment| {
+                (segment.start..segment.start + segment.span).contains(&page.virtual_address)
+            });
+            let kind = match space
+                .mappings
                 .iter()
-                .find(|f| f.address == page.physical_address)
-                .expect("Frame not found");
-            if frame.content.is_none() {
-                return;
+                .find(|mapping| mapping.contains(page.virtual_address))
+            {
+                Some(mapping) if mapping.kind == MapKind::Shared => "shared",
+                Some(_) => "private",
+                None if segment.is_some_and(|segment| segment.stack) => "stack",
+                None if segment.is_some_and(|segment| segment.writable) => "data",
+                None if segment.is_some() => "text",
+                None if (HEAP_START_PAGE..heap_end).contains(&page.virtual_address) => "heap",
+                None if pid == KERNEL_SPACE => "kernel",
+                None => "anon",
+            };
+            let flags = [
+                (FLAG_PRESENT, 'r'),
+                (FLAG_READ_WRITE, 'w'),
+                (FLAG_USER, 'u'),
+                (FLAG_PAGE_SIZE, 'h'),
+                (FLAG_ACCESSED, 'a'),
+                (FLAG_DIRTY, 'd'),
+            ]
+            .iter()
+            .map(|&(flag, c)| if page.flags & flag != 0 { c } else { '-' })
+            .chain(std::iter::once(if page.copy_on_write { 'c' } else { '-' }))
+            .collect::<String>();
+            let first_frame = self.frame_index(page.physical_address) as u64;
+            let frames = (first_frame..first_frame + self.entry_frames(page) as u64).collect();
+            let size = self.entry_size(page);
+
+            match regions.last_mut() {
+                Some(region)
+                    if next_address == Some(page.virtual_address)
+                        && region.kind == kind
+                        && region.flags == flags =>
+                {
+                    region.size += size;
+                    region.frames.extend(frames);
+                }
+                _ => regions.push(MemoryRegion {
+                    start: page.virtual_address * self.page_size,
+                    size,
+                    kind: kind.to_string(),
+                    flags,
+                    frames,
+                }),
             }
-            let content = frame.content.as_ref().expect("Content not found");
+            next_address = Some(page.virtual_address + self.entry_frames(page) as u64);
+        }
 
-            let bytes_to_copy = min(remaining_size as usize, content.len());
-            bytes.extend_from_slice(&content[..bytes_to_copy]);
-            remaining_size -= bytes_to_copy as u64;
-            current_virtual_address += 1;
+        Ok(regions)
+    }
+
+    /**
+     * Fraction of used frames in each of the given number of frame buckets.
+     */
+    pub fn frame_usage(&self, buckets: usize) -> Vec<f64> {
+        let per_bucket = self.frames.len().div_ceil(buckets.max(1)).max(1);
+        self.frames
+            .chunks(per_bucket)
+            .map(|chunk| chunk.iter().filter(|f| f.in_use).count() as f64 / chunk.len() as f64)
+            .collect()
+    }
+
+    /**
+     * Owner of every mapped page: (address space, virtual address, owner).
+     */
+    pub fn page_owners(&self) -> Vec<(u32, u64, Option<PageOwner>)> {
+        let mut owners: Vec<(u32, u64, Option<PageOwner>)> = self
+            .spaces
+            .iter()
+            .flat_map(|(&space, address_space)| {
+                address_space.page_table.keys().map(move |address| {
+                    let owner = if space == KERNEL_SPACE {
+                        self.owners.get(&address).cloned()
+                    } else {
+                        Some(PageOwner::Process(space))
+                    };
+                    (space, address, owner)
+                })
+            })
+            .collect();
+        owners.sort_by_key(|&(space, address, _)| (space, address));
+        owners
+    }
+
+    pub fn memcheck(&self) -> LeakReport {
+        let kernel_table = &self.spaces[&KERNEL_SPACE].page_table;
+        let mut unowned_pages: Vec<(u64, u64)> = kernel_table
+            .values()
+            .filter(|page| !self.owners.contains_key(&page.virtual_address))
+            .map(|page| (page.virtual_address, self.entry_size(page)))
+            .collect();
+        unowned_pages.sort();
+
+        let mut referenced: HashSet<usize> = HashSet::new();
+        self.spaces.values().for_each(|space| {
+            space.page_table.values().for_each(|page| {
+                let first = self.frame_index(page.physical_address);
+                referenced.extend(first..first + self.entry_frames(page));
+            });
+            space.page_table.table_frames().iter().for_each(|&table| {
+                referenced.insert(self.frame_index(table));
+            });
         });
+        let orphan_frames = self
+            .frames
+            .iter()
+            .enumerate()
+            .filter(|(index, frame)| frame.in_use && !referenced.contains(index))
+            .map(|(_, frame)| frame.id)
+            .collect();
 
-        bytes
+        LeakReport {
+            unowned_pages,
+            orphan_frames,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|index| (index % 251) as u8).collect()
+    }
+
+    /**
+     * Index of the frame backing a byte address of a process.
+     */
+    fn frame_of(vmm: &mut Vmm, pid: u32, address: u64) -> usize {
+        let physical_address = vmm.translate(pid, address / vmm.page_size).unwrap();
+        vmm.frame_index(physical_address)
+    }
+
+    #[test]
+    fn page_tables_take_frames_until_released() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        let (free_memory, tables) = (vmm.free_memory, vmm.stats().page_table_frames);
+        vmm.create_address_space(1).unwrap();
+        let address = vmm.load_segment(1, b"data", true).unwrap();
+
+        // The root, then a PDPT, a PD and a PT for the first page
+        assert_eq!(vmm.stats().page_table_frames, tables + 4);
+        assert_eq!(vmm.free_memory, free_memory - 5 * vmm.page_size);
+        vmm.switch_context(1);
+        vmm.read_bytes(1, address, 4).unwrap();
+        vmm.read_bytes(1, address, 4).unwrap();
+        let stats = vmm.stats();
+        assert!(stats.tlb_hits > 0 && stats.tlb_misses > 0);
+        assert_eq!(stats.tlb_flushes, 1);
+
+        vmm.release_address_space(1);
+        assert_eq!(vmm.stats().page_table_frames, tables);
+        assert_eq!(vmm.free_memory, free_memory);
+    }
+
+    #[test]
+    fn memcheck_reports_pages_without_owner() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        let (owned, _) = vmm.allocate_page().unwrap();
+        vmm.set_owner(&[owned], PageOwner::Pipe(0));
+        let (unowned, size) = vmm.allocate_page().unwrap();
+
+        let report = vmm.memcheck();
+        assert_eq!(report.unowned_pages, vec![(unowned, size)]);
+        assert!(report.orphan_frames.is_empty());
+
+        vmm.set_owner(&[unowned], PageOwner::Pipe(1));
+        assert!(vmm.memcheck().unowned_pages.is_empty());
+    }
+
+    #[test]
+    fn fork_shares_the_frames_of_the_parent() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        vmm.create_address_space(1).unwrap();
+        let address = vmm.load_segment(1, b"data", true).unwrap();
+
+        vmm.fork_address_space(1, 2).unwrap();
+        assert_eq!(
+            frame_of(&mut vmm, 1, address),
+            frame_of(&mut vmm, 2, address)
+        );
+        assert_eq!(vmm.read_bytes(2, address, 4).unwrap(), b"data");
+    }
+
+    #[test]
+    fn fork_and_exit_count_the_references_to_a_frame() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        vmm.create_address_space(1).unwrap();
+        let address = vmm.load_segment(1, b"data", true).unwrap();
+        let frame = frame_of(&mut vmm, 1, address);
+        assert_eq!(vmm.frames[frame].ref_count, 1);
+
+        vmm.fork_address_space(1, 2).unwrap();
+        vmm.fork_address_space(1, 3).unwrap();
+        assert_eq!(vmm.frames[frame].ref_count, 3);
+        vmm.release_address_space(2);
+        assert_eq!(vmm.frames[frame].ref_count, 2);
+        vmm.release_address_space(3);
+        assert_eq!(vmm.frames[frame].ref_count, 1);
+        assert!(vmm.frames[frame].in_use);
+
+        vmm.release_address_space(1);
+        assert_eq!(vmm.frames[frame].ref_count, 0);
+        assert!(!vmm.frames[frame].in_use);
+    }
+
+    #[test]
+    fn fork_out_of_memory_leaves_no_child_behind() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        vmm.create_address_space(1).unwrap();
+        let address = vmm.load_segment(1, b"data", true).unwrap();
+        let frame = frame_of(&mut vmm, 1, address);
+        // The root table of the child gets the last frame, its other tables none
+        while vmm.free_memory > vmm.page_size {
+            vmm.allocate_page().unwrap();
+        }
+        assert_eq!(vmm.free_memory, vmm.page_size);
+
+        assert_eq!(vmm.fork_address_space(1, 2), Err(Errno::ENOMEM));
+        assert_eq!(vmm.free_memory, vmm.page_size);
+        assert!(!vmm.spaces.contains_key(&2));
+        assert_eq!(vmm.frames[frame].ref_count, 1);
+        assert_eq!(vmm.read_bytes(1, address, 4).unwrap(), b"data");
+        // The parent keeps writing its pages in place
+        let page = vmm.spaces[&1].page_table.get(&address).unwrap();
+        assert_ne!(page.flags & FLAG_READ_WRITE, 0);
+        assert!(!page.copy_on_write);
+
+        // Without a frame for the root table of the child either
+        vmm.allocate_page().unwrap();
+        assert_eq!(vmm.fork_address_space(1, 2), Err(Errno::ENOMEM));
+        assert!(!vmm.spaces.contains_key(&2));
+        let page = vmm.spaces[&1].page_table.get(&address).unwrap();
+        assert_ne!(page.flags & FLAG_READ_WRITE, 0);
+        assert!(!page.copy_on_write);
+    }
+
+    #[test]
+    fn mmap_out_of_memory_maps_nothing() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        vmm.create_address_space(1).unwrap();
+        // Past the first PT, the mapping needs a table of its own
+        let backing: Vec<u64> = (0..HUGE_PAGE_SPAN + 1)
+            .map(|_| vmm.allocate_page().unwrap().0)
+            .collect();
+        let address = backing[0] * vmm.page_size;
+        let first = frame_of(&mut vmm, KERNEL_SPACE, address);
+        // Enough frames for the PDPT, PD and PT of the first page only
+        while vmm.free_memory > 3 * vmm.page_size {
+            vmm.allocate_page().unwrap();
+        }
+
+        assert_eq!(vmm.mmap(1, &backing, MapKind::Shared), Err(Errno::ENOMEM));
+        assert_eq!(vmm.free_memory, 3 * vmm.page_size);
+        assert_eq!(vmm.frames[first].ref_count, 1);
+        assert_eq!(vmm.resident_pages(1), 0);
+        assert!(vmm.spaces[&1].mappings.is_empty());
+        assert_eq!(vmm.spaces[&1].next_virtual_address, 0);
+
+        assert_eq!(vmm.mmap(1, &backing[..1], MapKind::Shared), Ok(0));
+        assert_eq!(vmm.frames[first].ref_count, 2);
+    }
+
+    #[test]
+    fn break_cannot_go_below_a_live_allocation() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        vmm.create_address_space(1).unwrap();
+        let block = vmm.malloc(1, 100).unwrap();
+        vmm.write_bytes(1, block, b"live").unwrap();
+        let start = vmm.space_mut(1).unwrap().heap.start;
+        let brk = vmm.sbrk(1, 0).unwrap();
+        let free_memory = vmm.free_memory;
+
+        assert_eq!(vmm.brk(1, start), Err(Errno::EINVAL));
+        assert_eq!(vmm.sbrk(1, 0), Ok(brk));
+        assert_eq!(vmm.free_memory, free_memory);
+        assert_eq!(vmm.read_bytes(1, block, 4).unwrap(), b"live");
+
+        vmm.free(1, block).unwrap();
+        assert_eq!(vmm.brk(1, start), Ok(start));
+        assert_eq!(vmm.read_bytes(1, block, 1), Err(Errno::EFAULT));
+    }
+
+    #[test]
+    fn huge_malloc_fails_with_enomem() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        vmm.create_address_space(1).unwrap();
+        let brk = vmm.sbrk(1, 0).unwrap();
+        let free_memory = vmm.free_memory;
+
+        for size in [u64::MAX, u64::MAX - 4096, 1 << 63, 1 << 40] {
+            assert_eq!(vmm.malloc(1, size), Err(Errno::ENOMEM));
+        }
+        assert_eq!(vmm.sbrk(1, 0), Ok(brk));
+        assert_eq!(vmm.free_memory, free_memory);
+        assert!(vmm.malloc(1, 100).is_ok());
+    }
+
+    #[test]
+    fn write_after_fork_copies_the_page() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        vmm.create_address_space(1).unwrap();
+        let address = vmm.load_segment(1, b"data", true).unwrap();
+        let frame = frame_of(&mut vmm, 1, address);
+        vmm.fork_address_space(1, 2).unwrap();
+
+        vmm.write_bytes(2, address, b"DATA").unwrap();
+        let copy = frame_of(&mut vmm, 2, address);
+        assert_ne!(copy, frame);
+        assert_eq!(vmm.read_bytes(1, address, 4).unwrap(), b"data");
+        assert_eq!(vmm.read_bytes(2, address, 4).unwrap(), b"DATA");
+        assert_eq!(vmm.frames[frame].ref_count, 1);
+        assert_eq!(vmm.frames[copy].ref_count, 1);
+
+        // The parent is the last one using its frame, it writes in place
+        vmm.write_bytes(1, address, b"Data").unwrap();
+        assert_eq!(frame_of(&mut vmm, 1, address), frame);
+        assert_eq!(vmm.read_bytes(2, address, 4).unwrap(), b"DATA");
+    }
+
+    #[test]
+    fn huge_page_holds_every_byte() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        let bytes = pattern(HUGE_PAGE_SIZE as usize + 100);
+        let pages = vmm.allocate_bytes(bytes.clone()).unwrap();
+        assert_eq!(vmm.stats().huge_pages, 1);
+        assert_eq!(vmm.get_bytes(pages.clone(), bytes.len() as u64), bytes);
+
+        vmm.write_page(KERNEL_SPACE, pages[0], 4096, b"kernel")
+            .unwrap();
+        let content = vmm.read_page(KERNEL_SPACE, pages[0]).unwrap();
+        assert_eq!(&content[4096..4102], b"kernel");
+    }
+
+    #[test]
+    fn huge_page_is_readable_and_writable_past_its_first_page() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        vmm.create_address_space(1).unwrap();
+        let bytes = pattern(HUGE_PAGE_SIZE as usize);
+        let pages = vmm.allocate_bytes(bytes.clone()).unwrap();
+
+        let address = vmm.mmap(1, &pages, MapKind::Shared).unwrap();
+        assert_eq!(address % HUGE_PAGE_SIZE, 0);
+        assert_eq!(
+            vmm.read_bytes(1, address + 4096, 8).unwrap(),
+            &bytes[4096..4104]
+        );
+        vmm.write_bytes(1, address + 4096, b"written").unwrap();
+        assert_eq!(vmm.read_bytes(1, address + 4096, 7).unwrap(), b"written");
+        let last = address + HUGE_PAGE_SIZE - 3;
+        vmm.write_bytes(1, last, b"end").unwrap();
+
+        let content = vmm.get_bytes(pages, HUGE_PAGE_SIZE);
+        assert_eq!(&content[4096..4103], b"written");
+        assert_eq!(&content[HUGE_PAGE_SIZE as usize - 3..], b"end");
+        assert_eq!(&content[..4096], &bytes[..4096]);
+    }
+
+    #[test]
+    fn private_huge_page_is_copied_whole() {
+        let mut vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        vmm.create_address_space(1).unwrap();
+        let bytes = pattern(HUGE_PAGE_SIZE as usize);
+        let pages = vmm.allocate_bytes(bytes.clone()).unwrap();
+
+        let address = vmm.mmap(1, &pages, MapKind::Private).unwrap();
+        vmm.write_bytes(1, address + 4096, b"private").unwrap();
+        assert_eq!(vmm.read_bytes(1, address + 4096, 7).unwrap(), b"private");
+        assert_eq!(
+            vmm.read_bytes(1, address + 8192, 8).unwrap(),
+            &bytes[8192..8200]
+        );
+        assert_eq!(vmm.get_bytes(pages, HUGE_PAGE_SIZE), bytes);
+    }
+
+    const LARGE_PAGE_SIZES: [PageSize; 2] = [PageSize::Size16K, PageSize::Size64K];
+
+    #[test]
+    fn heap_works_with_large_pages() {
+        for page_size in LARGE_PAGE_SIZES {
+            let mut vmm = Vmm::with_page_size(1 << 24, page_size);
+            let page = page_size.bytes();
+            vmm.create_address_space(1).unwrap();
+
+            let small = vmm.malloc(1, 100).unwrap();
+            let large = vmm.malloc(1, 2 * page + 5).unwrap();
+            let bytes = pattern(2 * page as usize + 5);
+            vmm.write_bytes(1, large, &bytes).unwrap();
+            vmm.write_bytes(1, small, b"small").unwrap();
+            assert_eq!(vmm.read_bytes(1, large, bytes.len()).unwrap(), bytes);
+            assert_eq!(vmm.read_bytes(1, small, 5).unwrap(), b"small");
+
+            let brk = vmm.sbrk(1, 0).unwrap();
+            assert_eq!(brk % page, 0);
+            vmm.free(1, large).unwrap();
+            assert_eq!(vmm.malloc(1, 2 * page), Ok(large));
+            assert_eq!(vmm.sbrk(1, 0), Ok(brk));
+        }
+    }
+
+    #[test]
+    fn fork_works_with_large_pages() {
+        for page_size in LARGE_PAGE_SIZES {
+            let mut vmm = Vmm::with_page_size(1 << 24, page_size);
+            let page = page_size.bytes();
+            vmm.create_address_space(1).unwrap();
+            let bytes = pattern(2 * page as usize + 1);
+            let address = vmm.load_segment(1, &bytes, true).unwrap();
+            let used_frames = vmm.stats().used_frames;
+
+            vmm.fork_address_space(1, 2).unwrap();
+            let second = address + page;
+            assert_eq!(frame_of(&mut vmm, 1, second), frame_of(&mut vmm, 2, second));
+            vmm.write_bytes(2, second + page - 2, b"child").unwrap();
+            assert_ne!(frame_of(&mut vmm, 1, second), frame_of(&mut vmm, 2, second));
+            assert_eq!(vmm.read_bytes(1, address, bytes.len()).unwrap(), bytes);
+            assert_eq!(vmm.read_bytes(2, second + page - 2, 5).unwrap(), b"child");
+
+            vmm.release_address_space(2);
+            assert_eq!(vmm.stats().used_frames, used_frames);
+        }
+    }
+
+    #[test]
+    fn mmap_works_with_large_pages() {
+        for page_size in LARGE_PAGE_SIZES {
+            let mut vmm = Vmm::with_page_size(1 << 24, page_size);
+            let page = page_size.bytes();
+            vmm.create_address_space(1).unwrap();
+            let bytes = pattern(2 * page as usize + 7);
+            let pages = vmm.allocate_bytes(bytes.clone()).unwrap();
+            assert_eq!(pages.len(), 3);
+
+            let shared = vmm.mmap(1, &pages, MapKind::Shared).unwrap();
+            let private = vmm.mmap(1, &pages, MapKind::Private).unwrap();
+            assert_eq!(shared % page, 0);
+            assert_eq!(vmm.read_bytes(1, private, bytes.len()).unwrap(), bytes);
+            vmm.write_bytes(1, shared + page - 3, b"shared").unwrap();
+            vmm.write_bytes(1, private + page, b"private").unwrap();
+
+            let content = vmm.get_bytes(pages, bytes.len() as u64);
+            assert_eq!(&content[page as usize - 3..page as usize + 3], b"shared");
+            assert_eq!(vmm.read_bytes(1, private + page, 7).unwrap(), b"private");
+            // The private copy stays out of the backing pages
+            let page = page as usize;
+            assert_eq!(&content[page + 3..page + 7], &bytes[page + 3..page + 7]);
+        }
     }
 }
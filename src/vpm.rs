@@ -1,107 +1,788 @@
 /**
  * Virtual Process Manager
  */
-use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
-
-use crate::{utils, vmm::Vmm};
-use core::time;
+use crate::{
+    errno::Errno,
+    exec::{Executable, SegmentKind},
+    fd,
+    ipc::IPC,
+    scheduler::{self, SCHEDULER},
+    signal::{DefaultAction, Signal, SignalAction},
+    sync::SYNC,
+    vmm::{Vmm, EXEC_SPACE},
+};
+use lazy_static::lazy_static;
 use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
     sync::{
         atomic::{AtomicU32, Ordering},
-        Arc, Mutex,
+        Arc, Condvar, Mutex, MutexGuard,
     },
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 static NEXT_PID: AtomicU32 = AtomicU32::new(1);
 
+thread_local! {
+    /**
+     * Process on whose behalf the thread runs.
+     */
+    static CURRENT: RefCell<Option<Vpm>> = const { RefCell::new(None) };
+}
+
+/**
+ * Orphans are adopted by the first process.
+ */
+pub const INIT_PID: u32 = 1;
+
+pub const MIN_NICE: i32 = -20;
+pub const MAX_NICE: i32 = 19;
+
+lazy_static! {
+    pub(crate) static ref PROCESS_TABLE: Mutex<HashMap<u32, ProcessControlBlock>> =
+        Mutex::new(HashMap::new());
+    /**
+     * Notified whenever a process changes state, scheduler ticks included.
+     */
+    pub(crate) static ref PROCESS_CHANGED: Condvar = Condvar::new();
+    static ref FOREGROUND: Mutex<Option<u32>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    Ready,
+    Blocked,
+    Stopped,
+    Zombie,
+}
+
+impl fmt::Display for ProcessState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            Self::Running => "R",
+            Self::Ready => "S",
+            Self::Blocked => "D",
+            Self::Stopped => "T",
+            Self::Zombie => "Z",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessControlBlock {
+    pub pid: u32,
+    pub ppid: u32,
+    pub state: ProcessState,
+    pub cmdline: String,
+    pub start_time: SystemTime,
+    pub exit_code: Option<i32>,
+    pub nice: i32,
+    pub cpu_time: Duration,
+    pub pending: Vec<Signal>,
+    pub handlers: HashMap<Signal, SignalAction>,
+}
+
+/**
+ * Snapshot of the process table sorted by pid.
+ */
+pub fn processes() -> Vec<ProcessControlBlock> {
+    let mut processes: Vec<ProcessControlBlock> =
+        PROCESS_TABLE.lock().unwrap().values().cloned().collect();
+    processes.sort_by_key(|process| process.pid);
+    processes
+}
+
+pub fn process(pid: u32) -> Option<ProcessControlBlock> {
+    PROCESS_TABLE.lock().unwrap().get(&pid).cloned()
+}
+
+/**
+ * Move the process to the given state if it is in one of the from states,
+ * a stopped process stays stopped until it gets SIGCONT.
+ */
+fn transition(pid: u32, from: &[ProcessState], to: ProcessState) -> Result<(), Errno> {
+    let mut table = PROCESS_TABLE.lock().unwrap();
+    let process = table.get_mut(&pid).ok_or(Errno::ESRCH)?;
+    if process.state == ProcessState::Zombie {
+        return Err(Errno::ESRCH);
+    }
+    if from.contains(&process.state) {
+        process.state = to;
+    }
+    drop(table);
+    PROCESS_CHANGED.notify_all();
+    Ok(())
+}
+
+/**
+ * Take the process off the CPU until it is woken up.
+ */
+pub fn block(pid: u32) -> Result<(), Errno> {
+    transition(
+        pid,
+        &[ProcessState::Running, ProcessState::Ready],
+        ProcessState::Blocked,
+    )
+}
+
+/**
+ * Make a blocked process runnable again, the scheduler decides when it runs.
+ */
+pub fn wake(pid: u32) -> Result<(), Errno> {
+    transition(pid, &[ProcessState::Blocked], ProcessState::Ready)
+}
+
+/**
+ * Process running in the foreground of the shell, target of Ctrl-C.
+ */
+pub fn foreground() -> Option<u32> {
+    *FOREGROUND.lock().unwrap()
+}
+
+/**
+ * Give the terminal to the process, returning the previous foreground.
+ */
+pub fn set_foreground(pid: Option<u32>) -> Option<u32> {
+    std::mem::replace(&mut *FOREGROUND.lock().unwrap(), pid)
+}
+
+/**
+ * Sleep on behalf of the process of the calling thread, which is blocked
+ * meanwhile. Returns false if the process was terminated.
+ */
+pub fn sleep(duration: Duration) -> bool {
+    let pid = CURRENT.with(|current| current.borrow().as_ref().map(|process| process.pid));
+    if let Some(pid) = pid {
+        block(pid).ok();
+    }
+    std::thread::sleep(duration);
+    if let Some(pid) = pid {
+        wake(pid).ok();
+    }
+    preempt()
+}
+
+/**
+ * Wait for a kernel object guarded by the mutex to change, the process
+ * being blocked meanwhile. Fails with EINTR once the process has been
+ * terminated.
+ */
+pub fn wait_on<'a, T>(
+    pid: u32,
+    guard: MutexGuard<'a, T>,
+    changed: &Condvar,
+) -> Result<MutexGuard<'a, T>, Errno> {
+    block(pid).ok();
+    // Time out to notice a termination nobody notifies us about
+    let (guard, _) = changed.wait_timeout(guard, scheduler::TICK).unwrap();
+    wake(pid).ok();
+    match process(pid) {
+        Some(process) if process.state != ProcessState::Zombie => Ok(guard),
+        _ => Err(Errno::EINTR),
+    }
+}
+
+/**
+ * Process the calling thread runs on behalf of, if any.
+ */
+pub fn current() -> Option<Vpm> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/**
+ * Preemption point for code running on behalf of the process of the
+ * calling thread, see Vpm::yield_cpu. Always true outside of a process.
+ */
+pub fn preempt() -> bool {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .is_none_or(|process| process.yield_cpu())
+    })
+}
+
+/**
+ * Set the nice value of the process, from -20 (favoured) to 19.
+ * Returns the previous value.
+ */
+pub fn set_nice(pid: u32, nice: i32) -> Result<i32, Errno> {
+    if !(MIN_NICE..=MAX_NICE).contains(&nice) {
+        return Err(Errno::EINVAL);
+    }
+    let mut table = PROCESS_TABLE.lock().unwrap();
+    let process = table.get_mut(&pid).ok_or(Errno::ESRCH)?;
+    Ok(std::mem::replace(&mut process.nice, nice))
+}
+
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub struct Vpm {
     pub pid: u32,
     pub vmm: Arc<Mutex<Vmm>>,
-    time: Arc<Mutex<u64>>,
-    children: Vec<Vpm>,
 }
 
 impl Vpm {
     pub fn new(vmm: Arc<Mutex<Vmm>>) -> Self {
-        let time = Arc::new(Mutex::new(0));
-        let process = Self {
-            pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
-            vmm,
-            time: Arc::clone(&time),
-            children: Vec::new(),
-        };
-
-        Vpm::start_time(Arc::clone(&time));
-        process
+        Self::spawn(vmm, 0, "init").expect("Cannot create address space")
     }
 
-    fn start_time(time: Arc<Mutex<u64>>) {
-        std::thread::spawn(move || loop {
-            let mut time_mutex = time.lock().unwrap();
-            *time_mutex += 1;
-            drop(time_mutex);
-            std::thread::sleep(Duration::from_secs(1));
-        });
+    /**
+     * Create a process, forking the address space of its parent unless it
+     * has none. Nothing is left behind when memory runs out.
+     */
+    fn spawn(vmm: Arc<Mutex<Vmm>>, ppid: u32, cmdline: &str) -> Result<Self, Errno> {
+        scheduler::start();
+        let (nice, handlers) = process(ppid)
+            .map(|parent| (parent.nice, parent.handlers))
+            .unwrap_or_default();
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+        let mut memory = vmm.lock().unwrap();
+        match ppid {
+            0 => memory.create_address_space(pid)?,
+            _ => memory.fork_address_space(ppid, pid)?,
+        }
+        drop(memory);
+        fd::inherit(ppid, pid, &vmm);
+        PROCESS_TABLE.lock().unwrap().insert(
+            pid,
+            ProcessControlBlock {
+                pid,
+                ppid,
+                state: ProcessState::Ready,
+                cmdline: cmdline.to_string(),
+                start_time: SystemTime::now(),
+                exit_code: None,
+                nice,
+                cpu_time: Duration::ZERO,
+                pending: Vec::new(),
+                handlers,
+            },
+        );
+        Ok(Self { pid, vmm })
     }
 
-    pub fn fork(&mut self) -> Vpm {
-        let main_process = self.clone();
-        Vpm::new(main_process.vmm)
+    fn fork_named(&mut self, cmdline: &str) -> Result<Vpm, Errno> {
+        Vpm::spawn(Arc::clone(&self.vmm), self.pid, cmdline)
     }
 
-    #[allow(dead_code)]
-    pub fn execute_child<F>(&mut self, func: F)
+    /**
+     * Run func in a child process on its own thread, returning the child pid.
+     * The child stays a zombie until it is waited for.
+     */
+    pub fn execute_child<F>(&mut self, cmdline: &str, func: F) -> Result<u32, Errno>
     where
-        F: FnOnce(&Self) + Send + 'static,
+        F: FnOnce(&Self) -> i32 + Send + 'static,
     {
-        let child_process = self.fork();
-        self.children.push(child_process.clone());
+        let child_process = self.fork_named(cmdline)?;
+        let pid = child_process.pid;
         std::thread::spawn(move || {
-            func(&child_process);
+            CURRENT.with(|current| current.replace(Some(child_process.clone())));
+            child_process
+                .vmm
+                .lock()
+                .unwrap()
+                .switch_context(child_process.pid);
+            let code = func(&child_process);
+            child_process.exit(code);
         });
+        Ok(pid)
     }
 
-    pub fn execute<F>(&mut self, func: F)
-    where
-        F: FnOnce(&Self),
-    {
-        let child_process = self.fork();
-        func(&child_process);
+    /**
+     * Replace the program the process runs: its address space is rebuilt
+     * from the segments of the executable and its shared memory detached,
+     * its descriptors and locks are kept. Returns the address each segment
+     * was loaded at. The new image is built aside, a failed exec leaves the
+     * old one untouched.
+     */
+    pub fn exec(&self, cmdline: &str, executable: &Executable) -> Result<Vec<u64>, Errno> {
+        let mut ipc = IPC.lock().unwrap();
+        let mut vmm = self.vmm.lock().unwrap();
+        vmm.create_address_space(EXEC_SPACE)?;
+        let addresses = executable
+            .segments
+            .iter()
+            .map(|segment| {
+                let writable = segment.kind == SegmentKind::Data;
+                vmm.load_segment(EXEC_SPACE, &segment.bytes, writable)
+            })
+            .collect::<Result<Vec<u64>, Errno>>();
+        let addresses = match addresses {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                vmm.release_address_space(EXEC_SPACE);
+                return Err(e);
+            }
+        };
+        ipc.detach_all(&mut vmm, self.pid);
+        drop(ipc);
+        vmm.replace_address_space(self.pid, EXEC_SPACE)?;
+        vmm.switch_context(self.pid);
+        drop(vmm);
+
+        let mut table = PROCESS_TABLE.lock().unwrap();
+        let process = table.get_mut(&self.pid).ok_or(Errno::ESRCH)?;
+        process.cmdline = cmdline.to_string();
+        Ok(addresses)
     }
 
-    pub fn show_processes(&self) {
+    /**
+     * Terminate the process with the given exit code.
+     */
+    pub fn exit(&self, code: i32) {
+        self.terminate(self.pid, code);
+    }
+
+    /**
+     * Terminate a process: its files are closed, its memory released, its
+     * shared memory detached, its locks released, its children are adopted
+     * by init and it stays a zombie until the parent reaps it. Terminating a
+     * zombie does nothing.
+     */
+    fn terminate(&self, pid: u32, code: i32) {
+        let alive = |table: &HashMap<u32, ProcessControlBlock>| {
+            table
+                .get(&pid)
+                .is_some_and(|process| process.state != ProcessState::Zombie)
+        };
+        if !alive(&PROCESS_TABLE.lock().unwrap()) {
+            return;
+        }
+
+        fd::close_all(pid);
+        let mut ipc = IPC.lock().unwrap();
+        let mut vmm = self.vmm.lock().unwrap();
+        ipc.detach_all(&mut vmm, pid);
+        vmm.release_address_space(pid);
+        drop(vmm);
+        drop(ipc);
+        SYNC.lock().unwrap().release_all(pid);
+
+        let mut table = PROCESS_TABLE.lock().unwrap();
+        if !alive(&table) {
+            return;
+        }
+        let mut reparented_zombie = false;
+        table
+            .values_mut()
+            .filter(|process| process.ppid == pid)
+            .for_each(|process| {
+                process.ppid = INIT_PID;
+                reparented_zombie |= process.state == ProcessState::Zombie;
+            });
+        let process = table.get_mut(&pid).unwrap();
+        process.state = ProcessState::Zombie;
+        process.exit_code = Some(code);
+        process.pending.clear();
+        let ppid = process.ppid;
+        // Nobody is left to reap a process without parent
+        if !table.contains_key(&ppid) {
+            table.remove(&pid);
+        }
+        if reparented_zombie && pid == INIT_PID {
+            table.retain(|_, process| {
+                !(process.ppid == INIT_PID && process.state == ProcessState::Zombie)
+            });
+        }
+        drop(table);
+        SCHEDULER.lock().unwrap().forget(pid);
+        PROCESS_CHANGED.notify_all();
+        self.kill(ppid, Signal::SIGCHLD).ok();
+    }
+
+    /**
+     * Send a signal to a process. Default actions are applied right away,
+     * handlers run when the process reaches its next preemption point.
+     * Init only receives the signals it handles.
+     */
+    pub fn kill(&self, pid: u32, signal: Signal) -> Result<(), Errno> {
+        let mut table = PROCESS_TABLE.lock().unwrap();
+        let process = table
+            .get_mut(&pid)
+            .filter(|process| process.state != ProcessState::Zombie)
+            .ok_or(Errno::ESRCH)?;
+        let ppid = process.ppid;
+        // A stopped process continues whatever the disposition of SIGCONT
+        if signal == Signal::SIGCONT && process.state == ProcessState::Stopped {
+            process.state = ProcessState::Ready;
+        }
+
+        let action = if signal.catchable() {
+            process.handlers.get(&signal).cloned().unwrap_or_default()
+        } else {
+            SignalAction::Default
+        };
+        let mut stopped = false;
+        match action {
+            SignalAction::Ignore => {}
+            SignalAction::Handle(_) => process.pending.push(signal),
+            SignalAction::Default if pid == INIT_PID => {}
+            SignalAction::Default => match signal.default_action() {
+                DefaultAction::Terminate => {
+                    drop(table);
+                    self.terminate(pid, signal.exit_code());
+                    return Ok(());
+                }
+                DefaultAction::Stop => {
+                    process.state = ProcessState::Stopped;
+                    stopped = true;
+                }
+                DefaultAction::Ignore | DefaultAction::Continue => {}
+            },
+        }
+        drop(table);
+        PROCESS_CHANGED.notify_all();
+        if stopped {
+            self.kill(ppid, Signal::SIGCHLD).ok();
+        }
+        Ok(())
+    }
+
+    /**
+     * Install the action taken when the process receives the signal,
+     * returning the previous one.
+     */
+    pub fn signal(&self, signal: Signal, action: SignalAction) -> Result<SignalAction, Errno> {
+        if !signal.catchable() {
+            return Err(Errno::EINVAL);
+        }
+        let mut table = PROCESS_TABLE.lock().unwrap();
+        let process = table.get_mut(&self.pid).ok_or(Errno::ESRCH)?;
+        Ok(process.handlers.insert(signal, action).unwrap_or_default())
+    }
+
+    /**
+     * Action taken when the process receives the signal.
+     */
+    pub fn action(&self, signal: Signal) -> SignalAction {
+        process(self.pid)
+            .and_then(|process| process.handlers.get(&signal).cloned())
+            .unwrap_or_default()
+    }
+
+    /**
+     * Preemption point: wait until the scheduler hands the virtual CPU to
+     * the process, then run the handlers of the pending signals.
+     * Returns false once the process has been terminated, its thread must
+     * then stop working on its behalf.
+     */
+    pub fn yield_cpu(&self) -> bool {
         loop {
-            enable_raw_mode().unwrap();
-
-            // Capture the event
-            let exist_event = crossterm::event::poll(time::Duration::from_millis(100)).unwrap();
-            if exist_event {
-                let event: crossterm::event::Event = crossterm::event::read().unwrap();
-                match event {
-                    crossterm::event::Event::Key(key_event) => {
-                        if key_event.code == crossterm::event::KeyCode::Esc {
-                            disable_raw_mode().unwrap();
-                            break;
+            let mut table = PROCESS_TABLE.lock().unwrap();
+            let pending = loop {
+                match table.get_mut(&self.pid) {
+                    None => return false,
+                    Some(process) => match process.state {
+                        ProcessState::Zombie => return false,
+                        ProcessState::Ready | ProcessState::Stopped => {}
+                        _ if process.pending.is_empty() => return true,
+                        _ => {
+                            let signals = std::mem::take(&mut process.pending);
+                            break signals
+                                .into_iter()
+                                .map(|signal| {
+                                    let action = process.handlers.get(&signal).cloned();
+                                    (signal, action)
+                                })
+                                .collect::<Vec<_>>();
                         }
-                    }
-                    _ => {}
+                    },
                 }
+                table = PROCESS_CHANGED.wait(table).unwrap();
+            };
+            drop(table);
+            pending.into_iter().for_each(|(signal, action)| {
+                if let Some(SignalAction::Handle(handler)) = action {
+                    handler(signal);
+                }
+            });
+        }
+    }
+
+    /**
+     * Reap a zombie child, any child when pid is None. Blocks until a child
+     * exits unless nohang is set, in which case Ok(None) means none has.
+     * Returns the pid and exit code of the reaped child.
+     */
+    pub fn waitpid(&self, pid: Option<u32>, nohang: bool) -> Result<Option<(u32, i32)>, Errno> {
+        let mut table = PROCESS_TABLE.lock().unwrap();
+        loop {
+            let children: Vec<&ProcessControlBlock> = table
+                .values()
+                .filter(|process| process.ppid == self.pid)
+                .filter(|process| pid.is_none_or(|pid| process.pid == pid))
+                .collect();
+            if children.is_empty() {
+                return Err(Errno::ECHILD);
             }
 
-            disable_raw_mode().unwrap();
+            let zombie = children
+                .iter()
+                .filter(|process| process.state == ProcessState::Zombie)
+                .min_by_key(|process| process.pid)
+                .map(|process| (process.pid, process.exit_code.unwrap_or(0)));
+            if let Some((zombie, code)) = zombie {
+                table.remove(&zombie);
+                return Ok(Some((zombie, code)));
+            }
+            if nohang {
+                return Ok(None);
+            }
+            table = PROCESS_CHANGED.wait(table).unwrap();
+        }
+    }
 
-            // Clear the screen
-            utils::clear_terminal();
+    /**
+     * Wait until the child exits or stops, like waitpid with WUNTRACED.
+     * Returns the exit code of the reaped child, None if it stopped.
+     */
+    pub fn waitpid_untraced(&self, pid: u32) -> Result<Option<i32>, Errno> {
+        let mut table = PROCESS_TABLE.lock().unwrap();
+        loop {
+            let child = table
+                .get(&pid)
+                .filter(|process| process.ppid == self.pid)
+                .ok_or(Errno::ECHILD)?;
+            match child.state {
+                ProcessState::Zombie => {
+                    let code = child.exit_code.unwrap_or(0);
+                    table.remove(&pid);
+                    return Ok(Some(code));
+                }
+                ProcessState::Stopped => return Ok(None),
+                _ => table = PROCESS_CHANGED.wait(table).unwrap(),
+            }
+        }
+    }
+}
 
-            let time = self.time.lock().unwrap();
-            println!("Press ESC to stop showing processes");
-            println!("PID: {} TIME: {}", self.pid, time);
-            self.children.iter().for_each(|child| {
-                println!("PID: {} Time: {}", child.pid, time);
-            });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{ProgramKind, Segment};
+    use crate::vmm::PageSize;
+
+    /**
+     * A process and its child, the signals are sent to the child since
+     * init only receives the signals it handles.
+     */
+    fn family() -> (Vpm, Vpm) {
+        let vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        let mut parent = Vpm::new(Arc::new(Mutex::new(vmm)));
+        let child = parent.fork_named("child").unwrap();
+        (parent, child)
+    }
+
+    fn state(pid: u32) -> Option<ProcessState> {
+        process(pid).map(|process| process.state)
+    }
+
+    /**
+     * The ticker moves runnable processes between ready and running.
+     */
+    fn runnable(pid: u32) -> bool {
+        matches!(
+            state(pid),
+            Some(ProcessState::Ready | ProcessState::Running)
+        )
+    }
+
+    #[test]
+    fn exec_out_of_memory_keeps_the_old_image() {
+        let vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        let process = Vpm::new(Arc::new(Mutex::new(vmm)));
+        let mut vmm = process.vmm.lock().unwrap();
+        let address = vmm.load_segment(process.pid, b"old image", true).unwrap();
+        let free_memory = vmm.free_memory;
+        let page_size = vmm.stats().page_size;
+        drop(vmm);
+
+        let executable = Executable {
+            kind: ProgramKind::Bytecode,
+            entry: 0,
+            interpreter: None,
+            segments: vec![Segment {
+                kind: SegmentKind::Data,
+                bytes: vec![0; (free_memory + page_size) as usize],
+            }],
+            symbols: Vec::new(),
+        };
+        assert_eq!(
+            process.exec("huge", &executable).unwrap_err(),
+            Errno::ENOMEM
+        );
+        let mut vmm = process.vmm.lock().unwrap();
+        assert_eq!(vmm.free_memory, free_memory);
+        assert_eq!(
+            vmm.read_bytes(process.pid, address, 9).unwrap(),
+            b"old image"
+        );
+        drop(vmm);
+        assert_eq!(super::process(process.pid).unwrap().cmdline, "init");
+    }
+
+    #[test]
+    fn fork_out_of_memory_leaves_the_kernel_unchanged() {
+        let vmm = Vmm::with_page_size(1 << 24, PageSize::default());
+        let mut parent = Vpm::new(Arc::new(Mutex::new(vmm)));
+        let mut vmm = parent.vmm.lock().unwrap();
+        vmm.load_segment(parent.pid, b"data", true).unwrap();
+        while vmm.free_memory > 0 {
+            vmm.allocate_page().unwrap();
         }
+        let resident = vmm.resident_pages(parent.pid);
+        drop(vmm);
+
+        assert_eq!(parent.fork_named("child").unwrap_err(), Errno::ENOMEM);
+        assert_eq!(
+            parent.execute_child("child", |_| 0).unwrap_err(),
+            Errno::ENOMEM
+        );
+        let vmm = parent.vmm.lock().unwrap();
+        assert_eq!(vmm.free_memory, 0);
+        assert_eq!(vmm.resident_pages(parent.pid), resident);
+        drop(vmm);
+        assert!(processes().iter().all(|process| process.ppid != parent.pid));
+        assert_eq!(parent.waitpid(None, true), Err(Errno::ECHILD));
+    }
+
+    #[test]
+    fn waitpid_reaps_a_zombie() {
+        let (parent, child) = family();
+        assert_eq!(parent.waitpid(Some(child.pid), true), Ok(None));
+
+        child.exit(7);
+        assert_eq!(state(child.pid), Some(ProcessState::Zombie));
+        assert_eq!(
+            parent.waitpid(Some(child.pid), false),
+            Ok(Some((child.pid, 7)))
+        );
+        assert_eq!(state(child.pid), None);
+        assert_eq!(parent.waitpid(Some(child.pid), true), Err(Errno::ECHILD));
+    }
+
+    #[test]
+    fn waitpid_blocks_until_a_child_exits() {
+        let (parent, child) = family();
+        let exiting = child.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            exiting.exit(3);
+        });
+        assert_eq!(
+            parent.waitpid(Some(child.pid), false),
+            Ok(Some((child.pid, 3)))
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn waitpid_without_children_fails_with_echild() {
+        let (parent, child) = family();
+        assert_eq!(child.waitpid(None, false), Err(Errno::ECHILD));
+        assert_eq!(child.waitpid(Some(parent.pid), true), Err(Errno::ECHILD));
+    }
+
+    #[test]
+    fn orphans_are_adopted_by_init() {
+        let (parent, mut child) = family();
+        let grandchild = child.fork_named("grandchild").unwrap();
+        assert_eq!(process(grandchild.pid).unwrap().ppid, child.pid);
+
+        child.exit(0);
+        assert_eq!(process(grandchild.pid).unwrap().ppid, INIT_PID);
+        assert_eq!(
+            parent.waitpid(Some(child.pid), true),
+            Ok(Some((child.pid, 0)))
+        );
+        grandchild.exit(0);
+    }
+
+    #[test]
+    fn default_action_terminates_or_stops() {
+        let (parent, child) = family();
+        parent.kill(child.pid, Signal::SIGCHLD).unwrap();
+        assert!(runnable(child.pid));
+
+        parent.kill(child.pid, Signal::SIGTSTP).unwrap();
+        assert_eq!(state(child.pid), Some(ProcessState::Stopped));
+        parent.kill(child.pid, Signal::SIGCONT).unwrap();
+        assert!(runnable(child.pid));
+
+        parent.kill(child.pid, Signal::SIGTERM).unwrap();
+        assert_eq!(state(child.pid), Some(ProcessState::Zombie));
+        assert_eq!(
+            parent.waitpid(Some(child.pid), true),
+            Ok(Some((child.pid, Signal::SIGTERM.exit_code())))
+        );
+        assert_eq!(parent.kill(child.pid, Signal::SIGTERM), Err(Errno::ESRCH));
+    }
+
+    #[test]
+    fn ignored_signal_leaves_the_process_alone() {
+        let (parent, child) = family();
+        let old = child.signal(Signal::SIGTERM, SignalAction::Ignore).unwrap();
+        assert!(matches!(old, SignalAction::Default));
+        assert!(matches!(
+            child.action(Signal::SIGTERM),
+            SignalAction::Ignore
+        ));
+
+        parent.kill(child.pid, Signal::SIGTERM).unwrap();
+        assert!(runnable(child.pid));
+        assert!(process(child.pid).unwrap().pending.is_empty());
+
+        child
+            .signal(Signal::SIGTERM, SignalAction::Default)
+            .unwrap();
+        parent.kill(child.pid, Signal::SIGTERM).unwrap();
+        assert_eq!(state(child.pid), Some(ProcessState::Zombie));
+    }
+
+    #[test]
+    fn handler_runs_at_the_next_preemption_point() {
+        let (parent, child) = family();
+        let caught = Arc::new(Mutex::new(Vec::new()));
+        let handler_caught = Arc::clone(&caught);
+        let handler: crate::signal::Handler = Arc::new(move |signal| {
+            handler_caught.lock().unwrap().push(signal);
+        });
+        child
+            .signal(Signal::SIGINT, SignalAction::Handle(handler))
+            .unwrap();
+
+        // Blocked, the child does not wait for the CPU to run its handlers
+        block(child.pid).unwrap();
+        parent.kill(child.pid, Signal::SIGINT).unwrap();
+        parent.kill(child.pid, Signal::SIGINT).unwrap();
+        assert!(caught.lock().unwrap().is_empty());
+        assert_eq!(process(child.pid).unwrap().pending.len(), 2);
+
+        assert!(child.yield_cpu());
+        assert_eq!(*caught.lock().unwrap(), [Signal::SIGINT, Signal::SIGINT]);
+        assert_eq!(state(child.pid), Some(ProcessState::Blocked));
+    }
+
+    #[test]
+    fn kill_and_stop_cannot_be_caught() {
+        let (parent, child) = family();
+        assert_eq!(
+            child
+                .signal(Signal::SIGKILL, SignalAction::Ignore)
+                .unwrap_err(),
+            Errno::EINVAL
+        );
+        assert_eq!(
+            child
+                .signal(Signal::SIGSTOP, SignalAction::Ignore)
+                .unwrap_err(),
+            Errno::EINVAL
+        );
+        parent.kill(child.pid, Signal::SIGKILL).unwrap();
+        assert_eq!(state(child.pid), Some(ProcessState::Zombie));
     }
 }